@@ -0,0 +1,2 @@
+DROP TABLE alr_goal_accounts;
+DROP TABLE alr_goals;
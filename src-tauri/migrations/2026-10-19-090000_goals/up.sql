@@ -0,0 +1,21 @@
+--  Savings goals: a target amount to reach by a given date, from the
+--  balance of a set of accounts (or only a share of some accounts).
+
+CREATE TABLE IF NOT EXISTS alr_goals (
+   id            integer  NOT NULL PRIMARY KEY AUTOINCREMENT,
+   name          text     NOT NULL,
+   description   text,
+   scaled_target integer  NOT NULL,  --  scaled by currency's price_scale
+   target_date   date     NOT NULL,
+   currency_id   integer  NOT NULL
+      REFERENCES alr_commodities (id) DEFERRABLE INITIALLY DEFERRED
+);
+CREATE TABLE IF NOT EXISTS alr_goal_accounts (
+   goal_id       integer  NOT NULL
+      REFERENCES alr_goals (id) DEFERRABLE INITIALLY DEFERRED,
+   account_id    integer  NOT NULL
+      REFERENCES alr_accounts (id) DEFERRABLE INITIALLY DEFERRED,
+   share         real     NOT NULL DEFAULT 1.0,  --  between 0.0 and 1.0
+   PRIMARY KEY (goal_id, account_id)
+);
+CREATE INDEX alr_goal_accounts_account_id ON alr_goal_accounts (account_id);
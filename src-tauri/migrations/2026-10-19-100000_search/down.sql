@@ -0,0 +1,10 @@
+DROP TRIGGER alr_search_account_update;
+DROP TRIGGER alr_search_payee_update;
+DROP TRIGGER alr_search_split_delete;
+DROP TRIGGER alr_search_split_update;
+DROP TRIGGER alr_search_split_insert;
+DROP TRIGGER alr_search_transaction_delete;
+DROP TRIGGER alr_search_transaction_update;
+DROP TRIGGER alr_search_transaction_insert;
+DROP TABLE alr_transactions_search;
+DROP VIEW alr_search_content;
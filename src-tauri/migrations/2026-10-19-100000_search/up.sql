@@ -0,0 +1,109 @@
+--  Full-text index over transactions. There is one row per transaction
+--  (the rowid is the transaction's id), which combines the memo and check
+--  number with the payees and names of accounts of all its splits.
+
+CREATE VIEW alr_search_content AS
+   SELECT
+      t.id,
+      COALESCE(t.memo, '') AS memo,
+      COALESCE(t.check_number, '') AS check_number,
+      COALESCE(
+         (SELECT group_concat(DISTINCT p.name)
+          FROM alr_splits s JOIN alr_payees p ON (s.payee_id = p.id)
+          WHERE s.transaction_id = t.id),
+         '') AS payees,
+      COALESCE(
+         (SELECT group_concat(DISTINCT a.name)
+          FROM alr_splits s JOIN alr_accounts a ON (s.account_id = a.id)
+          WHERE s.transaction_id = t.id),
+         '') AS accounts
+   FROM alr_transactions t
+;
+
+CREATE VIRTUAL TABLE alr_transactions_search USING fts5(
+   memo,
+   check_number,
+   payees,
+   accounts,
+   tokenize = 'unicode61 remove_diacritics 2'
+);
+
+INSERT INTO alr_transactions_search
+   (rowid, memo, check_number, payees, accounts)
+   SELECT * FROM alr_search_content;
+
+--  Keep the index in sync
+
+CREATE TRIGGER alr_search_transaction_insert
+   AFTER INSERT ON alr_transactions
+BEGIN
+   INSERT INTO alr_transactions_search
+      (rowid, memo, check_number, payees, accounts)
+      SELECT * FROM alr_search_content WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER alr_search_transaction_update
+   AFTER UPDATE ON alr_transactions
+BEGIN
+   DELETE FROM alr_transactions_search WHERE rowid = OLD.id;
+   INSERT INTO alr_transactions_search
+      (rowid, memo, check_number, payees, accounts)
+      SELECT * FROM alr_search_content WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER alr_search_transaction_delete
+   AFTER DELETE ON alr_transactions
+BEGIN
+   DELETE FROM alr_transactions_search WHERE rowid = OLD.id;
+END;
+
+CREATE TRIGGER alr_search_split_insert
+   AFTER INSERT ON alr_splits
+BEGIN
+   DELETE FROM alr_transactions_search WHERE rowid = NEW.transaction_id;
+   INSERT INTO alr_transactions_search
+      (rowid, memo, check_number, payees, accounts)
+      SELECT * FROM alr_search_content WHERE id = NEW.transaction_id;
+END;
+
+CREATE TRIGGER alr_search_split_update
+   AFTER UPDATE ON alr_splits
+BEGIN
+   DELETE FROM alr_transactions_search
+      WHERE rowid IN (OLD.transaction_id, NEW.transaction_id);
+   INSERT INTO alr_transactions_search
+      (rowid, memo, check_number, payees, accounts)
+      SELECT * FROM alr_search_content
+      WHERE id IN (OLD.transaction_id, NEW.transaction_id);
+END;
+
+CREATE TRIGGER alr_search_split_delete
+   AFTER DELETE ON alr_splits
+BEGIN
+   DELETE FROM alr_transactions_search WHERE rowid = OLD.transaction_id;
+   INSERT INTO alr_transactions_search
+      (rowid, memo, check_number, payees, accounts)
+      SELECT * FROM alr_search_content WHERE id = OLD.transaction_id;
+END;
+
+CREATE TRIGGER alr_search_payee_update
+   AFTER UPDATE OF name ON alr_payees
+BEGIN
+   DELETE FROM alr_transactions_search WHERE rowid IN
+      (SELECT transaction_id FROM alr_splits WHERE payee_id = NEW.id);
+   INSERT INTO alr_transactions_search
+      (rowid, memo, check_number, payees, accounts)
+      SELECT * FROM alr_search_content WHERE id IN
+         (SELECT transaction_id FROM alr_splits WHERE payee_id = NEW.id);
+END;
+
+CREATE TRIGGER alr_search_account_update
+   AFTER UPDATE OF name ON alr_accounts
+BEGIN
+   DELETE FROM alr_transactions_search WHERE rowid IN
+      (SELECT transaction_id FROM alr_splits WHERE account_id = NEW.id);
+   INSERT INTO alr_transactions_search
+      (rowid, memo, check_number, payees, accounts)
+      SELECT * FROM alr_search_content WHERE id IN
+         (SELECT transaction_id FROM alr_splits WHERE account_id = NEW.id);
+END;
@@ -0,0 +1,7 @@
+DROP VIEW alr_tagged_splits;
+DROP TRIGGER alr_tags_tag_delete;
+DROP TRIGGER alr_tags_split_delete;
+DROP TRIGGER alr_tags_transaction_delete;
+DROP TABLE alr_split_tags;
+DROP TABLE alr_transaction_tags;
+DROP TABLE alr_tags;
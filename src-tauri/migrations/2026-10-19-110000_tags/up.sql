@@ -0,0 +1,57 @@
+--  Tags are cross-cutting labels ("vacation 2025", "reimbursable",...)
+--  that can be attached to whole transactions or to individual splits.
+
+CREATE TABLE IF NOT EXISTS alr_tags (
+   id           integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+   name         text    NOT NULL UNIQUE,
+   description  text
+);
+CREATE TABLE IF NOT EXISTS alr_transaction_tags (
+   transaction_id integer NOT NULL
+      REFERENCES alr_transactions (id) DEFERRABLE INITIALLY DEFERRED,
+   tag_id         integer NOT NULL
+      REFERENCES alr_tags (id) DEFERRABLE INITIALLY DEFERRED,
+   PRIMARY KEY (transaction_id, tag_id)
+);
+CREATE TABLE IF NOT EXISTS alr_split_tags (
+   split_id     integer NOT NULL
+      REFERENCES alr_splits (id) DEFERRABLE INITIALLY DEFERRED,
+   tag_id       integer NOT NULL
+      REFERENCES alr_tags (id) DEFERRABLE INITIALLY DEFERRED,
+   PRIMARY KEY (split_id, tag_id)
+);
+CREATE INDEX alr_transaction_tags_tag_id ON alr_transaction_tags (tag_id);
+CREATE INDEX alr_split_tags_tag_id ON alr_split_tags (tag_id);
+
+--  Remove links when the tagged element is deleted
+
+CREATE TRIGGER alr_tags_transaction_delete
+   AFTER DELETE ON alr_transactions
+BEGIN
+   DELETE FROM alr_transaction_tags WHERE transaction_id = OLD.id;
+END;
+
+CREATE TRIGGER alr_tags_split_delete
+   AFTER DELETE ON alr_splits
+BEGIN
+   DELETE FROM alr_split_tags WHERE split_id = OLD.id;
+END;
+
+CREATE TRIGGER alr_tags_tag_delete
+   AFTER DELETE ON alr_tags
+BEGIN
+   DELETE FROM alr_transaction_tags WHERE tag_id = OLD.id;
+   DELETE FROM alr_split_tags WHERE tag_id = OLD.id;
+END;
+
+--  All splits for a tag: either the split itself is tagged, or its
+--  transaction is.
+
+CREATE VIEW alr_tagged_splits AS
+   SELECT s.id AS split_id, s.transaction_id, st.tag_id
+      FROM alr_split_tags st JOIN alr_splits s ON (st.split_id = s.id)
+   UNION
+   SELECT s.id AS split_id, s.transaction_id, tt.tag_id
+      FROM alr_transaction_tags tt
+         JOIN alr_splits s ON (tt.transaction_id = s.transaction_id)
+;
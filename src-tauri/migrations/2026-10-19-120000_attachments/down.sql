@@ -0,0 +1,2 @@
+DROP TRIGGER alr_attachments_transaction_delete;
+DROP TABLE alr_attachments;
@@ -0,0 +1,27 @@
+--  Files (receipts, invoices,...) attached to transactions. The files
+--  themselves are stored outside of the database, in a directory next to
+--  it, named after the SHA-256 of their contents. Several attachments can
+--  share the same file.
+
+CREATE TABLE IF NOT EXISTS alr_attachments (
+   id             integer   NOT NULL PRIMARY KEY AUTOINCREMENT,
+   transaction_id integer   NOT NULL
+      REFERENCES alr_transactions (id) DEFERRABLE INITIALLY DEFERRED,
+   hash           text      NOT NULL,  --  hex-encoded SHA-256
+   name           text      NOT NULL,  --  original file name
+   mime_type      text,
+   size           integer   NOT NULL,  --  in bytes
+   added          timestamp NOT NULL
+);
+CREATE INDEX alr_attachments_transaction_id
+   ON alr_attachments (transaction_id);
+CREATE INDEX alr_attachments_hash ON alr_attachments (hash);
+
+--  Files are not removed here; the integrity check reports them as
+--  orphans.
+
+CREATE TRIGGER alr_attachments_transaction_delete
+   AFTER DELETE ON alr_transactions
+BEGIN
+   DELETE FROM alr_attachments WHERE transaction_id = OLD.id;
+END;
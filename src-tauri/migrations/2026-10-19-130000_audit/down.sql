@@ -0,0 +1,15 @@
+DROP TRIGGER alr_audit_transactions_insert;
+DROP TRIGGER alr_audit_transactions_update;
+DROP TRIGGER alr_audit_transactions_delete;
+DROP TRIGGER alr_audit_splits_insert;
+DROP TRIGGER alr_audit_splits_update;
+DROP TRIGGER alr_audit_splits_delete;
+DROP TRIGGER alr_audit_accounts_insert;
+DROP TRIGGER alr_audit_accounts_update;
+DROP TRIGGER alr_audit_accounts_delete;
+DROP TRIGGER alr_audit_prices_insert;
+DROP TRIGGER alr_audit_prices_update;
+DROP TRIGGER alr_audit_prices_delete;
+DROP TABLE alr_audit_state;
+DROP TABLE alr_audit_log;
+DROP TABLE alr_audit_operations;
@@ -0,0 +1,322 @@
+--  Audit log: every change to transactions, splits, accounts and prices is
+--  recorded with images of the row before and after the change (as JSON).
+--  Changes are grouped into operations (one user action), which can be
+--  undone and redone as a whole.
+
+CREATE TABLE IF NOT EXISTS alr_audit_operations (
+   id           integer  NOT NULL PRIMARY KEY AUTOINCREMENT,
+   timestamp    datetime NOT NULL,
+   description  text     NOT NULL,
+   undone       boolean  NOT NULL DEFAULT FALSE
+);
+CREATE TABLE IF NOT EXISTS alr_audit_log (
+   id            integer  NOT NULL PRIMARY KEY AUTOINCREMENT,
+   operation_id  integer   --  NULL for changes made outside of operations
+      REFERENCES alr_audit_operations (id) DEFERRABLE INITIALLY DEFERRED,
+   timestamp     datetime NOT NULL,
+   table_name    text     NOT NULL,
+   row_id        integer  NOT NULL,
+   before        text,     --  NULL for inserted rows
+   after         text      --  NULL for deleted rows
+);
+CREATE INDEX alr_audit_log_operation_id ON alr_audit_log (operation_id);
+CREATE INDEX alr_audit_log_row ON alr_audit_log (table_name, row_id);
+
+--  Set by the application: the operation in progress, and whether the
+--  triggers should be disabled while undoing or redoing.
+
+CREATE TABLE IF NOT EXISTS alr_audit_state (
+   id            integer NOT NULL PRIMARY KEY CHECK (id = 1),
+   operation_id  integer,
+   replaying     boolean NOT NULL
+);
+INSERT INTO alr_audit_state (id, operation_id, replaying)
+   VALUES (1, NULL, FALSE);
+
+CREATE TRIGGER alr_audit_transactions_insert
+   AFTER INSERT ON alr_transactions
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_transactions', NEW.id,
+         NULL,
+         json_object(
+            'id', NEW.id,
+            'timestamp', NEW.timestamp,
+            'memo', NEW.memo,
+            'check_number', NEW.check_number,
+            'scheduled', NEW.scheduled,
+            'last_occurrence', NEW.last_occurrence,
+            'scenario_id', NEW.scenario_id)
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_transactions_update
+   AFTER UPDATE ON alr_transactions
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_transactions', NEW.id,
+         json_object(
+            'id', OLD.id,
+            'timestamp', OLD.timestamp,
+            'memo', OLD.memo,
+            'check_number', OLD.check_number,
+            'scheduled', OLD.scheduled,
+            'last_occurrence', OLD.last_occurrence,
+            'scenario_id', OLD.scenario_id),
+         json_object(
+            'id', NEW.id,
+            'timestamp', NEW.timestamp,
+            'memo', NEW.memo,
+            'check_number', NEW.check_number,
+            'scheduled', NEW.scheduled,
+            'last_occurrence', NEW.last_occurrence,
+            'scenario_id', NEW.scenario_id)
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_transactions_delete
+   AFTER DELETE ON alr_transactions
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_transactions', OLD.id,
+         json_object(
+            'id', OLD.id,
+            'timestamp', OLD.timestamp,
+            'memo', OLD.memo,
+            'check_number', OLD.check_number,
+            'scheduled', OLD.scheduled,
+            'last_occurrence', OLD.last_occurrence,
+            'scenario_id', OLD.scenario_id),
+         NULL
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_splits_insert
+   AFTER INSERT ON alr_splits
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_splits', NEW.id,
+         NULL,
+         json_object(
+            'id', NEW.id,
+            'scaled_qty', NEW.scaled_qty,
+            'scaled_value', NEW.scaled_value,
+            'reconcile', NEW.reconcile,
+            'reconcile_date', NEW.reconcile_date,
+            'post_date', NEW.post_date,
+            'account_id', NEW.account_id,
+            'payee_id', NEW.payee_id,
+            'transaction_id', NEW.transaction_id,
+            'value_commodity_id', NEW.value_commodity_id)
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_splits_update
+   AFTER UPDATE ON alr_splits
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_splits', NEW.id,
+         json_object(
+            'id', OLD.id,
+            'scaled_qty', OLD.scaled_qty,
+            'scaled_value', OLD.scaled_value,
+            'reconcile', OLD.reconcile,
+            'reconcile_date', OLD.reconcile_date,
+            'post_date', OLD.post_date,
+            'account_id', OLD.account_id,
+            'payee_id', OLD.payee_id,
+            'transaction_id', OLD.transaction_id,
+            'value_commodity_id', OLD.value_commodity_id),
+         json_object(
+            'id', NEW.id,
+            'scaled_qty', NEW.scaled_qty,
+            'scaled_value', NEW.scaled_value,
+            'reconcile', NEW.reconcile,
+            'reconcile_date', NEW.reconcile_date,
+            'post_date', NEW.post_date,
+            'account_id', NEW.account_id,
+            'payee_id', NEW.payee_id,
+            'transaction_id', NEW.transaction_id,
+            'value_commodity_id', NEW.value_commodity_id)
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_splits_delete
+   AFTER DELETE ON alr_splits
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_splits', OLD.id,
+         json_object(
+            'id', OLD.id,
+            'scaled_qty', OLD.scaled_qty,
+            'scaled_value', OLD.scaled_value,
+            'reconcile', OLD.reconcile,
+            'reconcile_date', OLD.reconcile_date,
+            'post_date', OLD.post_date,
+            'account_id', OLD.account_id,
+            'payee_id', OLD.payee_id,
+            'transaction_id', OLD.transaction_id,
+            'value_commodity_id', OLD.value_commodity_id),
+         NULL
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_accounts_insert
+   AFTER INSERT ON alr_accounts
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_accounts', NEW.id,
+         NULL,
+         json_object(
+            'id', NEW.id,
+            'name', NEW.name,
+            'description', NEW.description,
+            'iban', NEW.iban,
+            'number', NEW.number,
+            'closed', NEW.closed,
+            'commodity_scu', NEW.commodity_scu,
+            'last_reconciled', NEW.last_reconciled,
+            'opening_date', NEW.opening_date,
+            'commodity_id', NEW.commodity_id,
+            'institution_id', NEW.institution_id,
+            'kind_id', NEW.kind_id,
+            'parent_id', NEW.parent_id)
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_accounts_update
+   AFTER UPDATE ON alr_accounts
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_accounts', NEW.id,
+         json_object(
+            'id', OLD.id,
+            'name', OLD.name,
+            'description', OLD.description,
+            'iban', OLD.iban,
+            'number', OLD.number,
+            'closed', OLD.closed,
+            'commodity_scu', OLD.commodity_scu,
+            'last_reconciled', OLD.last_reconciled,
+            'opening_date', OLD.opening_date,
+            'commodity_id', OLD.commodity_id,
+            'institution_id', OLD.institution_id,
+            'kind_id', OLD.kind_id,
+            'parent_id', OLD.parent_id),
+         json_object(
+            'id', NEW.id,
+            'name', NEW.name,
+            'description', NEW.description,
+            'iban', NEW.iban,
+            'number', NEW.number,
+            'closed', NEW.closed,
+            'commodity_scu', NEW.commodity_scu,
+            'last_reconciled', NEW.last_reconciled,
+            'opening_date', NEW.opening_date,
+            'commodity_id', NEW.commodity_id,
+            'institution_id', NEW.institution_id,
+            'kind_id', NEW.kind_id,
+            'parent_id', NEW.parent_id)
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_accounts_delete
+   AFTER DELETE ON alr_accounts
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_accounts', OLD.id,
+         json_object(
+            'id', OLD.id,
+            'name', OLD.name,
+            'description', OLD.description,
+            'iban', OLD.iban,
+            'number', OLD.number,
+            'closed', OLD.closed,
+            'commodity_scu', OLD.commodity_scu,
+            'last_reconciled', OLD.last_reconciled,
+            'opening_date', OLD.opening_date,
+            'commodity_id', OLD.commodity_id,
+            'institution_id', OLD.institution_id,
+            'kind_id', OLD.kind_id,
+            'parent_id', OLD.parent_id),
+         NULL
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_prices_insert
+   AFTER INSERT ON alr_prices
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_prices', NEW.id,
+         NULL,
+         json_object(
+            'id', NEW.id,
+            'date', NEW.date,
+            'scaled_price', NEW.scaled_price,
+            'origin_id', NEW.origin_id,
+            'source_id', NEW.source_id,
+            'target_id', NEW.target_id)
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_prices_update
+   AFTER UPDATE ON alr_prices
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_prices', NEW.id,
+         json_object(
+            'id', OLD.id,
+            'date', OLD.date,
+            'scaled_price', OLD.scaled_price,
+            'origin_id', OLD.origin_id,
+            'source_id', OLD.source_id,
+            'target_id', OLD.target_id),
+         json_object(
+            'id', NEW.id,
+            'date', NEW.date,
+            'scaled_price', NEW.scaled_price,
+            'origin_id', NEW.origin_id,
+            'source_id', NEW.source_id,
+            'target_id', NEW.target_id)
+      FROM alr_audit_state;
+END;
+
+CREATE TRIGGER alr_audit_prices_delete
+   AFTER DELETE ON alr_prices
+   WHEN NOT (SELECT replaying FROM alr_audit_state)
+BEGIN
+   INSERT INTO alr_audit_log
+      (operation_id, timestamp, table_name, row_id, before, after)
+      SELECT operation_id, CURRENT_TIMESTAMP, 'alr_prices', OLD.id,
+         json_object(
+            'id', OLD.id,
+            'date', OLD.date,
+            'scaled_price', OLD.scaled_price,
+            'origin_id', OLD.origin_id,
+            'source_id', OLD.source_id,
+            'target_id', OLD.target_id),
+         NULL
+      FROM alr_audit_state;
+END;
@@ -0,0 +1 @@
+ALTER TABLE alr_payees DROP COLUMN iban;
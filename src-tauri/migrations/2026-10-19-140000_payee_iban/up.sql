@@ -0,0 +1,3 @@
+--  The account of a payee, as found in bank statements
+
+ALTER TABLE alr_payees ADD COLUMN iban text;
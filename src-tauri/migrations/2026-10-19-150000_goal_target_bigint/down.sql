@@ -0,0 +1,14 @@
+CREATE TABLE alr_goals_old (
+   id            integer  NOT NULL PRIMARY KEY AUTOINCREMENT,
+   name          text     NOT NULL,
+   description   text,
+   scaled_target integer  NOT NULL,  --  scaled by currency's price_scale
+   target_date   date     NOT NULL,
+   currency_id   integer  NOT NULL
+      REFERENCES alr_commodities (id) DEFERRABLE INITIALLY DEFERRED
+);
+INSERT INTO alr_goals_old
+   SELECT id, name, description, scaled_target, target_date, currency_id
+   FROM alr_goals;
+DROP TABLE alr_goals;
+ALTER TABLE alr_goals_old RENAME TO alr_goals;
@@ -0,0 +1,18 @@
+--  Goal targets are scaled integers like the other amounts, and need 64
+--  bits. SQLite cannot change the type of a column, so the table is
+--  rebuilt.
+
+CREATE TABLE alr_goals_new (
+   id            integer  NOT NULL PRIMARY KEY AUTOINCREMENT,
+   name          text     NOT NULL,
+   description   text,
+   scaled_target bigint   NOT NULL,  --  scaled by currency's price_scale
+   target_date   date     NOT NULL,
+   currency_id   integer  NOT NULL
+      REFERENCES alr_commodities (id) DEFERRABLE INITIALLY DEFERRED
+);
+INSERT INTO alr_goals_new
+   SELECT id, name, description, scaled_target, target_date, currency_id
+   FROM alr_goals;
+DROP TABLE alr_goals;
+ALTER TABLE alr_goals_new RENAME TO alr_goals;
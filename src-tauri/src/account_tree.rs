@@ -0,0 +1,241 @@
+//! The hierarchy of accounts (as given by their parent_id), where each
+//! node carries the value for the account itself and the total for its
+//! whole subtree.
+
+use super::accounts::AccountKindCategory;
+use super::dates::DateValues;
+use super::errors::AlereError;
+use super::metrics::{networth, sum_splits_per_account};
+use super::models::{AccountId, CommodityId};
+use super::occurrences::Occurrences;
+use super::scenarios::{Scenario, NO_SCENARIO};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use log::{info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum TreeValue {
+    // Market value of the networth accounts, at each of the dates
+    NETWORTH,
+
+    // Total of splits in the time range, for all accounts
+    BALANCE,
+
+    // Total of splits in the time range, for realized income and expenses.
+    // As for income_expense, income is positive and expenses negative.
+    INCOME_EXPENSE,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AccountNode {
+    account_id: AccountId,
+    own: Vec<Decimal>,   // one entry per date (or a single one for a range)
+    total: Vec<Decimal>, // including all children
+    children: Vec<AccountNode>,
+}
+
+struct TreeBuilder {
+    names: HashMap<AccountId, String>,
+    children: HashMap<AccountId, Vec<AccountId>>,
+    own: HashMap<AccountId, Vec<Decimal>>,
+    size: usize,
+}
+
+impl TreeBuilder {
+    /// Create the node for an account, or None if neither it nor its
+    /// children have any value.
+    fn build(
+        &self,
+        id: AccountId,
+        visited: &mut HashSet<AccountId>,
+    ) -> Option<AccountNode> {
+        if !visited.insert(id) {
+            return None; //  a cycle in parent_id
+        }
+
+        let mut children: Vec<AccountNode> = self.children
+            .get(&id)
+            .map(|c| c.iter().filter_map(|&ch| self.build(ch, visited)).collect())
+            .unwrap_or_default();
+        children.sort_by_key(|n| self.names.get(&n.account_id).map(|s| s.to_lowercase()));
+
+        let own = self.own.get(&id).cloned().unwrap_or_else(|| vec![Decimal::ZERO; self.size]);
+        let mut total = own.clone();
+        for c in &children {
+            for (t, v) in total.iter_mut().zip(c.total.iter()) {
+                *t += v;
+            }
+        }
+
+        if children.is_empty() && own.iter().all(|v| v.is_zero()) {
+            None
+        } else {
+            Some(AccountNode { account_id: id, own, total, children })
+        }
+    }
+}
+
+/// Compute the value of each account, for the requested kind of value.
+/// Only accounts with a value are returned.
+
+fn own_values(
+    value: TreeValue,
+    dates: &DateValues,
+    currency: CommodityId,
+    scenario: Scenario,
+) -> Result<HashMap<AccountId, Vec<Decimal>>, AlereError> {
+    Ok(match value {
+        TreeValue::NETWORTH => {
+            networth(dates, currency, scenario, &Occurrences::no_recurrence())?
+                .iter()
+                .map(|acc| (
+                    acc.account_id,
+                    acc.shares.iter()
+                        .zip(acc.price.iter())
+                        .map(|(s, p)| (s * p).normalize())
+                        .collect(),
+                ))
+                .collect()
+        }
+        TreeValue::BALANCE | TreeValue::INCOME_EXPENSE => {
+            let mut per_account = sum_splits_per_account(
+                dates, currency, scenario, &Occurrences::no_recurrence())?;
+            if let TreeValue::INCOME_EXPENSE = value {
+                let income = AccountKindCategory::INCOME as i32;
+                let expense = AccountKindCategory::EXPENSE as i32;
+                let realized: HashSet<AccountId> = {
+                    use super::schema::alr_account_kinds::dsl as k;
+                    use super::schema::alr_accounts::dsl as a;
+                    let c = &super::connections::get_connection()?;
+                    a::alr_accounts
+                        .inner_join(k::alr_account_kinds)
+                        .filter(k::category.eq_any(vec![income, expense]))
+                        .filter(k::is_unrealized.eq(false))
+                        .select(a::id)
+                        .load::<AccountId>(c)?
+                        .into_iter()
+                        .collect()
+                };
+                per_account.retain(|id, _| realized.contains(id));
+                per_account.values_mut().for_each(|v| *v = -*v);
+            }
+            per_account
+                .into_iter()
+                .map(|(id, v)| (id, vec![v]))
+                .collect()
+        }
+    })
+}
+
+/// The accounts at the top of the tree: those without a parent, and one
+/// account in each cycle of parents (which would otherwise not be part of
+/// the tree at all).
+
+fn tree_roots(accounts: &[(AccountId, Option<AccountId>)]) -> Vec<AccountId> {
+    let parents: HashMap<AccountId, Option<AccountId>> = accounts.iter().cloned().collect();
+    let mut roots: Vec<AccountId> = accounts
+        .iter()
+        .filter(|(_, p)| p.map(|p| !parents.contains_key(&p)).unwrap_or(true))
+        .map(|(id, _)| *id)
+        .collect();
+
+    // Accounts whose ancestors are known to lead to a root, or to a cycle
+    // that was already handled
+    let mut done: HashSet<AccountId> = roots.iter().cloned().collect();
+    for (id, _) in accounts {
+        let mut path = vec![];
+        let mut current = *id;
+        while !done.contains(&current) {
+            if let Some(pos) = path.iter().position(|a| *a == current) {
+                let cycle = &path[pos..];
+                let root = *cycle.iter().min().unwrap();
+                warn!("Cycle in the parents of accounts {:?}", cycle);
+                roots.push(root);
+                break;
+            }
+            path.push(current);
+            match parents.get(&current).cloned().flatten() {
+                Some(p) => current = p,
+                None => break,
+            }
+        }
+        done.extend(path);
+    }
+    roots
+}
+
+/// Return the tree of accounts, with values for each node and their
+/// subtree.
+///
+/// :param dates:
+///     For NETWORTH, the values are computed at each of those dates. For
+///     the other kinds of values, the splits between the first and last
+///     dates are added.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn account_tree(
+    dates: Vec<DateTime<Utc>>,
+    currency: CommodityId,
+    value: TreeValue,
+    scenario: Option<Scenario>,
+) -> Result<Vec<AccountNode>, AlereError> {
+    info!("account_tree {:?} {:?} {:?}", &dates, value, scenario);
+    let dates = DateValues::new(Some(dates.iter().map(|d| d.date()).collect()));
+    let own = own_values(value, &dates, currency, scenario.unwrap_or(NO_SCENARIO))?;
+
+    let accounts: Vec<(AccountId, String, Option<AccountId>)> = {
+        use super::schema::alr_accounts::dsl::*;
+        let c = &super::connections::get_connection()?;
+        alr_accounts
+            .select((id, name, parent_id))
+            .load(c)?
+    };
+    let known: HashSet<AccountId> = accounts.iter().map(|a| a.0).collect();
+    let mut builder = TreeBuilder {
+        names: HashMap::new(),
+        children: HashMap::new(),
+        own,
+        size: match value {
+            TreeValue::NETWORTH => dates.len(),
+            _ => 1,
+        },
+    };
+    let roots = tree_roots(
+        &accounts.iter().map(|(id, _, parent)| (*id, *parent)).collect::<Vec<_>>());
+    for (id, name, parent) in accounts {
+        builder.names.insert(id, name);
+        if let Some(p) = parent.filter(|p| known.contains(p)) {
+            builder.children.entry(p).or_insert_with(Vec::new).push(id);
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut result: Vec<AccountNode> = roots
+        .iter()
+        .filter_map(|&id| builder.build(id, &mut visited))
+        .collect();
+    result.sort_by_key(|n| builder.names.get(&n.account_id).map(|s| s.to_lowercase()));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roots() {
+        // 4 has an unknown parent, 5-6-7 form a cycle, and 8 is below it
+        let accounts = [
+            (1, None), (2, Some(1)), (3, Some(2)), (4, Some(99)),
+            (5, Some(7)), (6, Some(5)), (7, Some(6)), (8, Some(6)),
+            (9, Some(9)),
+        ];
+        let mut roots = tree_roots(&accounts);
+        roots.sort();
+        assert_eq!(roots, vec![1, 4, 5, 9]);
+    }
+}
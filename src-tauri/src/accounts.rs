@@ -1,3 +1,4 @@
+use super::errors::AlereError;
 use super::models::{Account, AccountKind, Commodity, Institution};
 use diesel::prelude::*;
 
@@ -9,21 +10,21 @@ pub struct Accounts {
     institutions: Vec<Institution>,
 }
 
-#[tauri::command]
-pub async fn fetch_accounts() -> Accounts {
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn fetch_accounts() -> Result<Accounts, AlereError> {
     use super::schema::alr_account_kinds::dsl::*;
     use super::schema::alr_accounts::dsl::*;
     use super::schema::alr_commodities::dsl::*;
     use super::schema::alr_institutions::dsl::*;
 
-    let c = &super::connections::get_connection();
+    let c = &super::connections::get_connection()?;
 
-    Accounts {
-        accounts: alr_accounts.load(c).expect("Error for accounts"),
-        commodities: alr_commodities.load(c).expect("Error for commodities"),
-        kinds: alr_account_kinds.load(c).expect("Error for kinds"),
-        institutions: alr_institutions.load(c).expect("Error in institution"),
-    }
+    Ok(Accounts {
+        accounts: alr_accounts.load(c)?,
+        commodities: alr_commodities.load(c)?,
+        kinds: alr_account_kinds.load(c)?,
+        institutions: alr_institutions.load(c)?,
+    })
 }
 
 pub mod commodity_kinds {
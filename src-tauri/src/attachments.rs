@@ -0,0 +1,329 @@
+//! Files (receipts, invoices,...) attached to transactions.
+//! The files are stored in a directory next to the database (see
+//! attachments_dir), named after the SHA-256 of their contents, so that
+//! identical files are only stored once. The alr_attachments table links them to transactions.
+
+use super::connections::{attachments_dir, last_insert_rowid};
+use super::errors::AlereError;
+use super::ledger::TransactionId;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use log::info;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+#[derive(Queryable, Debug, Serialize, Clone)]
+pub struct Attachment {
+    pub id: i32,
+    pub transaction_id: TransactionId,
+    pub hash: String,
+    pub name: String,
+    pub mime_type: Option<String>,
+    pub size: i64,
+    pub added: NaiveDateTime,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct AttachmentsCheck {
+    pub missing: Vec<Attachment>,   // file no longer exists
+    pub corrupted: Vec<Attachment>, // file contents do not match the hash
+    pub orphans: Vec<String>,       // files not used by any attachment
+    pub removed: bool,              // whether orphans were deleted
+}
+
+/// Where the file with the given hash is stored. Files are spread into
+/// sub-directories to keep each of them small.
+
+fn stored_path(hash: &str) -> PathBuf {
+    let mut p = attachments_dir();
+    p.push(&hash[..2.min(hash.len())]);
+    p.push(hash);
+    p
+}
+
+fn hash_contents(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Guess the mime type from the file extension
+/// Only the common formats for receipts and invoices are known.
+
+fn guess_mime_type(path: &Path) -> Option<String> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    let mime = match ext.as_str() {
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "tif" | "tiff" => "image/tiff",
+        "heic" => "image/heic",
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "xml" => "application/xml",
+        _ => return None,
+    };
+    Some(mime.to_string())
+}
+
+fn load_attachment(id: i32) -> Result<Attachment, AlereError> {
+    use super::schema::alr_attachments::dsl as a;
+    let c = &super::connections::get_connection()?;
+    a::alr_attachments
+        .find(id)
+        .first(c)
+        .optional()?
+        .ok_or_else(|| AlereError::not_found(format!("No such attachment {}", id)))
+}
+
+/// Store a copy of a file and attach it to a transaction.
+/// :param path:
+///     the file to attach. It is copied, so can be deleted afterwards.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn attach_file(
+    transactionid: TransactionId,
+    path: String,
+) -> Result<Attachment, AlereError> {
+    use super::schema::alr_attachments::dsl as a;
+    info!("attach_file {} {:?}", transactionid, &path);
+    let source = PathBuf::from(&path);
+    let data = std::fs::read(&source)?;
+    let hash = hash_contents(&data);
+
+    let target = stored_path(&hash);
+    if !target.exists() {
+        if let Some(dir) = target.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // Write to a temporary file first, so that an interrupted copy
+        // never leaves a truncated file under the final name.
+        let tmp = target.with_extension("tmp");
+        std::fs::write(&tmp, &data)?;
+        std::fs::rename(&tmp, &target)?;
+    }
+
+    let attachment = Attachment {
+        id: 0,
+        transaction_id: transactionid,
+        name: source
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| hash.clone()),
+        mime_type: guess_mime_type(&source),
+        size: data.len() as i64,
+        added: Utc::now().naive_utc(),
+        hash,
+    };
+    let c = &super::connections::get_connection()?;
+    c.transaction::<_, AlereError, _>(|| {
+        diesel::insert_into(a::alr_attachments)
+            .values((
+                a::transaction_id.eq(attachment.transaction_id),
+                a::hash.eq(&attachment.hash),
+                a::name.eq(&attachment.name),
+                a::mime_type.eq(&attachment.mime_type),
+                a::size.eq(attachment.size),
+                a::added.eq(attachment.added),
+            ))
+            .execute(c)?;
+        let id = diesel::select(last_insert_rowid).get_result::<i32>(c)?;
+        Ok(Attachment { id, ..attachment })
+    })
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn list_attachments(
+    transactionid: TransactionId,
+) -> Result<Vec<Attachment>, AlereError> {
+    use super::schema::alr_attachments::dsl as a;
+    info!("list_attachments {}", transactionid);
+    let c = &super::connections::get_connection()?;
+    Ok(a::alr_attachments
+        .filter(a::transaction_id.eq(transactionid))
+        .order(a::added)
+        .load(c)?)
+}
+
+/// The location of the stored file, so that the front-end can open it
+/// with the default application. The file must not be modified.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn attachment_path(id: i32) -> Result<String, AlereError> {
+    info!("attachment_path {}", id);
+    let attachment = load_attachment(id)?;
+    let path = stored_path(&attachment.hash);
+    if !path.exists() {
+        return Err(AlereError::not_found(
+            format!("Missing file for attachment {}", id)));
+    }
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Save a copy of the attached file.
+/// :param target:
+///     the file to create. If it is a directory, the original name of the
+///     file is used.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn export_attachment(id: i32, target: String) -> Result<String, AlereError> {
+    info!("export_attachment {} {:?}", id, &target);
+    let attachment = load_attachment(id)?;
+    let mut target = PathBuf::from(target);
+    if target.is_dir() {
+        target.push(&attachment.name);
+    }
+    std::fs::copy(stored_path(&attachment.hash), &target)?;
+    Ok(target.to_string_lossy().to_string())
+}
+
+/// Remove an attachment. The file is deleted when no other attachment
+/// uses it.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn delete_attachment(id: i32) -> Result<(), AlereError> {
+    use super::schema::alr_attachments::dsl as a;
+    info!("delete_attachment {}", id);
+    let attachment = load_attachment(id)?;
+    let c = &super::connections::get_connection()?;
+    let remaining = c.transaction::<_, AlereError, _>(|| {
+        diesel::delete(a::alr_attachments.find(id)).execute(c)?;
+        Ok(a::alr_attachments
+            .filter(a::hash.eq(&attachment.hash))
+            .count()
+            .get_result::<i64>(c)?)
+    })?;
+    if remaining == 0 {
+        _ = std::fs::remove_file(stored_path(&attachment.hash));
+    }
+    Ok(())
+}
+
+/// All files currently in the attachments directory, as (hash, path)
+
+fn stored_files() -> Vec<(String, PathBuf)> {
+    let mut result = vec![];
+    let subdirs = match std::fs::read_dir(attachments_dir()) {
+        Ok(d) => d,
+        Err(_) => return result, //  no attachment yet
+    };
+    for sub in subdirs.flatten() {
+        if let Ok(files) = std::fs::read_dir(sub.path()) {
+            for f in files.flatten() {
+                let path = f.path();
+                if path.is_file() {
+                    result.push((f.file_name().to_string_lossy().to_string(), path));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Check that all attachments have their file, with the expected contents,
+/// and find the files that are no longer used.
+/// :param removeorphans:
+///     if true, unused files are deleted.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn check_attachments(
+    removeorphans: bool,
+) -> Result<AttachmentsCheck, AlereError> {
+    use super::schema::alr_attachments::dsl as a;
+    info!("check_attachments {}", removeorphans);
+    let attachments: Vec<Attachment> = {
+        let c = &super::connections::get_connection()?;
+        a::alr_attachments.order(a::id).load(c)?
+    };
+
+    let mut result = AttachmentsCheck::default();
+    let mut known: HashSet<&str> = HashSet::new();
+    for att in &attachments {
+        let first_use = known.insert(&att.hash);
+        match std::fs::read(stored_path(&att.hash)) {
+            Err(_) => result.missing.push(att.clone()),
+            Ok(data) => {
+                // Only hash each file once, but report all attachments
+                // that use a corrupted file.
+                if (first_use && hash_contents(&data) != att.hash)
+                    || result.corrupted.iter().any(|c| c.hash == att.hash)
+                {
+                    result.corrupted.push(att.clone());
+                }
+            }
+        }
+    }
+
+    for (hash, path) in stored_files() {
+        if !known.contains(hash.as_str()) {
+            if removeorphans {
+                _ = std::fs::remove_file(&path);
+            }
+            result.orphans.push(path.to_string_lossy().to_string());
+        }
+    }
+    result.removed = removeorphans;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::{
+        database_path, get_connection, test_database, use_test_database};
+    use crate::importer::import_test_file;
+    use futures_executor::block_on;
+
+    const BOOK: &str = "\
+2020-01-01 open Assets:Bank EUR
+2020-01-01 open Expenses:Food EUR
+2020-01-02 * \"Groceries\"
+  Expenses:Food  10 EUR
+  Assets:Bank
+";
+
+    /// Attach a new file with the given contents to a new transaction
+    fn attach(name: &str, contents: &str) -> Attachment {
+        use crate::schema::alr_transactions::dsl as t;
+        import_test_file("book.beancount", BOOK, None);
+        let transaction: TransactionId = t::alr_transactions
+            .select(t::id)
+            .order(t::id.desc())
+            .first(&get_connection().unwrap())
+            .unwrap();
+        let path = database_path().with_file_name(name);
+        std::fs::write(&path, contents).unwrap();
+        block_on(attach_file(transaction, path.to_string_lossy().to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn separate_books() {
+        let _db = test_database("attachments");
+        let first = database_path();
+        let second = first.with_file_name("other.sqlite3");
+
+        let receipt = attach("receipt.txt", "first book");
+        use_test_database(&second);
+        let invoice = attach("invoice.txt", "second book");
+
+        // The file of the first book is not an orphan of the second one
+        let check = block_on(check_attachments(true)).unwrap();
+        assert!(check.orphans.is_empty());
+        assert!(check.missing.is_empty());
+
+        use_test_database(&first);
+        let check = block_on(check_attachments(true)).unwrap();
+        assert!(check.orphans.is_empty());
+        assert!(check.missing.is_empty());
+        assert!(block_on(attachment_path(receipt.id)).is_ok());
+
+        // Once detached, the file is deleted from the first book only
+        block_on(delete_attachment(receipt.id)).unwrap();
+        use_test_database(&second);
+        assert!(block_on(attachment_path(invoice.id)).is_ok());
+    }
+}
@@ -0,0 +1,335 @@
+//! Audit log of data changes.
+//! Triggers record all changes to transactions, splits, accounts and
+//! prices in alr_audit_log, with images of the rows (as JSON) before and
+//! after the change. Changes made by the application are grouped into
+//! operations (see with_operation), which can be undone and redone as a
+//! whole.
+//! Links to tags are not audited, so undoing the deletion of a transaction
+//! does not restore its tags.
+
+use super::connections::{get_connection, last_insert_rowid};
+use super::errors::AlereError;
+use super::ledger::TransactionId;
+use super::query_builder::{Query, Sql};
+use chrono::{NaiveDateTime, Utc};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bool, Integer, Nullable, Text, Timestamp};
+use diesel::sqlite::SqliteConnection;
+use log::info;
+use serde::Serialize;
+
+pub type OperationId = i32;
+
+/// The audited tables, and their columns (except id)
+const AUDITED: [(&str, &[&str]); 4] = [
+    ("alr_transactions", &[
+        "timestamp", "memo", "check_number", "scheduled", "last_occurrence",
+        "scenario_id",
+    ]),
+    ("alr_splits", &[
+        "scaled_qty", "scaled_value", "reconcile", "reconcile_date",
+        "post_date", "account_id", "payee_id", "transaction_id",
+        "value_commodity_id",
+    ]),
+    ("alr_accounts", &[
+        "name", "description", "iban", "number", "closed", "commodity_scu",
+        "last_reconciled", "opening_date", "commodity_id", "institution_id",
+        "kind_id", "parent_id",
+    ]),
+    ("alr_prices", &[
+        "date", "scaled_price", "origin_id", "source_id", "target_id",
+    ]),
+];
+
+const DEFAULT_HISTORY: u32 = 100;
+
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct Operation {
+    #[sql_type = "Integer"]
+    id: OperationId,
+
+    #[sql_type = "Timestamp"]
+    timestamp: NaiveDateTime,
+
+    #[sql_type = "Text"]
+    description: String,
+
+    #[sql_type = "Bool"]
+    undone: bool,
+
+    #[sql_type = "BigInt"]
+    changes: i64,  // number of rows modified
+}
+
+#[derive(QueryableByName)]
+struct LogRow {
+    #[sql_type = "Integer"]
+    id: i32,
+
+    #[sql_type = "Nullable<Integer>"]
+    operation_id: Option<OperationId>,
+
+    #[sql_type = "Nullable<Text>"]
+    description: Option<String>,
+
+    #[sql_type = "Nullable<Bool>"]
+    undone: Option<bool>,
+
+    #[sql_type = "Timestamp"]
+    timestamp: NaiveDateTime,
+
+    #[sql_type = "Text"]
+    table_name: String,
+
+    #[sql_type = "Integer"]
+    row_id: i32,
+
+    #[sql_type = "Nullable<Text>"]
+    before: Option<String>,
+
+    #[sql_type = "Nullable<Text>"]
+    after: Option<String>,
+}
+
+/// One change to a row
+
+#[derive(Serialize, Debug)]
+pub struct AuditEntry {
+    id: i32,
+    operation: Option<OperationId>,   // None if made outside of the application
+    description: Option<String>,
+    undone: bool,
+    timestamp: NaiveDateTime,
+    table: String,
+    row_id: i32,
+    before: Option<serde_json::Value>, // None when the row was inserted
+    after: Option<serde_json::Value>,  // None when the row was deleted
+}
+
+const LOG_COLUMNS: &str = "
+    l.id, l.operation_id, o.description, o.undone, l.timestamp,
+    l.table_name, l.row_id, l.before, l.after
+    FROM alr_audit_log l
+    LEFT JOIN alr_audit_operations o ON (o.id = l.operation_id)";
+
+fn parse_image(image: Option<String>) -> Result<Option<serde_json::Value>, AlereError> {
+    image
+        .map(|i| serde_json::from_str(&i).map_err(|e| AlereError::parse(e.to_string())))
+        .transpose()
+}
+
+/// Run f as a single operation, which can then be undone as a whole. This
+/// is done in a database transaction.
+/// :param description: shown to users in the history
+
+pub fn with_operation<T, F>(
+    c: &SqliteConnection,
+    description: &str,
+    f: F,
+) -> Result<T, AlereError>
+where
+    F: FnOnce() -> Result<T, AlereError>,
+{
+    use super::schema::alr_audit_operations::dsl as o;
+    use super::schema::alr_audit_state::dsl as st;
+    c.transaction::<_, AlereError, _>(|| {
+        // A new operation discards those that could have been redone
+        c.batch_execute(
+            "DELETE FROM alr_audit_log WHERE operation_id IN
+                (SELECT id FROM alr_audit_operations WHERE undone);
+             DELETE FROM alr_audit_operations WHERE undone;",
+        )?;
+        diesel::insert_into(o::alr_audit_operations)
+            .values((
+                o::timestamp.eq(Utc::now().naive_utc()),
+                o::description.eq(description),
+            ))
+            .execute(c)?;
+        let id = diesel::select(last_insert_rowid).get_result::<i32>(c)?;
+        diesel::update(st::alr_audit_state)
+            .set(st::operation_id.eq(Some(id)))
+            .execute(c)?;
+        let result = f()?;
+        diesel::update(st::alr_audit_state)
+            .set(st::operation_id.eq(None::<OperationId>))
+            .execute(c)?;
+        Ok(result)
+    })
+}
+
+/// Restore a row from its image, or delete it if there is no image
+
+fn apply_image(
+    c: &SqliteConnection,
+    table_name: &str,
+    row_id: i32,
+    image: &Option<String>,
+) -> Result<(), AlereError> {
+    let (table, columns) = AUDITED
+        .iter()
+        .find(|(t, _)| *t == table_name)
+        .ok_or_else(|| AlereError::parse(
+            format!("Unexpected table {} in audit log", table_name)))?;
+    let values: Vec<String> = columns
+        .iter()
+        .map(|col| format!("json_extract(:image, '$.{col}')"))
+        .collect();
+
+    let sql = match image {
+        None => Sql::new(format!("DELETE FROM {table} WHERE id = :id")),
+        Some(_) => {
+            let set: Vec<String> = columns
+                .iter()
+                .zip(&values)
+                .map(|(col, v)| format!("{col} = {v}"))
+                .collect();
+            let updated = Query::new(
+                Sql::new(format!("UPDATE {table} SET {} WHERE id = :id", set.join(", ")))
+                    .bind("id", row_id)
+                    .bind("image", image.clone()),
+            )
+            .build()?
+            .execute(c)?;
+            if updated > 0 {
+                return Ok(());
+            }
+            Sql::new(format!(
+                "INSERT INTO {table} (id, {}) VALUES (:id, {})",
+                columns.join(", "),
+                values.join(", "),
+            ))
+        }
+    };
+    Query::new(sql.bind("id", row_id).bind("image", image.clone()))
+        .build()?
+        .execute(c)?;
+    Ok(())
+}
+
+/// Undo or redo an operation, without recording new changes
+
+fn replay(c: &SqliteConnection, op: &mut Operation, undo: bool) -> Result<(), AlereError> {
+    use super::schema::alr_audit_operations::dsl as o;
+    use super::schema::alr_audit_state::dsl as st;
+    let rows: Vec<LogRow> = Query::new(
+        Sql::new(format!(
+            "SELECT {LOG_COLUMNS} WHERE l.operation_id = :op ORDER BY l.id {}",
+            if undo { "DESC" } else { "ASC" },
+        ))
+        .bind("op", op.id),
+    )
+    .build()?
+    .load(c)?;
+
+    diesel::update(st::alr_audit_state).set(st::replaying.eq(true)).execute(c)?;
+    for r in &rows {
+        apply_image(c, &r.table_name, r.row_id, if undo { &r.before } else { &r.after })?;
+    }
+    diesel::update(st::alr_audit_state).set(st::replaying.eq(false)).execute(c)?;
+    diesel::update(o::alr_audit_operations.find(op.id))
+        .set(o::undone.eq(undo))
+        .execute(c)?;
+    op.undone = undo;
+    Ok(())
+}
+
+/// Find an operation
+/// :param filter: the WHERE and ORDER BY clauses
+
+fn find_operations(
+    c: &SqliteConnection,
+    filter: &str,
+    limit: u32,
+) -> Result<Vec<Operation>, AlereError> {
+    Ok(Query::new(
+        Sql::new(format!(
+            "SELECT o.id, o.timestamp, o.description, o.undone,
+                (SELECT count(*) FROM alr_audit_log l
+                 WHERE l.operation_id = o.id) AS changes
+             FROM alr_audit_operations o
+             {filter}
+             LIMIT :limit"
+        ))
+        .bind("limit", limit),
+    )
+    .build()?
+    .load(c)?)
+}
+
+/// Undo the most recent operation. Returns None if there was nothing to
+/// undo.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn undo() -> Result<Option<Operation>, AlereError> {
+    info!("undo");
+    let c = &get_connection()?;
+    c.transaction::<_, AlereError, _>(|| {
+        let mut op = find_operations(
+            c, "WHERE NOT o.undone ORDER BY o.id DESC", 1)?.pop();
+        if let Some(op) = op.as_mut() {
+            replay(c, op, true)?;
+        }
+        Ok(op)
+    })
+}
+
+/// Redo the last operation that was undone. Returns None if there was
+/// nothing to redo.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn redo() -> Result<Option<Operation>, AlereError> {
+    info!("redo");
+    let c = &get_connection()?;
+    c.transaction::<_, AlereError, _>(|| {
+        let mut op = find_operations(
+            c, "WHERE o.undone ORDER BY o.id ASC", 1)?.pop();
+        if let Some(op) = op.as_mut() {
+            replay(c, op, false)?;
+        }
+        Ok(op)
+    })
+}
+
+/// The most recent operations, most recent first. Those that were undone
+/// are included, and can be redone.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn list_operations(limit: Option<u32>) -> Result<Vec<Operation>, AlereError> {
+    let c = &get_connection()?;
+    find_operations(c, "ORDER BY o.id DESC", limit.unwrap_or(DEFAULT_HISTORY))
+}
+
+/// All changes to a transaction and its splits, oldest first
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn transaction_history(
+    transactionid: TransactionId,
+) -> Result<Vec<AuditEntry>, AlereError> {
+    info!("transaction_history {}", transactionid);
+    let rows = super::connections::execute_and_log::<LogRow>(
+        "transaction_history",
+        Sql::new(format!(
+            "SELECT {LOG_COLUMNS}
+             WHERE (l.table_name = 'alr_transactions' AND l.row_id = :id)
+                OR (l.table_name = 'alr_splits'
+                    AND :id IN (json_extract(l.before, '$.transaction_id'),
+                                json_extract(l.after, '$.transaction_id')))
+             ORDER BY l.id"
+        ))
+        .bind("id", transactionid),
+    )?;
+    rows.into_iter()
+        .map(|r| Ok(AuditEntry {
+            id: r.id,
+            operation: r.operation_id,
+            description: r.description,
+            undone: r.undone.unwrap_or(false),
+            timestamp: r.timestamp,
+            table: r.table_name,
+            row_id: r.row_id,
+            before: parse_image(r.before)?,
+            after: parse_image(r.after)?,
+        }))
+        .collect()
+}
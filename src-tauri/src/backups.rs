@@ -0,0 +1,538 @@
+//! Automatic backups of the database.
+//! A copy is taken with sqlite's online backup API whenever a database is
+//! opened (so before its tables are upgraded), and before bulk operations
+//! like imports. Backups are stored in a "backups" directory next to the
+//! database, and rotated so that we keep the most recent one for each of
+//! the last few days, weeks and months.
+//! Backups always use the same passphrase as their database, and are
+//! converted when it changes.
+
+use super::connections::{current_passphrase, database_path, get_connection,
+                         quote_sql, replace_database};
+use super::databases::config_file;
+use super::errors::AlereError;
+use super::query_builder::{Query, Sql};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::connection::SimpleConnection;
+use diesel::sql_types::{BigInt, Text};
+use diesel::RunQueryDsl;
+use libsqlite3_sys as ffi;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+
+const DATE_FORMAT: &str = "%Y%m%d-%H%M%S";
+const DATE_LEN: usize = 15;  // length of formatted dates
+
+/// Tables compared in backup_diff
+const DIFF_TABLES: [&str; 10] = [
+    "alr_accounts",
+    "alr_commodities",
+    "alr_goals",
+    "alr_institutions",
+    "alr_payees",
+    "alr_prices",
+    "alr_scenarios",
+    "alr_splits",
+    "alr_tags",
+    "alr_transactions",
+];
+
+/// How many backups to keep: the most recent one for each of the last
+/// `daily` days, `weekly` weeks and `monthly` months.
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupSettings {
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        BackupSettings { daily: 7, weekly: 4, monthly: 12 }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Backup {
+    name: String,    // file name, used to restore
+    date: DateTime<Utc>,
+    reason: String,  // "startup", "manual", "import",...
+    size: u64,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(QueryableByName)]
+struct DiffRow {
+    #[sql_type = "BigInt"]
+    added: i64,
+
+    #[sql_type = "BigInt"]
+    removed: i64,
+
+    #[sql_type = "BigInt"]
+    changed: i64,
+}
+
+#[derive(QueryableByName)]
+struct ColumnRow {
+    #[sql_type = "Text"]
+    name: String,
+}
+
+/// How a table differs between a backup and the current database
+
+#[derive(Serialize, Debug)]
+pub struct TableDiff {
+    table: &'static str,
+    added: i64,    // rows only in the current database
+    removed: i64,  // rows only in the backup
+    changed: i64,  // rows in both, with different contents
+}
+
+/// A raw sqlite connection, closed when dropped. diesel does not give
+/// access to the backup API.
+
+struct RawDb(*mut ffi::sqlite3);
+
+impl RawDb {
+    fn open(path: &Path, flags: c_int, passphrase: Option<&str>) -> Result<Self, AlereError> {
+        let name = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|e| AlereError::validation(e.to_string()))?;
+        let mut db = std::ptr::null_mut();
+        let rc = unsafe {
+            ffi::sqlite3_open_v2(name.as_ptr(), &mut db, flags, std::ptr::null())
+        };
+        let raw = RawDb(db);  // closed even if open failed
+        raw.check(rc)?;
+        if let Some(p) = passphrase {
+            raw.exec(&format!("PRAGMA key = {};", quote_sql(p)))?;
+        }
+        Ok(raw)
+    }
+
+    fn check(&self, rc: c_int) -> Result<(), AlereError> {
+        if rc == ffi::SQLITE_OK || rc == ffi::SQLITE_DONE {
+            return Ok(());
+        }
+        let message = unsafe {
+            match self.0.is_null() {
+                true => CStr::from_ptr(ffi::sqlite3_errstr(rc)),
+                false => CStr::from_ptr(ffi::sqlite3_errmsg(self.0)),
+            }
+        };
+        Err(AlereError::Database {
+            message: message.to_string_lossy().to_string(),
+            query: None,
+        })
+    }
+
+    fn exec(&self, sql: &str) -> Result<(), AlereError> {
+        let sql = CString::new(sql).map_err(|e| AlereError::validation(e.to_string()))?;
+        let rc = unsafe {
+            ffi::sqlite3_exec(
+                self.0, sql.as_ptr(), None, std::ptr::null_mut(), std::ptr::null_mut())
+        };
+        self.check(rc)
+    }
+}
+
+impl Drop for RawDb {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_close(self.0);
+        }
+    }
+}
+
+/// Copy a database, while it might be in use. Both files use the same
+/// passphrase.
+
+fn copy_database(from: &Path, to: &Path, passphrase: Option<&str>) -> Result<(), AlereError> {
+    let src = RawDb::open(from, ffi::SQLITE_OPEN_READONLY, passphrase)?;
+    let dst = RawDb::open(
+        to, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE, passphrase)?;
+    let main = CString::new("main").unwrap();
+    unsafe {
+        let backup = ffi::sqlite3_backup_init(dst.0, main.as_ptr(), src.0, main.as_ptr());
+        if backup.is_null() {
+            return dst.check(ffi::sqlite3_errcode(dst.0));
+        }
+        let rc = ffi::sqlite3_backup_step(backup, -1);  // copy all pages
+        ffi::sqlite3_backup_finish(backup);
+        dst.check(rc)
+    }
+}
+
+fn backups_dir(db: &Path) -> PathBuf {
+    let mut dir = db.to_path_buf();
+    dir.set_file_name("backups");
+    dir
+}
+
+fn stem(db: &Path) -> String {
+    db.file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "alere".to_string())
+}
+
+/// The backups for a database, most recent first
+
+fn list_for(db: &Path) -> Vec<Backup> {
+    let prefix = format!("{}-", stem(db));
+    let mut result: Vec<Backup> = std::fs::read_dir(backups_dir(db))
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().to_string_lossy().to_string();
+                    let rest = name.strip_prefix(&prefix)?.strip_suffix(".sqlite3")?;
+                    let date = NaiveDateTime::parse_from_str(
+                        rest.get(..DATE_LEN)?, DATE_FORMAT).ok()?;
+                    let reason = rest.get(DATE_LEN + 1..)?.to_string();
+                    Some(Backup {
+                        date: DateTime::<Utc>::from_utc(date, Utc),
+                        reason,
+                        size: e.metadata().map(|m| m.len()).unwrap_or(0),
+                        path: e.path(),
+                        name,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    result.sort_by(|a, b| b.date.cmp(&a.date).then_with(|| b.name.cmp(&a.name)));
+    result
+}
+
+fn load_settings() -> BackupSettings {
+    config_file("backups.json")
+        .and_then(|f| std::fs::read_to_string(f).ok())
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+/// Delete the backups we no longer need
+
+fn rotate(db: &Path, settings: &BackupSettings, latest: Option<&Path>) {
+    let backups = list_for(db);
+    let mut keep = HashSet::new();
+    for (format, count) in [
+        ("%Y-%m-%d", settings.daily),
+        ("%G-W%V", settings.weekly),
+        ("%Y-%m", settings.monthly),
+    ] {
+        let mut periods = vec![];
+        for b in &backups {
+            let period = b.date.format(format).to_string();
+            if !periods.contains(&period) {
+                if periods.len() >= count as usize {
+                    break;
+                }
+                periods.push(period);
+                keep.insert(b.name.clone());
+            }
+        }
+    }
+    // The latest backup could be sorted after another one taken in the
+    // same second
+    let removed = backups
+        .iter()
+        .skip(1)
+        .filter(|b| !keep.contains(&b.name) && Some(b.path.as_path()) != latest);
+    for b in removed {
+        info!("Removing old backup {:?}", b.path);
+        if let Err(e) = std::fs::remove_file(&b.path) {
+            error!("Could not remove {:?}: {}", b.path, e);
+        }
+    }
+}
+
+/// Backup a database, then delete older backups.
+/// :param reason: why the backup is taken, part of its file name
+
+pub fn backup_database(
+    db: &Path,
+    passphrase: Option<&str>,
+    reason: &str,
+) -> Result<Backup, AlereError> {
+    let dir = backups_dir(db);
+    std::fs::create_dir_all(&dir)?;
+    let now = Utc::now();
+    let mut path = dir;
+    path.push(format!("{}-{}-{}.sqlite3", stem(db), now.format(DATE_FORMAT), reason));
+    info!("Backup {:?} to {:?}", db, path);
+    _ = std::fs::remove_file(&path);
+    copy_database(db, &path, passphrase)?;
+    rotate(db, &load_settings(), Some(&path));
+    list_for(db)
+        .into_iter()
+        .find(|b| b.path == path)
+        .ok_or_else(|| AlereError::not_found(format!("{:?}", path)))
+}
+
+/// Whether a database can be read with the given passphrase
+
+fn can_read(path: &Path, passphrase: Option<&str>) -> bool {
+    RawDb::open(path, ffi::SQLITE_OPEN_READONLY, passphrase)
+        .and_then(|db| db.exec("SELECT count(*) FROM sqlite_master"))
+        .is_ok()
+}
+
+/// Copy a backup to a new passphrase (or to plain text), then replace it
+
+fn convert_backup(path: &Path, old: Option<&str>, new: Option<&str>) -> Result<(), AlereError> {
+    let converted = path.with_extension("converting");
+    _ = std::fs::remove_file(&converted);
+    let exported = RawDb::open(path, ffi::SQLITE_OPEN_READWRITE, old)
+        .and_then(|db| db.exec(&format!(
+            "ATTACH DATABASE {} AS converted KEY {};
+             SELECT sqlcipher_export('converted');
+             DETACH DATABASE converted;",
+            quote_sql(&converted.to_string_lossy()),
+            quote_sql(new.unwrap_or("")),
+        )));
+    match exported {
+        Ok(()) => Ok(std::fs::rename(&converted, path)?),
+        Err(e) => {
+            _ = std::fs::remove_file(&converted);
+            Err(e)
+        }
+    }
+}
+
+/// Convert the backups of a database after its passphrase changed, so
+/// that they can still be restored and compared. Backups that already use
+/// the new passphrase (the one taken when the database was reopened) are
+/// left untouched.
+/// Errors are only logged, since the database itself was already converted.
+/// :param old: the previous passphrase, None if the database was not
+///    encrypted.
+/// :param new: the new passphrase, None if the database is now in plain
+///    text.
+
+pub fn rekey_backups(db: &Path, old: Option<&str>, new: Option<&str>) {
+    for b in list_for(db) {
+        if can_read(&b.path, new) {
+            continue;
+        }
+        info!("Converting backup {:?}", b.path);
+        if let Err(e) = convert_backup(&b.path, old, new) {
+            error!("Could not convert backup {:?}: {}", b.path, e);
+        }
+    }
+}
+
+/// Backup the current database before a bulk operation (an import for
+/// instance), so that it can be restored if the result is not the expected
+/// one.
+
+pub fn backup_before(operation: &str) -> Result<Backup, AlereError> {
+    backup_database(&database_path(), current_passphrase().as_deref(), operation)
+}
+
+fn find_backup(name: &str) -> Result<Backup, AlereError> {
+    list_for(&database_path())
+        .into_iter()
+        .find(|b| b.name == name)
+        .ok_or_else(|| AlereError::not_found(format!("No such backup {}", name)))
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn list_backups() -> Result<Vec<Backup>, AlereError> {
+    Ok(list_for(&database_path()))
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn create_backup() -> Result<Backup, AlereError> {
+    info!("create_backup");
+    backup_before("manual")
+}
+
+/// Replace the current data with a backup. The current data is itself
+/// saved first, so that the restore can be undone.
+/// The backup is copied next to the database, and only replaces it once
+/// the copy is complete, so the current data is still used on errors. The
+/// copy is taken first, since the new backup might rotate the restored one.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn restore_backup(name: String) -> Result<(), AlereError> {
+    info!("restore_backup {:?}", &name);
+    let backup = find_backup(&name)?;
+    let passphrase = current_passphrase();
+    let mut restoring = database_path().into_os_string();
+    restoring.push(".restoring");
+    let restoring = PathBuf::from(restoring);
+    _ = std::fs::remove_file(&restoring);
+    let restored = copy_database(&backup.path, &restoring, passphrase.as_deref())
+        .and_then(|_| backup_before("before-restore"))
+        .and_then(|_| replace_database(&restoring, passphrase.as_deref()));
+    _ = std::fs::remove_file(&restoring);  //  only left on errors
+    restored
+}
+
+/// Summary of the differences between a backup and the current data
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn backup_diff(name: String) -> Result<Vec<TableDiff>, AlereError> {
+    info!("backup_diff {:?}", &name);
+    let backup = find_backup(&name)?;
+    let connection = get_connection()?;
+    let mut attach = Sql::new("ATTACH DATABASE :file AS backup")
+        .bind("file", backup.path.to_string_lossy().to_string());
+    if let Some(p) = current_passphrase() {
+        attach = Sql::new("ATTACH DATABASE :file AS backup KEY :key")
+            .bind("file", backup.path.to_string_lossy().to_string())
+            .bind("key", p);
+    }
+    Query::new(attach).build()?.execute(&connection)?;
+
+    let mut result = vec![];
+    let diff = DIFF_TABLES.iter().try_for_each(|table| {
+        // Tables created by a later migration do not exist in the backup
+        let query = Query::new(Sql::new(format!(
+            "SELECT
+               (SELECT count(*) FROM main.{table}) AS added,
+               0 AS removed,
+               0 AS changed
+             WHERE NOT EXISTS (SELECT 1 FROM backup.sqlite_master WHERE name = :table)"
+        ))
+        .bind("table", *table));
+        let mut rows: Vec<DiffRow> = query.build()?.load(&connection)?;
+        if rows.is_empty() {
+            // Columns added by a later migration are not compared
+            let columns: Vec<ColumnRow> = Query::new(Sql::new(
+                "SELECT m.name FROM pragma_table_info(:table, 'main') m
+                 WHERE m.name IN
+                    (SELECT b.name FROM pragma_table_info(:table, 'backup') b)",
+            )
+            .bind("table", *table))
+            .build()?
+            .load(&connection)?;
+            let columns = columns
+                .into_iter()
+                .map(|c| format!("\"{}\"", c.name))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let query = Query::new(format!(
+                "SELECT
+                   (SELECT count(*) FROM main.{table}
+                    WHERE id NOT IN (SELECT id FROM backup.{table})) AS added,
+                   (SELECT count(*) FROM backup.{table}
+                    WHERE id NOT IN (SELECT id FROM main.{table})) AS removed,
+                   (SELECT count(*) FROM
+                      (SELECT {columns} FROM main.{table}
+                       WHERE id IN (SELECT id FROM backup.{table})
+                       EXCEPT SELECT {columns} FROM backup.{table})) AS changed"
+            ));
+            rows = query.build()?.load(&connection)?;
+        }
+        if let Some(r) = rows.first() {
+            result.push(TableDiff {
+                table,
+                added: r.added,
+                removed: r.removed,
+                changed: r.changed,
+            });
+        }
+        Ok::<(), AlereError>(())
+    });
+
+    // Always detach, even on errors, since the connection is reused
+    connection.batch_execute("DETACH DATABASE backup")?;
+    diff?;
+    Ok(result)
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn backup_settings() -> Result<BackupSettings, AlereError> {
+    Ok(load_settings())
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn set_backup_settings(settings: BackupSettings) -> Result<(), AlereError> {
+    info!("set_backup_settings {:?}", &settings);
+    let file = config_file("backups.json")
+        .ok_or_else(|| AlereError::not_found("No configuration directory"))?;
+    let json = serde_json::to_string_pretty(&settings)
+        .map_err(|e| AlereError::validation(e.to_string()))?;
+    std::fs::write(file, json)?;
+    rotate(&database_path(), &settings, None);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::test_database;
+    use crate::importer::import_test_file;
+    use diesel::prelude::*;
+    use futures_executor::block_on;
+
+    fn count_commodities() -> i64 {
+        use crate::schema::alr_commodities::dsl::*;
+        alr_commodities.count().get_result(&get_connection().unwrap()).unwrap()
+    }
+
+    /// Take a backup, dated some days ago so that it is not rotated by the
+    /// next ones. Returns its name.
+    fn old_backup(days: i64) -> String {
+        let backup = block_on(create_backup()).unwrap();
+        let date = backup.date - chrono::Duration::days(days);
+        let name = format!(
+            "{}-{}-{}.sqlite3",
+            stem(&database_path()), date.format(DATE_FORMAT), backup.reason);
+        std::fs::rename(&backup.path, backup.path.with_file_name(&name)).unwrap();
+        name
+    }
+
+    /// Add a commodity, then restore a backup taken before
+    fn check_restore(name: &str) {
+        let commodities = count_commodities();
+        diesel::sql_query(
+            "INSERT INTO alr_commodities
+                (name, symbol_before, symbol_after, kind, price_scale)
+             VALUES ('USD', '$', '', 'C', 100)")
+            .execute(&get_connection().unwrap())
+            .unwrap();
+        assert_eq!(count_commodities(), commodities + 1);
+
+        let diff = block_on(backup_diff(name.to_string())).unwrap();
+        let changed = diff.iter().find(|d| d.table == "alr_commodities").unwrap();
+        assert_eq!((changed.added, changed.removed), (1, 0));
+
+        block_on(restore_backup(name.to_string())).unwrap();
+        assert_eq!(count_commodities(), commodities);
+    }
+
+    #[test]
+    fn restore() {
+        let _db = test_database("restore");
+        import_test_file("eur.beancount", "1970-01-01 commodity EUR\n", None);
+        let backup = old_backup(1);
+        check_restore(&backup);
+        check_restore(&backup);
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn restore_after_rekey() {
+        use crate::encryption::{change_passphrase, decrypt_database, encrypt_database};
+        let _db = test_database("rekey");
+        import_test_file("eur.beancount", "1970-01-01 commodity EUR\n", None);
+        let plain = old_backup(2);
+        block_on(encrypt_database("first".into())).unwrap();
+        let first = old_backup(1);
+        block_on(change_passphrase("second".into())).unwrap();
+        check_restore(&first);
+        check_restore(&plain);
+
+        block_on(decrypt_database()).unwrap();
+        check_restore(&first);
+    }
+}
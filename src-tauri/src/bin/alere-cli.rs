@@ -0,0 +1,438 @@
+//! Command line interface to the reports, to use them from scripts or on
+//! a server:
+//!     alere-cli --db FILE [--format table|json|csv] COMMAND [OPTIONS]
+//! The passphrase for encrypted databases is read from the
+//! ALERE_PASSPHRASE environment variable.
+//! Build it with "--no-default-features" so that it does not link with
+//! GTK and WebKit, for instance on a headless server.
+
+use alere::cashflow::monthly_cashflow;
+use alere::connections::switch_database;
+use alere::consistency::check_database;
+use alere::databases::initial_database_path;
+use alere::dates::{DateRange, GroupBy};
+use alere::errors::AlereError;
+use alere::export_journal::{write_journal, JournalFormat};
+use alere::export_report::{export_report, Report};
+use alere::importer::import_file;
+use alere::income_expense::income_expense;
+use alere::ledger::ledger;
+use alere::metrics::networth_history;
+use alere::models::{AccountId, CommodityId};
+use alere::occurrences::Occurrences;
+use alere::qif::write_qif;
+use alere::quotes::quotes;
+use alere::scenarios::{Scenario, NO_SCENARIO};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use env_logger::Env;
+use futures_executor::block_on;
+use serde::Serialize;
+use serde_json::Value;
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: alere-cli [--db FILE [--create]] [--format table|json|csv] COMMAND [OPTIONS]
+
+Commands:
+   networth          Networth at the end of each month
+   ledger            Transactions for some accounts (--account, repeated)
+   cashflow          Income and expenses for each month
+   income-expense    Income and expenses per account (--income, --expense)
+   quotes            Performance of investments
+   import FILE...    Import files (Beancount .beancount or .bean, GnuCash
+                     .gnucash, QIF .qif, camt bank statements .xml).
+                     Transactions of QIF files, and statements whose IBAN
+                     is unknown, go to --account if specified
+   export beancount|hledger [FILE]
+                     Export the whole book as a plain-text journal, on the
+                     standard output if no file is specified
+   export qif [FILE] Export the ledger of --account as QIF
+   export networth|ledger|cashflow|income-expense|quotes FILE
+                     Export a report as a spreadsheet (.csv, .ods or .xlsx),
+                     the ledger for --account
+   check             Check the consistency of the database (exit status is 2
+                     if issues are found)
+   serve [ADDRESS]   Serve the commands over HTTP (default 127.0.0.1:8321),
+                     only if built with the http-server feature. The token
+                     is read from ALERE_HTTP_TOKEN, or generated. Browsers
+                     can use it from the origin in ALERE_HTTP_ORIGIN.
+
+Options:
+   --create          Create the database if the file does not exist
+   --from DATE       Start of the period (YYYY-MM-DD), default one year ago
+   --to DATE         End of the period, default today
+   --currency ID     Currency for reports, default 1
+   --scenario ID     Include the transactions of a scenario
+";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+struct Args {
+    db: Option<PathBuf>,
+    create: bool,
+    format: Format,
+    command: String,
+    files: Vec<String>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    currency: CommodityId,
+    scenario: Option<Scenario>,
+    accounts: Vec<AccountId>,
+    income: bool,
+    expense: bool,
+}
+
+fn parse_date(s: &str) -> Result<DateTime<Utc>, AlereError> {
+    let d = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|e| AlereError::validation(format!("Invalid date {}: {}", s, e)))?;
+    Ok(Utc.from_utc_date(&d).and_hms(0, 0, 0))
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, s: &str) -> Result<T, AlereError> {
+    s.parse()
+        .map_err(|_| AlereError::validation(format!("Invalid value for {}: {}", name, s)))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, AlereError> {
+    let today = Utc::today().and_hms(0, 0, 0);
+    let mut result = Args {
+        db: None,
+        create: false,
+        format: Format::Table,
+        command: String::new(),
+        files: vec![],
+        from: today - Duration::days(365),
+        to: today,
+        currency: 1,
+        scenario: None,
+        accounts: vec![],
+        income: false,
+        expense: false,
+    };
+
+    while let Some(arg) = args.next() {
+        // Options are either "--name value" or "--name=value"
+        let (name, inline) = match arg.split_once('=') {
+            Some((n, v)) if n.starts_with("--") => (n.to_string(), Some(v.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline.clone().or_else(|| args.next()).ok_or_else(|| {
+                AlereError::validation(format!("Missing value for {}", name))
+            })
+        };
+        match name.as_str() {
+            "--db" => result.db = Some(PathBuf::from(value()?)),
+            "--create" => result.create = true,
+            "--format" => {
+                result.format = match value()?.as_str() {
+                    "table" => Format::Table,
+                    "json" => Format::Json,
+                    "csv" => Format::Csv,
+                    f => return Err(AlereError::validation(format!("Invalid format {}", f))),
+                }
+            }
+            "--from" => result.from = parse_date(&value()?)?,
+            "--to" => result.to = parse_date(&value()?)?,
+            "--currency" => result.currency = parse_number(&name, &value()?)?,
+            "--scenario" => result.scenario = Some(parse_number(&name, &value()?)?),
+            "--account" => result.accounts.push(parse_number(&name, &value()?)?),
+            "--income" => result.income = true,
+            "--expense" => result.expense = true,
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                std::process::exit(0);
+            }
+            n if n.starts_with('-') => {
+                return Err(AlereError::validation(format!("Unknown option {}", n)));
+            }
+            _ if result.command.is_empty() => result.command = arg,
+            _ => result.files.push(arg),
+        }
+    }
+
+    // Show both when none is selected
+    if !result.income && !result.expense {
+        result.income = true;
+        result.expense = true;
+    }
+    Ok(result)
+}
+
+fn to_json<T: Serialize>(value: T) -> Result<Value, AlereError> {
+    serde_json::to_value(value).map_err(|e| AlereError::parse(e.to_string()))
+}
+
+/// Run the command, and return its result as JSON
+
+fn run(args: &Args) -> Result<Value, AlereError> {
+    match args.command.as_str() {
+        "networth" => to_json(block_on(networth_history(
+            args.from, args.to, args.currency, args.scenario))?),
+        "ledger" => to_json(block_on(ledger(
+            args.from,
+            args.to,
+            args.accounts.clone(),
+            0,
+            args.scenario,
+            None,
+        ))?),
+        "cashflow" => to_json(monthly_cashflow(
+            &DateRange::new(Some(args.from.date()), Some(args.to.date()), GroupBy::MONTHS),
+            args.currency,
+            args.scenario.unwrap_or(NO_SCENARIO),
+            &Occurrences::no_recurrence(),
+            0,
+            0,
+            &None,
+        )?),
+        "income-expense" => {
+            let mut v = to_json(block_on(income_expense(
+                args.income,
+                args.expense,
+                args.from,
+                args.to,
+                args.currency,
+                args.scenario,
+                None,
+            ))?)?;
+            Ok(v["items"].take())
+        }
+        "quotes" => to_json(block_on(quotes(
+            args.from, args.to, args.currency, None, None))?.0),
+        "check" => to_json(block_on(check_database())?),
+        "import" => {
+            if args.files.is_empty() {
+                return Err(AlereError::validation("Specify the files to import"));
+            }
+            let mut summaries = vec![];
+            for f in &args.files {
+                summaries.push(block_on(import_file(f.clone(), args.accounts.first().copied()))?);
+            }
+            to_json(summaries)
+        }
+        "export" => {
+            if args.files.first().map(String::as_str) == Some("qif") {
+                let account = *args.accounts.first()
+                    .ok_or_else(|| AlereError::validation("Specify the --account to export"))?;
+                return match args.files.get(1) {
+                    Some(path) => {
+                        let file = std::fs::File::create(path)?;
+                        to_json(write_qif(&mut std::io::BufWriter::new(file), account)?)
+                    }
+                    None => {
+                        write_qif(&mut std::io::stdout().lock(), account)?;
+                        Ok(Value::Null)
+                    }
+                };
+            }
+            let report = match args.files.first().map(String::as_str) {
+                Some("networth") => Some(Report::NETWORTH),
+                Some("ledger") => Some(Report::LEDGER),
+                Some("cashflow") => Some(Report::CASHFLOW),
+                Some("income-expense") => Some(Report::INCOME_EXPENSE),
+                Some("quotes") => Some(Report::QUOTES),
+                _ => None,
+            };
+            if let Some(report) = report {
+                let path = args.files.get(1)
+                    .ok_or_else(|| AlereError::validation("Specify the file to export to"))?;
+                return to_json(block_on(export_report(
+                    report,
+                    path.clone(),
+                    args.from,
+                    args.to,
+                    args.currency,
+                    Some(args.accounts.clone()).filter(|a| !a.is_empty()),
+                    args.scenario,
+                ))?);
+            }
+            let format = match args.files.first().map(String::as_str) {
+                Some("beancount") => JournalFormat::BEANCOUNT,
+                Some("hledger") => JournalFormat::HLEDGER,
+                _ => return Err(AlereError::validation(
+                    "Specify beancount, hledger, qif or a report")),
+            };
+            match args.files.get(1) {
+                Some(path) => {
+                    let file = std::fs::File::create(path)?;
+                    to_json(write_journal(&mut std::io::BufWriter::new(file), format)?)
+                }
+                None => {
+                    write_journal(&mut std::io::stdout().lock(), format)?;
+                    Ok(Value::Null)
+                }
+            }
+        }
+        #[cfg(feature = "http-server")]
+        "serve" => {
+            use alere::http_server::{new_token, origin_from_env, serve, DEFAULT_ADDRESS};
+            let token = std::env::var("ALERE_HTTP_TOKEN")
+                .ok()
+                .filter(|t| !t.is_empty())
+                .unwrap_or_else(|| {
+                    let t = new_token();
+                    eprintln!("Token: {}", t);
+                    t
+                });
+            let address = args.files.first().map(String::as_str).unwrap_or(DEFAULT_ADDRESS);
+            serve(address, &token, origin_from_env().as_deref()).map(|_| Value::Null)
+        }
+        "" => Err(AlereError::validation("No command specified")),
+        c => Err(AlereError::validation(format!("Unknown command {}", c))),
+    }
+}
+
+/// Flatten nested objects into "parent.child" columns. Arrays are kept
+/// as JSON.
+
+fn flatten(prefix: &str, value: &Value, into: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                let key = match prefix {
+                    "" => k.clone(),
+                    _ => format!("{}.{}", prefix, k),
+                };
+                flatten(&key, v, into);
+            }
+        }
+        Value::Null => into.push((prefix.to_string(), String::new())),
+        Value::String(s) => into.push((prefix.to_string(), s.clone())),
+        v => into.push((prefix.to_string(), v.to_string())),
+    }
+}
+
+/// Convert the result to a list of rows, all with the same columns
+
+fn to_rows(value: &Value) -> (Vec<String>, Vec<Vec<String>>) {
+    let items = match value {
+        Value::Array(a) => a.clone(),
+        v => vec![v.clone()],
+    };
+    let mut columns: Vec<String> = vec![];
+    let mut cells: Vec<Vec<(String, String)>> = vec![];
+    for item in &items {
+        let mut row = vec![];
+        match item {
+            Value::Object(_) => flatten("", item, &mut row),
+            v => flatten("value", v, &mut row),
+        }
+        for (k, _) in &row {
+            if !columns.contains(k) {
+                columns.push(k.clone());
+            }
+        }
+        cells.push(row);
+    }
+    let rows = cells
+        .into_iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|c| {
+                    row.iter()
+                        .find(|(k, _)| k == c)
+                        .map(|(_, v)| v.clone())
+                        .unwrap_or_default()
+                })
+                .collect()
+        })
+        .collect();
+    (columns, rows)
+}
+
+fn csv_field(s: &str) -> String {
+    match s.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.to_string(),
+    }
+}
+
+fn print(value: &Value, format: Format) {
+    if value.is_null() {
+        return;  // the command already wrote its output
+    }
+    if format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(value).unwrap_or_default());
+        return;
+    }
+
+    let (columns, rows) = to_rows(value);
+    if columns.is_empty() {
+        return;
+    }
+    match format {
+        Format::Csv => {
+            for row in std::iter::once(&columns).chain(rows.iter()) {
+                let line: Vec<String> = row.iter().map(|c| csv_field(c)).collect();
+                println!("{}", line.join(","));
+            }
+        }
+        _ => {
+            let widths: Vec<usize> = columns
+                .iter()
+                .enumerate()
+                .map(|(idx, c)| {
+                    rows.iter()
+                        .map(|r| r[idx].chars().count())
+                        .chain(std::iter::once(c.chars().count()))
+                        .max()
+                        .unwrap_or(0)
+                })
+                .collect();
+            let line = |row: &Vec<String>| {
+                row.iter()
+                    .zip(&widths)
+                    .map(|(c, w)| format!("{:<w$}", c, w = *w))
+                    .collect::<Vec<_>>()
+                    .join("  ")
+            };
+            println!("{}", line(&columns));
+            println!("{}", widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>().join("  "));
+            for r in &rows {
+                println!("{}", line(r));
+            }
+        }
+    }
+}
+
+fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let passphrase = std::env::var("ALERE_PASSPHRASE").ok().filter(|p| !p.is_empty());
+    let db = args.db.clone().unwrap_or_else(initial_database_path);
+    let result = if db.exists() || args.create {
+        switch_database(&db, passphrase.as_deref()).and_then(|_| run(&args))
+    } else {
+        Err(AlereError::not_found(format!(
+            "No database {}, use --create to create it",
+            db.display()
+        )))
+    };
+    match result {
+        Ok(value) => {
+            print(&value, args.format);
+            if args.command == "check" && value.as_array().map(|a| !a.is_empty()).unwrap_or(false) {
+                std::process::exit(2);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -3,35 +3,47 @@ use super::cte_list_splits::{
 use chrono::NaiveDate;
 use super::dates::{DateRange, DateSet, CTE_DATES};
 use super::occurrences::Occurrences;
+use super::query_builder::{Query, Sql};
 use super::scenarios::Scenario;
+use super::tags::{filter_splits_with_tags, TagId};
 use super::accounts::AccountKindCategory;
 use super::models::CommodityId;
-use diesel::sql_types::{Date, Float, Nullable};
+use super::decimals::SqlOptionalDecimal;
+use super::errors::AlereError;
+use diesel::sql_types::{Date, Nullable, Text};
+use rust_decimal::Decimal;
+use serde::Serialize;
 
 
-#[derive(QueryableByName)]
+#[derive(QueryableByName, Serialize)]
 pub struct CashFlow {
 
     #[sql_type = "Date"]
     pub month: NaiveDate,
 
-    #[sql_type = "Nullable<Float>"]
-    pub realized_inc_total: Option<f32>,
+    #[sql_type = "Nullable<Text>"]
+    #[diesel(deserialize_as = "SqlOptionalDecimal")]
+    pub realized_inc_total: Option<Decimal>,
 
-    #[sql_type = "Nullable<Float>"]
-    pub inc_average: Option<f32>,
+    #[sql_type = "Nullable<Text>"]
+    #[diesel(deserialize_as = "SqlOptionalDecimal")]
+    pub inc_average: Option<Decimal>,
 
-    #[sql_type = "Nullable<Float>"]
-    pub unrealized_inc_total: Option<f32>,
+    #[sql_type = "Nullable<Text>"]
+    #[diesel(deserialize_as = "SqlOptionalDecimal")]
+    pub unrealized_inc_total: Option<Decimal>,
 
-    #[sql_type = "Nullable<Float>"]
-    pub unrealized_average: Option<f32>,
+    #[sql_type = "Nullable<Text>"]
+    #[diesel(deserialize_as = "SqlOptionalDecimal")]
+    pub unrealized_average: Option<Decimal>,
 
-    #[sql_type = "Nullable<Float>"]
-    pub exp_total: Option<f32>,
+    #[sql_type = "Nullable<Text>"]
+    #[diesel(deserialize_as = "SqlOptionalDecimal")]
+    pub exp_total: Option<Decimal>,
 
-    #[sql_type = "Nullable<Float>"]
-    pub exp_average: Option<f32>,
+    #[sql_type = "Nullable<Text>"]
+    #[diesel(deserialize_as = "SqlOptionalDecimal")]
+    pub exp_average: Option<Decimal>,
 }
 
 pub fn monthly_cashflow(
@@ -41,65 +53,79 @@ pub fn monthly_cashflow(
     max_scheduled_occurrences: &Occurrences,
     prior: u8,
     after: u8,
-) -> Vec<CashFlow> {
+    tags: &Option<Vec<TagId>>,
+) -> Result<Vec<CashFlow>, AlereError> {
 
     let adjusted = dates.extend(prior, after);
     let adjusted_cte = adjusted.cte();
     let splits = cte_list_splits(dates, scenario, max_scheduled_occurrences);
-    let split_values = cte_splits_with_values();
+    let split_values = cte_splits_with_values(&splits);
     let income = AccountKindCategory::INCOME as u8;
     let expense = AccountKindCategory::EXPENSE as u8;
-    let query = format!(
+    let filter_tags = filter_splits_with_tags(tags, "s.split_id");
+    let query = Query::new(Sql::new(format!(
         "
-        WITH RECURSIVE {adjusted_cte},
-            {splits},
-            {split_values}
         SELECT
            tmp.month,
-           tmp.realized_inc_total,
-           AVG(tmp.realized_inc_total) OVER
-               (ORDER BY tmp.month
-                ROWS BETWEEN {prior} PRECEDING
-                AND {after} FOLLOWING) AS inc_average,
-           tmp.unrealized_inc_total,
-           AVG(tmp.unrealized_inc_total) OVER
-               (ORDER BY tmp.month
-                ROWS BETWEEN {prior} PRECEDING
-                AND {after} FOLLOWING) AS unrealized_average,
-           tmp.exp_total,
-           AVG(tmp.exp_total) OVER
-               (ORDER BY tmp.month
-                ROWS BETWEEN {prior} PRECEDING
-                AND {after} FOLLOWING) AS exp_average
+           alr_decimal(tmp.realized_inc_total, tmp.scale)
+              AS realized_inc_total,
+           alr_decimal(
+              SUM(tmp.realized_inc_total) OVER win,
+              COUNT(tmp.realized_inc_total) OVER win * tmp.scale)
+              AS inc_average,
+           alr_decimal(tmp.unrealized_inc_total, tmp.scale)
+              AS unrealized_inc_total,
+           alr_decimal(
+              SUM(tmp.unrealized_inc_total) OVER win,
+              COUNT(tmp.unrealized_inc_total) OVER win * tmp.scale)
+              AS unrealized_average,
+           alr_decimal(tmp.exp_total, tmp.scale) AS exp_total,
+           alr_decimal(
+              SUM(tmp.exp_total) OVER win,
+              COUNT(tmp.exp_total) OVER win * tmp.scale)
+              AS exp_average
         FROM
            (
-              --  Sum of splits for a given months, organized per category
+              --  Sum of splits for a given months, organized per category.
+              --  All splits have the same value_scale, since they are
+              --  in the same currency.
               SELECT
                  strftime('%Y-%m-01', s.post_date) as month,
-                 SUM(value) FILTER (WHERE
-                    k.category = {income}
+                 MAX(s.value_scale) AS scale,
+                 SUM(s.scaled_value) FILTER (WHERE
+                    k.category = :income
                     AND NOT k.is_unrealized
                  ) as realized_inc_total,
-                 SUM(value) FILTER (WHERE
-                    k.category = {income}
+                 SUM(s.scaled_value) FILTER (WHERE
+                    k.category = :income
                     AND k.is_unrealized
                  ) as unrealized_inc_total,
-                 SUM(value) FILTER (WHERE
-                    k.category = {expense}
+                 SUM(s.scaled_value) FILTER (WHERE
+                    k.category = :expense
                  ) as exp_total
               FROM
                  {CTE_SPLITS_WITH_VALUE} s
                  JOIN alr_accounts a ON (s.account_id=a.id)
                  JOIN alr_account_kinds k ON (a.kind_id=k.id)
-              WHERE s.value_commodity_id={currency}
+              WHERE s.value_commodity_id = :currency
+                 :filter_tags
               GROUP BY month
            ) tmp,
            {CTE_DATES}
-        WHERE tmp.month = strftime('%Y-%m-01', {CTE_DATES}.date);
+        WHERE tmp.month = strftime('%Y-%m-01', {CTE_DATES}.date)
+        WINDOW win AS (
+           ORDER BY tmp.month
+           ROWS BETWEEN :prior PRECEDING AND :after FOLLOWING);
         "
-    );
+    ))
+    .bind("income", income)
+    .bind("expense", expense)
+    .bind("currency", currency)
+    .bind("prior", prior)
+    .bind("after", after)
+    .fragment("filter_tags", filter_tags))
+    .with(&adjusted_cte)
+    .with(&split_values);
 
-    let result = super::connections::execute_and_log::<CashFlow>(
-        "monthly_cashflow", &query);
-    result.unwrap_or_default()
+    super::connections::execute_and_log::<CashFlow>("monthly_cashflow", query)
 }
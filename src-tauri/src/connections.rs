@@ -1,15 +1,20 @@
 use chrono::{NaiveDateTime, TimeZone};
 use chrono_tz::UTC;
-use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PooledConnection};
 use diesel::sql_types::{Nullable, Text, Timestamp};
 use diesel::sqlite::{Sqlite, SqliteConnection};
-use diesel::{sql_query, QueryResult, RunQueryDsl};
+use diesel::connection::SimpleConnection;
+use diesel::{QueryResult, RunQueryDsl};
 use memoize::memoize;
 use regex::Regex;
 use rrule::{RRule, RRuleError, RRuleSet, Unvalidated};
-use tauri::api::path::document_dir;
+use dirs_next::document_dir;
 use lazy_static::lazy_static;
-use log::{debug, log_enabled, Level::Debug};
+use log::{debug, error, info, log_enabled, Level::Debug};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use super::errors::AlereError;
+use super::query_builder::Query;
 
 diesel_migrations::embed_migrations!(); //  creates embedded_migrations
 
@@ -20,6 +25,9 @@ sql_function!(
         previous: Nullable<Timestamp>) -> Nullable<Timestamp>
 );
 
+//  The id of the last row inserted on this connection
+no_arg_sql_function!(last_insert_rowid, diesel::sql_types::Integer);
+
 #[memoize(Capacity: 120)] // thread-local
 fn parse_ruleset(start: NaiveDateTime, rule: String) -> Result<RRuleSet, RRuleError> {
     let s = UTC.timestamp(start.timestamp(), 0);
@@ -28,6 +36,18 @@ fn parse_ruleset(start: NaiveDateTime, rule: String) -> Result<RRuleSet, RRuleEr
     Ok(r)
 }
 
+/// Check whether a recurrence rule can be parsed
+/// :param start: the first occurrence
+
+pub fn check_rrule(start: NaiveDateTime, rule: &str) -> Result<(), AlereError> {
+    if rule.is_empty() {
+        return Ok(());
+    }
+    parse_ruleset(start, rule.to_string())
+        .map(|_| ())
+        .map_err(|e| AlereError::parse(e.to_string()))
+}
+
 fn next_event(
     rule: String,
     timestamp: NaiveDateTime,
@@ -59,12 +79,47 @@ fn next_event(
 fn add_functions(connection: &SqliteConnection) {
     alr_next_event::register_impl(connection, next_event)
         .expect("Could not register alr_next_event");
+    super::decimals::add_functions(connection);
 }
 
 type SqlitePool = Pool<ConnectionManager<SqliteConnection>>;
 
-fn create_pool() -> SqlitePool {
-    let db = match document_dir() {
+/// The database currently in use, and its pool of connections
+struct Database {
+    path: PathBuf,
+    pool: SqlitePool,
+    passphrase: Option<String>,
+}
+
+/// Unlock each new connection to an encrypted database. This must be the
+/// first statement executed on the connection.
+
+struct Passphrase(String);
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Passphrase(...)")  // never log the passphrase itself
+    }
+}
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for Passphrase {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!("PRAGMA key = {};", quote_sql(&self.0)))
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Quote a string so that it can be used as a literal in SQL, for the few
+/// statements (PRAGMA, ATTACH) where parameters cannot be bound.
+
+pub fn quote_sql(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// The default location of the database file
+
+pub fn default_database_path() -> PathBuf {
+    match document_dir() {
         Some(mut doc) => {
             doc.push("alere");
 
@@ -72,38 +127,200 @@ fn create_pool() -> SqlitePool {
             _ = std::fs::create_dir(doc.as_path());
 
             doc.push("alere_db.sqlite3");
-            String::from(doc.to_str().unwrap())
+            doc
         }
-        None => String::from("/tmp/alere_db.sqlite3"),
-    };
-    println!("Database is {:?}", &db);
-    let pool = SqlitePool::builder()
-        .max_size(8)
-        .build(ConnectionManager::new(db))
-        .expect("Failed to create connection pool");
+        None => PathBuf::from("/tmp/alere_db.sqlite3"),
+    }
+}
 
-    let connection = pool.get().unwrap();
+/// The location of the database file currently in use. Before any
+/// database was opened, this is the one given on the command line, or the
+/// default one.
+
+pub fn database_path() -> PathBuf {
+    match DATABASE.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(db) => db.path.clone(),
+        None => super::databases::initial_database_path(),
+    }
+}
+
+/// The passphrase of the database currently in use, if it is encrypted
+
+pub fn current_passphrase() -> Option<String> {
+    DATABASE.read().unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|db| db.passphrase.clone())
+}
 
-    let migrated = embedded_migrations::run_with_output(
-        &connection, &mut std::io::stdout());
-    match migrated {
-        Ok(_) => (),
-        Err(e) => println!("{}", e),
-    };
+/// The directory where attached files are stored, next to the database.
+/// It is named after the database ("book.sqlite3" uses "book.attachments"),
+/// so that databases in the same directory do not share their files.
 
-    pool
+pub fn attachments_dir() -> PathBuf {
+    database_path().with_extension("attachments")
+}
+
+fn create_pool(path: &Path, passphrase: Option<&str>) -> Result<SqlitePool, AlereError> {
+    let db = path.to_str()
+        .ok_or_else(|| AlereError::validation(
+            format!("Invalid database path {:?}", path)))?;
+    info!("Database is {:?}", db);
+    let existed = path.metadata().map(|m| m.len() > 0).unwrap_or(false);
+    let mut builder = SqlitePool::builder().max_size(8);
+    if let Some(p) = passphrase {
+        builder = builder.connection_customizer(Box::new(Passphrase(p.to_string())));
+    }
+    let pool = builder.build(ConnectionManager::new(db))?;
+
+    let connection = pool.get()?;
+
+    // With an invalid key, SQLCipher only reports that the file is not a
+    // database when it is first read.
+    if passphrase.is_some() {
+        connection.batch_execute("SELECT count(*) FROM sqlite_master")
+            .map_err(|_| AlereError::locked(format!("Invalid passphrase for {}", db)))?;
+    }
+
+    // Keep a copy of the data before the tables are upgraded. This is not
+    // blocking, since the database is still usable.
+    if existed {
+        if let Err(e) = super::backups::backup_database(path, passphrase, "startup") {
+            error!("Could not backup {}: {}", db, e);
+        }
+    }
+
+    let mut output = Vec::new();
+    let migrated = embedded_migrations::run_with_output(&connection, &mut output);
+    for line in String::from_utf8_lossy(&output).lines() {
+        info!("{}", line);
+    }
+    migrated.map_err(|e| AlereError::Database { message: e.to_string(), query: None })?;
+
+    Ok(pool)
+}
+
+/// Start using another database file. Connections already in use keep
+/// working on the previous database until they are released.
+/// The file is created (and its tables initialized) if needed.
+/// :param passphrase: the key for encrypted databases (or to create a new
+///    encrypted database).
+
+pub fn switch_database(path: &Path, passphrase: Option<&str>) -> Result<(), AlereError> {
+    let encrypted = super::encryption::is_encrypted(path);
+    match passphrase {
+        None if encrypted => {
+            return Err(AlereError::locked(format!(
+                "A passphrase is needed to open {}", path.display())));
+        }
+        Some(_) if path.exists() && !encrypted => {
+            return Err(AlereError::validation(format!(
+                "{} is not encrypted", path.display())));
+        }
+        Some(_) => super::encryption::check_supported()?,
+        None => {}
+    }
+
+    let pool = create_pool(path, passphrase)?;
+    *DATABASE.write().unwrap_or_else(|e| e.into_inner()) = Some(Database {
+        path: path.to_path_buf(),
+        pool,
+        passphrase: passphrase.map(|p| p.to_string()),
+    });
+    super::databases::add_recent(path);
+    Ok(())
+}
+
+/// Replace the file of the current database with another one (with the
+/// same tables), then start using it.
+/// The previous database stays in use until the new file could be opened,
+/// and is put back if that fails, so that there is always an open database.
+/// :param file: the new contents. It is moved, so no longer exists on
+///    success.
+/// :param passphrase: the key of the new file, if it is encrypted.
+
+pub fn replace_database(file: &Path, passphrase: Option<&str>) -> Result<(), AlereError> {
+    let mut current = DATABASE.write().unwrap_or_else(|e| e.into_inner());
+    let path = current.as_ref()
+        .map(|db| db.path.clone())
+        .ok_or_else(|| AlereError::not_found("No database is open"))?;
+    let mut previous = path.clone().into_os_string();
+    previous.push(".previous");
+    let previous = PathBuf::from(previous);
+
+    // Connections to the previous database keep working on the renamed
+    // file.
+    std::fs::rename(&path, &previous)?;
+    let pool = std::fs::rename(file, &path)
+        .map_err(AlereError::from)
+        .and_then(|_| create_pool(&path, passphrase));
+    match pool {
+        Ok(pool) => {
+            *current = Some(Database {
+                path,
+                pool,
+                passphrase: passphrase.map(|p| p.to_string()),
+            });
+            _ = std::fs::remove_file(&previous);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Could not open the new file for {:?}: {}", path, e);
+            std::fs::rename(&previous, &path)?;
+            Err(e)
+        }
+    }
+}
+
+/// Use a new empty database in a temporary directory, for tests. All tests
+/// share the current database, so the returned guard must be kept while
+/// it is used.
+
+#[cfg(test)]
+pub fn test_database(name: &str) -> std::sync::MutexGuard<'static, ()> {
+    lazy_static! {
+        static ref TESTS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    }
+    let guard = TESTS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut path = std::env::temp_dir();
+    path.push(format!("alere-test-{}-{}", std::process::id(), name));
+    _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path.push("alere.sqlite3");
+    use_test_database(&path);
+    guard
+}
+
+/// Switch to another database for tests, creating it if needed. Unlike
+/// switch_database, this does not change the list of recent files.
+
+#[cfg(test)]
+pub fn use_test_database(path: &Path) {
+    let pool = create_pool(path, None).unwrap();
+    *DATABASE.write().unwrap_or_else(|e| e.into_inner()) =
+        Some(Database { path: path.to_path_buf(), pool, passphrase: None });
+}
+
+/// The pool for the current database, opening the initial one if needed
+
+fn current_pool() -> Result<SqlitePool, AlereError> {
+    if let Some(db) = DATABASE.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return Ok(db.pool.clone());
+    }
+    switch_database(&super::databases::initial_database_path(), None)?;
+    current_pool()
 }
 
 lazy_static! {
-    static ref POOL: SqlitePool = create_pool();
+    static ref DATABASE: RwLock<Option<Database>> = RwLock::new(None);
     static ref RE_REMOVE_COMMENTS: Regex = Regex::new(r"--.*").unwrap();
     static ref RE_COLLAPSE_SPACES: Regex = Regex::new(r"\s+").unwrap();
 }
 
-pub fn get_connection() -> PooledConnection<ConnectionManager<SqliteConnection>> {
-    let connection = POOL.get().unwrap();
+pub fn get_connection(
+) -> Result<PooledConnection<ConnectionManager<SqliteConnection>>, AlereError> {
+    let connection = current_pool()?.get()?;
     add_functions(&connection);
-    connection
+    Ok(connection)
 }
 
 pub fn log_cleanup_query(msg: &str, query: &str) {
@@ -114,15 +331,53 @@ pub fn log_cleanup_query(msg: &str, query: &str) {
     }
 }
 
+/// Execute a query and log it on errors.
+/// The query is attached to the error in debug builds.
+
 pub fn execute_and_log<U: diesel::query_source::QueryableByName<Sqlite>>(
     msg: &str,
-    query: &str,
-) -> QueryResult<Vec<U>> {
-    let connection = super::connections::get_connection();
-    let res = sql_query(query).load(&connection);
-    if let Err(ref r) = res {
-        log_cleanup_query(&msg, &query);
+    query: impl Into<Query>,
+) -> Result<Vec<U>, AlereError> {
+    let query = query.into().build()?;
+    let connection = super::connections::get_connection()?;
+    let res: QueryResult<Vec<U>> = query.clone().load(&connection);
+    res.map_err(|r| {
+        let text = query.text();
+        log_cleanup_query(&msg, &text);
         log::error!("{:?}: Error in query {:?}", msg, r);
+        AlereError::from(r).with_query(&text)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::importer::import_test_file;
+
+    fn count_commodities() -> i64 {
+        use crate::schema::alr_commodities::dsl::*;
+        use diesel::prelude::*;
+        alr_commodities.count().get_result(&get_connection().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn replace() {
+        let _db = test_database("replace");
+        import_test_file("currency.beancount", "1970-01-01 commodity EUR\n", None);
+        let path = database_path();
+
+        // The current database is still usable after a failure
+        let invalid = path.with_file_name("invalid.sqlite3");
+        std::fs::write(&invalid, "not a database").unwrap();
+        assert!(replace_database(&invalid, None).is_err());
+        assert_eq!(database_path(), path);
+        assert_eq!(count_commodities(), 1);
+
+        let empty = path.with_file_name("empty.sqlite3");
+        drop(create_pool(&empty, None).unwrap());
+        replace_database(&empty, None).unwrap();
+        assert_eq!(database_path(), path);
+        assert_eq!(count_commodities(), 0);
+        assert!(!empty.exists());
     }
-    res
 }
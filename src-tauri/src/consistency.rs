@@ -0,0 +1,347 @@
+//! Validation of the data in the database.
+//! Nothing prevents importers or manual edits from storing invalid data,
+//! which would then result in wrong reports or errors in queries (for
+//! instance a zero quantity results in a division by zero when computing
+//! prices). Each check reports the faulty rows, with a suggested fix.
+
+use super::accounts::{commodity_kinds, AccountKindCategory};
+use super::connections::check_rrule;
+use super::errors::AlereError;
+use super::query_builder::Sql;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::sql_types::{Integer, Text};
+use log::info;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum IssueKind {
+    // Sum of the splits is not zero
+    UNBALANCED,
+
+    // Splits on a closed account, after it was closed
+    CLOSED_ACCOUNT,
+
+    // Recurrence rule that cannot be parsed. Such transactions are only
+    // seen once.
+    INVALID_RRULE,
+
+    // Reference to a missing row
+    ORPHAN,
+
+    // Commodity that cannot be converted to a currency, so has no value
+    NO_PRICE,
+
+    // Flags of an account kind that contradict each other
+    ACCOUNT_KIND,
+
+    // Zero or negative scaling factors, or quantities
+    INVALID_SCALE,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub table: &'static str,  // where the faulty row is
+    pub id: i32,
+    pub message: String,
+    pub fix: &'static str,    // suggested fix
+}
+
+#[derive(QueryableByName)]
+struct IssueRow {
+    #[sql_type = "Integer"]
+    id: i32,
+
+    #[sql_type = "Text"]
+    message: String,
+}
+
+struct Check {
+    kind: IssueKind,
+    table: &'static str,
+    fix: &'static str,
+    query: &'static str, // returns "id" and "message"
+}
+
+// Parameters available to all queries: ":income" and ":expense" for the
+// account kind categories, ":currency" for the commodity kind.
+
+const CHECKS: &[Check] = &[
+    // Only check transactions where all values are in the same commodity,
+    // since there is no exchange rate for the others.
+    Check {
+        kind: IssueKind::UNBALANCED,
+        table: "alr_transactions",
+        fix: "Add a split to balance the transaction, or fix the value of \
+              one of its splits",
+        query: "SELECT s.transaction_id AS id,
+                   'Transaction ' || s.transaction_id || ' is unbalanced by '
+                   || (CAST(SUM(s.scaled_value) AS FLOAT) / c.price_scale)
+                   || ' ' || c.name AS message
+                FROM alr_splits s
+                   JOIN alr_commodities c ON (c.id = s.value_commodity_id)
+                GROUP BY s.transaction_id
+                HAVING COUNT(DISTINCT s.value_commodity_id) = 1
+                   AND SUM(s.scaled_value) <> 0",
+    },
+
+    // There is no closing date for accounts, so use the last
+    // reconciliation instead.
+    Check {
+        kind: IssueKind::CLOSED_ACCOUNT,
+        table: "alr_splits",
+        fix: "Move the split to another account, or reopen the account",
+        query: "WITH closing AS (
+                   SELECT a.id, a.name,
+                      COALESCE(a.last_reconciled,
+                         (SELECT MAX(s2.post_date) FROM alr_splits s2
+                          WHERE s2.account_id = a.id AND s2.reconcile = 'R'))
+                      AS closed_on
+                   FROM alr_accounts a
+                   WHERE a.closed
+                )
+                SELECT s.id,
+                   'Split ' || s.id || ' on closed account ' || c.name
+                   || ' is dated ' || date(s.post_date)
+                   || ', after it was closed on ' || date(c.closed_on)
+                   AS message
+                FROM alr_splits s JOIN closing c ON (s.account_id = c.id)
+                WHERE s.post_date > c.closed_on",
+    },
+
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_splits",
+        fix: "Delete the split",
+        query: "SELECT s.id, 'Split ' || s.id || ' references missing \
+                   transaction ' || s.transaction_id AS message
+                FROM alr_splits s
+                   LEFT JOIN alr_transactions t ON (t.id = s.transaction_id)
+                WHERE t.id IS NULL",
+    },
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_splits",
+        fix: "Move the split to an existing account",
+        query: "SELECT s.id, 'Split ' || s.id || ' references missing \
+                   account ' || s.account_id AS message
+                FROM alr_splits s
+                   LEFT JOIN alr_accounts a ON (a.id = s.account_id)
+                WHERE a.id IS NULL",
+    },
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_splits",
+        fix: "Set the commodity of the value to an existing currency",
+        query: "SELECT s.id, 'Split ' || s.id || ' references missing \
+                   commodity ' || s.value_commodity_id AS message
+                FROM alr_splits s
+                   LEFT JOIN alr_commodities c ON (c.id = s.value_commodity_id)
+                WHERE c.id IS NULL",
+    },
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_splits",
+        fix: "Remove the payee from the split",
+        query: "SELECT s.id, 'Split ' || s.id || ' references missing \
+                   payee ' || s.payee_id AS message
+                FROM alr_splits s LEFT JOIN alr_payees p ON (p.id = s.payee_id)
+                WHERE s.payee_id IS NOT NULL AND p.id IS NULL",
+    },
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_transactions",
+        fix: "Delete the transaction",
+        query: "SELECT t.id, 'Transaction ' || t.id || ' has no split' \
+                   AS message
+                FROM alr_transactions t
+                WHERE NOT EXISTS
+                   (SELECT 1 FROM alr_splits s WHERE s.transaction_id = t.id)",
+    },
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_transactions",
+        fix: "Move the transaction to the actual data (scenario 0)",
+        query: "SELECT t.id, 'Transaction ' || t.id || ' references missing \
+                   scenario ' || t.scenario_id AS message
+                FROM alr_transactions t
+                   LEFT JOIN alr_scenarios c ON (c.id = t.scenario_id)
+                WHERE c.id IS NULL",
+    },
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_accounts",
+        fix: "Make the account a top-level account",
+        query: "SELECT a.id, 'Account ' || a.name || ' references missing \
+                   parent ' || a.parent_id AS message
+                FROM alr_accounts a LEFT JOIN alr_accounts p ON (p.id = a.parent_id)
+                WHERE a.parent_id IS NOT NULL AND p.id IS NULL",
+    },
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_accounts",
+        fix: "Change the kind of the account",
+        query: "SELECT a.id, 'Account ' || a.name || ' references missing \
+                   kind ' || a.kind_id AS message
+                FROM alr_accounts a
+                   LEFT JOIN alr_account_kinds k ON (k.id = a.kind_id)
+                WHERE k.id IS NULL",
+    },
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_accounts",
+        fix: "Change the commodity of the account",
+        query: "SELECT a.id, 'Account ' || a.name || ' references missing \
+                   commodity ' || a.commodity_id AS message
+                FROM alr_accounts a
+                   LEFT JOIN alr_commodities c ON (c.id = a.commodity_id)
+                WHERE c.id IS NULL",
+    },
+    Check {
+        kind: IssueKind::ORPHAN,
+        table: "alr_prices",
+        fix: "Delete the price",
+        query: "SELECT p.id, 'Price ' || p.id || ' references a missing \
+                   commodity' AS message
+                FROM alr_prices p
+                   LEFT JOIN alr_commodities o ON (o.id = p.origin_id)
+                   LEFT JOIN alr_commodities t ON (t.id = p.target_id)
+                WHERE o.id IS NULL OR t.id IS NULL",
+    },
+
+    // Commodities used by an account must have a value in each of the
+    // currencies in use, since reports can be shown in any of them. The
+    // networth goes through alr_price_history_with_turnkey, so a price in
+    // one currency and an exchange rate to the other is enough. A currency
+    // always has a price in itself.
+    Check {
+        kind: IssueKind::NO_PRICE,
+        table: "alr_commodities",
+        fix: "Add a price for the commodity, or an exchange rate between \
+              currencies, or configure an online source for quotes",
+        query: "WITH used AS (
+                   SELECT DISTINCT c.id, c.name, c.kind
+                   FROM alr_accounts a
+                      JOIN alr_commodities c ON (c.id = a.commodity_id)
+                )
+                SELECT u.id, 'No price for ' || u.name
+                   || ' in ' || cur.name AS message
+                FROM used u, used cur
+                WHERE cur.kind = :currency
+                AND NOT EXISTS
+                   (SELECT 1 FROM alr_price_history_with_turnkey p
+                    WHERE p.origin_id = u.id AND p.target_id = cur.id)",
+    },
+
+    Check {
+        kind: IssueKind::ACCOUNT_KIND,
+        table: "alr_account_kinds",
+        fix: "Set the category to income, or unset the income flags",
+        query: "SELECT id, 'Account kind ' || name || ' is work or passive \
+                   income, but not in the income category' AS message
+                FROM alr_account_kinds
+                WHERE (is_work_income OR is_passive_income)
+                   AND category <> :income",
+    },
+    Check {
+        kind: IssueKind::ACCOUNT_KIND,
+        table: "alr_account_kinds",
+        fix: "Unset one of the work income or passive income flags",
+        query: "SELECT id, 'Account kind ' || name || ' is both work and \
+                   passive income' AS message
+                FROM alr_account_kinds
+                WHERE is_work_income AND is_passive_income",
+    },
+    Check {
+        kind: IssueKind::ACCOUNT_KIND,
+        table: "alr_account_kinds",
+        fix: "Unset the networth flag",
+        query: "SELECT id, 'Account kind ' || name || ' is income or \
+                   expense, but part of the networth' AS message
+                FROM alr_account_kinds
+                WHERE category IN (:income, :expense) AND is_networth",
+    },
+
+    Check {
+        kind: IssueKind::INVALID_SCALE,
+        table: "alr_accounts",
+        fix: "Set the smallest commodity unit to a positive value, for \
+              instance 100",
+        query: "SELECT id, 'Account ' || name || ' has invalid smallest \
+                   commodity unit ' || commodity_scu AS message
+                FROM alr_accounts WHERE commodity_scu <= 0",
+    },
+    Check {
+        kind: IssueKind::INVALID_SCALE,
+        table: "alr_commodities",
+        fix: "Set the price scale to a positive value, for instance 100",
+        query: "SELECT id, 'Commodity ' || name || ' has invalid price \
+                   scale ' || price_scale AS message
+                FROM alr_commodities WHERE price_scale <= 0",
+    },
+    Check {
+        kind: IssueKind::INVALID_SCALE,
+        table: "alr_splits",
+        fix: "Set the quantity of the split, or its value to zero",
+        query: "SELECT id, 'Split ' || id || ' has a value but no quantity' \
+                   AS message
+                FROM alr_splits WHERE scaled_qty = 0 AND scaled_value <> 0",
+    },
+];
+
+/// Check the recurrence rules of all scheduled transactions
+
+fn check_rrules() -> Result<Vec<Issue>, AlereError> {
+    use super::schema::alr_transactions::dsl::*;
+    let c = &super::connections::get_connection()?;
+    let rules: Vec<(i32, NaiveDateTime, Option<String>)> = alr_transactions
+        .filter(scheduled.is_not_null())
+        .select((id, timestamp, scheduled))
+        .load(c)?;
+    Ok(rules
+        .into_iter()
+        .filter_map(|(tid, start, rule)| {
+            let rule = rule.unwrap_or_default();
+            check_rrule(start, &rule).err().map(|e| Issue {
+                kind: IssueKind::INVALID_RRULE,
+                table: "alr_transactions",
+                id: tid,
+                message: format!(
+                    "Transaction {} has invalid recurrence rule {:?}: {}",
+                    tid, rule, e),
+                fix: "Fix the recurrence rule, or make it a one-time \
+                      transaction",
+            })
+        })
+        .collect())
+}
+
+/// Run all consistency checks, and report the issues found.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn check_database() -> Result<Vec<Issue>, AlereError> {
+    info!("check_database");
+    let income = AccountKindCategory::INCOME as u32;
+    let expense = AccountKindCategory::EXPENSE as u32;
+    let mut result = vec![];
+    for check in CHECKS {
+        let rows = super::connections::execute_and_log::<IssueRow>(
+            "check_database",
+            Sql::new(check.query)
+                .bind("income", income)
+                .bind("expense", expense)
+                .bind("currency", commodity_kinds::CURRENCY),
+        )?;
+        result.extend(rows.into_iter().map(|r| Issue {
+            kind: check.kind,
+            table: check.table,
+            id: r.id,
+            message: r.message,
+            fix: check.fix,
+        }));
+    }
+    result.extend(check_rrules()?);
+    Ok(result)
+}
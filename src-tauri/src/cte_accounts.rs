@@ -1,19 +1,40 @@
 use super::cte_list_splits::CTE_SPLITS;
 use super::models::AccountId;
+use super::query_builder::{Cte, Sql};
 
 pub const CTE_TRANSACTIONS_FOR_ACCOUNTS: &str = "cte_tr_account";
 
 /// The list of transactions for which one of the splits is about one of
 /// the accounts.
-/// Requires CTE_SPLITS
+/// :param splits: as returned by cte_list_splits
 
-pub fn cte_transactions_for_accounts(account_ids: &[AccountId]) -> String {
-    let s: Vec<String> = account_ids.iter().map(|&id| id.to_string()).collect();
-    let ids = s.join(",");
-    format!(
-        "{CTE_TRANSACTIONS_FOR_ACCOUNTS} AS ( \
-        SELECT DISTINCT transaction_id \
-        FROM {CTE_SPLITS} s \
-        WHERE s.account_id IN ({ids}))"
+pub fn cte_transactions_for_accounts(splits: &Cte, account_ids: &[AccountId]) -> Cte {
+    Cte::new(
+        CTE_TRANSACTIONS_FOR_ACCOUNTS,
+        Sql::new(format!(
+            "SELECT DISTINCT transaction_id \
+            FROM {CTE_SPLITS} s \
+            WHERE s.account_id IN (:ids)"
+        ))
+        .bind("ids", account_ids),
     )
+    .requires(splits)
+}
+
+pub const CTE_ACCOUNT_NAMES: &str = "cte_account_names";
+
+/// The full name of each account, made of the names of its parents
+/// ("Assets:Bank:Checking").
+
+pub fn cte_account_names() -> Cte {
+    Cte::new(
+        CTE_ACCOUNT_NAMES,
+        Sql::new(format!(
+            "SELECT id, name FROM alr_accounts WHERE parent_id IS NULL \
+            UNION ALL \
+            SELECT a.id, n.full_name || ':' || a.name \
+            FROM alr_accounts a JOIN {CTE_ACCOUNT_NAMES} n ON (a.parent_id = n.account_id)"
+        )),
+    )
+    .columns("(account_id, full_name)")
 }
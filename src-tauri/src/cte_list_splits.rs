@@ -1,9 +1,11 @@
 use super::dates::DateSet;
 use super::occurrences::Occurrences;
+use super::query_builder::{Cte, Sql};
 use super::scenarios::{Scenario, NO_SCENARIO};
 
 pub const CTE_SPLITS: &str = "cte_splits";
 pub const CTE_SPLITS_WITH_VALUE: &str = "cte_splits_value";
+const CTE_RECURRING_SPLITS: &str = "recurring_splits_and_transaction";
 
 /// A common table expression that returns all splits to consider in the
 /// given time range, including the recurrences of scheduled transactions.
@@ -12,13 +14,12 @@ pub fn cte_list_splits(
     dates: &dyn DateSet,
     scenario: Scenario,
     max_scheduled_occurrences: &Occurrences,
-) -> String {
-    let dates_start = dates.get_start();
-    let dates_end = dates.get_end();
+) -> Cte {
+    let dates_start = dates.get_earliest();
+    let dates_end = dates.get_most_recent();
     let maxo = max_scheduled_occurrences.get_max_occurrences();
 
-    let non_recurring_splits = format!(
-        "
+    let non_recurring_splits = "
         SELECT
            t.id as transaction_id,
            1 as occurrence,
@@ -39,18 +40,19 @@ pub fn cte_list_splits(
         FROM alr_transactions t
            JOIN alr_splits s ON (s.transaction_id = t.id)
         WHERE t.scheduled IS NULL
-            AND (t.scenario_id = {NO_SCENARIO}
-                 OR t.scenario_id = {scenario})
-            AND post_date >= '{dates_start}'
-            AND post_date <= '{dates_end}'
-    "
-    );
+            AND (t.scenario_id = :no_scenario
+                 OR t.scenario_id = :scenario)
+            AND post_date >= :start
+            AND post_date <= :end
+    ";
 
     if maxo > 0 {
         // overrides the post_date for the splits associated with a
         // recurring transaction
-        return format!(
-            "recurring_splits_and_transaction AS (
+        let recurring = Cte::new(
+            CTE_RECURRING_SPLITS,
+            Sql::new(format!(
+            "
             SELECT
                t.id as transaction_id,
                1 as occurrence,
@@ -73,8 +75,8 @@ pub fn cte_list_splits(
             FROM alr_transactions t
                JOIN alr_splits s ON (s.transaction_id = t.id)
             WHERE t.scheduled IS NOT NULL
-               AND (t.scenario_id = {NO_SCENARIO}
-                    OR t.scenario_id = {scenario})
+               AND (t.scenario_id = :no_scenario
+                    OR t.scenario_id = :scenario)
 
             UNION SELECT
                s.transaction_id,
@@ -93,38 +95,63 @@ pub fn cte_list_splits(
                s.reconcile,
                s.payee_id,
                alr_next_event(s.scheduled, s.initial_timestamp, s.post_date)
-            FROM recurring_splits_and_transaction s
+            FROM {CTE_RECURRING_SPLITS} s
             WHERE s.post_date IS NOT NULL
-              AND s.post_date <= '{dates_end}'
-              AND s.occurrence < {maxo}
-        ), {CTE_SPLITS} AS (
-           SELECT * FROM recurring_splits_and_transaction
+              AND s.post_date <= :end
+              AND s.occurrence < :maxo
+            "))
+            .bind("no_scenario", NO_SCENARIO)
+            .bind("scenario", scenario)
+            .bind("end", dates_end)
+            .bind("maxo", maxo),
+        );
+        Cte::new(
+            CTE_SPLITS,
+            Sql::new(format!(
+            "
+           SELECT * FROM {CTE_RECURRING_SPLITS}
               WHERE post_date IS NOT NULL
                 --  The last computed occurrence might be later than expected
                 --  date
-                AND post_date <= '{dates_end}'
+                AND post_date <= :end
 
                 --  The next occurrence might be in the past if it was never
                 --  acknowledged.
-                --   AND post_date >= '{dates_start}'
+                --   AND post_date >= :start
            UNION {non_recurring_splits}
-        )"
-        );
+            "))
+            .bind("no_scenario", NO_SCENARIO)
+            .bind("scenario", scenario)
+            .bind("start", dates_start)
+            .bind("end", dates_end),
+        )
+        .requires(&recurring)
     } else {
-        return format!("{CTE_SPLITS} AS ({non_recurring_splits})");
+        Cte::new(
+            CTE_SPLITS,
+            Sql::new(non_recurring_splits)
+                .bind("no_scenario", NO_SCENARIO)
+                .bind("scenario", scenario)
+                .bind("start", dates_start)
+                .bind("end", dates_end),
+        )
     }
 }
 
 /// Returns all splits and their associated value, scaled as needed.
-/// Requires cte_list_splits
+/// The value is a float, so should only be used for filtering. Exact
+/// amounts are computed with alr_decimal(scaled_value, value_scale).
+/// :param splits: as returned by cte_list_splits
 
-pub fn cte_splits_with_values() -> String {
-    format!(
+pub fn cte_splits_with_values(splits: &Cte) -> Cte {
+    Cte::new(
+        CTE_SPLITS_WITH_VALUE,
+        Sql::new(format!(
         "
-        {CTE_SPLITS_WITH_VALUE} AS (
            SELECT
               s.*,
               CAST(s.scaled_value AS FLOAT) / c.price_scale AS value,
+              c.price_scale AS value_scale,
               CAST(s.scaled_value * alr_accounts.commodity_scu AS FLOAT)
                  / (s.scaled_qty * c.price_scale)
                  AS computed_price
@@ -132,7 +159,7 @@ pub fn cte_splits_with_values() -> String {
               {CTE_SPLITS} s
               JOIN alr_accounts ON (s.account_id = alr_accounts.id)
               JOIN alr_commodities c ON (s.value_commodity_id=c.id)
-        )
     "
-    )
+    )))
+    .requires(splits)
 }
@@ -1,5 +1,6 @@
 use super::cte_list_splits::CTE_SPLITS;
 use super::dates::SQL_ARMAGEDDON;
+use super::query_builder::{Cte, Sql};
 
 pub const CTE_BALANCES: &str = "cte_bl";
 pub const CTE_BALANCES_CURRENCY: &str = "cte_bl_cur";
@@ -9,13 +10,16 @@ pub const CTE_BALANCES_CURRENCY: &str = "cte_bl_cur";
 /// The result is a set of tuple
 ///    (account_id, shares, [min_date, max_date))
 /// that covers all time and all accounts.
+/// The exact number of shares is alr_decimal(scaled_shares, commodity_scu),
+/// whereas shares is a float.
 ///
-/// Requires cte_list_splits
+/// :param splits: as returned by cte_list_splits
 
-pub fn cte_balances() -> String {
-    format!(
+pub fn cte_balances(splits: &Cte) -> Cte {
+    Cte::new(
+        CTE_BALANCES,
+        Sql::new(format!(
         "
-        {CTE_BALANCES} AS (
            SELECT
               a.id AS account_id,
               a.commodity_id,
@@ -30,24 +34,31 @@ pub fn cte_balances() -> String {
                        ORDER BY s.post_date
                        ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW)
                  AS FLOAT
-                ) / a.commodity_scu AS shares
+                ) / a.commodity_scu AS shares,
+              sum(s.scaled_qty)
+                 OVER (PARTITION BY s.account_id
+                       ORDER BY s.post_date
+                       ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW)
+                 AS scaled_shares,
+              a.commodity_scu
            FROM
               {CTE_SPLITS} s
               JOIN alr_accounts a ON (s.account_id = a.id)
-        )
     "
-    )
+    )))
+    .requires(splits)
 }
 
 /// Similar to cte_balances, but also combines with the prices history to
 /// compute the money value of those shares. This might result in more
 /// time intervals.
-/// Requires cte_balances
+/// :param balances: as returned by cte_balances
 
-pub fn cte_balances_currency() -> String {
-    format!(
+pub fn cte_balances_currency(balances: &Cte) -> Cte {
+    Cte::new(
+        CTE_BALANCES_CURRENCY,
+        Sql::new(format!(
         "
-    {CTE_BALANCES_CURRENCY} AS (
         SELECT
            b.account_id,
            alr_commodities.id as currency_id,
@@ -56,6 +67,8 @@ pub fn cte_balances_currency() -> String {
            CAST(b.shares * p.scaled_price AS FLOAT)
               / source.price_scale as balance,
            b.shares,
+           b.scaled_shares,
+           b.commodity_scu,
            CAST(p.scaled_price AS FLOAT) / source.price_scale
               as computed_price
         FROM
@@ -77,6 +90,7 @@ pub fn cte_balances_currency() -> String {
 
            --  target commodities can only be currencies
            AND alr_commodities.kind = 'C'
-    )"
-    )
+    "
+    )))
+    .requires(balances)
 }
@@ -1,26 +1,35 @@
 use super::cte_query_balance::CTE_BALANCES_CURRENCY;
 use super::dates::CTE_DATES;
 use super::models::CommodityId;
+use super::query_builder::{Cte, Sql};
 
 pub const CTE_QUERY_NETWORTH: &str = "cte_qn";
 
-/// Create a query that returns the networth as computed for a set of
-/// dates. These dates must be found in the "dates(date)" table, which
-/// typically will be provided as a common table expression.
+/// Create a query that returns the components of the networth, as computed
+/// for a set of dates, as provided by the `dates` common table expression.
+/// For each date, there is one row per networth account, with the exact
+/// number of shares (as text) and their price in the currency. The total
+/// is computed by the caller, to avoid rounding errors.
 ///
-/// requires cte_balances_currency() and dates
-///
-/// :param max_scheduled_occurrences:
-///     if 0, ignore all scheduled transactions.
-///     if 1, only look at the next occurrence of them.
+/// :param balances_currency: as returned by cte_balances_currency. It
+///     determines whether scheduled transactions are taken into account.
+/// :param dates: as returned by DateSet::cte
 
-pub fn cte_query_networth(currency: CommodityId) -> String {
-    return format!(
+pub fn cte_query_networth(
+    balances_currency: &Cte,
+    dates: &Cte,
+    currency: CommodityId,
+) -> Cte {
+    Cte::new(
+        CTE_QUERY_NETWORTH,
+        Sql::new(format!(
         "
-       {CTE_QUERY_NETWORTH} AS (  \
        SELECT   \
           {CTE_DATES}.date, \
-          SUM({CTE_BALANCES_CURRENCY}.balance) AS value  \
+          {CTE_BALANCES_CURRENCY}.account_id, \
+          alr_decimal({CTE_BALANCES_CURRENCY}.scaled_shares, \
+                      {CTE_BALANCES_CURRENCY}.commodity_scu) AS shares, \
+          {CTE_BALANCES_CURRENCY}.computed_price \
        FROM {CTE_DATES}, \
           {CTE_BALANCES_CURRENCY}, \
           alr_accounts \
@@ -34,10 +43,13 @@ pub fn cte_query_networth(currency: CommodityId) -> String {
              <= strftime('%Y-%m-%d', {CTE_DATES}.date) \
           AND strftime('%Y-%m-%d', {CTE_DATES}.date) \
              < strftime('%Y-%m-%d', {CTE_BALANCES_CURRENCY}.maxdate) \
-          AND {CTE_BALANCES_CURRENCY}.currency_id = {currency} \
+          AND {CTE_BALANCES_CURRENCY}.currency_id = :currency \
           AND {CTE_BALANCES_CURRENCY}.account_id = alr_accounts.id  \
           AND k.is_networth  \
-       GROUP BY {CTE_DATES}.date \
-    )"
-    );
+    "
+        ))
+        .bind("currency", currency),
+    )
+    .requires(balances_currency)
+    .requires(dates)
 }
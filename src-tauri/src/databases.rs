@@ -0,0 +1,148 @@
+//! Selecting the database file.
+//! Several independent books can be kept, each in its own file. The
+//! initial one is given on the command line ("--database FILE") or in the
+//! ALERE_DB environment variable, and defaults to a file in the user's
+//! documents directory. The files opened recently are remembered in the
+//! user's configuration directory.
+
+use super::connections::{database_path, default_database_path, switch_database};
+use super::encryption::is_encrypted;
+use super::errors::AlereError;
+use dirs_next::config_dir;
+use log::{error, info};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+const MAX_RECENT: usize = 10;
+
+#[derive(Serialize, Debug)]
+pub struct DatabaseInfo {
+    path: String,
+    encrypted: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RecentDatabase {
+    path: String,
+    exists: bool,
+}
+
+/// The database to open on startup
+
+pub fn initial_database_path() -> PathBuf {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--database" {
+            if let Some(path) = args.next() {
+                return PathBuf::from(path);
+            }
+        } else if let Some(path) = arg.strip_prefix("--database=") {
+            return PathBuf::from(path);
+        }
+    }
+    match std::env::var_os("ALERE_DB") {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => default_database_path(),
+    }
+}
+
+/// A file in the user's configuration directory, shared by all databases
+
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    let mut dir = config_dir()?;
+    dir.push("alere");
+    _ = std::fs::create_dir_all(dir.as_path());
+    dir.push(name);
+    Some(dir)
+}
+
+/// The file in which the list of recent databases is stored
+
+fn recent_file() -> Option<PathBuf> {
+    config_file("recent_databases.json")
+}
+
+fn load_recent() -> Vec<PathBuf> {
+    recent_file()
+        .and_then(|f| std::fs::read_to_string(f).ok())
+        .and_then(|s| serde_json::from_str::<Vec<PathBuf>>(&s).ok())
+        .unwrap_or_default()
+}
+
+/// Move path to the top of the list of recent databases.
+/// Failures are only logged, since they do not prevent using the database.
+
+pub fn add_recent(path: &Path) {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut recent = load_recent();
+    recent.retain(|p| *p != path);
+    recent.insert(0, path);
+    recent.truncate(MAX_RECENT);
+
+    if let Some(f) = recent_file() {
+        let saved = serde_json::to_string_pretty(&recent)
+            .map_err(|e| e.to_string())
+            .and_then(|s| std::fs::write(&f, s).map_err(|e| e.to_string()));
+        if let Err(e) = saved {
+            error!("Could not save recent databases in {:?}: {}", f, e);
+        }
+    }
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn current_database() -> Result<DatabaseInfo, AlereError> {
+    let path = database_path();
+    Ok(DatabaseInfo {
+        encrypted: is_encrypted(&path),
+        path: path.to_string_lossy().to_string(),
+    })
+}
+
+/// The databases opened recently, most recent first
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn recent_databases() -> Result<Vec<RecentDatabase>, AlereError> {
+    Ok(load_recent()
+        .into_iter()
+        .map(|p| RecentDatabase {
+            exists: p.is_file(),
+            path: p.to_string_lossy().to_string(),
+        })
+        .collect())
+}
+
+/// Create a new, empty database and start using it.
+/// :param path: the file to create, which must not exist yet
+/// :param passphrase: if set, the new database is encrypted
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn create_database(
+    path: String,
+    passphrase: Option<String>,
+) -> Result<(), AlereError> {
+    info!("create_database {:?}", &path);
+    let p = PathBuf::from(&path);
+    if p.exists() {
+        return Err(AlereError::validation(format!("{} already exists", path)));
+    }
+    if let Some(dir) = p.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    switch_database(&p, passphrase.as_deref().filter(|s| !s.is_empty()))
+}
+
+/// Start using an existing database. Its tables are upgraded if needed.
+/// :param passphrase: needed when the database is encrypted
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn open_database(
+    path: String,
+    passphrase: Option<String>,
+) -> Result<(), AlereError> {
+    info!("open_database {:?}", &path);
+    let p = PathBuf::from(&path);
+    if !p.is_file() {
+        return Err(AlereError::not_found(format!("No such database {}", path)));
+    }
+    switch_database(&p, passphrase.as_deref().filter(|s| !s.is_empty()))
+}
@@ -1,7 +1,9 @@
 //! Describe a range or set of dates
 
 use super::cte_list_splits::{cte_list_splits, CTE_SPLITS};
-use chrono::{NaiveDate, Date, TimeZone, Utc, Duration};
+use super::errors::AlereError;
+use super::query_builder::{Cte, Query, Sql};
+use chrono::{NaiveDate, Date, Datelike, TimeZone, Utc, Duration};
 use serde::Deserialize;
 use lazy_static::lazy_static;
 use core::cmp::{max, min};
@@ -27,10 +29,10 @@ pub enum GroupBy {
 
 #[derive(QueryableByName)]
 struct SplitsRange {
-    #[sql_type = "diesel::sql_types::Date"]
-    mindate: NaiveDate,
-    #[sql_type = "diesel::sql_types::Date"]
-    maxdate: NaiveDate,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Date>"]
+    mindate: Option<NaiveDate>,  // None when there are no splits
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Date>"]
+    maxdate: Option<NaiveDate>,
 }
 
 
@@ -41,7 +43,7 @@ pub trait DateSet {
     fn get_most_recent(&self) -> Date<Utc>;
 
     /// Returns the query for a common table expression named CTE_DATES,
-    fn cte(&self) -> String;
+    fn cte(&self) -> Cte;
 
     /// Return a range that starts at the beginning of times and extends till
     /// the end of self
@@ -85,41 +87,34 @@ impl DateRange {
         &self,
         scenario: super::scenarios::Scenario,
         max_scheduled_occurrences: &super::occurrences::Occurrences,
-    ) -> Self {
+    ) -> Result<Self, AlereError> {
         let list_splits = cte_list_splits(
             self, scenario, &max_scheduled_occurrences);
-        let query = format!(
+        let query = Query::new(format!(
             "
-            WITH RECURSIVE {list_splits}
             SELECT strftime('%Y-%m-%d', min(post_date)) AS mindate,
             strftime('%Y-%m-%d', max(post_date)) AS maxdate
             FROM {CTE_SPLITS} "
-        );
-        let result = super::connections::execute_and_log::<SplitsRange>(
-            "restrict_to_splits", &query);
-        match result {
-            Ok(rows) => match rows.first() {
-                Some(r) => DateRange::new(
-                    Some(max(
-                        Utc.from_utc_date(&r.mindate),
-                        self.get_earliest())),
-                    Some(min(
-                        Utc.from_utc_date(&r.maxdate),
-                        self.get_most_recent())),
-                    self.granularity.clone(),
-                ),
-                None => DateRange::new(
-                    Some(self.get_earliest()),
-                    Some(self.get_most_recent()),
-                    self.granularity.clone(),
-                ),
-            },
-            Err(_) => DateRange::new(
+        ))
+        .with(&list_splits);
+        let rows = super::connections::execute_and_log::<SplitsRange>(
+            "restrict_to_splits", query)?;
+        Ok(match rows.first().and_then(|r| r.mindate.zip(r.maxdate)) {
+            Some((mindate, maxdate)) => DateRange::new(
+                Some(max(
+                    Utc.from_utc_date(&mindate),
+                    self.get_earliest())),
+                Some(min(
+                    Utc.from_utc_date(&maxdate),
+                    self.get_most_recent())),
+                self.granularity.clone(),
+            ),
+            None => DateRange::new(
                 Some(self.get_earliest()),
                 Some(self.get_most_recent()),
                 self.granularity.clone(),
             ),
-        }
+        })
     }
 
     /// Return a duration for a number of granularity
@@ -146,50 +141,52 @@ impl DateRange {
 }
 
 impl DateSet for DateRange {
-    fn cte(&self) -> String {
-        let start_str = self.start.format("%Y-%m-%d");
-        let end_str = self.end.format("%Y-%m-%d");
-
-        match self.granularity {
+    fn cte(&self) -> Cte {
+        let query = match self.granularity {
             GroupBy::YEARS => format!(
                 "
-                {CTE_DATES} (date) AS (
-                SELECT date('{end_str}', '+1 YEAR', 'start of year', '-1 day')
+                SELECT date(:end, '+1 YEAR', 'start of year', '-1 day')
                 UNION
                    SELECT date(m.date, '-1 YEAR')
                    FROM {CTE_DATES} m
-                   WHERE m.date >= '{start_str}'
-                   LIMIT {MAX_DATES})"
+                   WHERE m.date >= :start
+                   LIMIT :max_dates"
             ),
 
             GroupBy::MONTHS => format!(
                 "
-                {CTE_DATES} (date) AS (
                 SELECT
                    --  end of first month (though no need to go past the oldest
                    --  known date in the data)
-                   date('{start_str}', 'start of month', '+1 month', '-1 day')
+                   date(:start, 'start of month', '+1 month', '-1 day')
                 UNION
                    --  end of next month, though no need to go past the last known
                    --  date in the data
                    SELECT date(m.date, 'start of month', '+2 months', '-1 day')
                    FROM {CTE_DATES} m
-                   WHERE m.date <= '{end_str}'
-                   LIMIT {MAX_DATES})"
+                   WHERE m.date <= :end
+                   LIMIT :max_dates"
             ),
 
             GroupBy::DAYS => format!(
                 "
-                {CTE_DATES} (date) AS (
-                SELECT '{end_str}'
+                SELECT :end
                 UNION
                    SELECT date(m.date, '-1 day')
                    FROM {CTE_DATES} m
-                   WHERE m.date >= '{start_str}'
-                   LIMIT {MAX_DATES}
-                )"
+                   WHERE m.date >= :start
+                   LIMIT :max_dates
+                "
             ),
-        }
+        };
+        Cte::new(
+            CTE_DATES,
+            Sql::new(query)
+                .bind("start", self.start)
+                .bind("end", self.end)
+                .bind("max_dates", MAX_DATES),
+        )
+        .columns("(date)")
     }
 
     fn get_earliest(&self) -> Date<Utc> {
@@ -211,23 +208,56 @@ impl DateValues {
     pub fn new(dates: Option<Vec<Date<Utc>>>) -> Self {
         DateValues { dates }
     }
+
+    /// The explicit dates
+    pub fn dates(&self) -> &[Date<Utc>] {
+        self.dates.as_deref().unwrap_or(&[])
+    }
+
+    /// Number of explicit dates
+    pub fn len(&self) -> usize {
+        self.dates.as_ref().map(|d| d.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The start date, followed by the end of the `months` months starting
+    /// with the one that contains it.
+    pub fn end_of_months(start: Date<Utc>, months: u16) -> Self {
+        let mut dates = vec![start];
+        let (mut year, mut month) = (start.year(), start.month());
+        for _ in 0..months {
+            // first day of the next month, minus one day
+            let (y, m) = match month {
+                12 => (year + 1, 1),
+                _ => (year, month + 1),
+            };
+            year = y;
+            month = m;
+            dates.push(Utc.ymd(year, month, 1).pred());
+        }
+        DateValues::new(Some(dates))
+    }
 }
 
 impl DateSet for DateValues {
-    fn cte(&self) -> String {
-        let nested = match self.dates.as_ref() {
-            Some(d) => format!(
-                "VALUES {}",
-                d.iter()
-                    .enumerate()
-                    .map(|(idx, d)| format!("({},{})", idx + 1, d.format("'%Y-%m-%d'")))
-                    .collect::<Vec<_>>()
-                    .join(",")
+    fn cte(&self) -> Cte {
+        let sql = match self.dates.as_ref() {
+            Some(d) if !d.is_empty() => d.iter().enumerate().fold(
+                Sql::new(format!(
+                    "VALUES {}",
+                    (1..=d.len())
+                        .map(|idx| format!("({idx},:d{idx})"))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                )),
+                |sql, (idx, date)| sql.bind(&format!("d{}", idx + 1), *date),
             ),
-            None => "SELECT 1, NULL WHERE 0".to_string(),
+            _ => Sql::new("SELECT 1, NULL WHERE 0"),
         };
-
-        format!("{CTE_DATES} (idx, date) AS ({nested})")
+        Cte::new(CTE_DATES, sql).columns("(idx, date)")
     }
 
     fn get_earliest(&self) -> Date<Utc> {
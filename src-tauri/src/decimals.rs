@@ -0,0 +1,109 @@
+//! Exact decimal amounts.
+//!
+//! Amounts are stored in the database as scaled integers. Queries must not
+//! convert them to floats, but use the alr_decimal SQL function instead,
+//! which returns the exact value as text, for instance
+//!     alr_decimal(SUM(s.scaled_value), s.value_scale)
+//! The corresponding field is then loaded with
+//!     #[sql_type = "Text"]
+//!     #[diesel(deserialize_as = "SqlDecimal")]
+//!     value: Decimal,
+//! Decimal values are serialized as strings, so that the front-end receives
+//! exact values, and only rounds them for display.
+//! Prices and exchange rates are often computed as floats in the database
+//! (when converting in the reverse direction, for instance). They can also
+//! be loaded as a Decimal, but are then only precise to 15 digits.
+
+use diesel::deserialize::{self, FromSql};
+use diesel::sql_types::{BigInt, Nullable, Text};
+use diesel::sqlite::{Sqlite, SqliteConnection};
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+sql_function!(
+    fn alr_decimal(
+        num: Nullable<BigInt>,   //  the scaled value
+        den: Nullable<BigInt>    //  the scale
+    ) -> Nullable<Text>
+);
+
+/// Build a decimal from a scaled integer, as stored in the database
+
+pub fn from_scaled(scaled: i64, scale: i64) -> Option<Decimal> {
+    Decimal::from(scaled).checked_div(Decimal::from(scale)).map(|d| d.normalize())
+}
+
+/// Format a scaled amount, keeping the trailing zeros when the scale is a
+/// power of 10 (so that other tools infer the right precision).
+
+pub fn format_scaled(scaled: i64, scale: i32) -> String {
+    let mut s = scale;
+    let mut digits = 0;
+    while s > 1 && s % 10 == 0 {
+        s /= 10;
+        digits += 1;
+    }
+    match s {
+        1 => Decimal::new(scaled, digits).to_string(),
+        _ => from_scaled(scaled, scale.into()).unwrap_or_default().to_string(),
+    }
+}
+
+fn exact_ratio(num: Option<i64>, den: Option<i64>) -> Option<String> {
+    from_scaled(num?, den?).map(|d| d.to_string())
+}
+
+pub fn add_functions(connection: &SqliteConnection) {
+    alr_decimal::register_impl(connection, exact_ratio)
+        .expect("Could not register alr_decimal");
+}
+
+/// Parse the text returned by sqlite. This is either the output of
+/// alr_decimal, or a float possibly in scientific notation.
+
+fn parse(text: &str) -> deserialize::Result<Decimal> {
+    Decimal::from_str(text)
+        .or_else(|_| Decimal::from_scientific(text))
+        .map_err(|e| format!("Invalid decimal {:?}: {}", text, e).into())
+}
+
+/// Load a Decimal from a text column
+
+pub struct SqlDecimal(Decimal);
+
+impl FromSql<Text, Sqlite> for SqlDecimal {
+    fn from_sql(
+        value: Option<&<Sqlite as diesel::backend::Backend>::RawValue>,
+    ) -> deserialize::Result<Self> {
+        let text = <String as FromSql<Text, Sqlite>>::from_sql(value)?;
+        parse(&text).map(SqlDecimal)
+    }
+}
+
+impl From<SqlDecimal> for Decimal {
+    fn from(d: SqlDecimal) -> Decimal {
+        d.0
+    }
+}
+
+/// Load an optional Decimal from a nullable text column
+
+pub struct SqlOptionalDecimal(Option<Decimal>);
+
+impl FromSql<Nullable<Text>, Sqlite> for SqlOptionalDecimal {
+    fn from_sql(
+        value: Option<&<Sqlite as diesel::backend::Backend>::RawValue>,
+    ) -> deserialize::Result<Self> {
+        let text = <Option<String> as FromSql<Nullable<Text>, Sqlite>>::from_sql(value)?;
+        match text {
+            None => Ok(SqlOptionalDecimal(None)),
+            Some(t) => parse(&t).map(|d| SqlOptionalDecimal(Some(d))),
+        }
+    }
+}
+
+impl From<SqlOptionalDecimal> for Option<Decimal> {
+    fn from(d: SqlOptionalDecimal) -> Option<Decimal> {
+        d.0
+    }
+}
@@ -0,0 +1,146 @@
+//! Encryption of the database at rest, with SQLCipher.
+//! This is only available when building with the "sqlcipher" feature,
+//! which links with the system's SQLCipher library instead of the bundled
+//! sqlite:
+//!     cargo build --features sqlcipher
+//! An encrypted database is opened with a passphrase (see unlock_database),
+//! which is then given to each new connection.
+
+use super::backups::rekey_backups;
+use super::connections::{current_passphrase, database_path, get_connection, quote_sql,
+                         replace_database, switch_database};
+use super::errors::AlereError;
+use diesel::connection::SimpleConnection;
+use log::info;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Whether the file is an encrypted database. Plain sqlite files start
+/// with a known header, whereas encrypted ones look random.
+/// A missing or empty file is not encrypted.
+
+pub fn is_encrypted(path: &Path) -> bool {
+    let mut header = [0_u8; 16];
+    match std::fs::File::open(path).and_then(|mut f| f.read_exact(&mut header)) {
+        Ok(()) => &header != b"SQLite format 3\0",
+        Err(_) => false,
+    }
+}
+
+/// Report an error when the application was built without SQLCipher
+
+pub fn check_supported() -> Result<(), AlereError> {
+    if cfg!(feature = "sqlcipher") {
+        Ok(())
+    } else {
+        Err(AlereError::validation(
+            "Encryption is not supported, build with the sqlcipher feature"))
+    }
+}
+
+fn check_passphrase(passphrase: &str) -> Result<(), AlereError> {
+    match passphrase.is_empty() {
+        true => Err(AlereError::validation("The passphrase cannot be empty")),
+        false => Ok(()),
+    }
+}
+
+/// Copy the current database into a new file, encrypted with key (or in
+/// plain text if key is empty), then start using that file instead.
+/// Its backups are converted too.
+
+fn convert_current(key: &str) -> Result<(), AlereError> {
+    let path = database_path();
+    let old = current_passphrase();
+    let new = Some(key).filter(|k| !k.is_empty());
+    let mut converted = path.clone().into_os_string();
+    converted.push(".converting");
+    let converted = PathBuf::from(converted);
+    let target = converted.to_str()
+        .ok_or_else(|| AlereError::validation(
+            format!("Invalid database path {:?}", converted)))?;
+    _ = std::fs::remove_file(&converted);
+
+    {
+        let connection = get_connection()?;
+        connection.batch_execute(&format!(
+            "ATTACH DATABASE {} AS converted KEY {};
+             SELECT sqlcipher_export('converted');
+             DETACH DATABASE converted;",
+            quote_sql(target),
+            quote_sql(key),
+        ))?;
+    }
+
+    let replaced = replace_database(&converted, new);
+    _ = std::fs::remove_file(&converted);  //  only left on errors
+    replaced?;
+    rekey_backups(&path, old.as_deref(), new);
+    Ok(())
+}
+
+/// Give the passphrase for the current (encrypted) database, which is
+/// then opened.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn unlock_database(passphrase: String) -> Result<(), AlereError> {
+    info!("unlock_database");
+    check_passphrase(&passphrase)?;
+    switch_database(&database_path(), Some(&passphrase))
+}
+
+/// Change the passphrase of the current (encrypted) database, and of its
+/// backups.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn change_passphrase(passphrase: String) -> Result<(), AlereError> {
+    info!("change_passphrase");
+    check_supported()?;
+    check_passphrase(&passphrase)?;
+    let path = database_path();
+    if !is_encrypted(&path) {
+        return Err(AlereError::validation(format!(
+            "{} is not encrypted", path.display())));
+    }
+    let old = current_passphrase();
+
+    {
+        let connection = get_connection()?;
+        connection.batch_execute(
+            &format!("PRAGMA rekey = {};", quote_sql(&passphrase)))?;
+    }
+
+    // Other connections in the pool still use the old key
+    switch_database(&path, Some(&passphrase))?;
+    rekey_backups(&path, old.as_deref(), Some(&passphrase));
+    Ok(())
+}
+
+/// Encrypt the current database, which must be in plain text
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn encrypt_database(passphrase: String) -> Result<(), AlereError> {
+    info!("encrypt_database");
+    check_supported()?;
+    check_passphrase(&passphrase)?;
+    let path = database_path();
+    if is_encrypted(&path) {
+        return Err(AlereError::validation(format!(
+            "{} is already encrypted", path.display())));
+    }
+    convert_current(&passphrase)
+}
+
+/// Convert the current database back to plain text
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn decrypt_database() -> Result<(), AlereError> {
+    info!("decrypt_database");
+    check_supported()?;
+    let path = database_path();
+    if !is_encrypted(&path) {
+        return Err(AlereError::validation(format!(
+            "{} is not encrypted", path.display())));
+    }
+    convert_current("")
+}
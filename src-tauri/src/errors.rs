@@ -0,0 +1,117 @@
+//! Errors reported by the commands.
+//! They are serialized as JSON objects, for instance
+//!     {"kind": "Database", "message": "no such table", "query": "SELECT ..."}
+//! so that the front-end can display them instead of an empty result.
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind")]
+pub enum AlereError {
+    // Error while accessing the database. In debug builds, the query that
+    // failed is also reported.
+    Database {
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        query: Option<String>,
+    },
+
+    // Invalid data read from the database or from a file, for instance a
+    // recurrence rule or an amount that cannot be parsed.
+    Parse { message: String },
+
+    // Invalid parameters sent to a command
+    Validation { message: String },
+
+    // The requested row or file does not exist
+    NotFound { message: String },
+
+    // Error when reading or writing files
+    Io { message: String },
+
+    // The database is encrypted, and the passphrase is missing or invalid
+    Locked { message: String },
+}
+
+impl AlereError {
+    pub fn parse(message: impl Into<String>) -> Self {
+        AlereError::Parse { message: message.into() }
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        AlereError::Validation { message: message.into() }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        AlereError::NotFound { message: message.into() }
+    }
+
+    pub fn locked(message: impl Into<String>) -> Self {
+        AlereError::Locked { message: message.into() }
+    }
+
+    /// Attach the query that failed to a database error. This is only done
+    /// in debug builds, to avoid leaking details to users.
+
+    pub fn with_query(self, query: &str) -> Self {
+        match self {
+            AlereError::Database { message, .. } if cfg!(debug_assertions) => {
+                AlereError::Database {
+                    message,
+                    query: Some(query.to_string()),
+                }
+            }
+            e => e,
+        }
+    }
+}
+
+impl std::fmt::Display for AlereError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AlereError::Database { message, query: None } => {
+                write!(f, "Database error: {}", message)
+            }
+            AlereError::Database { message, query: Some(q) } => {
+                write!(f, "Database error: {} in {}", message, q)
+            }
+            AlereError::Parse { message } => write!(f, "Parse error: {}", message),
+            AlereError::Validation { message } => write!(f, "Invalid: {}", message),
+            AlereError::NotFound { message } => write!(f, "Not found: {}", message),
+            AlereError::Io { message } => write!(f, "I/O error: {}", message),
+            AlereError::Locked { message } => write!(f, "Locked: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for AlereError {}
+
+impl From<diesel::result::Error> for AlereError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => AlereError::not_found(e.to_string()),
+            e => AlereError::Database { message: e.to_string(), query: None },
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for AlereError {
+    fn from(e: diesel::r2d2::PoolError) -> Self {
+        AlereError::Database { message: e.to_string(), query: None }
+    }
+}
+
+impl From<std::io::Error> for AlereError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => AlereError::not_found(e.to_string()),
+            _ => AlereError::Io { message: e.to_string() },
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for AlereError {
+    fn from(e: zip::result::ZipError) -> Self {
+        AlereError::Io { message: e.to_string() }
+    }
+}
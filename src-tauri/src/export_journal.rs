@@ -0,0 +1,791 @@
+//! Export the whole book as a plain-text accounting journal, either in
+//! Beancount or in hledger (and ledger-cli) syntax. This is used to
+//! cross-check our numbers with those tools, and as an archive.
+//!
+//! Only actual transactions are exported (not those of scenarios).
+//! Account names are made of the names of their parents, below one of the
+//! standard roots (Assets, Liabilities, Income, Expenses), with the
+//! characters that are not valid for the tools replaced.
+//! Scheduled transactions are exported as periodic entries for hledger,
+//! starting at their next occurrence. Beancount has no such entries, so
+//! they are written as comments.
+
+use super::accounts::AccountKindCategory;
+use super::decimals::format_scaled as amount;
+use super::errors::AlereError;
+use super::models::{AccountId, CommodityId};
+use super::query_builder::Sql;
+use super::scenarios::NO_SCENARIO;
+use chrono::{Duration, NaiveDate, Utc};
+use diesel::sql_types::{BigInt, Bool, Date, Integer, Nullable, Text};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+pub enum JournalFormat {
+    BEANCOUNT,
+    HLEDGER,
+}
+
+/// Date used for directives that have no natural date
+const EPOCH: &str = "1970-01-01";
+
+#[derive(QueryableByName)]
+struct CommodityRow {
+    #[sql_type = "Integer"]
+    id: CommodityId,
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Nullable<Text>"]
+    iso_code: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    quote_symbol: Option<String>,
+    #[sql_type = "Integer"]
+    price_scale: i32,
+}
+
+#[derive(QueryableByName)]
+struct AccountRow {
+    #[sql_type = "Integer"]
+    id: AccountId,
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Nullable<Text>"]
+    description: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    iban: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    number: Option<String>,
+    #[sql_type = "Bool"]
+    closed: bool,
+    #[sql_type = "Integer"]
+    commodity_id: CommodityId,
+    #[sql_type = "Nullable<Integer>"]
+    parent_id: Option<AccountId>,
+    #[sql_type = "Integer"]
+    category: i32,
+    #[sql_type = "Nullable<Date>"]
+    opening_date: Option<NaiveDate>,
+    #[sql_type = "Nullable<Date>"]
+    first_split: Option<NaiveDate>,
+    #[sql_type = "Nullable<Date>"]
+    last_split: Option<NaiveDate>,
+}
+
+#[derive(QueryableByName)]
+struct PriceRow {
+    #[sql_type = "Date"]
+    date: NaiveDate,
+    #[sql_type = "BigInt"]
+    scaled_price: i64,
+    #[sql_type = "Integer"]
+    origin_id: CommodityId,
+    #[sql_type = "Integer"]
+    target_id: CommodityId,
+    #[sql_type = "Integer"]
+    price_scale: i32,  // of the origin
+}
+
+#[derive(QueryableByName)]
+struct TransactionRow {
+    #[sql_type = "Integer"]
+    id: i32,
+    #[sql_type = "Date"]
+    timestamp: NaiveDate,
+    #[sql_type = "Nullable<Text>"]
+    memo: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    check_number: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    scheduled: Option<String>,
+    #[sql_type = "Nullable<Date>"]
+    next_occurrence: Option<NaiveDate>,  // for scheduled transactions
+}
+
+#[derive(QueryableByName)]
+struct SplitRow {
+    #[sql_type = "Integer"]
+    transaction_id: i32,
+    #[sql_type = "Integer"]
+    account_id: AccountId,
+    #[sql_type = "BigInt"]
+    scaled_qty: i64,
+    #[sql_type = "Integer"]
+    commodity_scu: i32,
+    #[sql_type = "BigInt"]
+    scaled_value: i64,
+    #[sql_type = "Integer"]
+    value_commodity_id: CommodityId,
+    #[sql_type = "Integer"]
+    value_scale: i32,
+    #[sql_type = "Text"]
+    reconcile: String,
+    #[sql_type = "Date"]
+    post_date: NaiveDate,
+    #[sql_type = "Nullable<Text>"]
+    payee: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct JournalSummary {
+    commodities: usize,
+    accounts: usize,
+    prices: usize,
+    transactions: usize,
+    scheduled: usize,
+}
+
+/// Everything needed for the export, with names already converted to what
+/// the tools accept.
+
+struct Book {
+    commodities: Vec<(CommodityRow, String)>,
+    accounts: Vec<(AccountRow, String)>,
+    prices: Vec<PriceRow>,
+    transactions: Vec<(TransactionRow, Vec<SplitRow>)>,
+    commodity_names: HashMap<CommodityId, String>,
+    account_names: HashMap<AccountId, String>,
+    account_commodities: HashMap<AccountId, CommodityId>,
+}
+
+/// Number of decimal digits for a scale (100 => 2)
+fn precision(scale: i32) -> usize {
+    amount(0, scale).split('.').nth(1).map(|d| d.len()).unwrap_or(0)
+}
+
+/// Both tools require account components to start with an upper-case
+/// letter or a digit, and do not accept most punctuation.
+
+fn account_component(name: &str) -> String {
+    let mut result = String::new();
+    for c in name.chars() {
+        match c.is_alphanumeric() {
+            true => result.push(c),
+            false if !result.is_empty() && !result.ends_with('-') => result.push('-'),
+            false => {}
+        }
+    }
+    let result = result.trim_end_matches('-');
+    let mut chars = result.chars();
+    match chars.next() {
+        None => "Unnamed".to_string(),
+        Some(first) => {
+            let upper: String = first.to_uppercase().collect();
+            match upper.chars().all(|c| c.is_uppercase() || c.is_ascii_digit()) {
+                true => format!("{}{}", upper, chars.as_str()),
+                false => format!("X{}", result),
+            }
+        }
+    }
+}
+
+/// Beancount commodities are upper-case, start with a letter and are at
+/// most 24 characters long.
+
+fn commodity_symbol(c: &CommodityRow) -> String {
+    let source = c.iso_code.as_deref()
+        .or(c.quote_symbol.as_deref())
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or(&c.name);
+    let mut result: String = source
+        .trim()
+        .to_uppercase()
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | '0'..='9' | '.' | '_' | '\'' => c,
+            _ => '-',
+        })
+        .take(24)
+        .collect();
+    result = result.trim_end_matches(|c: char| !c.is_ascii_alphanumeric()).to_string();
+    if !result.starts_with(|c: char| c.is_ascii_uppercase()) {
+        result = format!("C{}", result);
+        result.truncate(24);
+    }
+    result
+}
+
+/// Make a name unique, by adding the id if needed
+fn unique(name: String, id: i32, used: &mut HashSet<String>) -> String {
+    let name = match used.contains(&name) {
+        true => format!("{}-{}", name, id),
+        false => name,
+    };
+    used.insert(name.clone());
+    name
+}
+
+fn root(category: i32) -> &'static str {
+    match category {
+        c if c == AccountKindCategory::EXPENSE as i32 => "Expenses",
+        c if c == AccountKindCategory::INCOME as i32 => "Income",
+        c if c == AccountKindCategory::LIABILITY as i32 => "Liabilities",
+        _ => "Assets",
+    }
+}
+
+fn load_book() -> Result<Book, AlereError> {
+    use super::connections::execute_and_log;
+
+    let commodities: Vec<CommodityRow> = execute_and_log(
+        "export_commodities",
+        "SELECT id, name, iso_code, quote_symbol, price_scale
+         FROM alr_commodities ORDER BY id",
+    )?;
+    let accounts: Vec<AccountRow> = execute_and_log(
+        "export_accounts",
+        Sql::new(
+            "SELECT a.id, a.name, a.description, a.iban, a.number, a.closed,
+                a.commodity_id, a.parent_id, k.category, a.opening_date,
+                date(min(s.post_date)) AS first_split,
+                date(max(s.post_date)) AS last_split
+             FROM alr_accounts a
+                JOIN alr_account_kinds k ON (a.kind_id = k.id)
+                LEFT JOIN (alr_splits s JOIN alr_transactions t
+                      ON (s.transaction_id = t.id
+                          AND t.scheduled IS NULL
+                          AND t.scenario_id = :no_scenario))
+                   ON (s.account_id = a.id)
+             GROUP BY a.id
+             ORDER BY a.id",
+        )
+        .bind("no_scenario", NO_SCENARIO),
+    )?;
+    let prices: Vec<PriceRow> = execute_and_log(
+        "export_prices",
+        "SELECT date(p.date) AS date, p.scaled_price, p.origin_id, p.target_id, c.price_scale
+         FROM alr_prices p JOIN alr_commodities c ON (p.origin_id = c.id)
+         ORDER BY p.date, p.id",
+    )?;
+    let transactions: Vec<TransactionRow> = execute_and_log(
+        "export_transactions",
+        Sql::new(
+            "SELECT t.id, date(t.timestamp) AS timestamp, t.memo, t.check_number, t.scheduled,
+                CASE WHEN t.scheduled IS NULL THEN NULL
+                   ELSE date(alr_next_event(
+                      t.scheduled, t.timestamp, t.last_occurrence))
+                END AS next_occurrence
+             FROM alr_transactions t
+             WHERE t.scenario_id = :no_scenario
+             ORDER BY t.timestamp, t.id",
+        )
+        .bind("no_scenario", NO_SCENARIO),
+    )?;
+    let splits: Vec<SplitRow> = execute_and_log(
+        "export_splits",
+        Sql::new(
+            "SELECT s.transaction_id, s.account_id, s.scaled_qty,
+                a.commodity_scu, s.scaled_value, s.value_commodity_id,
+                c.price_scale AS value_scale, s.reconcile, date(s.post_date) AS post_date,
+                p.name AS payee
+             FROM alr_splits s
+                JOIN alr_transactions t ON (s.transaction_id = t.id)
+                JOIN alr_accounts a ON (s.account_id = a.id)
+                JOIN alr_commodities c ON (s.value_commodity_id = c.id)
+                LEFT JOIN alr_payees p ON (s.payee_id = p.id)
+             WHERE t.scenario_id = :no_scenario
+             ORDER BY s.transaction_id, s.id",
+        )
+        .bind("no_scenario", NO_SCENARIO),
+    )?;
+
+    let mut used = HashSet::new();
+    let commodities: Vec<(CommodityRow, String)> = commodities
+        .into_iter()
+        .map(|c| {
+            let symbol = unique(commodity_symbol(&c), c.id, &mut used);
+            (c, symbol)
+        })
+        .collect();
+    let commodity_names = commodities.iter().map(|(c, s)| (c.id, s.clone())).collect();
+
+    // Full names of the accounts, from their parents
+    let by_id: HashMap<AccountId, &AccountRow> = accounts.iter().map(|a| (a.id, a)).collect();
+    let mut used = HashSet::new();
+    let mut account_names = HashMap::new();
+    for a in &accounts {
+        let mut path = vec![account_component(&a.name)];
+        let mut parent = a.parent_id;
+        while let Some(p) = parent.and_then(|p| by_id.get(&p)) {
+            if path.len() > by_id.len() {
+                break;  // cycle in the parents, reported by check_database
+            }
+            path.push(account_component(&p.name));
+            parent = p.parent_id;
+        }
+        path.push(root(a.category).to_string());
+        path.reverse();
+        account_names.insert(a.id, unique(path.join(":"), a.id, &mut used));
+    }
+    let account_commodities = accounts.iter().map(|a| (a.id, a.commodity_id)).collect();
+    let accounts = accounts
+        .into_iter()
+        .map(|a| {
+            let name = account_names[&a.id].clone();
+            (a, name)
+        })
+        .collect();
+
+    let mut grouped: HashMap<i32, Vec<SplitRow>> = HashMap::new();
+    for s in splits {
+        grouped.entry(s.transaction_id).or_default().push(s);
+    }
+    let transactions = transactions
+        .into_iter()
+        .map(|t| {
+            let splits = grouped.remove(&t.id).unwrap_or_default();
+            (t, splits)
+        })
+        .collect();
+
+    Ok(Book {
+        commodities,
+        accounts,
+        prices,
+        transactions,
+        commodity_names,
+        account_names,
+        account_commodities,
+    })
+}
+
+/// Convert a recurrence rule to an hledger period expression. Also returns
+/// whether the conversion is exact.
+/// :param from: the next occurrence
+
+fn period_expression(rule: &str, from: NaiveDate) -> (String, bool) {
+    let rule = rule
+        .lines()
+        .map(|l| l.trim())
+        .find(|l| !l.starts_with("DTSTART"))
+        .unwrap_or_default();
+    let rule = rule.strip_prefix("RRULE:").unwrap_or(rule);
+    if rule.is_empty() {
+        // Occurs only once
+        return (format!("every day from {} to {}", from, from + Duration::days(1)), true);
+    }
+
+    let mut exact = true;
+    let mut unit = "day";
+    let mut interval = 1;
+    let mut until = None;
+    for part in rule.split(';').filter(|p| !p.is_empty()) {
+        match part.split_once('=').unwrap_or((part, "")) {
+            ("FREQ", "DAILY") => unit = "day",
+            ("FREQ", "WEEKLY") => unit = "week",
+            ("FREQ", "MONTHLY") => unit = "month",
+            ("FREQ", "YEARLY") => unit = "year",
+            ("INTERVAL", n) => match n.parse::<u32>() {
+                Ok(n) => interval = n,
+                Err(_) => exact = false,
+            },
+            ("UNTIL", d) => match NaiveDate::parse_from_str(d.get(..8).unwrap_or(d), "%Y%m%d") {
+                // hledger's end date is exclusive
+                Ok(d) => until = Some(d + Duration::days(1)),
+                Err(_) => exact = false,
+            },
+            ("WKST", _) => {}
+            _ => exact = false,
+        }
+    }
+    let mut result = match interval {
+        1 => format!("every {} from {}", unit, from),
+        n => format!("every {} {}s from {}", n, unit, from),
+    };
+    if let Some(u) = until {
+        result.push_str(&format!(" to {}", u));
+    }
+    (result, exact)
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', " "))
+}
+
+fn one_line(s: &str) -> String {
+    s.replace(['\n', '\r'], " ").trim().to_string()
+}
+
+/// hledger requires quotes around commodities that are not only letters
+fn hledger_commodity(symbol: &str) -> String {
+    match symbol.chars().all(|c| c.is_alphabetic()) {
+        true => symbol.to_string(),
+        false => format!("\"{}\"", symbol),
+    }
+}
+
+/// The status mark for a split: reconciled splits are cleared, and cleared
+/// splits are pending.
+
+fn mark(reconcile: &str) -> &'static str {
+    match reconcile {
+        "R" => "* ",
+        "C" => "! ",
+        _ => "",
+    }
+}
+
+impl Book {
+    fn commodity(&self, id: CommodityId, format: JournalFormat) -> String {
+        let symbol = self.commodity_names.get(&id).cloned().unwrap_or_default();
+        match format {
+            JournalFormat::BEANCOUNT => symbol,
+            JournalFormat::HLEDGER => hledger_commodity(&symbol),
+        }
+    }
+
+    /// The quantity of a split, with its price when its commodity is not the
+    /// one of the transaction
+    fn posting_amount(
+        &self,
+        s: &SplitRow,
+        account_commodity: CommodityId,
+        format: JournalFormat,
+    ) -> String {
+        let qty = format!(
+            "{} {}",
+            amount(s.scaled_qty, s.commodity_scu),
+            self.commodity(account_commodity, format));
+        match account_commodity == s.value_commodity_id {
+            true => qty,
+            false => format!(
+                "{} @@ {} {}",
+                qty,
+                amount(s.scaled_value.abs(), s.value_scale),
+                self.commodity(s.value_commodity_id, format)),
+        }
+    }
+
+    /// Write the header line and postings of a transaction
+    fn transaction(
+        &self,
+        t: &TransactionRow,
+        splits: &[SplitRow],
+        date: NaiveDate,
+        header: &str,
+        format: JournalFormat,
+    ) -> String {
+        let payee = splits.iter().find_map(|s| s.payee.as_deref());
+        let memo = t.memo.as_deref().filter(|m| !m.trim().is_empty());
+        let check = t.check_number.as_deref().filter(|c| !c.trim().is_empty());
+        let mut out = String::new();
+
+        match format {
+            JournalFormat::BEANCOUNT => {
+                out.push_str(header);
+                match payee {
+                    Some(p) => out.push_str(&format!(
+                        " {} {}", quote(p), quote(memo.unwrap_or_default()))),
+                    None => out.push_str(&format!(" {}", quote(memo.unwrap_or_default()))),
+                }
+                out.push('\n');
+                if let Some(c) = check {
+                    out.push_str(&format!("  check: {}\n", quote(c)));
+                }
+            }
+            JournalFormat::HLEDGER => {
+                out.push_str(header);
+                if let Some(c) = check {
+                    out.push_str(&format!(" ({})", one_line(c).replace(')', "")));
+                }
+                let description = match (payee, memo) {
+                    (Some(p), Some(m)) => format!("{} | {}", one_line(p), one_line(m)),
+                    (Some(p), None) => one_line(p),
+                    (None, Some(m)) => one_line(m),
+                    (None, None) => String::new(),
+                };
+                if !description.is_empty() {
+                    // periodic entries need two spaces before the description
+                    out.push_str(if header.starts_with('~') { "  " } else { " " });
+                    out.push_str(&description);
+                }
+                out.push('\n');
+            }
+        }
+
+        for s in splits {
+            let account_commodity = self.account_commodities
+                .get(&s.account_id)
+                .copied()
+                .unwrap_or(s.value_commodity_id);
+            let account = self.account_names.get(&s.account_id).cloned().unwrap_or_default();
+            let posting = format!(
+                "{}{}  {}",
+                mark(&s.reconcile),
+                account,
+                self.posting_amount(s, account_commodity, format));
+            let post_date = s.post_date;
+            let other_date = t.scheduled.is_none() && post_date != date;
+            match format {
+                JournalFormat::BEANCOUNT => {
+                    out.push_str(&format!("  {}\n", posting));
+                    if other_date {
+                        out.push_str(&format!("    post-date: {}\n", post_date));
+                    }
+                }
+                JournalFormat::HLEDGER => {
+                    out.push_str(&format!("    {}", posting));
+                    if other_date {
+                        out.push_str(&format!("  ; date:{}", post_date));
+                    }
+                    out.push('\n');
+                }
+            }
+        }
+        out
+    }
+
+    fn write(&self, out: &mut impl Write, format: JournalFormat) -> Result<JournalSummary, AlereError> {
+        let mut summary = JournalSummary::default();
+        let today = Utc::today().naive_utc();
+        writeln!(out, "; Exported from alere on {}\n", today)?;
+        if format == JournalFormat::BEANCOUNT {
+            writeln!(out, "option \"title\" \"alere\"\n")?;
+        }
+
+        for (c, symbol) in &self.commodities {
+            match format {
+                JournalFormat::BEANCOUNT => {
+                    writeln!(out, "{} commodity {}", EPOCH, symbol)?;
+                    writeln!(out, "  name: {}", quote(&c.name))?;
+                    writeln!(out, "  price-scale: {}", c.price_scale)?;
+                    if let Some(q) = &c.quote_symbol {
+                        writeln!(out, "  quote-symbol: {}", quote(q))?;
+                    }
+                }
+                JournalFormat::HLEDGER => {
+                    // The format sets the precision
+                    let sample = format!("{:.*}", precision(c.price_scale), 1000.0);
+                    writeln!(out, "commodity {} {}  ; {}",
+                        sample, hledger_commodity(symbol), one_line(&c.name))?;
+                }
+            }
+            summary.commodities += 1;
+        }
+        writeln!(out)?;
+
+        for (a, name) in &self.accounts {
+            match format {
+                JournalFormat::BEANCOUNT => {
+                    let open = [a.opening_date, a.first_split]
+                        .iter()
+                        .flatten()
+                        .min()
+                        .map(|d| d.to_string())
+                        .unwrap_or_else(|| EPOCH.to_string());
+                    writeln!(out, "{} open {} {}",
+                        open, name, self.commodity(a.commodity_id, format))?;
+                    writeln!(out, "  name: {}", quote(&a.name))?;
+                    for (key, value) in [
+                        ("description", &a.description),
+                        ("iban", &a.iban),
+                        ("number", &a.number),
+                    ] {
+                        if let Some(v) = value.as_deref().filter(|v| !v.is_empty()) {
+                            writeln!(out, "  {}: {}", key, quote(v))?;
+                        }
+                    }
+                }
+                JournalFormat::HLEDGER => {
+                    let kind = match root(a.category) {
+                        "Expenses" => "X",
+                        "Income" => "R",
+                        "Liabilities" => "L",
+                        _ => "A",
+                    };
+                    let closed = match a.closed {
+                        true => ", closed:",
+                        false => "",
+                    };
+                    writeln!(out, "account {}  ; type: {}{}", name, kind, closed)?;
+                    if let Some(d) = a.description.as_deref().filter(|d| !d.is_empty()) {
+                        writeln!(out, "  ; {}", one_line(d))?;
+                    }
+                }
+            }
+            summary.accounts += 1;
+        }
+        writeln!(out)?;
+
+        for p in &self.prices {
+            let price = format!(
+                "{} {} {}",
+                self.commodity(p.origin_id, format),
+                amount(p.scaled_price, p.price_scale),
+                self.commodity(p.target_id, format));
+            match format {
+                JournalFormat::BEANCOUNT => writeln!(out, "{} price {}", p.date, price)?,
+                JournalFormat::HLEDGER => writeln!(out, "P {} {}", p.date, price)?,
+            }
+            summary.prices += 1;
+        }
+        writeln!(out)?;
+
+        for (t, splits) in &self.transactions {
+            match (&t.scheduled, t.next_occurrence) {
+                (None, _) => {
+                    // Use the earliest date of the splits, others are
+                    // given on the postings
+                    let date = splits
+                        .iter()
+                        .map(|s| s.post_date)
+                        .min()
+                        .unwrap_or(t.timestamp);
+                    let header = match format {
+                        JournalFormat::BEANCOUNT => format!("{} *", date),
+                        JournalFormat::HLEDGER => date.to_string(),
+                    };
+                    writeln!(
+                        out,
+                        "{}",
+                        self.transaction(t, splits, date, &header, format)
+                    )?;
+                    summary.transactions += 1;
+                }
+                (Some(_), None) => {} // no more occurrences
+                (Some(rule), Some(next)) => {
+                    let (period, exact) = period_expression(rule, next);
+                    match format {
+                        JournalFormat::BEANCOUNT => {
+                            match rule.is_empty() {
+                                true => writeln!(out, "; Scheduled {}", period)?,
+                                false => {
+                                    writeln!(out, "; Scheduled {}: {}", period, one_line(rule))?
+                                }
+                            }
+                            let text =
+                                self.transaction(t, splits, next, &format!("{} *", next), format);
+                            for line in text.lines() {
+                                writeln!(out, "; {}", line)?;
+                            }
+                            writeln!(out)?;
+                        }
+                        JournalFormat::HLEDGER => {
+                            if !exact {
+                                writeln!(out, "; approximation of {}", one_line(rule))?;
+                            }
+                            writeln!(
+                                out,
+                                "{}",
+                                self.transaction(t, splits, next, &format!("~ {}", period), format)
+                            )?;
+                        }
+                    }
+                    summary.scheduled += 1;
+                }
+            }
+        }
+
+        if format == JournalFormat::BEANCOUNT {
+            for (a, name) in self.accounts.iter().filter(|(a, _)| a.closed) {
+                let close = [a.opening_date, a.last_split]
+                    .iter()
+                    .flatten()
+                    .max()
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(|| EPOCH.to_string());
+                writeln!(out, "{} close {}", close, name)?;
+            }
+        }
+        out.flush()?;
+        Ok(summary)
+    }
+}
+
+/// Write the whole book in the given format
+
+pub fn write_journal(
+    out: &mut impl Write,
+    format: JournalFormat,
+) -> Result<JournalSummary, AlereError> {
+    load_book()?.write(out, format)
+}
+
+/// Export the whole book to a file, in Beancount or hledger syntax
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn export_journal(
+    format: JournalFormat,
+    path: String,
+) -> Result<JournalSummary, AlereError> {
+    info!("export_journal {}", path);
+    let file = std::fs::File::create(&path)?;
+    write_journal(&mut std::io::BufWriter::new(file), format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::test_database;
+    use crate::importer::import_test_file;
+
+    const BOOK: &str = r#"
+1970-01-01 commodity EUR
+  name: "Euro"
+  price-scale: 100
+1970-01-01 commodity AAPL
+  name: "Apple Inc"
+  price-scale: 10000
+  quote-symbol: "AAPL"
+
+2026-03-01 open Assets:Checking EUR
+  name: "Checking"
+2026-03-01 open Assets:Savings EUR
+2026-03-05 open Expenses:Food EUR
+2026-03-01 open Income:Salary EUR
+2026-05-02 open Assets:Checking:Apple-shares AAPL
+
+2026-05-01 price AAPL 150.2500 EUR
+
+2026-03-01 * "salary"
+  Assets:Checking  3000.00 EUR
+  Income:Salary  -3000.00 EUR
+
+2026-03-05 * "Grocer" "groceries"
+  Assets:Checking  -45.50 EUR
+  Expenses:Food  45.50 EUR
+
+2026-04-01 * "transfer"
+  Assets:Checking  -10.00 EUR
+  Assets:Savings  10.00 EUR
+
+2026-04-02 * "transfer back"
+  Assets:Savings  -10.00 EUR
+  Assets:Checking  10.00 EUR
+
+2026-05-02 * "Broker \"X\"" "Buy"
+  check: "42"
+  * Assets:Checking:Apple-shares  2.000 AAPL @@ 300.50 EUR
+  ! Assets:Checking  -300.50 EUR
+    post-date: 2026-05-03
+
+2026-06-01 close Assets:Savings
+"#;
+
+    /// Import a Beancount file in a new database, and export it again
+    fn import_export(name: &str, book: &str) -> String {
+        let _db = test_database(name);
+        let summary = import_test_file("book.beancount", book, None);
+        assert_eq!(summary.warnings, Vec::<String>::new());
+
+        let mut out = vec![];
+        write_journal(&mut out, JournalFormat::BEANCOUNT).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .filter(|l| !l.starts_with("; Exported"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn beancount_roundtrip() {
+        let first = import_export("beancount1", BOOK);
+        let second = import_export("beancount2", &first);
+        assert_eq!(first, second);
+        assert!(first.contains("2.000 AAPL @@ 300.50 EUR"), "{}", first);
+        assert!(first.contains("close Assets:Savings"), "{}", first);
+    }
+}
@@ -0,0 +1,842 @@
+//! Export the main reports as spreadsheets, in CSV, OpenDocument (.ods) or
+//! Office Open XML (.xlsx) format, depending on the extension of the file.
+//!
+//! Cells are typed, so that spreadsheets see dates, numbers, percentages
+//! and amounts in their currency, rather than text. Accounts are shown
+//! with their full name ("Assets:Bank:Checking"), and amounts with the
+//! symbol of their commodity. CSV has no types: numbers are written without
+//! symbol, dates as YYYY-MM-DD, and the currency (or percent sign) of a
+//! column is given in its header.
+
+use super::accounts::commodity_kinds;
+use super::cashflow::monthly_cashflow;
+use super::cte_accounts::{cte_account_names, CTE_ACCOUNT_NAMES};
+use super::dates::{DateRange, GroupBy};
+use super::errors::AlereError;
+use super::income_expense::income_expense;
+use super::ledger::ledger;
+use super::metrics::networth_history;
+use super::models::{AccountId, CommodityId, Commodity};
+use super::occurrences::Occurrences;
+use super::query_builder::{Query, Sql};
+use super::quotes::quotes;
+use super::scenarios::{Scenario, NO_SCENARIO};
+use super::xml::escape;
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Integer, Text};
+use log::info;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Seek, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum Report {
+    LEDGER,           // for a single account
+    INCOME_EXPENSE,   // total per account
+    CASHFLOW,         // per month
+    NETWORTH,         // per month
+    QUOTES,           // positions at the end of the period
+}
+
+enum Cell {
+    Empty,
+    Text(String),
+    Number(Decimal),
+    Amount(Decimal, CommodityId),
+    Date(NaiveDate),
+    Percent(f32),     // as a ratio, 0.05 for 5%
+}
+
+impl Cell {
+    fn amount(value: Option<Decimal>, commodity: CommodityId) -> Self {
+        match value {
+            Some(v) => Cell::Amount(v, commodity),
+            None => Cell::Empty,
+        }
+    }
+
+    /// A return on investment, where 1.0 means no gain. Those are NaN
+    /// when nothing was invested.
+    fn roi(ratio: f32) -> Self {
+        match ratio.is_finite() {
+            true => Cell::Percent(ratio - 1.0),
+            false => Cell::Empty,
+        }
+    }
+}
+
+struct Table {
+    name: &'static str,
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<Cell>>,
+}
+
+#[derive(QueryableByName)]
+struct AccountRow {
+    #[sql_type = "Integer"]
+    account_id: AccountId,
+    #[sql_type = "Text"]
+    full_name: String,
+    #[sql_type = "Integer"]
+    commodity_id: CommodityId,
+}
+
+/// What is needed to display accounts and amounts
+
+struct Names {
+    accounts: HashMap<AccountId, AccountRow>,
+    commodities: HashMap<CommodityId, Commodity>,
+}
+
+impl Names {
+    fn load() -> Result<Self, AlereError> {
+        let names = cte_account_names();
+        let rows: Vec<AccountRow> = super::connections::execute_and_log(
+            "export_report_accounts",
+            Query::new(Sql::new(format!(
+                "SELECT n.account_id, n.full_name, a.commodity_id
+                 FROM {CTE_ACCOUNT_NAMES} n JOIN alr_accounts a ON (a.id = n.account_id)"
+            )))
+            .with(&names),
+        )?;
+        let commodities: Vec<Commodity> = {
+            use super::schema::alr_commodities::dsl::*;
+            let c = &super::connections::get_connection()?;
+            alr_commodities.load::<Commodity>(c)?
+        };
+        Ok(Names {
+            accounts: rows.into_iter().map(|r| (r.account_id, r)).collect(),
+            commodities: commodities.into_iter().map(|c| (c.id, c)).collect(),
+        })
+    }
+
+    fn account(&self, id: AccountId) -> String {
+        self.accounts
+            .get(&id)
+            .map(|a| a.full_name.clone())
+            .unwrap_or_else(|| format!("#{}", id))
+    }
+
+    /// The number of decimal places used to show the commodity
+    fn decimals(&self, id: CommodityId) -> u32 {
+        let mut scale = self.commodities.get(&id).map(|c| c.price_scale).unwrap_or(100);
+        let mut places = 0;
+        while scale >= 10 {
+            scale /= 10;
+            places += 1;
+        }
+        places
+    }
+
+    /// The symbol to show before and after amounts. Commodities without
+    /// symbols (stocks for instance) are shown with their code or name.
+    fn symbols(&self, id: CommodityId) -> (String, String) {
+        match self.commodities.get(&id) {
+            None => (String::new(), String::new()),
+            Some(c) if c.symbol_before.is_empty() && c.symbol_after.is_empty() => (
+                String::new(),
+                format!(" {}", c.iso_code.as_deref().unwrap_or(&c.name)),
+            ),
+            Some(c) => (c.symbol_before.clone(), c.symbol_after.clone()),
+        }
+    }
+
+    /// The ISO code, as required by OpenDocument for currency cells
+    fn code(&self, id: CommodityId) -> String {
+        self.commodities
+            .get(&id)
+            .map(|c| c.iso_code.clone().unwrap_or_else(|| c.name.clone()))
+            .unwrap_or_default()
+    }
+
+    fn value(&self, value: Decimal, commodity: CommodityId) -> String {
+        format!("{:.*}", self.decimals(commodity) as usize, value)
+    }
+}
+
+/// The transactions of one account, with the amount of its own splits and
+/// the names of the other accounts as category
+
+async fn ledger_table(
+    names: &Names,
+    mindate: DateTime<Utc>,
+    maxdate: DateTime<Utc>,
+    account: AccountId,
+    scenario: Option<Scenario>,
+) -> Result<Table, AlereError> {
+    let commodity = names.accounts.get(&account)
+        .ok_or_else(|| AlereError::not_found(format!("No account {}", account)))?
+        .commodity_id;
+    let is_currency = names.commodities.get(&commodity)
+        .map(|c| c.kind == commodity_kinds::CURRENCY)
+        .unwrap_or(true);
+    let mut columns = vec![
+        "Date", "Check", "Payee", "Memo", "Category", "Reconciled", "Amount", "Balance",
+    ];
+    if !is_currency {
+        columns.extend(["Shares", "Total shares"]);
+    }
+
+    let transactions = ledger(mindate, maxdate, vec![account], 0, scenario, None).await?;
+    let rows = transactions
+        .iter()
+        .map(|t| {
+            let (own, other): (Vec<_>, Vec<_>) =
+                t.splits.iter().partition(|s| s.account_id == account);
+            let currency = own.first().map(|s| s.currency).unwrap_or(commodity);
+            let payee = own.iter().chain(&other)
+                .map(|s| s.payee.as_str())
+                .find(|p| !p.is_empty())
+                .unwrap_or_default();
+            let mut category: Vec<String> = vec![];
+            for s in &other {
+                let n = names.account(s.account_id);
+                if !category.contains(&n) {
+                    category.push(n);
+                }
+            }
+            let mut row = vec![
+                Cell::Date(t.date.naive_utc().date()),
+                Cell::Text(t.check_number.clone()),
+                Cell::Text(payee.to_string()),
+                Cell::Text(t.memo.clone()),
+                Cell::Text(category.join(", ")),
+                Cell::Text(own.first().map(|s| s.reconcile.to_string()).unwrap_or_default()),
+                Cell::Amount(own.iter().map(|s| s.amount).sum(), currency),
+                Cell::amount(t.balance, currency),
+            ];
+            if !is_currency {
+                row.push(Cell::Number(own.iter().map(|s| s.shares).sum()));
+                row.push(Cell::Number(t.balance_shares));
+            }
+            row
+        })
+        .collect();
+    Ok(Table { name: "Ledger", columns, rows })
+}
+
+async fn income_expense_table(
+    names: &Names,
+    mindate: DateTime<Utc>,
+    maxdate: DateTime<Utc>,
+    currency: CommodityId,
+    scenario: Option<Scenario>,
+) -> Result<Table, AlereError> {
+    let result =
+        income_expense(true, true, mindate, maxdate, currency, scenario, None).await?;
+    let mut items: Vec<(String, Decimal)> = result.items
+        .iter()
+        .map(|i| (names.account(i.accountid), i.value))
+        .collect();
+    items.sort();
+    Ok(Table {
+        name: "Income and expenses",
+        columns: vec!["Account", "Amount"],
+        rows: items
+            .into_iter()
+            .map(|(name, value)| vec![Cell::Text(name), Cell::Amount(value, currency)])
+            .collect(),
+    })
+}
+
+fn cashflow_table(
+    mindate: DateTime<Utc>,
+    maxdate: DateTime<Utc>,
+    currency: CommodityId,
+    scenario: Option<Scenario>,
+) -> Result<Table, AlereError> {
+    let months = monthly_cashflow(
+        &DateRange::new(Some(mindate.date()), Some(maxdate.date()), GroupBy::MONTHS),
+        currency,
+        scenario.unwrap_or(NO_SCENARIO),
+        &Occurrences::no_recurrence(),
+        0,
+        0,
+        &None,
+    )?;
+    Ok(Table {
+        name: "Cashflow",
+        columns: vec![
+            "Month",
+            "Realized income",
+            "Average income",
+            "Unrealized income",
+            "Average unrealized",
+            "Expenses",
+            "Average expenses",
+        ],
+        rows: months
+            .iter()
+            .map(|m| vec![
+                Cell::Date(m.month),
+                Cell::amount(m.realized_inc_total, currency),
+                Cell::amount(m.inc_average, currency),
+                Cell::amount(m.unrealized_inc_total, currency),
+                Cell::amount(m.unrealized_average, currency),
+                Cell::amount(m.exp_total, currency),
+                Cell::amount(m.exp_average, currency),
+            ])
+            .collect(),
+    })
+}
+
+async fn networth_table(
+    mindate: DateTime<Utc>,
+    maxdate: DateTime<Utc>,
+    currency: CommodityId,
+    scenario: Option<Scenario>,
+) -> Result<Table, AlereError> {
+    let points = networth_history(mindate, maxdate, currency, scenario).await?;
+    Ok(Table {
+        name: "Networth",
+        columns: vec!["Date", "Networth", "Change", "Average change"],
+        rows: points
+            .iter()
+            .map(|p| vec![
+                Cell::Date(p.date),
+                Cell::Amount(p.value, currency),
+                Cell::Amount(p.diff, currency),
+                Cell::Amount(p.average, currency),
+            ])
+            .collect(),
+    })
+}
+
+/// One row per investment account, with its position at the end of the
+/// period. Cash held in investment accounts is not a position.
+
+async fn quotes_table(
+    names: &Names,
+    mindate: DateTime<Utc>,
+    maxdate: DateTime<Utc>,
+    currency: CommodityId,
+    accounts: Option<Vec<AccountId>>,
+) -> Result<Table, AlereError> {
+    let (symbols, per_account) = quotes(mindate, maxdate, currency, None, accounts).await?;
+    let mut rows: Vec<(String, Vec<Cell>)> = vec![];
+    for s in symbols.iter().filter(|s| !s.is_currency) {
+        for a in s.accounts.iter().filter_map(|id| per_account.get(id)) {
+            let name = names.account(a.account);
+            rows.push((name.clone(), vec![
+                Cell::Text(name),
+                Cell::Text(s.ticker.clone()),
+                Cell::Number(a.end.shares),
+                Cell::Amount(a.end.invested, currency),
+                Cell::Amount(a.end.equity, currency),
+                Cell::Amount(a.end.gains, currency),
+                Cell::Amount(a.end.pl, currency),
+                Cell::roi(a.end.roi),
+                Cell::roi(a.annualized_roi),
+                Cell::roi(a.period_roi),
+                Cell::amount(a.end.avg_cost, currency),
+            ]));
+        }
+    }
+    rows.sort_by(|r1, r2| r1.0.cmp(&r2.0));
+    Ok(Table {
+        name: "Quotes",
+        columns: vec![
+            "Account",
+            "Symbol",
+            "Shares",
+            "Invested",
+            "Equity",
+            "Gains",
+            "P&L",
+            "ROI",
+            "Annualized ROI",
+            "Period ROI",
+            "Average cost",
+        ],
+        rows: rows.into_iter().map(|(_, r)| r).collect(),
+    })
+}
+
+/// The commodities used in the table, in a stable order
+
+fn used_commodities(table: &Table) -> Vec<CommodityId> {
+    table.rows
+        .iter()
+        .flatten()
+        .filter_map(|c| match c {
+            Cell::Amount(_, id) => Some(*id),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+fn csv_field(s: &str) -> String {
+    match s.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.to_string(),
+    }
+}
+
+fn write_csv(out: &mut impl Write, table: &Table, names: &Names) -> Result<(), AlereError> {
+    let headers: Vec<String> = table.columns
+        .iter()
+        .enumerate()
+        .map(|(idx, col)| {
+            let cells = table.rows.iter().filter_map(|r| r.get(idx));
+            let mut commodities = BTreeSet::new();
+            let mut percent = false;
+            for c in cells {
+                match c {
+                    Cell::Amount(_, id) => { commodities.insert(*id); }
+                    Cell::Percent(_) => percent = true,
+                    _ => {}
+                }
+            }
+            match (commodities.len(), percent) {
+                (1, _) => {
+                    let (before, after) =
+                        names.symbols(*commodities.iter().next().unwrap());
+                    format!("{} ({})", col, format!("{}{}", before, after).trim())
+                }
+                (_, true) => format!("{} (%)", col),
+                _ => col.to_string(),
+            }
+        })
+        .collect();
+    writeln!(out, "{}", headers.iter().map(|h| csv_field(h)).collect::<Vec<_>>().join(","))?;
+
+    for row in &table.rows {
+        let line: Vec<String> = row
+            .iter()
+            .map(|c| match c {
+                Cell::Empty => String::new(),
+                Cell::Text(s) => csv_field(s),
+                Cell::Number(n) => n.normalize().to_string(),
+                Cell::Amount(v, id) => names.value(*v, *id),
+                Cell::Date(d) => d.format("%Y-%m-%d").to_string(),
+                Cell::Percent(p) => format!("{:.2}", p * 100.0),
+            })
+            .collect();
+        writeln!(out, "{}", line.join(","))?;
+    }
+    Ok(())
+}
+
+const ODS_MIMETYPE: &str = "application/vnd.oasis.opendocument.spreadsheet";
+
+fn ods_content(table: &Table, names: &Names) -> String {
+    let commodities = used_commodities(table);
+    let mut x = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <office:document-content \
+         xmlns:office=\"urn:oasis:names:tc:opendocument:xmlns:office:1.0\" \
+         xmlns:style=\"urn:oasis:names:tc:opendocument:xmlns:style:1.0\" \
+         xmlns:text=\"urn:oasis:names:tc:opendocument:xmlns:text:1.0\" \
+         xmlns:table=\"urn:oasis:names:tc:opendocument:xmlns:table:1.0\" \
+         xmlns:number=\"urn:oasis:names:tc:opendocument:xmlns:datastyle:1.0\" \
+         xmlns:fo=\"urn:oasis:names:tc:opendocument:xmlns:xsl-fo-compatible:1.0\" \
+         office:version=\"1.2\">\n\
+         <office:automatic-styles>\n\
+         <number:date-style style:name=\"Ndate\">\
+         <number:year number:style=\"long\"/><number:text>-</number:text>\
+         <number:month number:style=\"long\"/><number:text>-</number:text>\
+         <number:day number:style=\"long\"/></number:date-style>\n\
+         <number:percentage-style style:name=\"Npercent\">\
+         <number:number number:decimal-places=\"2\" number:min-integer-digits=\"1\"/>\
+         <number:text>%</number:text></number:percentage-style>\n",
+    );
+    for id in &commodities {
+        let (before, after) = names.symbols(*id);
+        let number = format!(
+            "<number:number number:decimal-places=\"{}\" \
+             number:min-integer-digits=\"1\" number:grouping=\"true\"/>",
+            names.decimals(*id),
+        );
+        let symbol = |s: &str| match s.trim() {
+            "" => String::new(),
+            t => format!("<number:currency-symbol>{}</number:currency-symbol>", escape(t)),
+        };
+        let space = |s: &str, text: &str| match s.starts_with(' ') || s.ends_with(' ') {
+            true => text.to_string(),
+            false => String::new(),
+        };
+        x.push_str(&format!(
+            "<number:currency-style style:name=\"N{id}\">{}{}{}{}{}</number:currency-style>\n",
+            symbol(&before),
+            space(&before, "<number:text> </number:text>"),
+            number,
+            space(&after, "<number:text> </number:text>"),
+            symbol(&after),
+        ));
+    }
+    x.push_str(
+        "<style:style style:name=\"Chead\" style:family=\"table-cell\">\
+         <style:text-properties fo:font-weight=\"bold\"/></style:style>\n\
+         <style:style style:name=\"Cdate\" style:family=\"table-cell\" \
+         style:data-style-name=\"Ndate\"/>\n\
+         <style:style style:name=\"Cpercent\" style:family=\"table-cell\" \
+         style:data-style-name=\"Npercent\"/>\n",
+    );
+    for id in &commodities {
+        x.push_str(&format!(
+            "<style:style style:name=\"C{id}\" style:family=\"table-cell\" \
+             style:data-style-name=\"N{id}\"/>\n"
+        ));
+    }
+    x.push_str(&format!(
+        "</office:automatic-styles>\n\
+         <office:body><office:spreadsheet>\n\
+         <table:table table:name=\"{}\">\n\
+         <table:table-column table:number-columns-repeated=\"{}\"/>\n<table:table-row>",
+        escape(table.name),
+        table.columns.len(),
+    ));
+    for col in &table.columns {
+        x.push_str(&format!(
+            "<table:table-cell table:style-name=\"Chead\" office:value-type=\"string\">\
+             <text:p>{}</text:p></table:table-cell>",
+            escape(col),
+        ));
+    }
+    x.push_str("</table:table-row>\n");
+
+    for row in &table.rows {
+        x.push_str("<table:table-row>");
+        for cell in row {
+            x.push_str(&match cell {
+                Cell::Empty => "<table:table-cell/>".to_string(),
+                Cell::Text(s) => format!(
+                    "<table:table-cell office:value-type=\"string\">\
+                     <text:p>{}</text:p></table:table-cell>",
+                    escape(s),
+                ),
+                Cell::Number(n) => format!(
+                    "<table:table-cell office:value-type=\"float\" office:value=\"{n}\">\
+                     <text:p>{n}</text:p></table:table-cell>",
+                    n = n.normalize(),
+                ),
+                Cell::Amount(v, id) => {
+                    let (before, after) = names.symbols(*id);
+                    let value = names.value(*v, *id);
+                    format!(
+                        "<table:table-cell table:style-name=\"C{id}\" \
+                         office:value-type=\"currency\" office:currency=\"{}\" \
+                         office:value=\"{value}\"><text:p>{}</text:p></table:table-cell>",
+                        escape(&names.code(*id)),
+                        escape(&format!("{}{}{}", before, value, after)),
+                    )
+                }
+                Cell::Date(d) => format!(
+                    "<table:table-cell table:style-name=\"Cdate\" \
+                     office:value-type=\"date\" office:date-value=\"{d}\">\
+                     <text:p>{d}</text:p></table:table-cell>",
+                    d = d.format("%Y-%m-%d"),
+                ),
+                Cell::Percent(p) => format!(
+                    "<table:table-cell table:style-name=\"Cpercent\" \
+                     office:value-type=\"percentage\" office:value=\"{}\">\
+                     <text:p>{:.2}%</text:p></table:table-cell>",
+                    p,
+                    p * 100.0,
+                ),
+            });
+        }
+        x.push_str("</table:table-row>\n");
+    }
+    x.push_str("</table:table>\n</office:spreadsheet></office:body>\n</office:document-content>\n");
+    x
+}
+
+/// Add a file to a zip archive, compressed except for the mimetype of
+/// OpenDocument files
+
+fn add_file<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    name: &str,
+    data: &[u8],
+) -> Result<(), AlereError> {
+    let method = match name {
+        "mimetype" => CompressionMethod::Stored,
+        _ => CompressionMethod::Deflated,
+    };
+    zip.start_file(name, FileOptions::default().compression_method(method))?;
+    zip.write_all(data)?;
+    Ok(())
+}
+
+fn write_ods(out: impl Write + Seek, table: &Table, names: &Names) -> Result<(), AlereError> {
+    let mut zip = ZipWriter::new(out);
+    // Must be the first entry, so that the type can be found at a fixed
+    // offset
+    add_file(&mut zip, "mimetype", ODS_MIMETYPE.as_bytes())?;
+    add_file(
+        &mut zip,
+        "META-INF/manifest.xml",
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <manifest:manifest \
+             xmlns:manifest=\"urn:oasis:names:tc:opendocument:xmlns:manifest:1.0\" \
+             manifest:version=\"1.2\">\n\
+             <manifest:file-entry manifest:full-path=\"/\" manifest:version=\"1.2\" \
+             manifest:media-type=\"{ODS_MIMETYPE}\"/>\n\
+             <manifest:file-entry manifest:full-path=\"content.xml\" \
+             manifest:media-type=\"text/xml\"/>\n\
+             </manifest:manifest>\n"
+        )
+        .as_bytes(),
+    )?;
+    add_file(&mut zip, "content.xml", ods_content(table, names).as_bytes())?;
+    zip.finish()?;
+    Ok(())
+}
+
+const XLSX_MAIN: &str = "http://schemas.openxmlformats.org/spreadsheetml/2006/main";
+const XLSX_RELATIONSHIPS: &str =
+    "http://schemas.openxmlformats.org/package/2006/relationships";
+const XLSX_DOCUMENT: &str =
+    "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+
+/// Cell styles, as indexes in cellXfs. Those for amounts come after, one
+/// per commodity.
+const XF_HEADER: usize = 1;
+const XF_DATE: usize = 2;
+const XF_PERCENT: usize = 3;
+const XF_FIRST_AMOUNT: usize = 4;
+const FIRST_CUSTOM_FORMAT: usize = 164;
+
+/// The name of a column: A, B, ..., Z, AA, ...
+fn xlsx_column(mut idx: usize) -> String {
+    let mut name = vec![];
+    loop {
+        name.insert(0, (b'A' + (idx % 26) as u8) as char);
+        if idx < 26 {
+            break;
+        }
+        idx = idx / 26 - 1;
+    }
+    name.into_iter().collect()
+}
+
+fn xlsx_styles(commodities: &[CommodityId], names: &Names) -> String {
+    let mut formats = vec![format!(
+        "<numFmt numFmtId=\"{FIRST_CUSTOM_FORMAT}\" formatCode=\"yyyy-mm-dd\"/>"
+    )];
+    let mut xfs = vec![
+        "<xf numFmtId=\"0\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\"/>".to_string(),
+        "<xf numFmtId=\"0\" fontId=\"1\" fillId=\"0\" borderId=\"0\" xfId=\"0\" \
+         applyFont=\"1\"/>".to_string(),
+        format!(
+            "<xf numFmtId=\"{FIRST_CUSTOM_FORMAT}\" fontId=\"0\" fillId=\"0\" borderId=\"0\" \
+             xfId=\"0\" applyNumberFormat=\"1\"/>"
+        ),
+        "<xf numFmtId=\"10\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\" \
+         applyNumberFormat=\"1\"/>".to_string(),
+    ];
+    for (idx, id) in commodities.iter().enumerate() {
+        let (before, after) = names.symbols(*id);
+        let decimals = names.decimals(*id) as usize;
+        let number = match decimals {
+            0 => "#,##0".to_string(),
+            d => format!("#,##0.{}", "0".repeat(d)),
+        };
+        let quoted = |s: &str| match s {
+            "" => String::new(),
+            s => format!("\"{}\"", s.replace('"', "")),
+        };
+        let code = format!("{}{}{}", quoted(&before), number, quoted(&after));
+        let fmt = FIRST_CUSTOM_FORMAT + 1 + idx;
+        formats.push(format!(
+            "<numFmt numFmtId=\"{fmt}\" formatCode=\"{}\"/>",
+            escape(&code),
+        ));
+        xfs.push(format!(
+            "<xf numFmtId=\"{fmt}\" fontId=\"0\" fillId=\"0\" borderId=\"0\" xfId=\"0\" \
+             applyNumberFormat=\"1\"/>"
+        ));
+    }
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+         <styleSheet xmlns=\"{XLSX_MAIN}\">\n\
+         <numFmts count=\"{}\">{}</numFmts>\n\
+         <fonts count=\"2\"><font><sz val=\"11\"/><name val=\"Calibri\"/></font>\
+         <font><b/><sz val=\"11\"/><name val=\"Calibri\"/></font></fonts>\n\
+         <fills count=\"2\"><fill><patternFill patternType=\"none\"/></fill>\
+         <fill><patternFill patternType=\"gray125\"/></fill></fills>\n\
+         <borders count=\"1\"><border><left/><right/><top/><bottom/><diagonal/></border>\
+         </borders>\n\
+         <cellStyleXfs count=\"1\"><xf numFmtId=\"0\" fontId=\"0\" fillId=\"0\" \
+         borderId=\"0\"/></cellStyleXfs>\n\
+         <cellXfs count=\"{}\">{}</cellXfs>\n\
+         </styleSheet>\n",
+        formats.len(),
+        formats.join(""),
+        xfs.len(),
+        xfs.join(""),
+    )
+}
+
+fn xlsx_sheet(table: &Table, commodities: &[CommodityId], names: &Names) -> String {
+    // Spreadsheets count days from 1899-12-30
+    let epoch = NaiveDate::from_ymd(1899, 12, 30);
+    let mut x = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+         <worksheet xmlns=\"{XLSX_MAIN}\"><sheetData>\n<row r=\"1\">"
+    );
+    for (idx, col) in table.columns.iter().enumerate() {
+        x.push_str(&format!(
+            "<c r=\"{}1\" s=\"{XF_HEADER}\" t=\"inlineStr\"><is><t>{}</t></is></c>",
+            xlsx_column(idx),
+            escape(col),
+        ));
+    }
+    x.push_str("</row>\n");
+
+    for (r, row) in table.rows.iter().enumerate() {
+        let r = r + 2;
+        x.push_str(&format!("<row r=\"{r}\">"));
+        for (idx, cell) in row.iter().enumerate() {
+            let at = format!("{}{}", xlsx_column(idx), r);
+            x.push_str(&match cell {
+                Cell::Empty => String::new(),
+                Cell::Text(s) => format!(
+                    "<c r=\"{at}\" t=\"inlineStr\"><is><t xml:space=\"preserve\">{}</t></is></c>",
+                    escape(s),
+                ),
+                Cell::Number(n) => format!("<c r=\"{at}\"><v>{}</v></c>", n.normalize()),
+                Cell::Amount(v, id) => format!(
+                    "<c r=\"{at}\" s=\"{}\"><v>{}</v></c>",
+                    XF_FIRST_AMOUNT + commodities.iter().position(|c| c == id).unwrap_or(0),
+                    names.value(*v, *id),
+                ),
+                Cell::Date(d) => format!(
+                    "<c r=\"{at}\" s=\"{XF_DATE}\"><v>{}</v></c>",
+                    (*d - epoch).num_days(),
+                ),
+                Cell::Percent(p) => format!("<c r=\"{at}\" s=\"{XF_PERCENT}\"><v>{}</v></c>", p),
+            });
+        }
+        x.push_str("</row>\n");
+    }
+    x.push_str("</sheetData></worksheet>\n");
+    x
+}
+
+fn write_xlsx(out: impl Write + Seek, table: &Table, names: &Names) -> Result<(), AlereError> {
+    let commodities = used_commodities(table);
+    let mut zip = ZipWriter::new(out);
+    add_file(
+        &mut zip,
+        "[Content_Types].xml",
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+         <Types xmlns=\"http://schemas.openxmlformats.org/package/2006/content-types\">\
+         <Default Extension=\"rels\" \
+         ContentType=\"application/vnd.openxmlformats-package.relationships+xml\"/>\
+         <Default Extension=\"xml\" ContentType=\"application/xml\"/>\
+         <Override PartName=\"/xl/workbook.xml\" ContentType=\"application/\
+         vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml\"/>\
+         <Override PartName=\"/xl/worksheets/sheet1.xml\" ContentType=\"application/\
+         vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml\"/>\
+         <Override PartName=\"/xl/styles.xml\" ContentType=\"application/\
+         vnd.openxmlformats-officedocument.spreadsheetml.styles+xml\"/>\
+         </Types>\n"
+            .as_bytes(),
+    )?;
+    add_file(
+        &mut zip,
+        "_rels/.rels",
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+             <Relationships xmlns=\"{XLSX_RELATIONSHIPS}\">\
+             <Relationship Id=\"rId1\" Type=\"{XLSX_DOCUMENT}/officeDocument\" \
+             Target=\"xl/workbook.xml\"/></Relationships>\n"
+        )
+        .as_bytes(),
+    )?;
+    add_file(
+        &mut zip,
+        "xl/workbook.xml",
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+             <workbook xmlns=\"{XLSX_MAIN}\" xmlns:r=\"{XLSX_DOCUMENT}\">\
+             <sheets><sheet name=\"{}\" sheetId=\"1\" r:id=\"rId1\"/></sheets></workbook>\n",
+            escape(table.name),
+        )
+        .as_bytes(),
+    )?;
+    add_file(
+        &mut zip,
+        "xl/_rels/workbook.xml.rels",
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+             <Relationships xmlns=\"{XLSX_RELATIONSHIPS}\">\
+             <Relationship Id=\"rId1\" Type=\"{XLSX_DOCUMENT}/worksheet\" \
+             Target=\"worksheets/sheet1.xml\"/>\
+             <Relationship Id=\"rId2\" Type=\"{XLSX_DOCUMENT}/styles\" \
+             Target=\"styles.xml\"/></Relationships>\n"
+        )
+        .as_bytes(),
+    )?;
+    add_file(&mut zip, "xl/worksheets/sheet1.xml", xlsx_sheet(table, &commodities, names).as_bytes())?;
+    add_file(&mut zip, "xl/styles.xml", xlsx_styles(&commodities, names).as_bytes())?;
+    zip.finish()?;
+    Ok(())
+}
+
+/// Export a report to a spreadsheet, in a format guessed from the extension
+/// (csv, ods or xlsx), and return the number of rows.
+/// :param accounts: the account for the ledger (exactly one), or the
+///    investment accounts to include in quotes (all if not specified).
+///    Ignored for the other reports.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn export_report(
+    report: Report,
+    path: String,
+    mindate: DateTime<Utc>,
+    maxdate: DateTime<Utc>,
+    currency: CommodityId,
+    accounts: Option<Vec<AccountId>>,
+    scenario: Option<Scenario>,
+) -> Result<usize, AlereError> {
+    info!("export_report {} {:?} {:?} {}", path, &mindate, &maxdate, currency);
+    let extension = Path::new(&path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let write: fn(&mut std::io::BufWriter<std::fs::File>, &Table, &Names)
+        -> Result<(), AlereError> = match extension.as_str()
+    {
+        "csv" => |out, table, names| write_csv(out, table, names),
+        "ods" => |out, table, names| write_ods(out, table, names),
+        "xlsx" => |out, table, names| write_xlsx(out, table, names),
+        e => return Err(AlereError::validation(format!("Unsupported file format {:?}", e))),
+    };
+
+    let names = Names::load()?;
+    let table = match report {
+        Report::LEDGER => {
+            let account = match accounts.as_deref() {
+                Some([a]) => *a,
+                _ => return Err(AlereError::validation(
+                    "Specify the account for the ledger")),
+            };
+            ledger_table(&names, mindate, maxdate, account, scenario).await?
+        }
+        Report::INCOME_EXPENSE =>
+            income_expense_table(&names, mindate, maxdate, currency, scenario).await?,
+        Report::CASHFLOW => cashflow_table(mindate, maxdate, currency, scenario)?,
+        Report::NETWORTH => networth_table(mindate, maxdate, currency, scenario).await?,
+        Report::QUOTES =>
+            quotes_table(&names, mindate, maxdate, currency, accounts).await?,
+    };
+
+    let file = std::fs::File::create(&path)?;
+    let mut out = std::io::BufWriter::new(file);
+    write(&mut out, &table, &names)?;
+    out.flush()?;
+    Ok(table.rows.len())
+}
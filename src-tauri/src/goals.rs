@@ -0,0 +1,380 @@
+//! Savings goals: a target amount to reach by a given date, from the
+//! balance of a set of accounts (or a share of them).
+
+use super::connections::last_insert_rowid;
+use super::dates::DateValues;
+use super::decimals::SqlDecimal;
+use super::errors::AlereError;
+use super::metrics::{networth, sum_splits_per_account};
+use super::models::{AccountId, CommodityId};
+use super::occurrences::Occurrences;
+use super::scenarios::NO_SCENARIO;
+use chrono::{Duration, NaiveDate, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Date, Double, Integer, Nullable, Text};
+use log::info;
+use rust_decimal::prelude::*; //  to_i64, from_f64
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const DAYS_PER_MONTH: f64 = 30.44;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GoalAccount {
+    pub account_id: AccountId,
+    pub share: f64, // between 0.0 and 1.0
+}
+
+#[derive(Serialize, Debug)]
+pub struct GoalDescr {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub target: Decimal,
+    pub target_date: NaiveDate,
+    pub currency_id: CommodityId,
+    pub accounts: Vec<GoalAccount>,
+}
+
+#[derive(QueryableByName)]
+struct GoalRow {
+    #[sql_type = "Integer"]
+    id: i32,
+
+    #[sql_type = "Text"]
+    name: String,
+
+    #[sql_type = "Nullable<Text>"]
+    description: Option<String>,
+
+    #[sql_type = "Text"]
+    #[diesel(deserialize_as = "SqlDecimal")]
+    target: Decimal,
+
+    #[sql_type = "Date"]
+    target_date: NaiveDate,
+
+    #[sql_type = "Integer"]
+    currency_id: CommodityId,
+}
+
+/// Load all goals and their accounts
+
+pub fn load_goals() -> Result<Vec<GoalDescr>, AlereError> {
+    let rows = super::connections::execute_and_log::<GoalRow>(
+        "goals",
+        "SELECT g.id, g.name, g.description, \
+            alr_decimal(g.scaled_target, c.price_scale) AS target, \
+            g.target_date, g.currency_id \
+         FROM alr_goals g JOIN alr_commodities c ON (g.currency_id = c.id) \
+         ORDER BY g.target_date",
+    )?;
+    let links: Vec<(i32, AccountId, f64)> = {
+        use super::schema::alr_goal_accounts::dsl::*;
+        let c = &super::connections::get_connection()?;
+        alr_goal_accounts
+            .select((goal_id, account_id, share))
+            .load(c)?
+    };
+    Ok(rows
+        .into_iter()
+        .map(|g| GoalDescr {
+            accounts: links.iter()
+                .filter(|(gid, _, _)| *gid == g.id)
+                .map(|(_, account_id, share)| GoalAccount {
+                    account_id: *account_id,
+                    share: *share,
+                })
+                .collect(),
+            id: g.id,
+            name: g.name,
+            description: g.description,
+            target: g.target,
+            target_date: g.target_date,
+            currency_id: g.currency_id,
+        })
+        .collect())
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn goals() -> Result<Vec<GoalDescr>, AlereError> {
+    info!("goals");
+    load_goals()
+}
+
+/// Insert or replace a goal, and its list of accounts.
+
+fn save_goal(
+    c: &SqliteConnection,
+    id: Option<i32>,
+    goal: &GoalDescr,
+) -> Result<i32, AlereError> {
+    c.transaction::<_, AlereError, _>(|| {
+        let scale: i32 = {
+            use super::schema::alr_commodities::dsl::*;
+            alr_commodities.find(goal.currency_id).select(price_scale).first(c)?
+        };
+        let scaled = goal.target
+            .checked_mul(Decimal::from(scale))
+            .and_then(|t| t.round().to_i64())
+            .ok_or_else(|| AlereError::validation(
+                format!("Target {} is too large", goal.target)))?;
+        let goal_id = match id {
+            None => {
+                diesel::sql_query(
+                    "INSERT INTO alr_goals
+                       (name, description, scaled_target, target_date,
+                        currency_id)
+                     VALUES (?, ?, ?, ?, ?)",
+                )
+                .bind::<Text, _>(&goal.name)
+                .bind::<Nullable<Text>, _>(&goal.description)
+                .bind::<BigInt, _>(scaled)
+                .bind::<Date, _>(goal.target_date)
+                .bind::<Integer, _>(goal.currency_id)
+                .execute(c)?;
+                diesel::select(last_insert_rowid).get_result::<i32>(c)?
+            }
+            Some(id) => {
+                let count = diesel::sql_query(
+                    "UPDATE alr_goals
+                     SET name=?, description=?, scaled_target=?,
+                        target_date=?, currency_id=?
+                     WHERE id=?",
+                )
+                .bind::<Text, _>(&goal.name)
+                .bind::<Nullable<Text>, _>(&goal.description)
+                .bind::<BigInt, _>(scaled)
+                .bind::<Date, _>(goal.target_date)
+                .bind::<Integer, _>(goal.currency_id)
+                .bind::<Integer, _>(id)
+                .execute(c)?;
+                if count == 0 {
+                    return Err(AlereError::not_found(
+                        format!("No such goal {}", id)));
+                }
+                diesel::sql_query("DELETE FROM alr_goal_accounts WHERE goal_id=?")
+                    .bind::<Integer, _>(id)
+                    .execute(c)?;
+                id
+            }
+        };
+        for acc in &goal.accounts {
+            diesel::sql_query(
+                "INSERT INTO alr_goal_accounts (goal_id, account_id, share)
+                 VALUES (?, ?, ?)",
+            )
+            .bind::<Integer, _>(goal_id)
+            .bind::<Integer, _>(acc.account_id)
+            .bind::<Double, _>(acc.share.clamp(0.0, 1.0))
+            .execute(c)?;
+        }
+        Ok(goal_id)
+    })
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn create_goal(
+    name: String,
+    description: Option<String>,
+    target: Decimal,
+    targetdate: NaiveDate,
+    currency: CommodityId,
+    accounts: Vec<GoalAccount>,
+) -> Result<i32, AlereError> {
+    info!("create_goal {:?} {} {}", &name, target, targetdate);
+    let c = &super::connections::get_connection()?;
+    let goal = GoalDescr {
+        id: 0,
+        name,
+        description,
+        target,
+        target_date: targetdate,
+        currency_id: currency,
+        accounts,
+    };
+    save_goal(c, None, &goal)
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn update_goal(
+    id: i32,
+    name: String,
+    description: Option<String>,
+    target: Decimal,
+    targetdate: NaiveDate,
+    currency: CommodityId,
+    accounts: Vec<GoalAccount>,
+) -> Result<(), AlereError> {
+    info!("update_goal {} {:?} {} {}", id, &name, target, targetdate);
+    let c = &super::connections::get_connection()?;
+    let goal = GoalDescr {
+        id,
+        name,
+        description,
+        target,
+        target_date: targetdate,
+        currency_id: currency,
+        accounts,
+    };
+    save_goal(c, Some(id), &goal).map(|_| ())
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn delete_goal(id: i32) -> Result<(), AlereError> {
+    info!("delete_goal {}", id);
+    let c = &super::connections::get_connection()?;
+    c.transaction::<_, AlereError, _>(|| {
+        diesel::sql_query("DELETE FROM alr_goal_accounts WHERE goal_id=?")
+            .bind::<Integer, _>(id)
+            .execute(c)?;
+        diesel::sql_query("DELETE FROM alr_goals WHERE id=?")
+            .bind::<Integer, _>(id)
+            .execute(c)?;
+        Ok(())
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct GoalProgress {
+    pub goal: GoalDescr,
+    pub current: Decimal,              // current value of the linked accounts
+    pub progress: f64,                 // 1.0 when the goal is reached
+    pub average_contribution: Decimal, // per month, over the recent history
+    pub required_monthly: Decimal,     // to reach the target on time
+    pub projected_completion: Option<NaiveDate>,
+    pub on_track: bool,
+}
+
+/// Balances today, and the contributions over the recent history, for
+/// all accounts in a given currency.
+
+struct AccountFigures {
+    balance: HashMap<AccountId, Decimal>,
+    contributions: HashMap<AccountId, Decimal>,
+}
+
+fn account_figures(
+    currency: CommodityId,
+    months: u8,
+) -> Result<AccountFigures, AlereError> {
+    let today = Utc::today();
+    let start = today - Duration::days((months as f64 * DAYS_PER_MONTH) as i64);
+    let dates = DateValues::new(Some(vec![start, today]));
+    let balance = networth(
+        &dates, currency, NO_SCENARIO, &Occurrences::no_recurrence())?
+        .iter()
+        .map(|acc| (acc.account_id, acc.shares[1] * acc.price[1]))
+        .collect();
+    let contributions = sum_splits_per_account(
+        &dates, currency, NO_SCENARIO, &Occurrences::no_recurrence())?;
+    Ok(AccountFigures { balance, contributions })
+}
+
+/// Report progress on all goals.
+/// :param months:
+///    the number of recent months used to compute the average monthly
+///    contribution (defaults to 6).
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn goals_progress(
+    months: Option<u8>,
+) -> Result<Vec<GoalProgress>, AlereError> {
+    info!("goals_progress {:?}", months);
+    let months = months.unwrap_or(6).max(1);
+    let today = Utc::today().naive_utc();
+    let goals = load_goals()?;
+    let mut per_currency: HashMap<CommodityId, AccountFigures> = HashMap::new();
+    for goal in &goals {
+        if !per_currency.contains_key(&goal.currency_id) {
+            per_currency.insert(
+                goal.currency_id, account_figures(goal.currency_id, months)?);
+        }
+    }
+
+    Ok(goals
+        .into_iter()
+        .map(|goal| {
+            let figures = &per_currency[&goal.currency_id];
+            let share = |a: &GoalAccount| Decimal::from_f64(a.share).unwrap_or_default();
+            let current: Decimal = goal.accounts.iter()
+                .map(|a| figures.balance.get(&a.account_id).copied().unwrap_or_default()
+                     * share(a))
+                .sum();
+            let contributed: Decimal = goal.accounts.iter()
+                .map(|a| figures.contributions.get(&a.account_id).copied().unwrap_or_default()
+                     * share(a))
+                .sum();
+            let average_contribution = contributed / Decimal::from(months);
+            let remaining = goal.target - current;
+            let months_left = Decimal::from_f64(
+                (goal.target_date - today).num_days() as f64 / DAYS_PER_MONTH)
+                .unwrap_or_default();
+            let required_monthly = if remaining <= Decimal::ZERO {
+                Decimal::ZERO
+            } else if months_left < Decimal::ONE {
+                remaining
+            } else {
+                remaining / months_left
+            };
+            let projected_completion = if remaining <= Decimal::ZERO {
+                Some(today)
+            } else if average_contribution > Decimal::ZERO {
+                remaining.checked_div(average_contribution)
+                    .and_then(|m| m.to_f64())
+                    .map(|m| {
+                        today + Duration::days((m * DAYS_PER_MONTH).ceil() as i64)
+                    })
+            } else {
+                None
+            };
+            GoalProgress {
+                current,
+                progress: match goal.target {
+                    t if t > Decimal::ZERO => (current / t).to_f64().unwrap_or(0.0),
+                    _ => 1.0,
+                },
+                average_contribution,
+                required_monthly,
+                on_track: projected_completion
+                    .map(|d| d <= goal.target_date)
+                    .unwrap_or(false),
+                projected_completion,
+                goal,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::{get_connection, test_database};
+    use crate::importer::import_test_file;
+    use futures_executor::block_on;
+
+    #[test]
+    fn large_target() {
+        let _db = test_database("goals");
+        import_test_file("currency.beancount", "1970-01-01 commodity EUR\n", None);
+        let eur: CommodityId = {
+            use crate::schema::alr_commodities::dsl::*;
+            alr_commodities.select(id).first(&get_connection().unwrap()).unwrap()
+        };
+        let date = NaiveDate::from_ymd(2040, 1, 1);
+
+        // Does not fit in 32 bits once scaled
+        let target = Decimal::from_str("123456789012.34").unwrap();
+        block_on(create_goal(
+            "House".into(), None, target, date, eur, vec![])).unwrap();
+        let goals = load_goals().unwrap();
+        assert_eq!(goals.len(), 1);
+        assert_eq!(goals[0].target, target);
+
+        // Does not fit in 64 bits once scaled
+        let target = Decimal::from_str("1e25").unwrap();
+        assert!(block_on(create_goal(
+            "Moon".into(), None, target, date, eur, vec![])).is_err());
+        assert_eq!(load_goals().unwrap().len(), 1);
+    }
+}
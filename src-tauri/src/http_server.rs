@@ -0,0 +1,386 @@
+//! An embedded HTTP server, which exposes the commands of the application
+//! as JSON endpoints, for instance for scripts or to access the data from a
+//! browser:
+//!
+//!     POST /api/<command>
+//!     Authorization: Bearer <token>
+//!     {"mindate": "2022-01-01T00:00:00Z", "maxdate": ..., "currency": 1}
+//!
+//! The body is a JSON object with the same arguments as sent by the
+//! front-end (missing arguments are null). The result of the command is
+//! returned as JSON, or an AlereError with an HTTP error status.
+//! GET /api/commands returns the list of available commands. Commands that
+//! take file paths, or change the database in use, are only available in
+//! the application (see for_each_command).
+//! Browsers only accept the responses for pages served from the origin
+//! given in ALERE_HTTP_ORIGIN, if any.
+//!
+//! This is only a minimal HTTP/1.1 implementation (one request per
+//! connection, no chunked encoding), and is only compiled with the
+//! "http-server" feature.
+
+use super::errors::AlereError;
+use futures_executor::block_on;
+use log::{error, info, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Only local connections are accepted by default
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:8321";
+
+const MAX_HEADERS: usize = 100;
+const MAX_LINE: usize = 8 * 1024;
+const MAX_BODY: usize = 16 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of connections processed in parallel. Further connections wait
+/// in the listen queue of the system.
+const WORKERS: usize = 4;
+
+/// Read a named argument of a command. Missing arguments are null, as for
+/// Tauri.
+
+fn arg<T: DeserializeOwned>(args: &Map<String, Value>, name: &str) -> Result<T, AlereError> {
+    serde_json::from_value(args.get(name).cloned().unwrap_or(Value::Null))
+        .map_err(|e| AlereError::validation(format!("Invalid argument {}: {}", name, e)))
+}
+
+fn to_json<T: Serialize>(value: T) -> Result<Value, AlereError> {
+    serde_json::to_value(value).map_err(|e| AlereError::parse(e.to_string()))
+}
+
+macro_rules! dispatch {
+    (
+        remote: [$( $module:ident :: $func:ident ( $($arg:ident),* ) ),* $(,)?],
+        local: [$( $lmodule:ident :: $lfunc:ident ( $($larg:ident),* ) ),* $(,)?] $(,)?
+    ) => {
+        /// Names of the commands available over HTTP
+        const COMMANDS: &[&str] = &[$( stringify!($func) ),*];
+
+        /// Names of the commands only available in the application
+        const LOCAL_COMMANDS: &[&str] = &[$( stringify!($lfunc) ),*];
+
+        /// Run a command, and return its result as JSON
+        fn call(command: &str, args: &Map<String, Value>) -> Result<Value, AlereError> {
+            match command {
+                $(
+                    stringify!($func) => to_json(block_on(super::$module::$func(
+                        $( arg(args, stringify!($arg))? ),*
+                    ))?),
+                )*
+                c if LOCAL_COMMANDS.contains(&c) => Err(AlereError::validation(
+                    format!("Command {} is not available over HTTP", c))),
+                c => Err(AlereError::not_found(format!("Unknown command {}", c))),
+            }
+        }
+    };
+}
+
+crate::for_each_command!(dispatch);
+
+/// Generate a random token for authentication
+pub fn new_token() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// Compare the tokens, in a time that does not depend on where they differ
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    length: usize,
+    body: Vec<u8>,
+}
+
+fn bad_request(message: &str) -> AlereError {
+    AlereError::validation(format!("Invalid HTTP request: {}", message))
+}
+
+/// Read one line, ended by a newline, without accepting more than MAX_LINE
+/// bytes from the client.
+
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> Result<(), AlereError> {
+    line.clear();
+    reader.take(MAX_LINE as u64).read_line(line)?;
+    if !line.ends_with('\n') {
+        return Err(bad_request("line too long or incomplete"));
+    }
+    Ok(())
+}
+
+/// Read the request line and the headers. The body is only read once the
+/// client has been authenticated, see read_body.
+
+fn read_head(reader: &mut impl BufRead) -> Result<Request, AlereError> {
+    let mut line = String::new();
+    read_line(reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(m), Some(p)) => (m.to_string(), p.to_string()),
+        _ => return Err(bad_request("missing method or path")),
+    };
+
+    let mut authorization = None;
+    let mut length = 0;
+    let mut count = 0;
+    loop {
+        read_line(reader, &mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        count += 1;
+        if count > MAX_HEADERS {
+            return Err(bad_request("too many headers"));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "authorization" => authorization = Some(value.to_string()),
+                "content-length" => {
+                    length = value.parse().map_err(|_| bad_request("Content-Length"))?
+                }
+                "transfer-encoding" => return Err(bad_request("chunked encoding")),
+                _ => {}
+            }
+        }
+    }
+    Ok(Request { method, path, authorization, length, body: vec![] })
+}
+
+fn read_body(reader: &mut impl Read, request: &mut Request) -> Result<(), AlereError> {
+    if request.length > MAX_BODY {
+        return Err(bad_request("body too large"));
+    }
+    request.body = vec![0; request.length];
+    reader.read_exact(&mut request.body)?;
+    Ok(())
+}
+
+/// The HTTP status for an error
+fn status(e: &AlereError) -> u16 {
+    match e {
+        AlereError::Validation { .. } => 400,
+        AlereError::NotFound { .. } => 404,
+        AlereError::Locked { .. } => 423,
+        AlereError::Database { .. }
+        | AlereError::Parse { .. }
+        | AlereError::Io { .. } => 500,
+    }
+}
+
+/// Headers that let pages from `origin` read the responses in a browser.
+/// Without them, browsers only let pages served by us read them.
+
+fn cors_headers(origin: Option<&str>) -> String {
+    match origin {
+        None => String::new(),
+        Some(o) => format!(
+            "Access-Control-Allow-Origin: {o}\r\n\
+             Access-Control-Allow-Headers: Authorization, Content-Type\r\n\
+             Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n"
+        ),
+    }
+}
+
+fn write_response(
+    mut stream: &TcpStream,
+    status: u16,
+    extra_headers: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        423 => "Locked",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         {extra_headers}\r\n\
+         {body}",
+        body.len(),
+    )?;
+    stream.flush()
+}
+
+fn is_authorized(request: &Request, token: &str) -> bool {
+    request
+        .authorization
+        .as_deref()
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .map(|t| same_token(t.trim(), token))
+        .unwrap_or(false)
+}
+
+/// Process an authorized request, and return the status and body of the
+/// response
+fn handle(request: &Request) -> (u16, String) {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let command = match path.strip_prefix("/api/") {
+        Some(c) => c,
+        None => return (404, r#"{"kind":"NotFound","message":"Unknown URL"}"#.into()),
+    };
+
+    let result = match (request.method.as_str(), command) {
+        ("GET", "commands") => to_json(COMMANDS),
+        ("POST", _) => {
+            info!("http {}", command);
+            let args = match request.body.iter().all(|b| b.is_ascii_whitespace()) {
+                true => Ok(Map::new()),
+                false => serde_json::from_slice::<Map<String, Value>>(&request.body)
+                    .map_err(|e| AlereError::validation(
+                        format!("Arguments must be a JSON object: {}", e))),
+            };
+            args.and_then(|args| call(command, &args))
+        }
+        _ => return (405, r#"{"kind":"Validation","message":"Use POST"}"#.into()),
+    };
+    match result {
+        Ok(v) => (200, v.to_string()),
+        Err(e) => (status(&e), serde_json::to_string(&e).unwrap_or_default()),
+    }
+}
+
+/// Run handle, and report panics as internal errors, so that the worker
+/// keeps running.
+
+fn handle_or_500(request: &Request) -> (u16, String) {
+    catch_unwind(AssertUnwindSafe(|| handle(request))).unwrap_or_else(|_| {
+        error!("http {}: panic", request.path);
+        let e = AlereError::Database { message: "Internal error".into(), query: None };
+        (500, serde_json::to_string(&e).unwrap_or_default())
+    })
+}
+
+fn handle_connection(stream: TcpStream, token: &str, cors: &str) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = match read_head(&mut reader) {
+        Ok(r) => r,
+        Err(e) => {
+            let body = serde_json::to_string(&e).unwrap_or_default();
+            return write_response(&stream, status(&e), cors, &body);
+        }
+    };
+    if request.method == "OPTIONS" {
+        // CORS preflight requests are sent without the token
+        return write_response(&stream, 204, cors, "");
+    }
+
+    // Reject unknown clients before they can send a large body
+    if !is_authorized(&request, token) {
+        return write_response(
+            &stream,
+            401,
+            &format!("{cors}WWW-Authenticate: Bearer\r\n"),
+            r#"{"kind":"Validation","message":"Invalid token"}"#,
+        );
+    }
+    if let Err(e) = read_body(&mut reader, &mut request) {
+        let body = serde_json::to_string(&e).unwrap_or_default();
+        return write_response(&stream, status(&e), cors, &body);
+    }
+    let (status, body) = handle_or_500(&request);
+    write_response(&stream, status, cors, &body)
+}
+
+/// Accept connections on an address, and process them in a fixed number of
+/// worker threads. This only returns if the address cannot be used, or all
+/// workers have stopped.
+/// :param token: the clients must send it in an "Authorization: Bearer"
+///    header.
+/// :param origin: the origin (for instance "http://localhost:3000") of the
+///    web pages allowed to use the server from a browser, if any.
+
+pub fn serve(address: &str, token: &str, origin: Option<&str>) -> Result<(), AlereError> {
+    if token.is_empty() {
+        return Err(AlereError::validation("A token is required"));
+    }
+    let listener = TcpListener::bind(address)?;
+    let local = listener.local_addr()?;
+    if !local.ip().is_loopback() {
+        warn!("http server is reachable from other machines on {}", local);
+    }
+    info!("http server listening on {}", local);
+
+    // The connections are only accepted when a worker is available
+    let (sender, receiver) = sync_channel::<TcpStream>(0);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKERS {
+        let receiver = Arc::clone(&receiver);
+        let token = token.to_string();
+        let cors = cors_headers(origin);
+        std::thread::spawn(move || loop {
+            let stream = match receiver.lock().map(|r| r.recv()) {
+                Ok(Ok(stream)) => stream,
+                _ => return,
+            };
+            if let Err(e) = handle_connection(stream, &token, &cors) {
+                error!("http connection: {}", e);
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if sender.send(stream).is_err() {
+                    // All workers have stopped, after a panic
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "no http worker left",
+                    ).into());
+                }
+            }
+            Err(e) => error!("http connection: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// The origin allowed to use the server from a browser, from
+/// ALERE_HTTP_ORIGIN
+
+pub fn origin_from_env() -> Option<String> {
+    std::env::var("ALERE_HTTP_ORIGIN").ok().filter(|o| !o.is_empty())
+}
+
+/// Start the server in the background if ALERE_HTTP_TOKEN is set. The
+/// address can be changed with ALERE_HTTP_ADDRESS, and the allowed origin
+/// with ALERE_HTTP_ORIGIN.
+
+pub fn start_from_env() {
+    let token = match std::env::var("ALERE_HTTP_TOKEN") {
+        Ok(t) if !t.is_empty() => t,
+        _ => return,
+    };
+    let address = std::env::var("ALERE_HTTP_ADDRESS")
+        .unwrap_or_else(|_| DEFAULT_ADDRESS.to_string());
+    let origin = origin_from_env();
+    std::thread::spawn(move || {
+        if let Err(e) = serve(&address, &token, origin.as_deref()) {
+            error!("http server on {}: {}", address, e);
+        }
+    });
+}
@@ -0,0 +1,963 @@
+//! Import a Beancount journal.
+//!
+//! Supported directives are open, close, commodity, price, balance and
+//! transactions (with costs and price annotations), as well as include,
+//! option and pushtag/poptag. Other directives (pad, note, document,
+//! event, query, custom) are counted as ignored.
+//! Accounts only have one commodity in alere, so the other commodities
+//! posted to a Beancount account are stored in child accounts named after
+//! the commodity.
+//! Lots are not tracked individually: reducing a position with an empty
+//! cost ("{}") uses the average cost of the position.
+//! Balance assertions that do not match after the import are reported as
+//! checkpoints; those that match mark the account as reconciled.
+
+use super::accounts::{commodity_kinds, AccountKindCategory};
+use super::errors::AlereError;
+use super::importer::{kind_names, Importer, NewSplit, NewTransaction};
+use super::models::{AccountId, CommodityId};
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Maximum depth of nested include directives
+const MAX_INCLUDES: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+}
+
+#[derive(Clone)]
+struct Amount {
+    number: Decimal,
+    commodity: String,
+}
+
+enum Cost {
+    PerUnit(Amount),
+    Total(Amount),
+    Reduce,  // "{}", use the cost of the position
+}
+
+enum Price {
+    PerUnit(Amount),
+    Total(Amount),
+}
+
+struct Posting {
+    location: String,
+    flag: Option<String>,
+    account: String,
+    units: Option<Amount>,
+    cost: Option<Cost>,
+    price: Option<Price>,
+    meta: HashMap<String, String>,
+}
+
+struct Transaction {
+    payee: Option<String>,
+    narration: String,
+    tags: Vec<String>,
+    meta: HashMap<String, String>,
+    postings: Vec<Posting>,
+}
+
+enum Entry {
+    Open { account: String, currencies: Vec<String>, meta: HashMap<String, String> },
+    Close { account: String },
+    Commodity { symbol: String, meta: HashMap<String, String> },
+    Price { commodity: String, amount: Amount },
+    Balance { account: String, amount: Amount },
+    Transaction(Transaction),
+}
+
+struct Directive {
+    date: NaiveDate,
+    location: String,  // file and line, for error messages
+    entry: Entry,
+}
+
+#[derive(Default)]
+struct Journal {
+    directives: Vec<Directive>,
+    options: HashMap<String, Vec<String>>,
+    ignored: usize,
+}
+
+fn error(location: &str, message: impl std::fmt::Display) -> AlereError {
+    AlereError::parse(format!("{}: {}", location, message))
+}
+
+/// Split a line into tokens. Comments are discarded.
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    fn flush(word: &mut String, tokens: &mut Vec<Token>) {
+        if !word.is_empty() {
+            tokens.push(Token::Word(std::mem::take(word)));
+        }
+    }
+
+    let mut tokens = vec![];
+    let mut word = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                flush(&mut word, &mut tokens);
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated string".into()),
+                        Some('"') => break,
+                        Some('\\') => s.extend(chars.next()),
+                        Some(c) => s.push(c),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            ';' => break,
+            '{' | '}' | '@' => {
+                flush(&mut word, &mut tokens);
+                let mut t = c.to_string();
+                if chars.peek() == Some(&c) {
+                    t.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(t));
+            }
+            // Commas are either thousands separators or separate lists
+            ',' if word.ends_with(|c: char| c.is_ascii_digit())
+                && chars.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) => {}
+            ',' => {
+                flush(&mut word, &mut tokens);
+                tokens.push(Token::Word(",".into()));
+            }
+            c if c.is_whitespace() => flush(&mut word, &mut tokens),
+            c => word.push(c),
+        }
+    }
+    flush(&mut word, &mut tokens);
+    Ok(tokens)
+}
+
+fn word(token: Option<&Token>) -> Option<&str> {
+    match token {
+        Some(Token::Word(w)) => Some(w),
+        _ => None,
+    }
+}
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(&s.replace('/', "-"), "%Y-%m-%d").ok()
+}
+
+fn parse_number(s: &str) -> Option<Decimal> {
+    Decimal::from_str(s.trim_start_matches('+')).ok()
+}
+
+fn is_account(s: &str) -> bool {
+    s.contains(':') && s.starts_with(|c: char| c.is_uppercase())
+}
+
+/// Parse "NUMBER COMMODITY" at the start of the tokens
+fn parse_amount(tokens: &[Token], location: &str) -> Result<Option<Amount>, AlereError> {
+    let number = match word(tokens.first()) {
+        None => return Ok(None),
+        Some(n) => n,
+    };
+    let number = parse_number(number).ok_or_else(|| error(
+        location, format!("unsupported amount {}", number)))?;
+    let commodity = word(tokens.get(1)).ok_or_else(|| error(location, "missing commodity"))?;
+    Ok(Some(Amount { number, commodity: commodity.to_string() }))
+}
+
+/// Parse the contents of a cost, between braces. Only the amount is kept
+/// (not the date or label of the lot).
+
+fn parse_cost(tokens: &[Token], total: bool, location: &str) -> Result<Cost, AlereError> {
+    if tokens.iter().any(|t| word(Some(t)) == Some("#")) {
+        return Err(error(location, "compound costs are not supported"));
+    }
+    for component in tokens.split(|t| word(Some(t)) == Some(",")) {
+        if let (Some(n), Some(_)) = (word(component.first()), word(component.get(1))) {
+            if parse_number(n).is_some() {
+                let amount = parse_amount(component, location)?.unwrap();
+                return Ok(match total {
+                    true => Cost::Total(amount),
+                    false => Cost::PerUnit(amount),
+                });
+            }
+        }
+    }
+    Ok(Cost::Reduce)
+}
+
+fn parse_posting(tokens: &[Token], location: &str) -> Result<Posting, AlereError> {
+    let mut pos = 0;
+    let flag = match word(tokens.first()) {
+        Some(f) if f.chars().count() == 1 && !is_account(f) => {
+            pos += 1;
+            Some(f.to_string())
+        }
+        _ => None,
+    };
+    let account = word(tokens.get(pos))
+        .filter(|a| is_account(a))
+        .ok_or_else(|| error(location, "expected an account"))?
+        .to_string();
+    pos += 1;
+
+    let rest = &tokens[pos..];
+    let end = rest
+        .iter()
+        .position(|t| matches!(word(Some(t)), Some("{") | Some("{{") | Some("@") | Some("@@")))
+        .unwrap_or(rest.len());
+    let units = parse_amount(&rest[..end], location)?;
+    let mut rest = &rest[end..];
+
+    let mut cost = None;
+    if let Some(open @ ("{" | "{{")) = word(rest.first()) {
+        let close = if open == "{" { "}" } else { "}}" };
+        let end = rest
+            .iter()
+            .position(|t| word(Some(t)) == Some(close))
+            .ok_or_else(|| error(location, "unterminated cost"))?;
+        cost = Some(parse_cost(&rest[1..end], open == "{{", location)?);
+        rest = &rest[end + 1..];
+    }
+
+    let mut price = None;
+    if let Some(at @ ("@" | "@@")) = word(rest.first()) {
+        let amount = parse_amount(&rest[1..], location)?
+            .ok_or_else(|| error(location, "missing price"))?;
+        price = Some(match at {
+            "@" => Price::PerUnit(amount),
+            _ => Price::Total(amount),
+        });
+    }
+
+    Ok(Posting {
+        location: location.to_string(),
+        flag,
+        account,
+        units,
+        cost,
+        price,
+        meta: HashMap::new(),
+    })
+}
+
+/// A metadata line "key: value"
+fn parse_meta(tokens: &[Token]) -> Option<(String, String)> {
+    let key = word(tokens.first())?.strip_suffix(':')?;
+    if !key.starts_with(|c: char| c.is_ascii_lowercase()) {
+        return None;
+    }
+    let value = match tokens.get(1) {
+        Some(Token::Str(s)) | Some(Token::Word(s)) => s.clone(),
+        None => String::new(),
+    };
+    Some((key.to_string(), value))
+}
+
+impl Journal {
+    /// Parse a file, and the files it includes
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<(), AlereError> {
+        if depth > MAX_INCLUDES {
+            return Err(AlereError::validation(format!(
+                "Too many nested includes in {}", path.display())));
+        }
+        let text = std::fs::read_to_string(path)?;
+        let mut pushed_tags: Vec<String> = vec![];
+        let mut current: Option<Directive> = None;
+
+        for (idx, line) in text.lines().enumerate() {
+            let location = format!("{}:{}", path.display(), idx + 1);
+            let tokens = tokenize(line).map_err(|e| error(&location, e))?;
+            if tokens.is_empty() {
+                continue;
+            }
+
+            // Indented lines belong to the current directive
+            if line.starts_with(|c: char| c.is_whitespace()) {
+                let meta = parse_meta(&tokens);
+                match (current.as_mut().map(|d| &mut d.entry), meta) {
+                    (Some(Entry::Transaction(t)), Some((k, v))) => {
+                        match t.postings.last_mut() {
+                            Some(p) => p.meta.insert(k, v),
+                            None => t.meta.insert(k, v),
+                        };
+                    }
+                    (Some(Entry::Transaction(t)), None) => {
+                        t.postings.push(parse_posting(&tokens, &location)?);
+                    }
+                    (Some(Entry::Open { meta, .. }), Some((k, v)))
+                    | (Some(Entry::Commodity { meta, .. }), Some((k, v))) => {
+                        meta.insert(k, v);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            self.directives.extend(current.take());
+            let first = word(tokens.first()).unwrap_or_default();
+            let date = match parse_date(first) {
+                Some(d) => d,
+                None => {
+                    match first {
+                        "option" => {
+                            if let (Some(Token::Str(k)), Some(Token::Str(v))) =
+                                (tokens.get(1), tokens.get(2))
+                            {
+                                self.options.entry(k.clone()).or_default().push(v.clone());
+                            }
+                        }
+                        "include" => {
+                            let name = match tokens.get(1) {
+                                Some(Token::Str(s)) => s,
+                                _ => return Err(error(&location, "invalid include")),
+                            };
+                            let included = path.parent().unwrap_or(Path::new("")).join(name);
+                            self.parse_file(&included, depth + 1)?;
+                        }
+                        "pushtag" => pushed_tags.extend(
+                            word(tokens.get(1)).map(|t| t.trim_start_matches('#').to_string())),
+                        "poptag" => {
+                            let tag = word(tokens.get(1)).unwrap_or_default()
+                                .trim_start_matches('#');
+                            pushed_tags.retain(|t| t != tag);
+                        }
+                        // plugins, org-mode headers,...
+                        _ => {}
+                    }
+                    continue;
+                }
+            };
+
+            let keyword = word(tokens.get(1)).unwrap_or_default();
+            let arg = |idx: usize| -> Result<String, AlereError> {
+                word(tokens.get(idx))
+                    .map(|w| w.to_string())
+                    .ok_or_else(|| error(&location, format!("invalid {} directive", keyword)))
+            };
+            let entry = match keyword {
+                "open" => Entry::Open {
+                    account: arg(2)?,
+                    currencies: tokens[3..]
+                        .iter()
+                        .filter_map(|t| word(Some(t)))
+                        .filter(|w| *w != ",")
+                        .map(|w| w.to_string())
+                        .collect(),
+                    meta: HashMap::new(),
+                },
+                "close" => Entry::Close { account: arg(2)? },
+                "commodity" => Entry::Commodity { symbol: arg(2)?, meta: HashMap::new() },
+                "price" => Entry::Price {
+                    commodity: arg(2)?,
+                    amount: parse_amount(&tokens[3..], &location)?
+                        .ok_or_else(|| error(&location, "missing price"))?,
+                },
+                "balance" => Entry::Balance {
+                    account: arg(2)?,
+                    amount: parse_amount(&tokens[3..], &location)?
+                        .ok_or_else(|| error(&location, "missing amount"))?,
+                },
+                "pad" | "note" | "document" | "event" | "query" | "custom" => {
+                    self.ignored += 1;
+                    continue;
+                }
+                flag if flag == "txn" || flag.chars().count() == 1 => {
+                    let strings: Vec<&String> = tokens[2..]
+                        .iter()
+                        .filter_map(|t| match t {
+                            Token::Str(s) => Some(s),
+                            _ => None,
+                        })
+                        .collect();
+                    let (payee, narration) = match strings.as_slice() {
+                        [p, n, ..] => (Some(p.to_string()), n.to_string()),
+                        [n] => (None, n.to_string()),
+                        [] => (None, String::new()),
+                    };
+                    let mut tags = pushed_tags.clone();
+                    tags.extend(
+                        tokens[2..]
+                            .iter()
+                            .filter_map(|t| word(Some(t)))
+                            .filter_map(|w| w.strip_prefix('#'))
+                            .map(|w| w.to_string()),
+                    );
+                    Entry::Transaction(Transaction {
+                        payee: payee.filter(|p| !p.is_empty()),
+                        narration,
+                        tags,
+                        meta: HashMap::new(),
+                        postings: vec![],
+                    })
+                }
+                k => return Err(error(&location, format!("unknown directive {}", k))),
+            };
+            current = Some(Directive { date, location, entry });
+        }
+        self.directives.extend(current.take());
+        Ok(())
+    }
+}
+
+/// A posting, with its amount converted to the currency of the
+/// transaction
+
+struct Resolved {
+    account: String,
+    units: Amount,
+    weight: Amount,
+    reconcile: &'static str,
+    post_date: NaiveDate,
+}
+
+/// Number of decimal digits the number was written with
+fn digits(d: &Decimal) -> u32 {
+    d.scale()
+}
+
+/// Number of decimal digits for a scale (2 for 100)
+fn scale_digits(scale: i32) -> u32 {
+    (scale.max(1) as f64).log10().ceil() as u32
+}
+
+/// Total costs and prices have the sign of the quantity
+fn sign(d: &Decimal) -> Decimal {
+    match d.is_sign_negative() {
+        true => Decimal::NEGATIVE_ONE,
+        false => Decimal::ONE,
+    }
+}
+
+fn scale_for(digits: u32) -> i32 {
+    10_i32.pow(digits.min(9))
+}
+
+struct BeancountImport<'a, 'b> {
+    imp: &'b mut Importer<'a>,
+    journal: Journal,
+    commodities: HashMap<String, CommodityId>,
+    stocks: HashSet<String>,
+    main_commodity: HashMap<String, String>,  // for each account
+    names: HashMap<String, String>,           // from the metadata of open
+    scu: HashMap<(String, String), u32>,       // digits of the quantities
+    accounts: HashMap<(String, String), AccountId>,
+}
+
+impl<'a, 'b> BeancountImport<'a, 'b> {
+    fn option(&self, name: &str) -> Option<&str> {
+        self.journal.options.get(name).and_then(|v| v.first()).map(|s| s.as_str())
+    }
+
+    /// The kind of accounts below a root account
+    fn kind(&self, root: &str, commodity: &str) -> Option<(&'static str, AccountKindCategory)> {
+        let root_name = |option: &str, default: &str| {
+            self.option(option).unwrap_or(default) == root
+        };
+        if root_name("name_assets", "Assets") {
+            Some(match self.stocks.contains(commodity) {
+                true => (kind_names::STOCK, AccountKindCategory::EQUITY),
+                false => (kind_names::BANK, AccountKindCategory::EQUITY),
+            })
+        } else if root_name("name_liabilities", "Liabilities") {
+            Some((kind_names::LIABILITY, AccountKindCategory::LIABILITY))
+        } else if root_name("name_equity", "Equity") {
+            Some((kind_names::EQUITY, AccountKindCategory::EQUITY))
+        } else if root_name("name_income", "Income") {
+            Some((kind_names::INCOME, AccountKindCategory::INCOME))
+        } else if root_name("name_expenses", "Expenses") {
+            Some((kind_names::EXPENSE, AccountKindCategory::EXPENSE))
+        } else {
+            None
+        }
+    }
+
+    fn commodity(&self, symbol: &str, location: &str) -> Result<CommodityId, AlereError> {
+        self.commodities
+            .get(symbol)
+            .copied()
+            .ok_or_else(|| error(location, format!("unknown commodity {}", symbol)))
+    }
+
+    /// Create all commodities, with a scale large enough for all amounts
+    /// and prices in the file
+
+    fn create_commodities(&mut self) -> Result<(), AlereError> {
+        let mut order: Vec<String> = vec![];
+        let mut max_digits: HashMap<String, u32> = HashMap::new();
+        let mut names: HashMap<String, String> = HashMap::new();
+        let mut add = |symbol: &str, d: u32| {
+            let e = max_digits.entry(symbol.to_string()).or_insert_with(|| {
+                order.push(symbol.to_string());
+                0
+            });
+            *e = (*e).max(d);
+        };
+
+        for d in &self.journal.directives {
+            match &d.entry {
+                Entry::Commodity { symbol, meta } => {
+                    let scale = meta.get("price-scale").and_then(|s| s.parse::<i64>().ok());
+                    add(symbol, scale.map(|s| scale_digits(s as i32)).unwrap_or(0));
+                    if let Some(n) = meta.get("name") {
+                        names.insert(symbol.clone(), n.clone());
+                    }
+                }
+                Entry::Open { currencies, .. } => currencies.iter().for_each(|c| add(c, 0)),
+                Entry::Price { commodity, amount } => {
+                    add(commodity, digits(&amount.number));
+                    add(&amount.commodity, 0);
+                }
+                Entry::Balance { amount, .. } => add(&amount.commodity, digits(&amount.number)),
+                Entry::Transaction(t) => {
+                    for p in &t.postings {
+                        if let Some(u) = &p.units {
+                            // quantities are scaled by the account
+                            add(&u.commodity, 0);
+                        }
+                        match (&p.units, &p.cost, &p.price) {
+                            (Some(u), Some(Cost::PerUnit(a)), _)
+                            | (Some(u), None, Some(Price::PerUnit(a))) => {
+                                add(&u.commodity, digits(&a.number));
+                                add(&a.commodity, 0);
+                            }
+                            (_, Some(Cost::Total(a)), _) | (_, None, Some(Price::Total(a))) => {
+                                add(&a.commodity, digits(&a.number));
+                            }
+                            _ => {}
+                        }
+                        if let (Some(Price::PerUnit(a)) | Some(Price::Total(a)), Some(_)) =
+                            (&p.price, &p.cost)
+                        {
+                            add(&a.commodity, 0);
+                        }
+                    }
+                }
+                Entry::Close { .. } => {}
+            }
+        }
+
+        // Digits of the explicit amounts in each currency
+        for d in &self.journal.directives {
+            if let Entry::Transaction(t) = &d.entry {
+                for p in &t.postings {
+                    if let Some(u) = &p.units {
+                        if let Some(e) = max_digits.get_mut(&u.commodity) {
+                            *e = (*e).max(digits(&u.number));
+                        }
+                    }
+                    if p.cost.is_some() {
+                        self.stocks.insert(p.units.as_ref().map(|u| u.commodity.clone())
+                            .unwrap_or_default());
+                    }
+                }
+            }
+        }
+
+        for symbol in order {
+            let is_currency = !self.stocks.contains(&symbol)
+                && symbol.len() == 3
+                && symbol.chars().all(|c| c.is_ascii_uppercase());
+            let (kind, min_digits) = match is_currency {
+                true => (commodity_kinds::CURRENCY, 2),
+                false => (commodity_kinds::STOCK, 4),
+            };
+            if !is_currency {
+                self.stocks.insert(symbol.clone());
+            }
+            let id = self.imp.commodity(
+                &symbol,
+                names.get(&symbol).map(|n| n.as_str()),
+                kind,
+                scale_for(max_digits[&symbol].max(min_digits)),
+            )?;
+            self.commodities.insert(symbol, id);
+        }
+        Ok(())
+    }
+
+    /// Convert the amounts of the postings to the currency of the
+    /// transaction, and compute the elided amounts
+
+    fn resolve(&mut self) -> Result<Vec<Option<Vec<Resolved>>>, AlereError> {
+        // Positions held at cost: quantity and total cost
+        let mut lots: HashMap<(String, String), (Decimal, Amount)> = HashMap::new();
+        let mut result = vec![];
+
+        for d in &self.journal.directives {
+            let t = match &d.entry {
+                Entry::Transaction(t) => t,
+                _ => {
+                    result.push(None);
+                    continue;
+                }
+            };
+            let mut resolved = vec![];
+            let mut elided: Option<&Posting> = None;
+            let mut residual: Vec<(String, Decimal, u32)> = vec![];
+
+            for p in &t.postings {
+                let units = match &p.units {
+                    None if elided.is_some() => {
+                        return Err(error(&p.location, "only one posting can have no amount"));
+                    }
+                    None => {
+                        elided = Some(p);
+                        continue;
+                    }
+                    Some(u) => u,
+                };
+                let key = (p.account.clone(), units.commodity.clone());
+                let weight = match (&p.cost, &p.price) {
+                    (Some(Cost::PerUnit(c)), _) => Amount {
+                        number: units.number * c.number,
+                        commodity: c.commodity.clone(),
+                    },
+                    (Some(Cost::Total(c)), _) => Amount {
+                        number: c.number * sign(&units.number),
+                        commodity: c.commodity.clone(),
+                    },
+                    (Some(Cost::Reduce), price) => match (lots.get(&key), price) {
+                        (Some((qty, cost)), _) if !qty.is_zero() => Amount {
+                            number: units.number * cost.number / qty,
+                            commodity: cost.commodity.clone(),
+                        },
+                        (_, Some(Price::PerUnit(pr))) => Amount {
+                            number: units.number * pr.number,
+                            commodity: pr.commodity.clone(),
+                        },
+                        (_, Some(Price::Total(pr))) => Amount {
+                            number: pr.number * sign(&units.number),
+                            commodity: pr.commodity.clone(),
+                        },
+                        _ => return Err(error(&p.location, "no position to reduce")),
+                    },
+                    (None, Some(Price::PerUnit(pr))) => Amount {
+                        number: units.number * pr.number,
+                        commodity: pr.commodity.clone(),
+                    },
+                    (None, Some(Price::Total(pr))) => Amount {
+                        number: pr.number * sign(&units.number),
+                        commodity: pr.commodity.clone(),
+                    },
+                    (None, None) => units.clone(),
+                };
+
+                // Round computed amounts to the precision of the currency
+                let weight_scale = self.imp.price_scale(self.commodity(&weight.commodity, &p.location)?);
+                let weight = Amount {
+                    number: weight.number.round_dp(scale_digits(weight_scale)),
+                    commodity: weight.commodity,
+                };
+
+                if p.cost.is_some() {
+                    let lot = lots.entry(key).or_insert_with(|| (
+                        Decimal::ZERO,
+                        Amount { number: Decimal::ZERO, commodity: weight.commodity.clone() },
+                    ));
+                    lot.0 += units.number;
+                    lot.1.number += weight.number;
+                }
+
+                match residual.iter_mut().find(|(c, _, _)| *c == weight.commodity) {
+                    Some(r) => {
+                        r.1 += weight.number;
+                        r.2 = r.2.max(digits(&units.number));
+                    }
+                    None => residual.push((
+                        weight.commodity.clone(), weight.number, digits(&units.number))),
+                }
+                resolved.push(Resolved {
+                    account: p.account.clone(),
+                    units: units.clone(),
+                    weight,
+                    reconcile: reconcile(&p.flag),
+                    post_date: post_date(p, d.date),
+                });
+            }
+
+            for (commodity, sum, max_digits) in residual {
+                if sum.is_zero() {
+                    continue;
+                }
+                match elided {
+                    Some(p) => {
+                        let amount = Amount { number: -sum, commodity };
+                        resolved.push(Resolved {
+                            account: p.account.clone(),
+                            units: amount.clone(),
+                            weight: amount,
+                            reconcile: reconcile(&p.flag),
+                            post_date: post_date(p, d.date),
+                        });
+                    }
+                    None if sum.abs() > Decimal::new(5, max_digits + 1) => {
+                        self.imp.warn(format!(
+                            "{}: transaction does not balance ({} {})",
+                            d.location, sum, commodity));
+                    }
+                    None => {}
+                }
+            }
+
+            for r in &resolved {
+                self.main_commodity
+                    .entry(r.account.clone())
+                    .or_insert_with(|| r.units.commodity.clone());
+                let e = self.scu.entry((r.account.clone(), r.units.commodity.clone())).or_default();
+                *e = (*e).max(digits(&r.units.number));
+            }
+            result.push(Some(resolved));
+        }
+        Ok(result)
+    }
+
+    /// The account used for a commodity in a Beancount account, created as
+    /// needed
+
+    fn account(&mut self, path: &str, symbol: &str, location: &str) -> Result<AccountId, AlereError> {
+        let key = (path.to_string(), symbol.to_string());
+        if let Some(id) = self.accounts.get(&key) {
+            return Ok(*id);
+        }
+        let main = self.main_commodity.entry(path.to_string()).or_insert_with(|| symbol.to_string()).clone();
+        if main != symbol {
+            // a child account for other commodities
+            let parent = self.account(path, &main, location)?;
+            let id = self.create(Some(parent), symbol, path, symbol, location)?;
+            self.accounts.insert(key, id);
+            return Ok(id);
+        }
+
+        let mut components: Vec<&str> = path.split(':').collect();
+        let leaf = components.pop().unwrap_or_default();
+        let mut parent = None;
+        for (idx, component) in components.iter().enumerate().skip(1) {
+            let prefix = components[..=idx].join(":");
+            parent = Some(match self.accounts.get(&(prefix.clone(), String::new())) {
+                Some(id) => *id,
+                None => {
+                    let id = self.create(parent, component, path, symbol, location)?;
+                    self.accounts.insert((prefix, String::new()), id);
+                    id
+                }
+            });
+        }
+        let name = self.names.get(path).cloned().unwrap_or_else(|| leaf.to_string());
+        let id = self.create(parent, &name, path, symbol, location)?;
+        self.accounts.insert(key, id);
+        Ok(id)
+    }
+
+    fn create(
+        &mut self,
+        parent: Option<AccountId>,
+        name: &str,
+        path: &str,
+        symbol: &str,
+        location: &str,
+    ) -> Result<AccountId, AlereError> {
+        let root = path.split(':').next().unwrap_or_default();
+        let kind = self.kind(root, symbol)
+            .ok_or_else(|| error(location, format!("invalid root account {}", root)))?;
+        let commodity = self.commodity(symbol, location)?;
+        let min_digits = match self.stocks.contains(symbol) {
+            true => 0,
+            false => 2,
+        };
+        let digits = self.scu
+            .get(&(path.to_string(), symbol.to_string()))
+            .copied()
+            .unwrap_or(0)
+            .max(min_digits);
+        self.imp.account(parent, name, kind, commodity, scale_for(digits))
+    }
+
+    fn import(&mut self) -> Result<(), AlereError> {
+        // Beancount directives can be in any order
+        self.journal.directives.sort_by_key(|d| {
+            let order = match d.entry {
+                Entry::Open { .. } | Entry::Commodity { .. } => 0,
+                Entry::Balance { .. } => 1,
+                Entry::Close { .. } => 3,
+                _ => 2,
+            };
+            (d.date, order)
+        });
+        self.imp.summary.ignored += self.journal.ignored;
+
+        for d in &self.journal.directives {
+            if let Entry::Open { account, currencies: c, meta } = &d.entry {
+                if let Some(first) = c.first() {
+                    self.main_commodity.insert(account.clone(), first.clone());
+                }
+                if let Some(name) = meta.get("name") {
+                    self.names.insert(account.clone(), name.clone());
+                }
+            }
+        }
+        self.create_commodities()?;
+        let resolved = self.resolve()?;
+
+        let directives = std::mem::take(&mut self.journal.directives);
+        for (d, resolved) in directives.iter().zip(resolved) {
+            match &d.entry {
+                Entry::Open { account, currencies, meta } => {
+                    let symbol = match (currencies.first(), self.main_commodity.get(account)) {
+                        (Some(c), _) | (None, Some(c)) => c.clone(),
+                        (None, None) => continue,  // never used
+                    };
+                    let id = self.account(account, &symbol, &d.location)?;
+                    self.imp.update_account(
+                        id,
+                        meta.get("description").map(|s| s.as_str()),
+                        meta.get("iban").map(|s| s.as_str()),
+                        meta.get("number").map(|s| s.as_str()),
+                        Some(d.date),
+                    )?;
+                }
+                Entry::Close { account } => {
+                    let ids: Vec<AccountId> = self.accounts
+                        .iter()
+                        .filter(|((path, symbol), _)| path == account && !symbol.is_empty())
+                        .map(|(_, id)| *id)
+                        .collect();
+                    for id in ids {
+                        self.imp.close_account(id)?;
+                    }
+                }
+                Entry::Commodity { .. } => {}
+                Entry::Price { commodity, amount } => {
+                    let origin = self.commodity(commodity, &d.location)?;
+                    let target = self.commodity(&amount.commodity, &d.location)?;
+                    self.imp.price(d.date, origin, target, amount.number)?;
+                }
+                Entry::Balance { .. } => {}  // once all transactions are imported
+                Entry::Transaction(t) => {
+                    let payee = match &t.payee {
+                        Some(p) => Some(self.imp.payee(p)?),
+                        None => None,
+                    };
+                    let mut splits = vec![];
+                    for r in resolved.unwrap_or_default() {
+                        splits.push(NewSplit {
+                            account: self.account(&r.account, &r.units.commodity, &d.location)?,
+                            qty: r.units.number,
+                            value: r.weight.number,
+                            value_commodity: self.commodity(&r.weight.commodity, &d.location)?,
+                            reconcile: r.reconcile,
+                            reconcile_date: None,
+                            post_date: r.post_date,
+                            payee,
+                        });
+                    }
+                    let mut tags = vec![];
+                    for tag in &t.tags {
+                        tags.push(self.imp.tag(tag)?);
+                    }
+                    self.imp.transaction(&NewTransaction {
+                        timestamp: d.date,
+                        memo: Some(t.narration.clone()).filter(|n| !n.is_empty()),
+                        check_number: t.meta.get("check").cloned(),
+                        tags,
+                        splits,
+                        scheduled: None,
+                        last_occurrence: None,
+                    })?;
+                }
+            }
+        }
+
+        for d in &directives {
+            if let Entry::Balance { account, amount } = &d.entry {
+                let id = self.account(account, &amount.commodity, &d.location)?;
+                self.imp.checkpoint(id, d.date, amount.number)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn reconcile(flag: &Option<String>) -> &'static str {
+    match flag.as_deref() {
+        Some("*") => "R",
+        Some("!") => "C",
+        _ => "n",
+    }
+}
+
+fn post_date(p: &Posting, date: NaiveDate) -> NaiveDate {
+    p.meta.get("post-date").and_then(|d| parse_date(d)).unwrap_or(date)
+}
+
+/// Import a Beancount file (and those it includes)
+
+pub fn import(imp: &mut Importer, path: &Path) -> Result<(), AlereError> {
+    let mut journal = Journal::default();
+    journal.parse_file(path, 0)?;
+    BeancountImport {
+        imp,
+        journal,
+        commodities: HashMap::new(),
+        stocks: HashSet::new(),
+        main_commodity: HashMap::new(),
+        names: HashMap::new(),
+        scu: HashMap::new(),
+        accounts: HashMap::new(),
+    }
+    .import()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connections::test_database;
+    use crate::export_journal::{write_journal, JournalFormat};
+    use crate::importer::import_test_file;
+
+    // Once scaled, the amounts do not fit in 32 bits
+    const BOOK: &str = r#"
+1970-01-01 commodity EUR
+  price-scale: 100
+2026-01-01 open Assets:Checking EUR
+2026-01-01 open Income:Lottery EUR
+
+2026-01-02 * "jackpot"
+  Assets:Checking  30000000.25 EUR
+  Income:Lottery  -30000000.25 EUR
+
+2026-01-03 balance Assets:Checking  30000000.25 EUR
+"#;
+
+    fn export() -> String {
+        let mut out = vec![];
+        write_journal(&mut out, JournalFormat::BEANCOUNT).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn large_amounts() {
+        let exported = {
+            let _db = test_database("beancount_large");
+            let summary = import_test_file("book.beancount", BOOK, None);
+            assert_eq!(summary.transactions, 1);
+            assert_eq!(summary.checkpoints.len(), 0, "{:?}", summary.checkpoints);
+            export()
+        };
+        assert!(exported.contains("Assets:Checking  30000000.25 EUR"), "{}", exported);
+
+        let _db = test_database("beancount_large_again");
+        let summary = import_test_file("book.beancount", &exported, None);
+        assert_eq!(summary.transactions, 1);
+        assert_eq!(export(), exported);
+    }
+}
@@ -0,0 +1,381 @@
+//! Import bank statements in the ISO 20022 cash management formats:
+//! camt.053 (end of day statements), camt.052 (intraday reports) and
+//! camt.054 (debit and credit notifications).
+//!
+//! Each statement goes to the account with the same IBAN, else to the
+//! default account of the importer, else to a new bank account.
+//! The booking date of an entry is the date of the transaction, and its
+//! value date the date of the split in the account. Only booked entries are
+//! imported, since pending ones may still change.
+//! The counterparts of entries (debtor of credits, creditor of debits)
+//! become payees, with their IBAN. Statements have no categories: the other
+//! side of an entry is one of our accounts when the IBAN of the counterpart
+//! matches, or else "Uncategorized". Batch entries get one split for each
+//! of their transactions.
+//! The opening balance of each statement plus its entries must give its
+//! closing balance, or a warning is reported. The closing balance is also
+//! compared with the balance of the account (see Importer::checkpoint).
+
+use super::accounts::{commodity_kinds, AccountKindCategory};
+use super::errors::AlereError;
+use super::importer::{kind_names, Importer, NewSplit, NewTransaction, UNCATEGORIZED};
+use super::models::AccountId;
+use super::xml::{self, Element};
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use std::path::Path;
+use std::str::FromStr;
+
+const OPENING_BALANCES: &str = "Opening Balances";
+
+fn invalid(message: impl std::fmt::Display) -> AlereError {
+    AlereError::parse(format!("Invalid camt file: {}", message))
+}
+
+/// The date of an element, which has either a "Dt" or a "DtTm" child
+fn date_of(e: Option<&Element>) -> Option<NaiveDate> {
+    let e = e?;
+    let text = e.text_of("Dt").or_else(|| e.text_of("DtTm"))?;
+    NaiveDate::parse_from_str(text.get(..10)?, "%Y-%m-%d").ok()
+}
+
+/// Whether an element is a credit, from its "CdtDbtInd" child
+fn is_credit(e: &Element) -> Option<bool> {
+    match e.text_of("CdtDbtInd")? {
+        "CRDT" => Some(true),
+        "DBIT" => Some(false),
+        _ => None,
+    }
+}
+
+fn signed(amount: &Element, credit: bool) -> Option<Decimal> {
+    let value = Decimal::from_str(amount.text.trim()).ok()?;
+    Some(if credit { value } else { -value })
+}
+
+/// The signed amount of an entry or balance, and its currency
+fn amount(e: &Element) -> Option<(Decimal, &str)> {
+    let amt = e.child("Amt")?;
+    Some((signed(amt, is_credit(e)?)?, amt.attribute("Ccy").unwrap_or_default()))
+}
+
+/// The signed amount of a transaction in a batch entry. Older versions
+/// of the format have no direction for transactions.
+
+fn transaction_amount(tx: &Element, entry_credit: bool) -> Option<Decimal> {
+    let amt = tx.child("Amt").or_else(|| tx.find("AmtDtls/TxAmt/Amt"))?;
+    signed(amt, is_credit(tx).unwrap_or(entry_credit))
+}
+
+fn non_empty(s: Option<&str>) -> Option<&str> {
+    s.filter(|s| !s.is_empty())
+}
+
+/// The name and IBAN of the counterpart of a transaction
+fn counterpart(tx: &Element, credit: bool) -> (Option<&str>, Option<&str>) {
+    let (party, account) = if credit { ("Dbtr", "DbtrAcct") } else { ("Cdtr", "CdtrAcct") };
+    let parties = match tx.child("RltdPties") {
+        Some(p) => p,
+        None => return (None, None),
+    };
+    let name = parties
+        .find(party)
+        .and_then(|p| non_empty(p.text_of("Nm")).or_else(|| non_empty(p.text_of("Pty/Nm"))));
+    let iban = parties.find(account).and_then(|a| non_empty(a.text_of("Id/IBAN")));
+    (name, iban)
+}
+
+/// The description of a transaction, from its remittance information
+fn transaction_memo(tx: &Element) -> Option<String> {
+    let unstructured: Vec<&str> = tx
+        .child("RmtInf")
+        .map(|r| r.children("Ustrd").map(|u| u.text.trim()).filter(|u| !u.is_empty()).collect())
+        .unwrap_or_default();
+    if !unstructured.is_empty() {
+        return Some(unstructured.join(" "));
+    }
+    non_empty(tx.text_of("RmtInf/Strd/CdtrRefInf/Ref"))
+        .or_else(|| non_empty(tx.text_of("AddtlTxInf")))
+        .map(str::to_string)
+}
+
+/// A balance of the statement, from its type code
+fn balance(stmt: &Element, codes: &[&str]) -> Option<(Decimal, NaiveDate)> {
+    codes.iter().find_map(|code| {
+        let b = stmt
+            .children("Bal")
+            .find(|b| b.text_of("Tp/CdOrPrtry/Cd") == Some(code))?;
+        Some((amount(b)?.0, date_of(b.child("Dt"))?))
+    })
+}
+
+struct CamtImport<'a, 'b> {
+    imp: &'b mut Importer<'a>,
+
+    // Transfers already imported, whose other side is expected later:
+    // (booking date, account of the other side, account, amount for the
+    // other side)
+    transfers: Vec<(NaiveDate, AccountId, AccountId, Decimal)>,
+}
+
+impl<'a, 'b> CamtImport<'a, 'b> {
+    /// The account of a statement, and whether it was just created
+    fn account(&mut self, stmt: &Element) -> Result<(AccountId, bool), AlereError> {
+        let iban = non_empty(stmt.text_of("Acct/Id/IBAN"));
+        if let Some(a) = iban.map(|i| self.imp.find_account_iban(i)).transpose()?.flatten() {
+            return Ok((a, false));
+        }
+        if let Some(a) = self.imp.default_account {
+            return Ok((a, false));
+        }
+        let number = non_empty(stmt.text_of("Acct/Id/Othr/Id"));
+        let currency = non_empty(stmt.text_of("Acct/Ccy")).or_else(|| {
+            stmt.children("Bal").chain(stmt.children("Ntry")).find_map(|e| amount(e).map(|a| a.1))
+        });
+        let currency = match non_empty(currency) {
+            Some(c) => self.imp.commodity(c, None, commodity_kinds::CURRENCY, 100)?,
+            None => self.imp.default_currency()?,
+        };
+        let name = non_empty(stmt.text_of("Acct/Nm"))
+            .or(iban)
+            .or(number)
+            .ok_or_else(|| invalid("statement without an account"))?;
+        let created = self.imp.find_account(None, name).is_none();
+        let account = self.imp.account(
+            None,
+            name,
+            (kind_names::BANK, AccountKindCategory::EQUITY),
+            currency,
+            100,
+        )?;
+        self.imp.update_account(account, None, iban, number, None)?;
+        Ok((account, created))
+    }
+
+    /// Whether the other side of this transfer was already imported
+    fn take_transfer(
+        &mut self,
+        date: NaiveDate,
+        account: AccountId,
+        other: AccountId,
+        amount: Decimal,
+    ) -> bool {
+        match self.transfers.iter().position(|t| *t == (date, account, other, amount)) {
+            Some(idx) => {
+                self.transfers.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn entry(
+        &mut self,
+        statement: &str,
+        entry: &Element,
+        account: AccountId,
+    ) -> Result<(), AlereError> {
+        let (amount, _) = amount(entry)
+            .ok_or_else(|| invalid(format!("statement {}: entry without amount", statement)))?;
+        let credit = amount >= Decimal::ZERO;
+        let value_date = date_of(entry.child("ValDt"));
+        let booking = date_of(entry.child("BookgDt"))
+            .or(value_date)
+            .ok_or_else(|| invalid(format!("statement {}: entry without date", statement)))?;
+        let currency = self.imp.account_commodity(account)
+            .ok_or_else(|| AlereError::not_found(format!("No account {}", account)))?;
+
+        // Batch entries are split when the amounts of their transactions
+        // add up to the entry.
+        let details: Vec<&Element> = entry
+            .children("NtryDtls")
+            .flat_map(|d| d.children("TxDtls"))
+            .collect();
+        let amounts: Option<Vec<Decimal>> =
+            details.iter().map(|tx| transaction_amount(tx, credit)).collect();
+        let parts: Vec<(Decimal, Option<&Element>)> = match amounts {
+            Some(a) if details.len() > 1 && a.iter().sum::<Decimal>() == amount => {
+                a.into_iter().zip(details.iter().map(|tx| Some(*tx))).collect()
+            }
+            _ => vec![(amount, details.first().copied())],
+        };
+
+        let mut own = amount;
+        let mut splits = vec![];
+        let mut memos: Vec<String> = vec![];
+        let mut check_number = None;
+        let mut own_payee = None;
+        for (part, tx) in &parts {
+            let (name, iban) = tx.map(|tx| counterpart(tx, *part >= Decimal::ZERO)).unwrap_or_default();
+            let payee = match name.or(iban) {
+                Some(n) => Some(self.imp.payee_iban(n, iban)?),
+                None => None,
+            };
+            let other = match iban.map(|i| self.imp.find_account_iban(i)).transpose()?.flatten() {
+                Some(other) if other != account => {
+                    if self.take_transfer(booking, account, other, *part) {
+                        own -= *part;
+                        continue;
+                    }
+                    self.transfers.push((booking, other, account, -*part));
+                    other
+                }
+                _ => {
+                    let kind = match *part > Decimal::ZERO {
+                        true => (kind_names::INCOME, AccountKindCategory::INCOME),
+                        false => (kind_names::EXPENSE, AccountKindCategory::EXPENSE),
+                    };
+                    self.imp.account(None, UNCATEGORIZED, kind, currency, 100)?
+                }
+            };
+            if parts.len() == 1 {
+                own_payee = payee;
+            }
+            if let Some(m) = tx.and_then(transaction_memo) {
+                if !memos.contains(&m) {
+                    memos.push(m);
+                }
+            }
+            check_number = check_number.or_else(|| tx.and_then(|t| non_empty(t.text_of("Refs/ChqNb"))));
+            splits.push(NewSplit {
+                account: other,
+                qty: -*part,
+                value: -*part,
+                value_commodity: currency,
+                reconcile: "n",
+                reconcile_date: None,
+                post_date: booking,
+                payee,
+            });
+        }
+        if splits.is_empty() && own.is_zero() {
+            return Ok(());
+        }
+        if memos.is_empty() {
+            memos.extend(non_empty(entry.text_of("AddtlNtryInf")).map(str::to_string));
+        }
+        splits.insert(0, NewSplit {
+            account,
+            qty: own,
+            value: own,
+            value_commodity: currency,
+            reconcile: "C",  // the bank has booked it
+            reconcile_date: None,
+            post_date: value_date.unwrap_or(booking),
+            payee: own_payee,
+        });
+        self.imp.transaction(&NewTransaction {
+            timestamp: booking,
+            memo: (!memos.is_empty()).then(|| memos.join("; ")),
+            check_number: check_number.map(str::to_string),
+            tags: vec![],
+            splits,
+            scheduled: None,
+            last_occurrence: None,
+        })?;
+        Ok(())
+    }
+
+    fn statement(
+        &mut self,
+        stmt: &Element,
+        account: AccountId,
+        created: bool,
+    ) -> Result<(), AlereError> {
+        let id = stmt.text_of("Id").unwrap_or_default();
+        let currency = self.imp.account_commodity(account);
+        let opening = balance(stmt, &["OPBD", "PRCD"]);
+        let closing = balance(stmt, &["CLBD"]);
+
+        // A new account starts with the opening balance of its statement
+        if let (true, Some((opening, date))) = (created, opening) {
+            if !opening.is_zero() {
+                let currency = currency.unwrap_or_default();
+                let equity = self.imp.account(
+                    None,
+                    OPENING_BALANCES,
+                    (kind_names::EQUITY, AccountKindCategory::EQUITY),
+                    currency,
+                    100,
+                )?;
+                let split = |account, amount, reconcile| NewSplit {
+                    account,
+                    qty: amount,
+                    value: amount,
+                    value_commodity: currency,
+                    reconcile,
+                    reconcile_date: None,
+                    post_date: date,
+                    payee: None,
+                };
+                self.imp.transaction(&NewTransaction {
+                    timestamp: date,
+                    memo: Some("Opening balance".to_string()),
+                    check_number: None,
+                    tags: vec![],
+                    splits: vec![split(account, opening, "C"), split(equity, -opening, "n")],
+                    scheduled: None,
+                    last_occurrence: None,
+                })?;
+            }
+        }
+
+        let mut movements = Decimal::ZERO;
+        for entry in stmt.children("Ntry") {
+            let status = entry.text_of("Sts/Cd").or_else(|| entry.text_of("Sts"));
+            if matches!(status, Some(s) if s != "BOOK") {
+                self.imp.summary.ignored += 1;
+                continue;
+            }
+            let (amount, ccy) = amount(entry)
+                .ok_or_else(|| invalid(format!("statement {}: entry without amount", id)))?;
+            movements += amount;
+            if !ccy.is_empty() && self.imp.find_commodity(ccy) != currency {
+                self.imp.summary.ignored += 1;
+                self.imp.warn(format!(
+                    "Statement {}: entry of {} {} is not in the currency of the account",
+                    id, amount, ccy));
+                continue;
+            }
+            self.entry(id, entry, account)?;
+        }
+
+        if let (Some((opening, _)), Some((closing, _))) = (opening, closing) {
+            if opening + movements != closing {
+                self.imp.warn(format!(
+                    "Statement {}: the opening balance {} plus the entries {} is {}, \
+                     but the closing balance is {}",
+                    id, opening, movements, opening + movements, closing));
+            }
+        }
+        if let Some((closing, date)) = closing {
+            self.imp.checkpoint(account, date.succ_opt().unwrap_or(date), closing)?;
+        }
+        Ok(())
+    }
+}
+
+/// Import all the statements of a camt file
+pub fn import(imp: &mut Importer, path: &Path) -> Result<(), AlereError> {
+    let text = std::fs::read_to_string(path)?;
+    let root = xml::parse(&text)?;
+    let message = root
+        .children
+        .iter()
+        .find(|c| c.is("BkToCstmrStmt") || c.is("BkToCstmrAcctRpt") || c.is("BkToCstmrDbtCdtNtfctn"))
+        .ok_or_else(|| invalid("not a camt.052, camt.053 or camt.054 document"))?;
+    let mut camt = CamtImport { imp, transfers: vec![] };
+
+    // All accounts are found first, so that transfers between the
+    // statements of the file are recognized
+    let statements = message
+        .children
+        .iter()
+        .filter(|c| c.is("Stmt") || c.is("Rpt") || c.is("Ntfctn"))
+        .map(|stmt| Ok((stmt, camt.account(stmt)?)))
+        .collect::<Result<Vec<_>, AlereError>>()?;
+    for (stmt, (account, created)) in statements {
+        camt.statement(stmt, account, created)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,973 @@
+//! Import a GnuCash book, saved either as XML (usually gzipped) or as
+//! SQLite. Both are first read into the same structures, which are then
+//! imported.
+//!
+//! The model of GnuCash is close to ours: splits have a quantity (in the
+//! commodity of the account, with its scu) and a value (in the currency of
+//! the transaction).
+//! Splits in trading accounts are not imported: the values of the other
+//! splits already balance the transaction.
+//! Scheduled transactions are imported when their amounts are plain numbers
+//! (not formulas with variables), and only their first recurrence is used.
+//! Hidden accounts are imported as closed.
+
+use super::accounts::{commodity_kinds, AccountKindCategory};
+use super::errors::AlereError;
+use super::importer::{kind_names, Importer, NewSplit, NewTransaction};
+use super::models::{AccountId, CommodityId};
+use super::xml::{self, Element};
+use chrono::{Datelike, Duration, NaiveDate};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer, Nullable, Text};
+use diesel::sqlite::SqliteConnection;
+use flate2::read::GzDecoder;
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+
+type Guid = String;
+
+/// Scale for the prices of commodities that are not currencies
+const STOCK_PRICE_SCALE: i32 = 10_000;
+
+struct Commodity {
+    key: String,  // "namespace::mnemonic"
+    mnemonic: String,
+    name: Option<String>,
+    is_currency: bool,
+    fraction: i64,
+}
+
+struct Account {
+    guid: Guid,
+    name: String,
+    kind: String,   // ASSET, BANK, STOCK, ...
+    commodity: Option<String>,
+    scu: i64,
+    parent: Option<Guid>,
+    code: Option<String>,
+    description: Option<String>,
+    hidden: bool,
+}
+
+struct Split {
+    account: Guid,
+    value: Decimal,
+    quantity: Decimal,
+    reconciled: String,
+    reconcile_date: Option<NaiveDate>,
+    memo: String,
+    sx_account: Option<Guid>,      // for templates of scheduled transactions
+    sx_amount: Option<Decimal>,
+}
+
+struct Transaction {
+    currency: String,
+    num: String,
+    date: NaiveDate,
+    description: String,
+    splits: Vec<Split>,
+}
+
+struct Price {
+    commodity: String,
+    currency: String,
+    date: NaiveDate,
+    value: Decimal,
+}
+
+struct Recurrence {
+    mult: i64,
+    period: String,
+    start: NaiveDate,
+}
+
+struct Schedule {
+    name: String,
+    enabled: bool,
+    start: NaiveDate,
+    end: Option<NaiveDate>,
+    last: Option<NaiveDate>,
+    num_occur: i64,
+    template_account: Guid,
+    recurrences: Vec<Recurrence>,
+}
+
+#[derive(Default)]
+struct Book {
+    commodities: Vec<Commodity>,
+    accounts: Vec<Account>,
+    transactions: Vec<Transaction>,
+    templates: Vec<Transaction>,
+    prices: Vec<Price>,
+    schedules: Vec<Schedule>,
+}
+
+fn invalid(message: impl std::fmt::Display) -> AlereError {
+    AlereError::parse(format!("Invalid GnuCash book: {}", message))
+}
+
+/// A GnuCash numeric, stored as a fraction
+fn numeric(num: i64, denom: i64) -> Result<Decimal, AlereError> {
+    if denom == 0 {
+        return Err(invalid("zero denominator"));
+    }
+    Ok(Decimal::from(num) / Decimal::from(denom))
+}
+
+fn parse_numeric(s: &str) -> Result<Decimal, AlereError> {
+    let (num, denom) = s.trim().split_once('/').unwrap_or((s.trim(), "1"));
+    match (num.parse(), denom.parse()) {
+        (Ok(n), Ok(d)) => numeric(n, d),
+        _ => Err(invalid(format!("numeric {:?}", s))),
+    }
+}
+
+/// Parse dates in any of the formats used by GnuCash ("2020-01-05 10:59:00
+/// +0000", "20200105105900", "2020-01-05"). Times stored in UTC are
+/// rounded to the nearest day: older versions stored the local midnight
+/// (the previous evening in UTC for timezones east of Greenwich), while
+/// recent ones use 10:59 UTC.
+/// :param utc: whether the time is in UTC (SQLite) or local (XML)
+
+fn parse_date(s: &str, utc: bool) -> Option<NaiveDate> {
+    let digits: String = s.chars().filter(|c| c.is_ascii_digit()).take(10).collect();
+    let date = NaiveDate::parse_from_str(digits.get(..8)?, "%Y%m%d").ok()?;
+    let hour: u32 = digits.get(8..10).and_then(|h| h.parse().ok()).unwrap_or(0);
+    Some(match utc && hour >= 12 {
+        true => date + Duration::days(1),
+        false => date,
+    })
+}
+
+fn is_currency_namespace(space: &str) -> bool {
+    space == "CURRENCY" || space == "ISO4217"
+}
+
+fn non_empty(s: Option<&str>) -> Option<String> {
+    s.map(|s| s.trim()).filter(|s| !s.is_empty()).map(|s| s.to_string())
+}
+
+/// Flatten the slots of an element, with "frame/key" for nested frames
+fn xml_slots<'a>(slots: Option<&'a Element>, prefix: &str, out: &mut HashMap<String, &'a Element>) {
+    for slot in slots.into_iter().flat_map(|s| s.children("slot")) {
+        let (key, value) = match (slot.text_of("slot:key"), slot.child("slot:value")) {
+            (Some(k), Some(v)) => (format!("{}{}", prefix, k), v),
+            _ => continue,
+        };
+        if value.attribute("type") == Some("frame") {
+            xml_slots(Some(value), &format!("{}/", key), out);
+        } else {
+            out.insert(key, value);
+        }
+    }
+}
+
+/// Parse a number typed in a formula, with either a decimal point or a
+/// decimal comma, and optional thousands separators. "1,250" is rejected,
+/// since it could be 1250 or 1.25.
+
+fn formula_number(f: &str) -> Option<Decimal> {
+    let (int, frac) = match f.rfind([',', '.']) {
+        None => (f, ""),
+        Some(pos) => {
+            let sep = &f[pos..pos + 1];
+            let ambiguous = sep == "," && !f.contains('.') && f.matches(',').count() == 1;
+            if ambiguous && f.len() - pos - 1 == 3 {
+                return None;
+            }
+            match f.matches(sep).count() {
+                1 => (&f[..pos], &f[pos + 1..]),
+                _ => (f, ""),  // only thousands separators
+            }
+        }
+    };
+
+    // Thousands separators must be followed by three digits
+    let groups: Vec<&str> = int.split([',', '.']).collect();
+    if groups[1..].iter().any(|g| g.len() != 3 || !g.bytes().all(|b| b.is_ascii_digit())) {
+        return None;
+    }
+    let mut number = groups.concat();
+    if !frac.is_empty() {
+        number.push('.');
+        number.push_str(frac);
+    }
+    number.parse().ok()
+}
+
+/// The amount of a split in a scheduled transaction: debit - credit
+fn sx_amount(
+    debit: Option<Decimal>,
+    credit: Option<Decimal>,
+    debit_formula: Option<&str>,
+    credit_formula: Option<&str>,
+) -> Option<Decimal> {
+    // Formulas can be plain numbers, or use variables that we cannot
+    // evaluate
+    let formula = |f: Option<&str>| -> Option<Decimal> {
+        match f.map(|f| f.trim()) {
+            None => Some(Decimal::ZERO),
+            Some("") => Some(Decimal::ZERO),
+            Some(f) => formula_number(f),
+        }
+    };
+    let debit = debit.or_else(|| formula(debit_formula))?;
+    let credit = credit.or_else(|| formula(credit_formula))?;
+    Some(debit - credit)
+}
+
+fn xml_commodity_key(e: Option<&Element>) -> Option<String> {
+    let e = e?;
+    Some(format!("{}::{}", e.text_of("cmdty:space")?, e.text_of("cmdty:id")?))
+}
+
+fn xml_date(e: Option<&Element>) -> Option<NaiveDate> {
+    let e = e?;
+    let text = e.text_of("ts:date").or_else(|| e.text_of("gdate"))?;
+    parse_date(text, false)
+}
+
+fn xml_account(e: &Element) -> Result<Account, AlereError> {
+    let mut slots = HashMap::new();
+    xml_slots(e.child("act:slots"), "", &mut slots);
+    Ok(Account {
+        guid: e.text_of("act:id").ok_or_else(|| invalid("account without id"))?.to_string(),
+        name: e.text_of("act:name").unwrap_or_default().to_string(),
+        kind: e.text_of("act:type").unwrap_or_default().to_string(),
+        commodity: xml_commodity_key(e.child("act:commodity")),
+        scu: e.text_of("act:commodity-scu").and_then(|s| s.parse().ok()).unwrap_or(100),
+        parent: e.text_of("act:parent").map(|s| s.to_string()),
+        code: non_empty(e.text_of("act:code")),
+        description: non_empty(e.text_of("act:description")),
+        hidden: slots.get("hidden").map(|v| v.text.trim() == "true").unwrap_or(false),
+    })
+}
+
+fn xml_transaction(e: &Element) -> Result<Transaction, AlereError> {
+    let id = e.text_of("trn:id").unwrap_or_default();
+    let mut splits = vec![];
+    for s in e.child("trn:splits").into_iter().flat_map(|s| s.children("trn:split")) {
+        let mut slots = HashMap::new();
+        xml_slots(s.child("split:slots"), "", &mut slots);
+        let numeric_slot = |key: &str| slots.get(key).and_then(|v| parse_numeric(&v.text).ok());
+        let text_slot = |key: &str| slots.get(key).map(|v| v.text.as_str());
+        splits.push(Split {
+            account: s.text_of("split:account")
+                .ok_or_else(|| invalid(format!("split without account in {}", id)))?
+                .to_string(),
+            value: parse_numeric(s.text_of("split:value").unwrap_or("0"))?,
+            quantity: parse_numeric(s.text_of("split:quantity").unwrap_or("0"))?,
+            reconciled: s.text_of("split:reconciled-state").unwrap_or("n").to_string(),
+            reconcile_date: xml_date(s.child("split:reconcile-date")),
+            memo: s.text_of("split:memo").unwrap_or_default().to_string(),
+            sx_account: slots.get("sched-xaction/account").map(|v| v.text.trim().to_string()),
+            sx_amount: sx_amount(
+                numeric_slot("sched-xaction/debit-numeric"),
+                numeric_slot("sched-xaction/credit-numeric"),
+                text_slot("sched-xaction/debit-formula"),
+                text_slot("sched-xaction/credit-formula"),
+            ),
+        });
+    }
+    Ok(Transaction {
+        currency: xml_commodity_key(e.child("trn:currency"))
+            .ok_or_else(|| invalid(format!("no currency for {}", id)))?,
+        num: e.text_of("trn:num").unwrap_or_default().to_string(),
+        date: xml_date(e.child("trn:date-posted"))
+            .ok_or_else(|| invalid(format!("no date for {}", id)))?,
+        description: e.text_of("trn:description").unwrap_or_default().to_string(),
+        splits,
+    })
+}
+
+fn read_xml(text: &str) -> Result<Book, AlereError> {
+    let root = xml::parse(text)?;
+    let book_element = root.child("gnc:book").unwrap_or(&root);
+    let mut book = Book::default();
+
+    for e in &book_element.children {
+        match e.name.as_str() {
+            "gnc:commodity" => {
+                let space = e.text_of("cmdty:space").unwrap_or_default();
+                let mnemonic = e.text_of("cmdty:id").unwrap_or_default();
+                book.commodities.push(Commodity {
+                    key: format!("{}::{}", space, mnemonic),
+                    mnemonic: mnemonic.to_string(),
+                    name: non_empty(e.text_of("cmdty:name")),
+                    is_currency: is_currency_namespace(space),
+                    fraction: e.text_of("cmdty:fraction")
+                        .and_then(|f| f.parse().ok())
+                        .unwrap_or(100),
+                });
+            }
+            "gnc:pricedb" => {
+                for p in e.children("price") {
+                    if let (Some(commodity), Some(currency), Some(date), Some(value)) = (
+                        xml_commodity_key(p.child("price:commodity")),
+                        xml_commodity_key(p.child("price:currency")),
+                        xml_date(p.child("price:time")),
+                        p.text_of("price:value"),
+                    ) {
+                        book.prices.push(Price {
+                            commodity, currency, date, value: parse_numeric(value)?,
+                        });
+                    }
+                }
+            }
+            "gnc:account" => book.accounts.push(xml_account(e)?),
+            "gnc:transaction" => book.transactions.push(xml_transaction(e)?),
+            "gnc:template-transactions" => {
+                for t in e.children("gnc:transaction") {
+                    book.templates.push(xml_transaction(t)?);
+                }
+            }
+            "gnc:schedxaction" => {
+                let name = e.text_of("sx:name").unwrap_or_default().to_string();
+                let recurrences = e
+                    .child("sx:schedule")
+                    .into_iter()
+                    .flat_map(|s| s.children("gnc:recurrence"))
+                    .filter_map(|r| Some(Recurrence {
+                        mult: r.text_of("recurrence:mult")?.parse().ok()?,
+                        period: r.text_of("recurrence:period_type")?.to_string(),
+                        start: xml_date(r.child("recurrence:start"))?,
+                    }))
+                    .collect();
+                book.schedules.push(Schedule {
+                    enabled: e.text_of("sx:enabled") != Some("n"),
+                    start: xml_date(e.child("sx:start"))
+                        .ok_or_else(|| invalid(format!("no start date for {}", name)))?,
+                    end: xml_date(e.child("sx:end")),
+                    last: xml_date(e.child("sx:last")),
+                    num_occur: e.text_of("sx:num-occur").and_then(|n| n.parse().ok()).unwrap_or(0),
+                    template_account: e.text_of("sx:templ-acct").unwrap_or_default().to_string(),
+                    recurrences,
+                    name,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(book)
+}
+
+#[derive(QueryableByName)]
+struct SqlBook {
+    #[sql_type = "Text"]
+    root_template_guid: String,
+}
+
+#[derive(QueryableByName)]
+struct SqlCommodity {
+    #[sql_type = "Text"]
+    guid: String,
+    #[sql_type = "Text"]
+    namespace: String,
+    #[sql_type = "Text"]
+    mnemonic: String,
+    #[sql_type = "Nullable<Text>"]
+    fullname: Option<String>,
+    #[sql_type = "BigInt"]
+    fraction: i64,
+}
+
+#[derive(QueryableByName)]
+struct SqlAccount {
+    #[sql_type = "Text"]
+    guid: String,
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Text"]
+    account_type: String,
+    #[sql_type = "Nullable<Text>"]
+    commodity_guid: Option<String>,
+    #[sql_type = "BigInt"]
+    commodity_scu: i64,
+    #[sql_type = "Nullable<Text>"]
+    parent_guid: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    code: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    description: Option<String>,
+    #[sql_type = "Integer"]
+    hidden: i32,
+}
+
+#[derive(QueryableByName)]
+struct SqlTransaction {
+    #[sql_type = "Text"]
+    guid: String,
+    #[sql_type = "Text"]
+    currency_guid: String,
+    #[sql_type = "Text"]
+    num: String,
+    #[sql_type = "Nullable<Text>"]
+    post_date: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    description: Option<String>,
+}
+
+#[derive(QueryableByName)]
+struct SqlSplit {
+    #[sql_type = "Text"]
+    guid: String,
+    #[sql_type = "Text"]
+    tx_guid: String,
+    #[sql_type = "Text"]
+    account_guid: String,
+    #[sql_type = "Text"]
+    memo: String,
+    #[sql_type = "Text"]
+    reconcile_state: String,
+    #[sql_type = "Nullable<Text>"]
+    reconcile_date: Option<String>,
+    #[sql_type = "BigInt"]
+    value_num: i64,
+    #[sql_type = "BigInt"]
+    value_denom: i64,
+    #[sql_type = "BigInt"]
+    quantity_num: i64,
+    #[sql_type = "BigInt"]
+    quantity_denom: i64,
+}
+
+#[derive(QueryableByName)]
+struct SqlPrice {
+    #[sql_type = "Text"]
+    commodity_guid: String,
+    #[sql_type = "Text"]
+    currency_guid: String,
+    #[sql_type = "Text"]
+    date: String,
+    #[sql_type = "BigInt"]
+    value_num: i64,
+    #[sql_type = "BigInt"]
+    value_denom: i64,
+}
+
+#[derive(QueryableByName)]
+struct SqlSchedule {
+    #[sql_type = "Text"]
+    guid: String,
+    #[sql_type = "Nullable<Text>"]
+    name: Option<String>,
+    #[sql_type = "Integer"]
+    enabled: i32,
+    #[sql_type = "Nullable<Text>"]
+    start_date: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    end_date: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    last_occur: Option<String>,
+    #[sql_type = "Integer"]
+    num_occur: i32,
+    #[sql_type = "Text"]
+    template_act_guid: String,
+}
+
+#[derive(QueryableByName)]
+struct SqlRecurrence {
+    #[sql_type = "Text"]
+    obj_guid: String,
+    #[sql_type = "Integer"]
+    recurrence_mult: i32,
+    #[sql_type = "Text"]
+    recurrence_period_type: String,
+    #[sql_type = "Text"]
+    recurrence_period_start: String,
+}
+
+#[derive(QueryableByName)]
+struct SqlSlot {
+    #[sql_type = "Text"]
+    obj_guid: String,
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Nullable<Text>"]
+    string_val: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    guid_val: Option<String>,
+    #[sql_type = "Nullable<BigInt>"]
+    numeric_val_num: Option<i64>,
+    #[sql_type = "Nullable<BigInt>"]
+    numeric_val_denom: Option<i64>,
+}
+
+fn read_sqlite(path: &Path) -> Result<Book, AlereError> {
+    let url = path.to_str()
+        .ok_or_else(|| AlereError::validation(format!("Invalid path {:?}", path)))?;
+    let c = SqliteConnection::establish(url)
+        .map_err(|e| AlereError::validation(format!("Cannot open {}: {}", url, e)))?;
+    let mut book = Book::default();
+
+    let mut commodity_keys = HashMap::new();
+    for cm in diesel::sql_query(
+        "SELECT guid, namespace, mnemonic, fullname, fraction FROM commodities"
+    ).load::<SqlCommodity>(&c)? {
+        let key = format!("{}::{}", cm.namespace, cm.mnemonic);
+        commodity_keys.insert(cm.guid, key.clone());
+        book.commodities.push(Commodity {
+            key,
+            is_currency: is_currency_namespace(&cm.namespace),
+            mnemonic: cm.mnemonic,
+            name: non_empty(cm.fullname.as_deref()),
+            fraction: cm.fraction,
+        });
+    }
+    let commodity = |guid: &str| -> Result<String, AlereError> {
+        commodity_keys.get(guid).cloned().ok_or_else(|| invalid(format!("no commodity {}", guid)))
+    };
+
+    for a in diesel::sql_query(
+        "SELECT guid, name, account_type, commodity_guid, commodity_scu, parent_guid,
+            code, description, COALESCE(hidden, 0) AS hidden
+         FROM accounts"
+    ).load::<SqlAccount>(&c)? {
+        book.accounts.push(Account {
+            commodity: match &a.commodity_guid {
+                Some(g) => Some(commodity(g)?),
+                None => None,
+            },
+            guid: a.guid,
+            name: a.name,
+            kind: a.account_type,
+            scu: a.commodity_scu,
+            parent: a.parent_guid,
+            code: non_empty(a.code.as_deref()),
+            description: non_empty(a.description.as_deref()),
+            hidden: a.hidden != 0,
+        });
+    }
+
+    // Slots of the splits of scheduled transactions, in a frame
+    let mut sx_slots: HashMap<String, Vec<SqlSlot>> = HashMap::new();
+    for s in diesel::sql_query(
+        "SELECT f.obj_guid, s.name, s.string_val, s.guid_val,
+            s.numeric_val_num, s.numeric_val_denom
+         FROM slots s JOIN slots f ON s.obj_guid = f.guid_val
+         WHERE f.name = 'sched-xaction'"
+    ).load::<SqlSlot>(&c)? {
+        sx_slots.entry(s.obj_guid.clone()).or_default().push(s);
+    }
+
+    let mut splits: HashMap<String, Vec<Split>> = HashMap::new();
+    for s in diesel::sql_query(
+        "SELECT guid, tx_guid, account_guid, memo, reconcile_state, reconcile_date,
+            value_num, value_denom, quantity_num, quantity_denom
+         FROM splits"
+    ).load::<SqlSplit>(&c)? {
+        let slots = sx_slots.remove(&s.guid).unwrap_or_default();
+        let slot = |key: &str| slots.iter().find(|sl| sl.name == format!("sched-xaction/{}", key));
+        let numeric_slot = |key: &str| match slot(key) {
+            Some(SqlSlot { numeric_val_num: Some(n), numeric_val_denom: Some(d), .. }) =>
+                numeric(*n, *d).ok(),
+            _ => None,
+        };
+        let text_slot = |key: &str| slot(key).and_then(|s| s.string_val.as_deref());
+        splits.entry(s.tx_guid.clone()).or_default().push(Split {
+            account: s.account_guid,
+            value: numeric(s.value_num, s.value_denom)?,
+            quantity: numeric(s.quantity_num, s.quantity_denom)?,
+            reconciled: s.reconcile_state,
+            reconcile_date: s.reconcile_date.as_deref().and_then(|d| parse_date(d, true)),
+            memo: s.memo,
+            sx_account: slot("account").and_then(|s| s.guid_val.clone()),
+            sx_amount: sx_amount(
+                numeric_slot("debit-numeric"),
+                numeric_slot("credit-numeric"),
+                text_slot("debit-formula"),
+                text_slot("credit-formula"),
+            ),
+        });
+    }
+
+    // Templates of scheduled transactions use accounts below a separate root
+    let mut templates: HashSet<String> = diesel::sql_query(
+        "SELECT root_template_guid FROM books"
+    )
+    .load::<SqlBook>(&c)?
+    .into_iter()
+    .map(|b| b.root_template_guid)
+    .collect();
+    // Accounts are not sorted, so iterate until all descendants are found
+    loop {
+        let before = templates.len();
+        for a in &book.accounts {
+            if a.parent.as_ref().map(|p| templates.contains(p)).unwrap_or(false) {
+                templates.insert(a.guid.clone());
+            }
+        }
+        if templates.len() == before {
+            break;
+        }
+    }
+    book.accounts.retain(|a| !templates.contains(&a.guid));
+
+    for t in diesel::sql_query(
+        "SELECT guid, currency_guid, num, post_date, description FROM transactions"
+    ).load::<SqlTransaction>(&c)? {
+        let transaction = Transaction {
+            currency: commodity(&t.currency_guid)?,
+            num: t.num,
+            date: t.post_date.as_deref().and_then(|d| parse_date(d, true))
+                .ok_or_else(|| invalid(format!("no date for {}", t.guid)))?,
+            description: t.description.unwrap_or_default(),
+            splits: splits.remove(&t.guid).unwrap_or_default(),
+        };
+        match transaction.splits.iter().any(|s| templates.contains(&s.account)) {
+            true => book.templates.push(transaction),
+            false => book.transactions.push(transaction),
+        }
+    }
+
+    for p in diesel::sql_query(
+        "SELECT commodity_guid, currency_guid, date, value_num, value_denom FROM prices"
+    ).load::<SqlPrice>(&c)? {
+        book.prices.push(Price {
+            commodity: commodity(&p.commodity_guid)?,
+            currency: commodity(&p.currency_guid)?,
+            date: parse_date(&p.date, true).ok_or_else(|| invalid("price without date"))?,
+            value: numeric(p.value_num, p.value_denom)?,
+        });
+    }
+
+    let mut recurrences: HashMap<String, Vec<Recurrence>> = HashMap::new();
+    for r in diesel::sql_query(
+        "SELECT obj_guid, recurrence_mult, recurrence_period_type, recurrence_period_start
+         FROM recurrences ORDER BY id"
+    ).load::<SqlRecurrence>(&c)? {
+        if let Some(start) = parse_date(&r.recurrence_period_start, false) {
+            recurrences.entry(r.obj_guid).or_default().push(Recurrence {
+                mult: r.recurrence_mult.into(),
+                period: r.recurrence_period_type,
+                start,
+            });
+        }
+    }
+    for s in diesel::sql_query(
+        "SELECT guid, name, enabled, start_date, end_date, last_occur, num_occur,
+            template_act_guid
+         FROM schedxactions"
+    ).load::<SqlSchedule>(&c)? {
+        let name = s.name.unwrap_or_default();
+        book.schedules.push(Schedule {
+            enabled: s.enabled != 0,
+            start: s.start_date.as_deref().and_then(|d| parse_date(d, false))
+                .ok_or_else(|| invalid(format!("no start date for {}", name)))?,
+            end: s.end_date.as_deref().and_then(|d| parse_date(d, false)),
+            last: s.last_occur.as_deref().and_then(|d| parse_date(d, false)),
+            num_occur: s.num_occur.into(),
+            template_account: s.template_act_guid,
+            recurrences: recurrences.remove(&s.guid).unwrap_or_default(),
+            name,
+        });
+    }
+    Ok(book)
+}
+
+/// The kind of account for a GnuCash account type. Trading accounts are
+/// not imported.
+
+fn account_kind(kind: &str) -> Option<(&'static str, AccountKindCategory)> {
+    Some(match kind {
+        "BANK" | "CASH" => (kind_names::BANK, AccountKindCategory::EQUITY),
+        "STOCK" | "MUTUAL" => (kind_names::STOCK, AccountKindCategory::EQUITY),
+        "ASSET" | "RECEIVABLE" => (kind_names::ASSET, AccountKindCategory::ASSET),
+        "CREDIT" | "LIABILITY" | "PAYABLE" => {
+            (kind_names::LIABILITY, AccountKindCategory::LIABILITY)
+        }
+        "INCOME" => (kind_names::INCOME, AccountKindCategory::INCOME),
+        "EXPENSE" => (kind_names::EXPENSE, AccountKindCategory::EXPENSE),
+        "EQUITY" => (kind_names::EQUITY, AccountKindCategory::EQUITY),
+        _ => return None,
+    })
+}
+
+fn reconcile(state: &str) -> &'static str {
+    match state {
+        "c" => "C",
+        "y" | "f" => "R",  // reconciled or frozen
+        _ => "n",
+    }
+}
+
+/// The recurrence rule for a scheduled transaction, or None if it cannot
+/// be represented.
+
+fn recurrence_rule(s: &Schedule) -> Option<String> {
+    let r = s.recurrences.first()?;
+    const WEEKDAYS: [&str; 7] = ["MO", "TU", "WE", "TH", "FR", "SA", "SU"];
+    let weekday = WEEKDAYS[r.start.weekday().num_days_from_monday() as usize];
+    let (freq, extra) = match r.period.as_str() {
+        "once" => return Some(String::new()),
+        "day" => ("DAILY", String::new()),
+        "week" => ("WEEKLY", String::new()),
+        "month" => ("MONTHLY", String::new()),
+        "end of month" => ("MONTHLY", ";BYMONTHDAY=-1".to_string()),
+        "nth weekday" => ("MONTHLY", format!(";BYDAY={}{}", (r.start.day() - 1) / 7 + 1, weekday)),
+        "last weekday" => ("MONTHLY", format!(";BYDAY=-1{}", weekday)),
+        "year" => ("YEARLY", String::new()),
+        _ => return None,
+    };
+    let mut rule = format!("FREQ={};INTERVAL={}{}", freq, r.mult.max(1), extra);
+    if let Some(end) = s.end {
+        rule.push_str(&end.format(";UNTIL=%Y%m%dT235959Z").to_string());
+    } else if s.num_occur > 0 {
+        rule.push_str(&format!(";COUNT={}", s.num_occur));
+    }
+    Some(rule)
+}
+
+struct GnucashImport<'a, 'b> {
+    imp: &'b mut Importer<'a>,
+    commodities: HashMap<String, CommodityId>,
+    accounts: HashMap<Guid, AccountId>,
+    skipped: HashSet<Guid>,  // root and trading accounts
+}
+
+impl<'a, 'b> GnucashImport<'a, 'b> {
+    fn commodities(&mut self, book: &Book) -> Result<(), AlereError> {
+        for cm in &book.commodities {
+            if cm.key.starts_with("template::") {
+                continue;
+            }
+            let (kind, scale) = match cm.is_currency {
+                true => (commodity_kinds::CURRENCY, cm.fraction.clamp(100, 1_000_000) as i32),
+                false => (commodity_kinds::STOCK, STOCK_PRICE_SCALE),
+            };
+            let id = self.imp.commodity(&cm.mnemonic, cm.name.as_deref(), kind, scale)?;
+            self.commodities.insert(cm.key.clone(), id);
+        }
+        Ok(())
+    }
+
+    fn commodity(&self, key: &str) -> Result<CommodityId, AlereError> {
+        self.commodities.get(key).copied().ok_or_else(|| invalid(format!("unknown commodity {}", key)))
+    }
+
+    /// Create the account and its parents
+    fn account(
+        &mut self,
+        guid: &str,
+        by_guid: &HashMap<&str, &Account>,
+        default_commodity: CommodityId,
+    ) -> Result<Option<AccountId>, AlereError> {
+        if let Some(id) = self.accounts.get(guid) {
+            return Ok(Some(*id));
+        }
+        if self.skipped.contains(guid) {
+            return Ok(None);
+        }
+        let a = match by_guid.get(guid) {
+            Some(a) => *a,
+            None => return Err(invalid(format!("unknown account {}", guid))),
+        };
+        let kind = match account_kind(&a.kind) {
+            Some(k) => k,
+            None => {
+                self.skipped.insert(guid.to_string());
+                return Ok(None);
+            }
+        };
+        // Top-level accounts are children of the (skipped) root account
+        let parent = match &a.parent {
+            Some(p) => self.account(p, by_guid, default_commodity)?,
+            None => None,
+        };
+        let commodity = match &a.commodity {
+            Some(key) => self.commodity(key)?,
+            None => default_commodity,
+        };
+        let id = self.imp.account(parent, &a.name, kind, commodity, a.scu.max(1) as i32)?;
+        self.imp.update_account(id, a.description.as_deref(), None, a.code.as_deref(), None)?;
+        if a.hidden {
+            self.imp.close_account(id)?;
+        }
+        self.accounts.insert(guid.to_string(), id);
+        Ok(Some(id))
+    }
+
+    fn import(&mut self, book: Book) -> Result<(), AlereError> {
+        self.commodities(&book)?;
+        let default_commodity = book
+            .commodities
+            .iter()
+            .find(|c| c.is_currency)
+            .and_then(|c| self.commodities.get(&c.key).copied())
+            .ok_or_else(|| invalid("no currency"))?;
+
+        let by_guid: HashMap<&str, &Account> =
+            book.accounts.iter().map(|a| (a.guid.as_str(), a)).collect();
+        for a in &book.accounts {
+            self.account(&a.guid, &by_guid, default_commodity)?;
+        }
+
+        let mut transactions = book.transactions;
+        transactions.sort_by_key(|t| t.date);
+        for t in &transactions {
+            let currency = self.commodity(&t.currency)?;
+            let mut splits = vec![];
+            for s in &t.splits {
+                let account = match self.account(&s.account, &by_guid, default_commodity)? {
+                    Some(a) => a,
+                    None => continue,  // trading accounts
+                };
+                splits.push(NewSplit {
+                    account,
+                    qty: s.quantity,
+                    value: s.value,
+                    value_commodity: currency,
+                    reconcile: reconcile(&s.reconciled),
+                    reconcile_date: s.reconcile_date.filter(|_| s.reconciled == "y"),
+                    post_date: t.date,
+                    payee: None,
+                });
+            }
+            self.imp.transaction(&NewTransaction {
+                timestamp: t.date,
+                memo: memo(t),
+                check_number: non_empty(Some(&t.num)),
+                tags: vec![],
+                splits,
+                scheduled: None,
+                last_occurrence: None,
+            })?;
+        }
+
+        for p in &book.prices {
+            let (origin, target) = match (
+                self.commodities.get(&p.commodity),
+                self.commodities.get(&p.currency),
+            ) {
+                (Some(o), Some(t)) => (*o, *t),
+                _ => continue,
+            };
+            // Prices computed by GnuCash have lots of decimals
+            let digits = (self.imp.price_scale(origin) as f64).log10().ceil() as u32;
+            self.imp.price(p.date, origin, target, p.value.round_dp(digits))?;
+        }
+
+        for s in &book.schedules {
+            self.schedule(s, &book.templates)?;
+        }
+        Ok(())
+    }
+
+    fn schedule(&mut self, s: &Schedule, templates: &[Transaction]) -> Result<(), AlereError> {
+        if !s.enabled {
+            self.imp.summary.ignored += 1;
+            return Ok(());
+        }
+        let rule = recurrence_rule(s);
+        if s.recurrences.len() > 1 {
+            self.imp.warn(format!(
+                "Scheduled transaction {}: only the first recurrence was imported", s.name));
+        }
+        let first = s.recurrences.first().map(|r| r.start.max(s.start)).unwrap_or(s.start);
+        let is_template = |t: &&Transaction| t.splits.iter().any(|sp| sp.account == s.template_account);
+        for t in templates.iter().filter(is_template) {
+            let currency = self.commodity(&t.currency)?;
+            let mut splits = vec![];
+            for sp in &t.splits {
+                let account = sp.sx_account.as_ref().and_then(|a| self.accounts.get(a)).copied();
+                match (account, sp.sx_amount) {
+                    (Some(account), Some(amount)) => splits.push(NewSplit {
+                        account,
+                        qty: amount,
+                        value: amount,
+                        value_commodity: currency,
+                        reconcile: "n",
+                        reconcile_date: None,
+                        post_date: first,
+                        payee: None,
+                    }),
+                    _ => {
+                        splits.clear();
+                        break;
+                    }
+                }
+            }
+            match (&rule, splits.is_empty()) {
+                (Some(rule), false) => {
+                    self.imp.transaction(&NewTransaction {
+                        timestamp: first,
+                        memo: non_empty(Some(&s.name)).or_else(|| memo(t)),
+                        check_number: None,
+                        tags: vec![],
+                        splits,
+                        scheduled: Some(rule.clone()),
+                        last_occurrence: s.last,
+                    })?;
+                }
+                _ => {
+                    self.imp.summary.ignored += 1;
+                    self.imp.warn(format!(
+                        "Scheduled transaction {} could not be imported (unsupported \
+                         formula or recurrence)", s.name));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The description of the transaction, followed by the memos of its splits
+fn memo(t: &Transaction) -> Option<String> {
+    let mut parts: Vec<&str> = vec![];
+    for p in std::iter::once(t.description.as_str()).chain(t.splits.iter().map(|s| s.memo.as_str())) {
+        let p = p.trim();
+        if !p.is_empty() && !parts.contains(&p) {
+            parts.push(p);
+        }
+    }
+    non_empty(Some(&parts.join("; ")))
+}
+
+/// Import a GnuCash book (XML, compressed or not, or SQLite)
+
+pub fn import(imp: &mut Importer, path: &Path) -> Result<(), AlereError> {
+    let data = std::fs::read(path)?;
+    let book = if data.starts_with(b"SQLite format 3\0") {
+        read_sqlite(path)?
+    } else {
+        let data = match data.starts_with(&[0x1f, 0x8b]) {
+            true => {
+                let mut text = vec![];
+                GzDecoder::new(data.as_slice()).read_to_end(&mut text)?;
+                text
+            }
+            false => data,
+        };
+        read_xml(&String::from_utf8(data).map_err(|_| invalid("not UTF-8"))?)?
+    };
+    GnucashImport {
+        imp,
+        commodities: HashMap::new(),
+        accounts: HashMap::new(),
+        skipped: HashSet::new(),
+    }
+    .import(book)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn formulas() {
+        assert_eq!(formula_number("12"), Some(dec!(12)));
+        assert_eq!(formula_number("-12.50"), Some(dec!(-12.5)));
+        assert_eq!(formula_number("12,50"), Some(dec!(12.5)));
+        assert_eq!(formula_number("1,234.56"), Some(dec!(1234.56)));
+        assert_eq!(formula_number("1.234,56"), Some(dec!(1234.56)));
+        assert_eq!(formula_number("1,234,567"), Some(dec!(1234567)));
+        assert_eq!(formula_number("1.234.567,5"), Some(dec!(1234567.5)));
+        assert_eq!(formula_number("1,250"), None);
+        assert_eq!(formula_number("1,25,0.5"), None);
+        assert_eq!(formula_number("amount * 2"), None);
+        assert_eq!(sx_amount(None, None, Some("12,50"), Some("")), Some(dec!(12.5)));
+    }
+}
@@ -0,0 +1,595 @@
+//! Support for the importers of other file formats.
+//! An Importer finds (or creates) the commodities, accounts, payees and
+//! tags referenced by the imported files, and inserts the transactions and
+//! prices. Importers should run in a single operation (see
+//! audit::with_operation), after backups::backup_before, so that a failed
+//! import leaves no partial data and a wrong one can be undone.
+
+use super::accounts::{commodity_kinds, AccountKindCategory};
+use super::connections::last_insert_rowid;
+use super::decimals::from_scaled;
+use super::errors::AlereError;
+use super::models::{AccountId, AccountKindId, CommodityId};
+use super::scenarios::NO_SCENARIO;
+use super::tags::TagId;
+use chrono::NaiveDate;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Date, Integer};
+use diesel::sqlite::SqliteConnection;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use log::info;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Names of the account kinds created by the initial migration
+pub mod kind_names {
+    pub const BANK: &str = "Bank account";
+    pub const STOCK: &str = "Stock";
+    pub const INVESTMENT: &str = "Investment";
+    pub const ASSET: &str = "Asset";
+    pub const LIABILITY: &str = "Liability";
+    pub const EQUITY: &str = "Equity";
+    pub const INCOME: &str = "Misc income";
+    pub const EXPENSE: &str = "Expense";
+}
+
+pub type PayeeId = i32;
+
+/// The account for transactions whose category is not known
+pub const UNCATEGORIZED: &str = "Uncategorized";
+
+/// An IBAN without spaces, in upper case
+pub fn normalize_iban(iban: &str) -> String {
+    iban.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_uppercase()
+}
+
+/// A balance found in an imported file (a balance assertion or a bank
+/// statement for instance), which does not match the balance computed
+/// from the transactions.
+
+#[derive(Serialize, Debug)]
+pub struct Checkpoint {
+    pub account: AccountId,
+    pub date: NaiveDate,
+    pub expected: Decimal,
+    pub actual: Decimal,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct ImportSummary {
+    pub commodities: usize,   // number of rows created
+    pub accounts: usize,
+    pub prices: usize,
+    pub transactions: usize,
+    pub scheduled: usize,
+    pub ignored: usize,       // entries of the file that are not supported
+    pub warnings: Vec<String>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+pub struct NewSplit {
+    pub account: AccountId,
+    pub qty: Decimal,                // in the account's commodity
+    pub value: Decimal,              // in value_commodity
+    pub value_commodity: CommodityId,
+    pub reconcile: &'static str,     // "n", "C" or "R"
+    pub reconcile_date: Option<NaiveDate>,
+    pub post_date: NaiveDate,
+    pub payee: Option<PayeeId>,
+}
+
+pub struct NewTransaction {
+    pub timestamp: NaiveDate,
+    pub memo: Option<String>,
+    pub check_number: Option<String>,
+    pub tags: Vec<TagId>,
+    pub splits: Vec<NewSplit>,
+    pub scheduled: Option<String>,  // a recurrence rule, "" for once
+    pub last_occurrence: Option<NaiveDate>,
+}
+
+struct AccountInfo {
+    commodity: CommodityId,
+    scu: i32,
+}
+
+pub struct Importer<'a> {
+    c: &'a SqliteConnection,
+    commodities: HashMap<String, CommodityId>,  // by symbol or name
+    scales: HashMap<CommodityId, i32>,
+    accounts: HashMap<(Option<AccountId>, String), AccountId>,
+    account_info: HashMap<AccountId, AccountInfo>,
+    kinds: Vec<(AccountKindId, String, i32)>,
+    payees: HashMap<String, PayeeId>,
+    tags: HashMap<String, TagId>,
+    pub default_account: Option<AccountId>,  // for files that do not name one
+    pub summary: ImportSummary,
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(c: &'a SqliteConnection) -> Result<Self, AlereError> {
+        let mut importer = Importer {
+            c,
+            commodities: HashMap::new(),
+            scales: HashMap::new(),
+            accounts: HashMap::new(),
+            account_info: HashMap::new(),
+            kinds: vec![],
+            payees: HashMap::new(),
+            tags: HashMap::new(),
+            default_account: None,
+            summary: ImportSummary::default(),
+        };
+
+        {
+            use super::schema::alr_commodities::dsl as cm;
+            let rows: Vec<(CommodityId, String, Option<String>, Option<String>, i32)> =
+                cm::alr_commodities
+                    .select((cm::id, cm::name, cm::iso_code, cm::quote_symbol, cm::price_scale))
+                    .order(cm::id.desc())  // the oldest wins for duplicates
+                    .load(c)?;
+            for (id, name, iso, quote, scale) in rows {
+                importer.scales.insert(id, scale);
+                for key in [Some(name), quote, iso].into_iter().flatten() {
+                    importer.commodities.insert(key.to_uppercase(), id);
+                }
+            }
+        }
+        {
+            use super::schema::alr_accounts::dsl as a;
+            let rows: Vec<(AccountId, String, Option<AccountId>, CommodityId, i32)> =
+                a::alr_accounts
+                    .select((a::id, a::name, a::parent_id, a::commodity_id, a::commodity_scu))
+                    .order(a::id.desc())
+                    .load(c)?;
+            for (id, name, parent, commodity, scu) in rows {
+                importer.accounts.insert((parent, name), id);
+                importer.account_info.insert(id, AccountInfo { commodity, scu });
+            }
+        }
+        {
+            use super::schema::alr_account_kinds::dsl as k;
+            importer.kinds = k::alr_account_kinds
+                .select((k::id, k::name, k::category))
+                .order(k::id)
+                .load(c)?;
+        }
+        {
+            use super::schema::alr_payees::dsl as p;
+            let rows: Vec<(PayeeId, String)> =
+                p::alr_payees.select((p::id, p::name)).order(p::id.desc()).load(c)?;
+            importer.payees = rows.into_iter().map(|(id, name)| (name, id)).collect();
+        }
+        {
+            use super::schema::alr_tags::dsl as t;
+            let rows: Vec<(TagId, String)> =
+                t::alr_tags.select((t::id, t::name)).order(t::id.desc()).load(c)?;
+            importer.tags = rows.into_iter().map(|(id, name)| (name, id)).collect();
+        }
+        Ok(importer)
+    }
+
+    pub fn warn(&mut self, message: impl Into<String>) {
+        self.summary.warnings.push(message.into());
+    }
+
+    /// Convert to a scaled integer, as stored in the database. Amounts
+    /// that need to be rounded are reported as warnings.
+    /// :param what: describes the amount, for the warning
+
+    pub fn scaled(&mut self, value: Decimal, scale: i32, what: &str) -> Result<i64, AlereError> {
+        let exact = value.checked_mul(Decimal::from(scale)).ok_or_else(|| {
+            AlereError::validation(format!("{} is too large", what))
+        })?;
+        let rounded = exact.round();
+        let scaled = rounded.to_i64().ok_or_else(|| AlereError::validation(
+            format!("{} is too large", what)))?;
+        if rounded != exact {
+            self.warn(format!("{} rounded to {}", what,
+                from_scaled(scaled, scale.into()).unwrap_or_default()));
+        }
+        Ok(scaled)
+    }
+
+    /// The scale used for values in the commodity
+    pub fn price_scale(&self, commodity: CommodityId) -> i32 {
+        self.scales.get(&commodity).copied().unwrap_or(100)
+    }
+
+    /// Find a commodity by ISO code, quote symbol or name.
+    pub fn find_commodity(&self, symbol: &str) -> Option<CommodityId> {
+        self.commodities.get(&symbol.to_uppercase()).copied()
+    }
+
+    /// The currency for files that do not specify one: that of the default
+    /// account, or else the first currency created.
+
+    pub fn default_currency(&self) -> Result<CommodityId, AlereError> {
+        use super::schema::alr_commodities::dsl as cm;
+        if let Some(c) = self.default_account.and_then(|a| self.account_commodity(a)) {
+            return Ok(c);
+        }
+        cm::alr_commodities
+            .select(cm::id)
+            .filter(cm::kind.eq(commodity_kinds::CURRENCY))
+            .order(cm::id)
+            .first(self.c)
+            .optional()?
+            .ok_or_else(|| AlereError::validation(
+                "No currency defined, select the account to import into"))
+    }
+
+    /// Find a commodity, or create it
+    /// :param kind: one of commodity_kinds
+    /// :param price_scale: the scale for values and prices of the commodity
+
+    pub fn commodity(
+        &mut self,
+        symbol: &str,
+        name: Option<&str>,
+        kind: &str,
+        price_scale: i32,
+    ) -> Result<CommodityId, AlereError> {
+        use super::schema::alr_commodities::dsl as cm;
+        if let Some(id) = self.find_commodity(symbol) {
+            return Ok(id);
+        }
+        let is_currency = kind == commodity_kinds::CURRENCY;
+        diesel::insert_into(cm::alr_commodities)
+            .values((
+                cm::name.eq(name.unwrap_or(symbol)),
+                cm::symbol_before.eq(""),
+                cm::symbol_after.eq(symbol),
+                cm::iso_code.eq(if is_currency { Some(symbol) } else { None }),
+                cm::kind.eq(kind),
+                cm::price_scale.eq(price_scale),
+                cm::quote_symbol.eq(if is_currency { None } else { Some(symbol) }),
+            ))
+            .execute(self.c)?;
+        let id = diesel::select(last_insert_rowid).get_result::<i32>(self.c)?;
+        self.commodities.insert(symbol.to_uppercase(), id);
+        self.scales.insert(id, price_scale);
+        self.summary.commodities += 1;
+        Ok(id)
+    }
+
+    /// The kind with the given name, or another one in the same category
+    fn kind(&self, name: &str, category: AccountKindCategory) -> Result<AccountKindId, AlereError> {
+        self.kinds
+            .iter()
+            .find(|(_, n, _)| n == name)
+            .or_else(|| self.kinds.iter().find(|(_, _, c)| *c == category as i32))
+            .map(|(id, _, _)| *id)
+            .ok_or_else(|| AlereError::not_found(format!("No account kind {}", name)))
+    }
+
+    pub fn find_account(&self, parent: Option<AccountId>, name: &str) -> Option<AccountId> {
+        self.accounts.get(&(parent, name.to_string())).copied()
+    }
+
+    /// Find an account from its name only, preferring top-level accounts
+    pub fn find_account_named(&self, name: &str) -> Option<AccountId> {
+        self.find_account(None, name).or_else(|| {
+            self.accounts
+                .iter()
+                .filter(|((_, n), _)| n == name)
+                .map(|(_, id)| *id)
+                .min()
+        })
+    }
+
+    /// Find an account from its IBAN
+    pub fn find_account_iban(&self, iban: &str) -> Result<Option<AccountId>, AlereError> {
+        use super::schema::alr_accounts::dsl as a;
+        let iban = normalize_iban(iban);
+        let rows: Vec<(AccountId, Option<String>)> = a::alr_accounts
+            .select((a::id, a::iban))
+            .filter(a::iban.is_not_null())
+            .order(a::id)
+            .load(self.c)?;
+        Ok(rows
+            .into_iter()
+            .find(|(_, i)| i.as_deref().map(normalize_iban).as_ref() == Some(&iban))
+            .map(|(id, _)| id))
+    }
+
+    /// Find an account from its name and parent, or create it.
+    /// :param kind: one of kind_names
+    /// :param scu: the scale for quantities
+
+    pub fn account(
+        &mut self,
+        parent: Option<AccountId>,
+        name: &str,
+        kind: (&str, AccountKindCategory),
+        commodity: CommodityId,
+        scu: i32,
+    ) -> Result<AccountId, AlereError> {
+        use super::schema::alr_accounts::dsl as a;
+        if let Some(id) = self.find_account(parent, name) {
+            return Ok(id);
+        }
+        let kind_id = self.kind(kind.0, kind.1)?;
+        diesel::insert_into(a::alr_accounts)
+            .values((
+                a::name.eq(name),
+                a::closed.eq(false),
+                a::commodity_scu.eq(scu),
+                a::commodity_id.eq(commodity),
+                a::kind_id.eq(kind_id),
+                a::parent_id.eq(parent),
+            ))
+            .execute(self.c)?;
+        let id = diesel::select(last_insert_rowid).get_result::<i32>(self.c)?;
+        self.accounts.insert((parent, name.to_string()), id);
+        self.account_info.insert(id, AccountInfo { commodity, scu });
+        self.summary.accounts += 1;
+        Ok(id)
+    }
+
+    /// The commodity of an account
+    pub fn account_commodity(&self, account: AccountId) -> Option<CommodityId> {
+        self.account_info.get(&account).map(|a| a.commodity)
+    }
+
+    /// Set the details of an account, when they are known
+    pub fn update_account(
+        &mut self,
+        account: AccountId,
+        description: Option<&str>,
+        iban: Option<&str>,
+        number: Option<&str>,
+        opening_date: Option<NaiveDate>,
+    ) -> Result<(), AlereError> {
+        diesel::sql_query(
+            "UPDATE alr_accounts SET
+                description = COALESCE(?, description),
+                iban = COALESCE(?, iban),
+                number = COALESCE(?, number),
+                opening_date = COALESCE(?, opening_date)
+             WHERE id = ?",
+        )
+        .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(description)
+        .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(iban)
+        .bind::<diesel::sql_types::Nullable<diesel::sql_types::Text>, _>(number)
+        .bind::<diesel::sql_types::Nullable<Date>, _>(opening_date)
+        .bind::<Integer, _>(account)
+        .execute(self.c)?;
+        Ok(())
+    }
+
+    pub fn close_account(&mut self, account: AccountId) -> Result<(), AlereError> {
+        use super::schema::alr_accounts::dsl as a;
+        diesel::update(a::alr_accounts.find(account))
+            .set(a::closed.eq(true))
+            .execute(self.c)?;
+        Ok(())
+    }
+
+    pub fn payee(&mut self, name: &str) -> Result<PayeeId, AlereError> {
+        use super::schema::alr_payees::dsl as p;
+        if let Some(id) = self.payees.get(name) {
+            return Ok(*id);
+        }
+        diesel::insert_into(p::alr_payees).values(p::name.eq(name)).execute(self.c)?;
+        let id = diesel::select(last_insert_rowid).get_result::<i32>(self.c)?;
+        self.payees.insert(name.to_string(), id);
+        Ok(id)
+    }
+
+    /// Find a payee, or create it, and record its IBAN if it had none
+    pub fn payee_iban(&mut self, name: &str, iban: Option<&str>) -> Result<PayeeId, AlereError> {
+        use super::schema::alr_payees::dsl as p;
+        let id = self.payee(name)?;
+        if let Some(iban) = iban {
+            diesel::update(p::alr_payees.find(id))
+                .filter(p::iban.is_null())
+                .set(p::iban.eq(normalize_iban(iban)))
+                .execute(self.c)?;
+        }
+        Ok(id)
+    }
+
+    pub fn tag(&mut self, name: &str) -> Result<TagId, AlereError> {
+        use super::schema::alr_tags::dsl as t;
+        if let Some(id) = self.tags.get(name) {
+            return Ok(*id);
+        }
+        diesel::insert_into(t::alr_tags).values(t::name.eq(name)).execute(self.c)?;
+        let id = diesel::select(last_insert_rowid).get_result::<i32>(self.c)?;
+        self.tags.insert(name.to_string(), id);
+        Ok(id)
+    }
+
+    /// Record the price of one unit of origin, in target
+    pub fn price(
+        &mut self,
+        date: NaiveDate,
+        origin: CommodityId,
+        target: CommodityId,
+        price: Decimal,
+    ) -> Result<(), AlereError> {
+        use super::accounts::price_sources;
+        use super::schema::alr_prices::dsl as p;
+        let scaled = self.scaled(
+            price, self.price_scale(origin), &format!("Price on {}", date))?;
+        diesel::insert_into(p::alr_prices)
+            .values((
+                p::date.eq(date.and_hms(0, 0, 0)),
+                p::scaled_price.eq(scaled),
+                p::origin_id.eq(origin),
+                p::source_id.eq(price_sources::USER),
+                p::target_id.eq(target),
+            ))
+            .execute(self.c)?;
+        self.summary.prices += 1;
+        Ok(())
+    }
+
+    pub fn transaction(&mut self, t: &NewTransaction) -> Result<i32, AlereError> {
+        use super::schema::alr_splits::dsl as s;
+        use super::schema::alr_transaction_tags::dsl as tt;
+        use super::schema::alr_transactions::dsl as tr;
+        diesel::insert_into(tr::alr_transactions)
+            .values((
+                tr::timestamp.eq(t.timestamp.and_hms(0, 0, 0)),
+                tr::memo.eq(&t.memo),
+                tr::check_number.eq(&t.check_number),
+                tr::scheduled.eq(&t.scheduled),
+                tr::last_occurrence.eq(t.last_occurrence.map(|d| d.and_hms(0, 0, 0))),
+                tr::scenario_id.eq(NO_SCENARIO as i32),
+            ))
+            .execute(self.c)?;
+        let id = diesel::select(last_insert_rowid).get_result::<i32>(self.c)?;
+
+        for split in &t.splits {
+            let scu = self.account_info.get(&split.account).map(|a| a.scu).unwrap_or(1);
+            let what = format!("Amount on {}", split.post_date);
+            let qty = self.scaled(split.qty, scu, &what)?;
+            let value = self.scaled(split.value, self.price_scale(split.value_commodity), &what)?;
+            diesel::insert_into(s::alr_splits)
+                .values((
+                    s::scaled_qty.eq(qty),
+                    s::scaled_value.eq(value),
+                    s::reconcile.eq(split.reconcile),
+                    s::reconcile_date.eq(split.reconcile_date.map(|d| d.and_hms(0, 0, 0))),
+                    s::post_date.eq(split.post_date.and_hms(0, 0, 0)),
+                    s::account_id.eq(split.account),
+                    s::payee_id.eq(split.payee),
+                    s::transaction_id.eq(id),
+                    s::value_commodity_id.eq(split.value_commodity),
+                ))
+                .execute(self.c)?;
+        }
+        for tag in &t.tags {
+            diesel::replace_into(tt::alr_transaction_tags)
+                .values((tt::transaction_id.eq(id), tt::tag_id.eq(tag)))
+                .execute(self.c)?;
+        }
+        match t.scheduled {
+            Some(_) => self.summary.scheduled += 1,
+            None => self.summary.transactions += 1,
+        }
+        Ok(id)
+    }
+
+    /// The balance of an account and its children (only those in the same
+    /// commodity), at the start of the date.
+
+    pub fn balance(&self, account: AccountId, date: NaiveDate) -> Result<Decimal, AlereError> {
+        #[derive(QueryableByName)]
+        struct Balance {
+            #[sql_type = "BigInt"]
+            scaled: i64,
+            #[sql_type = "Integer"]
+            scu: i32,
+        }
+        let rows: Vec<Balance> = diesel::sql_query(
+            "WITH RECURSIVE children(id) AS (
+                SELECT ?
+                UNION SELECT a.id FROM alr_accounts a JOIN children c
+                   ON (a.parent_id = c.id)
+             )
+             SELECT COALESCE(sum(s.scaled_qty), 0) AS scaled,
+                a.commodity_scu AS scu
+             FROM alr_splits s
+                JOIN alr_transactions t ON (s.transaction_id = t.id)
+                JOIN alr_accounts a ON (s.account_id = a.id)
+             WHERE s.account_id IN (SELECT id FROM children)
+                AND a.commodity_id =
+                   (SELECT commodity_id FROM alr_accounts WHERE id = ?)
+                AND t.scheduled IS NULL
+                AND t.scenario_id = ?
+                AND s.post_date < ?
+             GROUP BY a.commodity_scu",
+        )
+        .bind::<Integer, _>(account)
+        .bind::<Integer, _>(account)
+        .bind::<Integer, _>(NO_SCENARIO as i32)
+        .bind::<Date, _>(date)
+        .load(self.c)?;
+        Ok(rows
+            .iter()
+            .filter_map(|b| from_scaled(b.scaled, b.scu.into()))
+            .sum())
+    }
+
+    /// Check a balance found in the file. If it matches (up to the
+    /// precision it was given with), the account is marked as reconciled
+    /// at that date. Otherwise, a checkpoint is reported.
+
+    pub fn checkpoint(
+        &mut self,
+        account: AccountId,
+        date: NaiveDate,
+        expected: Decimal,
+    ) -> Result<(), AlereError> {
+        use super::schema::alr_accounts::dsl as a;
+        let actual = self.balance(account, date)?;
+        let tolerance = Decimal::new(5, expected.scale() + 1);
+        if (actual - expected).abs() <= tolerance {
+            diesel::update(a::alr_accounts.find(account))
+                .filter(a::last_reconciled.is_null()
+                    .or(a::last_reconciled.lt(date.and_hms(0, 0, 0))))
+                .set(a::last_reconciled.eq(date.and_hms(0, 0, 0)))
+                .execute(self.c)?;
+        } else {
+            self.summary.checkpoints.push(Checkpoint { account, date, expected, actual });
+        }
+        Ok(())
+    }
+}
+
+/// Import a file, in a format guessed from its extension. The database is
+/// backed up first, and the import can be undone as a single operation.
+/// :param account: where to import transactions when the file does not say
+///    (QIF files, bank statements). Accounts are created otherwise.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn import_file(
+    path: String,
+    account: Option<AccountId>,
+) -> Result<ImportSummary, AlereError> {
+    info!("import_file {} {:?}", path, account);
+    let path = Path::new(&path);
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let import: fn(&mut Importer, &Path) -> Result<(), AlereError> = match extension.as_str() {
+        "beancount" | "bean" => super::import_beancount::import,
+        "gnucash" => super::import_gnucash::import,
+        "qif" => super::qif::import,
+        "xml" | "camt" => super::import_camt::import,
+        e => return Err(AlereError::validation(format!("Unsupported file format {:?}", e))),
+    };
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+
+    super::backups::backup_before("import")?;
+    let c = super::connections::get_connection()?;
+    super::audit::with_operation(&c, &format!("Import {}", name), || {
+        let mut importer = Importer::new(&c)?;
+        if let Some(a) = account {
+            if importer.account_commodity(a).is_none() {
+                return Err(AlereError::not_found(format!("No account {}", a)));
+            }
+            importer.default_account = Some(a);
+        }
+        import(&mut importer, path)?;
+        Ok(importer.summary)
+    })
+}
+
+/// Import a file with the given name and content into the current
+/// database, for tests, see connections::test_database
+
+#[cfg(test)]
+pub fn import_test_file(name: &str, content: &str, account: Option<AccountId>) -> ImportSummary {
+    let mut path = super::connections::database_path();
+    path.set_file_name(name);
+    std::fs::write(&path, content).unwrap();
+    futures_executor::block_on(import_file(path.display().to_string(), account)).unwrap()
+}
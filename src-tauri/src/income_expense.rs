@@ -1,35 +1,42 @@
 use super::cte_list_splits::{
     cte_list_splits, cte_splits_with_values, CTE_SPLITS_WITH_VALUE};
 use super::dates::{DateValues};
+use super::errors::AlereError;
 use super::models::{AccountId, CommodityId};
 use super::occurrences::Occurrences;
+use super::query_builder::{Query, Sql};
+use super::scenarios::{Scenario, NO_SCENARIO};
+use super::tags::{filter_splits_with_tags, TagId};
 use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
 use serde::Serialize;
 use super::accounts::AccountKindCategory;
 use log::info;
 
 #[derive(Serialize)]
 pub struct OneIncomeExpense {
-    accountid: AccountId,
-    value: f32,      // total for this account in the time range
+    pub accountid: AccountId,
+    pub value: Decimal,  // total for this account in the time range
 }
 
 #[derive(Serialize)]
 pub struct IncomeExpenseInPeriod {
-    items: Vec<OneIncomeExpense>,
-    mindate: DateTime<Utc>,
-    maxdate: DateTime<Utc>,
+    pub items: Vec<OneIncomeExpense>,
+    pub mindate: DateTime<Utc>,
+    pub maxdate: DateTime<Utc>,
 }
 
 
-#[tauri::command]
+#[cfg_attr(feature = "gui", tauri::command)]
 pub async fn income_expense(
     income: bool,
     expense: bool,
     mindate: DateTime<Utc>,
     maxdate: DateTime<Utc>,
     currency: CommodityId,
-) -> IncomeExpenseInPeriod {
+    scenario: Option<Scenario>,
+    tags: Option<Vec<TagId>>,
+) -> Result<IncomeExpenseInPeriod, AlereError> {
     info!("income_expense {:?} {:?} income={} expense={}",
           &mindate, &maxdate, income, expense);
 
@@ -41,58 +48,49 @@ pub async fn income_expense(
         categories.push(AccountKindCategory::INCOME);
     }
     if categories.len() == 0 {
-        return IncomeExpenseInPeriod {
+        return Ok(IncomeExpenseInPeriod {
             items: vec![],
             mindate,
             maxdate,
-        };
+        });
     }
 
     let list_splits = cte_list_splits(
         &DateValues::new(Some(vec![mindate.date(), maxdate.date()])),
-        super::scenarios::NO_SCENARIO,
+        scenario.unwrap_or(NO_SCENARIO),
         &Occurrences::no_recurrence());
-    let with_values = cte_splits_with_values();
-    let cats = categories.iter()
-        .map(|&cat| (cat as u32).to_string())
-        .collect::<Vec<_>>()
-        .join(",");
-    let query = format!(
+    let with_values = cte_splits_with_values(&list_splits);
+    let cats: Vec<u32> = categories.iter().map(|&cat| cat as u32).collect();
+    let filter_tags = filter_splits_with_tags(&tags, "s.split_id");
+    let query = Query::new(Sql::new(format!(
         "
-        WITH RECURSIVE {list_splits}, \
-          {with_values} \
-        SELECT s.account_id, SUM(s.value) AS value \
+        SELECT s.account_id, \
+           alr_decimal(SUM(s.scaled_value), MAX(s.value_scale)) AS value \
         FROM {CTE_SPLITS_WITH_VALUE} s \
         JOIN alr_accounts a ON (a.id = s.account_id) \
         JOIN alr_account_kinds k ON (k.id = a.kind_id) \
-        WHERE s.value_commodity_id = {currency} \
+        WHERE s.value_commodity_id = :currency \
         AND NOT k.is_unrealized \
-        AND k.category IN ({cats}) \
+        AND k.category IN (:cats) \
+        :filter_tags \
         GROUP BY s.account_id
         "
-    );
+    ))
+    .bind("currency", currency)
+    .bind("cats", &cats)
+    .fragment("filter_tags", filter_tags))
+    .with(&with_values);
     let rows =
         super::connections::execute_and_log
-            ::<super::metrics::SplitsPerAccount>("income_expense", &query);
-    match rows {
-        Ok(r) => {
-            IncomeExpenseInPeriod {
-                mindate: mindate,
-                maxdate: maxdate,
-                items: r.iter()
-                    .map(|acc| OneIncomeExpense {
-                        accountid: acc.account_id,
-                        value: -acc.value
-                    })
-                    .collect()
-            }
-        },
-        Err(_) => {
-            IncomeExpenseInPeriod {
-                items: vec![],
-                mindate,
-                maxdate,
-            }
-        }
-    }
+            ::<super::metrics::SplitsPerAccount>("income_expense", query)?;
+    Ok(IncomeExpenseInPeriod {
+        mindate: mindate,
+        maxdate: maxdate,
+        items: rows.iter()
+            .map(|acc| OneIncomeExpense {
+                accountid: acc.account_id,
+                value: -acc.value
+            })
+            .collect()
+    })
 }
@@ -1,42 +1,48 @@
 use super::cte_accounts::{cte_transactions_for_accounts, CTE_TRANSACTIONS_FOR_ACCOUNTS};
 use super::cte_list_splits::{cte_list_splits, cte_splits_with_values, CTE_SPLITS_WITH_VALUE};
 use super::dates::{DateSet, DateValues};
+use super::decimals::{from_scaled, SqlDecimal, SqlOptionalDecimal};
+use super::errors::AlereError;
 use super::models::{AccountId, CommodityId};
 use super::occurrences::Occurrences;
+use super::query_builder::{Cte, Query, Sql};
+use super::scenarios::{Scenario, NO_SCENARIO};
+use super::tags::{filter_transactions_with_tags, TagId};
 use chrono::{DateTime, NaiveDate, TimeZone, Utc};
-use diesel::sql_types::{Bool, Date, Float, Integer, Nullable, Text};
-use serde::Serialize;
+use diesel::sql_types::{BigInt, Bool, Date, Integer, Nullable, Text};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use log::info;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SplitDescr {
-    account_id: AccountId,
-    post_date: DateTime<Utc>,
-    amount: f32,
-    currency: CommodityId,
-    reconcile: char,
-    shares: f32,
-    price: f32,
-    payee: String,
+    pub account_id: AccountId,
+    pub post_date: DateTime<Utc>,
+    pub amount: Decimal,
+    pub currency: CommodityId,
+    pub reconcile: char,
+    pub shares: Decimal,
+    pub price: Option<Decimal>,
+    pub payee: String,
 }
 
-type TransactionId = i32;
+pub type TransactionId = i32;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct TransactionDescr {
-    id: TransactionId,
-    occurrence: i32,
-    date: DateTime<Utc>,
-    balance: f32,
-    balance_shares: f32,
-    memo: String,
-    check_number: String,
-    is_recurring: bool,
-    splits: Vec<SplitDescr>,
+    pub id: TransactionId,
+    pub occurrence: i32,
+    pub date: DateTime<Utc>,
+    pub balance: Option<Decimal>,  // None if there is no known price
+    pub balance_shares: Decimal,
+    pub memo: String,
+    pub check_number: String,
+    pub is_recurring: bool,
+    pub splits: Vec<SplitDescr>,
 }
 
 #[derive(QueryableByName)]
-struct SplitRow {
+pub struct SplitRow {
     #[sql_type = "Integer"]
     transaction_id: TransactionId,
 
@@ -52,14 +58,15 @@ struct SplitRow {
     #[sql_type = "Nullable<Text>"]
     check_number: Option<String>,
 
-    #[sql_type = "Float"]
-    scaled_qty: f32,
+    #[sql_type = "BigInt"]
+    scaled_qty: i64,
 
-    #[sql_type = "Float"]
-    commodity_scu: f32,
+    #[sql_type = "BigInt"]
+    commodity_scu: i64,
 
-    #[sql_type = "Nullable<Float>"]
-    computed_price: Option<f32>,
+    #[sql_type = "Nullable<Text>"]
+    #[diesel(deserialize_as = "SqlOptionalDecimal")]
+    computed_price: Option<Decimal>,
 
     #[sql_type = "Integer"]
     account_id: AccountId,
@@ -67,8 +74,9 @@ struct SplitRow {
     #[sql_type = "Date"]
     post_date: NaiveDate,
 
-    #[sql_type = "Float"]
-    value: f32,
+    #[sql_type = "Text"]
+    #[diesel(deserialize_as = "SqlDecimal")]
+    value: Decimal,
 
     #[sql_type = "Integer"]
     value_commodity_id: CommodityId,
@@ -82,8 +90,8 @@ struct SplitRow {
     #[sql_type = "Nullable<Text>"]
     payee: Option<String>,
 
-    #[sql_type = "Float"]
-    scaled_qty_balance: f32,
+    #[sql_type = "BigInt"]
+    scaled_qty_balance: i64,
 }
 
 /// Return the ledger information.
@@ -97,33 +105,27 @@ struct SplitRow {
 /// :param max_scheduled_occurrences:
 ///     if 0, ignore all scheduled transactions.
 ///     if 1, only look at the next occurrence of them.
+/// :param scenario:
+///     which scenario to include in addition to the actual transactions.
+/// :param tags:
+///     if specified, only the transactions with one of those tags (on the
+///     transaction or one of its splits) are returned.
 
-#[tauri::command]
+#[cfg_attr(feature = "gui", tauri::command)]
 pub async fn ledger(
     mindate: DateTime<Utc>,
     maxdate: DateTime<Utc>,
     accountids: Vec<AccountId>,
     occurrences: u16,
-) -> Vec<TransactionDescr> {
+    scenario: Option<Scenario>,
+    tags: Option<Vec<TagId>>,
+) -> Result<Vec<TransactionDescr>, AlereError> {
     info!(
-        "ledger {mindate} {maxdate} {:?} {:?}",
-        accountids, occurrences
+        "ledger {mindate} {maxdate} {:?} {:?} {:?} {:?}",
+        accountids, occurrences, scenario, tags
     );
     let occ = Occurrences::new(occurrences);
     let dates = DateValues::new(Some(vec![mindate.date(), maxdate.date()]));
-    let (filter_acct_cte, filter_acct_from) = match accountids.len() {
-        0 => ("".to_string(), "".to_string()),
-        _ => {
-            let acc = cte_transactions_for_accounts(&accountids);
-            (
-                format!(", {acc}"),
-                format!(
-                    " JOIN {CTE_TRANSACTIONS_FOR_ACCOUNTS} t \
-                 USING (transaction_id)"
-                ),
-            )
-        }
-    };
     let ref_id: AccountId = match accountids.len() {
         1 => *accountids.first().unwrap(),
         _ => -1,
@@ -131,17 +133,20 @@ pub async fn ledger(
 
     let list_splits = cte_list_splits(
         &dates.unbounded_start(), // from start to get balance right
-        super::scenarios::NO_SCENARIO,
+        scenario.unwrap_or(NO_SCENARIO),
         &occ,
     );
-    let with_values = cte_splits_with_values();
-    let dates_start = dates.get_start();
-    let query = format!(
-        " \
-       WITH RECURSIVE {list_splits}  \
-       , {with_values}
-       {filter_acct_cte}
-       , all_splits_since_epoch AS (
+    let with_values = cte_splits_with_values(&list_splits);
+    let for_accounts = cte_transactions_for_accounts(&list_splits, &accountids);
+    let filter_acct_from = match accountids.len() {
+        0 => "".to_string(),
+        _ => format!(" JOIN {CTE_TRANSACTIONS_FOR_ACCOUNTS} t USING (transaction_id)"),
+    };
+    let filter_tags = filter_transactions_with_tags(&tags, "s.transaction_id");
+    let mut all_splits = Cte::new(
+        "all_splits_since_epoch",
+        Sql::new(format!(
+        "
           SELECT
              s.transaction_id,
              s.occurrence,
@@ -153,7 +158,7 @@ pub async fn ledger(
              s.computed_price,
              s.account_id,
              strftime('%Y-%m-%d', s.post_date) AS post_date,
-             s.value,
+             alr_decimal(s.scaled_value, s.value_scale) AS value,
              s.value_commodity_id,
              s.reconcile,
              s.scheduled,
@@ -167,62 +172,336 @@ pub async fn ledger(
              {filter_acct_from}
              JOIN alr_accounts a ON (s.account_id = a.id)
              LEFT JOIN alr_payees p ON (s.payee_id = p.id)
-       )
+       "
+    )))
+    .requires(&with_values);
+    if !accountids.is_empty() {
+        all_splits = all_splits.requires(&for_accounts);
+    }
+    let query = Query::new(Sql::new(
+       "
        SELECT s.*
        FROM all_splits_since_epoch s
-       WHERE s.post_date >= '{dates_start}'
+       WHERE (s.post_date >= :start
 
          --  Always include non-validated occurrences of recurring
          --  transactions.
-         OR s.scheduled IS NOT NULL
+         OR s.scheduled IS NOT NULL)
+         :filter_tags
        ORDER BY s.timestamp, s.transaction_id
        "
+    )
+    .bind("start", dates.get_earliest())
+    .fragment("filter_tags", filter_tags))
+    .with(&all_splits);
+
+    let rows = super::connections::execute_and_log::<SplitRow>(
+        "ledger", query)?;
+    Ok(splits_to_transactions(rows, ref_id))
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct LedgerFilters {
+    pub payee: Option<String>,         // substring of the payee's name
+    pub memo: Option<String>,          // substring of the memo
+    pub minamount: Option<f64>,        // compared to absolute value of splits
+    pub maxamount: Option<f64>,
+    pub reconcile: Option<String>,     // any of the reconcile flags, e.g. "nC"
+    pub counterpart: Option<AccountId>, // one of the splits is for this account
+    pub recurring: Option<bool>,       // only occurrences of recurring ones
+    pub tags: Option<Vec<TagId>>,      // any of those tags on split or transaction
+}
+
+/// Identifies a transaction (and occurrence) in the ledger. The ledger is
+/// sorted on those fields.
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LedgerCursor {
+    date: NaiveDate,
+    id: TransactionId,
+    occurrence: i32,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PageRequest {
+    pub cursor: Option<LedgerCursor>, // start after this transaction
+    pub size: Option<u32>,
+    pub descending: Option<bool>,     // most recent transactions first
+}
+
+#[derive(Serialize, Debug)]
+pub struct LedgerPage {
+    transactions: Vec<TransactionDescr>, // always sorted by ascending date
+    next: Option<LedgerCursor>,          // None on the last page
+}
+
+const DEFAULT_PAGE_SIZE: u32 = 200;
+
+/// A LIKE pattern (with ESCAPE '\') matching any text that contains `text`
+
+fn like_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// Return one page of the ledger, with optional filters.
+/// The filters on payee, amount and reconcile state apply to the splits
+/// for `accountids` (or any split when no account is given). Filtering
+/// does not change the running balance, which is still computed from all
+/// the transactions of the account. The balance is computed from the
+/// start balance before the first transaction of the page, rather than
+/// from a running sum over the whole history.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn ledger_page(
+    mindate: DateTime<Utc>,
+    maxdate: DateTime<Utc>,
+    accountids: Vec<AccountId>,
+    occurrences: u16,
+    scenario: Option<Scenario>,
+    filters: Option<LedgerFilters>,
+    page: Option<PageRequest>,
+) -> Result<LedgerPage, AlereError> {
+    info!(
+        "ledger_page {mindate} {maxdate} {:?} {:?} {:?} {:?} {:?}",
+        accountids, occurrences, scenario, filters, page
+    );
+    let filters = filters.unwrap_or_default();
+    let page = page.unwrap_or_default();
+    let size = page.size.unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+    let descending = page.descending.unwrap_or(false);
+    let occ = Occurrences::new(occurrences);
+    let dates = DateValues::new(Some(vec![mindate.date(), maxdate.date()]));
+    let ref_id: AccountId = match accountids.len() {
+        1 => *accountids.first().unwrap(),
+        _ => -1,
+    };
+    let filter_acct = match accountids.len() {
+        0 => Sql::empty(),
+        _ => Sql::new("AND s.account_id IN (:accountids)").bind("accountids", &accountids),
+    };
+    let (cmp, dir) = match descending {
+        true => ("<", "DESC"),
+        false => (">", "ASC"),
+    };
+
+    let list_splits = cte_list_splits(
+        &dates.unbounded_start(), // from start to get balance right
+        scenario.unwrap_or(NO_SCENARIO),
+        &occ,
     );
+    let with_values = cte_splits_with_values(&list_splits);
+    let filter_tags = filter_transactions_with_tags(&filters.tags, "s.transaction_id");
+    let keyed = Cte::new(
+        "keyed",
+        Sql::new(format!(
+            "SELECT s.*, strftime('%Y-%m-%d', s.timestamp) AS day
+             FROM {CTE_SPLITS_WITH_VALUE} s"
+        )),
+    )
+    .requires(&with_values);
+    let page_cte = Cte::new(
+        "page",
+        Sql::new(format!(
+            "
+          SELECT s.day, s.transaction_id, s.occurrence
+          FROM keyed s
+          WHERE (s.post_date >= :start
+                 --  Always include non-validated occurrences of recurring
+                 --  transactions.
+                 OR s.scheduled IS NOT NULL)
+             :filter_acct
+             AND (:payee IS NULL OR s.payee_id IN
+                    (SELECT p.id FROM alr_payees p WHERE p.name LIKE :payee ESCAPE '\\'))
+             AND (:memo IS NULL OR s.memo LIKE :memo ESCAPE '\\')
+             AND (:minamount IS NULL OR ABS(s.value) >= :minamount)
+             AND (:maxamount IS NULL OR ABS(s.value) <= :maxamount)
+             AND (:reconcile IS NULL OR instr(:reconcile, s.reconcile) > 0)
+             AND (:counterpart IS NULL OR s.transaction_id IN
+                    (SELECT c.transaction_id FROM keyed c
+                     WHERE c.account_id = :counterpart))
+             AND (NOT :recurring OR s.scheduled IS NOT NULL)
+             :filter_tags
+             AND (:cdate IS NULL
+                  OR (s.day, s.transaction_id, s.occurrence) {cmp} (:cdate, :cid, :cocc))
+          GROUP BY s.transaction_id, s.occurrence
+          ORDER BY s.day {dir}, s.transaction_id {dir}, s.occurrence {dir}
+          LIMIT :size
+            "
+        ))
+        .bind("start", dates.get_earliest())
+        .fragment("filter_acct", filter_acct)
+        .bind("payee", filters.payee.as_deref().map(like_pattern))
+        .bind("memo", filters.memo.as_deref().map(like_pattern))
+        .bind("minamount", filters.minamount)
+        .bind("maxamount", filters.maxamount)
+        .bind("reconcile", filters.reconcile)
+        .bind("counterpart", filters.counterpart)
+        .bind("recurring", filters.recurring.unwrap_or(false))
+        .fragment("filter_tags", filter_tags)
+        .bind("cdate", page.cursor.as_ref().map(|c| c.date))
+        .bind("cid", page.cursor.as_ref().map(|c| c.id))
+        .bind("cocc", page.cursor.as_ref().map(|c| c.occurrence))
+        .bind("size", size),
+    )
+    .requires(&keyed);
+    let first_in_page = Cte::new(
+        "first_in_page",
+        Sql::new(
+            "SELECT * FROM page
+             ORDER BY day, transaction_id, occurrence LIMIT 1",
+        ),
+    )
+    .requires(&page_cte);
+    let last_in_page = Cte::new(
+        "last_in_page",
+        Sql::new(
+            "SELECT * FROM page
+             ORDER BY day DESC, transaction_id DESC, occurrence DESC LIMIT 1",
+        ),
+    )
+    .requires(&page_cte);
+    let start_balance = Cte::new(
+        "start_balance",
+        Sql::new(
+            "
+          SELECT COALESCE(SUM(s.scaled_qty), 0) AS qty
+          FROM keyed s
+          WHERE s.account_id = :ref_id
+             AND (s.day, s.transaction_id, s.occurrence)
+                < (SELECT day, transaction_id, occurrence FROM first_in_page)
+            ",
+        )
+        .bind("ref_id", ref_id),
+    )
+    .requires(&first_in_page);
+    let running = Cte::new(
+        "running",
+        Sql::new(
+            "
+          SELECT
+             s.split_id,
+             s.transaction_id,
+             s.occurrence,
+             (SELECT qty FROM start_balance)
+                + SUM(s.scaled_qty) OVER
+                  (ORDER BY s.day, s.transaction_id, s.occurrence, s.split_id
+                   ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW)
+                AS scaled_qty_balance
+          FROM keyed s
+          WHERE s.account_id = :ref_id
+             AND (s.day, s.transaction_id, s.occurrence)
+                >= (SELECT day, transaction_id, occurrence FROM first_in_page)
+             AND (s.day, s.transaction_id, s.occurrence)
+                <= (SELECT day, transaction_id, occurrence FROM last_in_page)
+            ",
+        )
+        .bind("ref_id", ref_id),
+    )
+    .requires(&start_balance)
+    .requires(&last_in_page);
+    let query = Query::new(
+        "
+       SELECT
+          s.transaction_id,
+          s.occurrence,
+          s.day AS timestamp,
+          s.memo AS memo,
+          s.check_number AS check_number,
+          s.scaled_qty,
+          a.commodity_scu,
+          s.computed_price,
+          s.account_id,
+          strftime('%Y-%m-%d', s.post_date) AS post_date,
+          alr_decimal(s.scaled_value, s.value_scale) AS value,
+          s.value_commodity_id,
+          s.reconcile,
+          s.scheduled,
+          p.name AS payee,
+          COALESCE(r.scaled_qty_balance, 0) AS scaled_qty_balance
+       FROM keyed s
+          JOIN page USING (transaction_id, occurrence)
+          JOIN alr_accounts a ON (s.account_id = a.id)
+          LEFT JOIN alr_payees p ON (s.payee_id = p.id)
+          LEFT JOIN running r
+             ON (r.split_id = s.split_id
+                 AND r.transaction_id = s.transaction_id
+                 AND r.occurrence = s.occurrence)
+       ORDER BY s.day, s.transaction_id, s.occurrence, s.split_id
+       ",
+    )
+    .with(&running);
 
     let rows = super::connections::execute_and_log::<SplitRow>(
-        "ledger", &query);
-    match rows {
-        Ok(r) => {
-            let mut result: Vec<TransactionDescr> = vec![];
-            for split in r {
-                let need_new = result.is_empty()
-                    || result.last().unwrap().id != split.transaction_id
-                    || result.last().unwrap().occurrence != split.occurrence;
-                if need_new {
-                    result.push(TransactionDescr {
-                        id: split.transaction_id,
-                        occurrence: split.occurrence,
-                        date: Utc.from_utc_date(&split.timestamp).and_hms(0, 0, 0),
-                        balance: 0.0,
-                        balance_shares: 0.0,
-                        memo: split.memo.unwrap_or_else(|| "".to_string()),
-                        check_number: split.check_number
-                            .unwrap_or_else(|| "".to_string()),
-                        is_recurring: split.scheduled.unwrap_or(false),
-                        splits: vec![],
-                    });
-                }
-
-                let mut r = result.last_mut().unwrap();
-
-                if split.account_id == ref_id {
-                    r.balance_shares += split.scaled_qty_balance / split.commodity_scu;
-                    r.balance = r.balance_shares * split.computed_price.unwrap_or(std::f32::NAN);
-                }
-
-                r.splits.push(SplitDescr {
-                    account_id: split.account_id,
-                    post_date: Utc.from_utc_date(&split.post_date).and_hms(0, 0, 0),
-                    amount: split.value,
-                    currency: split.value_commodity_id,
-                    reconcile: split.reconcile.chars().next().unwrap(),
-                    shares: split.scaled_qty / split.commodity_scu,
-                    price: split.computed_price.unwrap_or(std::f32::NAN),
-                    payee: split.payee.unwrap_or_else(|| "".to_string()),
-                });
-            }
-            result
+        "ledger_page", query)?;
+
+    let transactions = splits_to_transactions(rows, ref_id);
+    let last = match descending {
+        true => transactions.first(),
+        false => transactions.last(),
+    };
+    let next = match transactions.len() < size as usize {
+        true => None,
+        false => last.map(|t| LedgerCursor {
+            date: t.date.naive_utc().date(),
+            id: t.id,
+            occurrence: t.occurrence,
+        }),
+    };
+    Ok(LedgerPage { transactions, next })
+}
+
+/// Group splits into transactions. The splits must be sorted so that all
+/// splits of a transaction (and occurrence) are consecutive.
+/// The balance of each transaction is computed for the `ref_id` account.
+
+pub fn splits_to_transactions(
+    rows: Vec<SplitRow>,
+    ref_id: AccountId,
+) -> Vec<TransactionDescr> {
+    let mut result: Vec<TransactionDescr> = vec![];
+    for split in rows {
+        let need_new = result.is_empty()
+            || result.last().unwrap().id != split.transaction_id
+            || result.last().unwrap().occurrence != split.occurrence;
+        if need_new {
+            result.push(TransactionDescr {
+                id: split.transaction_id,
+                occurrence: split.occurrence,
+                date: Utc.from_utc_date(&split.timestamp).and_hms(0, 0, 0),
+                balance: Some(Decimal::ZERO),
+                balance_shares: Decimal::ZERO,
+                memo: split.memo.unwrap_or_else(|| "".to_string()),
+                check_number: split.check_number
+                    .unwrap_or_else(|| "".to_string()),
+                is_recurring: split.scheduled.unwrap_or(false),
+                splits: vec![],
+            });
         }
-        Err(_) => vec![],
+
+        let r = result.last_mut().unwrap();
+
+        if split.account_id == ref_id {
+            r.balance_shares += from_scaled(split.scaled_qty_balance, split.commodity_scu)
+                .unwrap_or_default();
+            r.balance = split.computed_price
+                .map(|p| (r.balance_shares * p).normalize());
+        }
+
+        r.splits.push(SplitDescr {
+            account_id: split.account_id,
+            post_date: Utc.from_utc_date(&split.post_date).and_hms(0, 0, 0),
+            amount: split.value,
+            currency: split.value_commodity_id,
+            reconcile: split.reconcile.chars().next().unwrap(),
+            shares: from_scaled(split.scaled_qty, split.commodity_scu)
+                .unwrap_or_default(),
+            price: split.computed_price,
+            payee: split.payee.unwrap_or_else(|| "".to_string()),
+        });
     }
+    result
 }
@@ -0,0 +1,148 @@
+//! The report engine of alere: access to the database and all the
+//! commands. It is shared by the Tauri application and the command line
+//! tool (alere-cli).
+
+#[macro_use]
+extern crate diesel;
+
+#[macro_use]
+extern crate diesel_migrations;
+
+pub mod account_tree;
+pub mod accounts;
+pub mod attachments;
+pub mod audit;
+pub mod backups;
+pub mod cashflow;
+pub mod connections;
+pub mod consistency;
+pub mod cte_accounts;
+pub mod cte_list_splits;
+pub mod cte_query_balance;
+pub mod cte_query_networth;
+pub mod databases;
+pub mod dates;
+pub mod decimals;
+pub mod encryption;
+pub mod errors;
+pub mod export_journal;
+pub mod export_report;
+pub mod goals;
+#[cfg(feature = "http-server")]
+pub mod http_server;
+pub mod import_beancount;
+pub mod import_camt;
+pub mod import_gnucash;
+pub mod importer;
+pub mod income_expense;
+pub mod ledger;
+pub mod means;
+pub mod metrics;
+pub mod models;
+pub mod occurrences;
+pub mod projection;
+pub mod qif;
+pub mod query_builder;
+pub mod quotes;
+pub mod scenarios;
+pub mod schema;
+pub mod search;
+pub mod simulation;
+pub mod tags;
+pub mod xml;
+
+/// Call the macro `$callback` with the list of all commands exposed to the
+/// front-end, as `module::function(arguments)`. This ensures that the Tauri
+/// application and the HTTP server (see http_server.rs) expose the same
+/// commands. Arguments are given by name, as sent by the front-end.
+/// The `local` commands read or write files given by the caller, or
+/// change which database is used, so are not served over HTTP.
+
+#[macro_export]
+macro_rules! for_each_command {
+    ($callback:ident) => {
+        $callback! {
+            remote: [
+                account_tree::account_tree(dates, currency, value, scenario),
+                accounts::fetch_accounts(),
+                attachments::attachment_path(id),
+                attachments::check_attachments(removeorphans),
+                attachments::delete_attachment(id),
+                attachments::list_attachments(transactionid),
+                audit::list_operations(limit),
+                audit::redo(),
+                audit::transaction_history(transactionid),
+                audit::undo(),
+                backups::backup_diff(name),
+                backups::backup_settings(),
+                backups::create_backup(),
+                backups::list_backups(),
+                consistency::check_database(),
+                databases::current_database(),
+                databases::recent_databases(),
+                goals::create_goal(
+                    name, description, target, targetdate, currency, accounts
+                ),
+                goals::delete_goal(id),
+                goals::goals(),
+                goals::goals_progress(months),
+                goals::update_goal(
+                    id, name, description, target, targetdate, currency, accounts
+                ),
+                income_expense::income_expense(
+                    income, expense, mindate, maxdate, currency, scenario, tags
+                ),
+                ledger::ledger(
+                    mindate, maxdate, accountids, occurrences, scenario, tags
+                ),
+                ledger::ledger_page(
+                    mindate, maxdate, accountids, occurrences, scenario, filters, page
+                ),
+                means::mean(
+                    mindate, maxdate, currency, prior, after, unrealized, scenario, tags
+                ),
+                metrics::balance(dates, currency),
+                metrics::metrics(mindate, maxdate, currency, scenario),
+                metrics::networth_history(mindate, maxdate, currency, scenario),
+                projection::networth_projection(
+                    currency, years, annualreturn, scenario
+                ),
+                quotes::quotes(mindate, maxdate, currency, commodities, accounts),
+                scenarios::clone_to_scenario(scenario, transactionids),
+                scenarios::compare_scenarios(
+                    mindate, maxdate, currency, scenarios, scheduled
+                ),
+                scenarios::create_scenario(name, description),
+                scenarios::delete_scenario(id),
+                scenarios::list_scenarios(),
+                scenarios::update_scenario(id, name, description),
+                search::search(query),
+                simulation::retirement_simulation(params),
+                tags::create_tag(name, description),
+                tags::delete_tag(id),
+                tags::list_tags(),
+                tags::set_tag(tag, transactionids, splitids, tagged),
+                tags::tag_report(mindate, maxdate, currency, tags, scenario),
+                tags::update_tag(id, name, description),
+            ],
+            local: [
+                attachments::attach_file(transactionid, path),
+                attachments::export_attachment(id, target),
+                backups::restore_backup(name),
+                backups::set_backup_settings(settings),
+                databases::create_database(path, passphrase),
+                databases::open_database(path, passphrase),
+                encryption::change_passphrase(passphrase),
+                encryption::decrypt_database(),
+                encryption::encrypt_database(passphrase),
+                encryption::unlock_database(passphrase),
+                export_journal::export_journal(format, path),
+                export_report::export_report(
+                    report, path, mindate, maxdate, currency, accounts, scenario
+                ),
+                importer::import_file(path, account),
+                qif::export_qif(account, path),
+            ],
+        }
+    };
+}
@@ -1,5 +1,9 @@
+use super::errors::AlereError;
 use super::models::{CommodityId};
+use super::scenarios::{Scenario, NO_SCENARIO};
+use super::tags::TagId;
 use chrono::{NaiveDate, DateTime, Utc, Datelike};
+use rust_decimal::Decimal;
 use serde::Serialize;
 use log::info;
 use std::collections::HashMap;
@@ -7,15 +11,15 @@ use std::collections::HashMap;
 #[derive(Serialize)]
 pub struct Point {
     date: NaiveDate,
-    value_expenses: f32,
-    average_expenses: f32,
-    value_realized: f32,
-    value_networth_delta: f32,
-    average_networth_delta: f32,
+    value_expenses: Option<Decimal>,
+    average_expenses: Option<Decimal>,
+    value_realized: Option<Decimal>,
+    value_networth_delta: Decimal,
+    average_networth_delta: Decimal,
 }
 
 
-#[tauri::command]
+#[cfg_attr(feature = "gui", tauri::command)]
 pub async fn mean(
     mindate: DateTime<Utc>,
     maxdate: DateTime<Utc>,
@@ -23,7 +27,10 @@ pub async fn mean(
     prior: u8,
     after: u8,
     unrealized: bool,
-) -> Vec<Point> {
+    scenario: Option<Scenario>,
+    tags: Option<Vec<TagId>>,
+) -> Result<Vec<Point>, AlereError> {
+    let scenario = scenario.unwrap_or(NO_SCENARIO);
     info!("mean {:?} {:?} prior={} after={} unrealized={} {}",
           &mindate, &maxdate, prior, after, unrealized, currency);
 
@@ -32,9 +39,9 @@ pub async fn mean(
         Some(maxdate.date()),
         super::dates::GroupBy::MONTHS,
     ).restrict_to_splits(
-        super::scenarios::NO_SCENARIO,
+        scenario,
         &super::occurrences::Occurrences::no_recurrence(),
-    );
+    )?;
 
     // The "unrealized" part must be computed from variations in the
     // networth since the variation in prices, for instance, are not
@@ -46,11 +53,11 @@ pub async fn mean(
         let points = super::metrics::query_networth_history(
             &dates,
             currency,
-            super::scenarios::NO_SCENARIO,
+            scenario,
             &super::occurrences::Occurrences::no_recurrence(),
             prior,
             after,
-        );
+        )?;
         for p in &points {
             unreal.insert(
                 NaiveDate::from_ymd(p.date.year(), p.date.month(), 1),
@@ -62,23 +69,24 @@ pub async fn mean(
     let cashflow = super::cashflow::monthly_cashflow(
         &dates,
         currency,
-        super::scenarios::NO_SCENARIO,
+        scenario,
         &super::occurrences::Occurrences::no_recurrence(),
         prior,
         after,
-    );
+        &tags,
+    )?;
 
     let mut result = Vec::new();
     for c in cashflow.iter() {
-        let u = unreal.get(&c.month).unwrap_or(&(0.0, 0.0));
+        let u = unreal.get(&c.month).unwrap_or(&(Decimal::ZERO, Decimal::ZERO));
         result.push(Point {
             date: c.month,
-            value_expenses: -c.exp_total.unwrap_or(f32::NAN),
-            average_expenses: -c.exp_average.unwrap_or(f32::NAN),
-            value_realized: -c.realized_inc_total.unwrap_or(f32::NAN),
+            value_expenses: c.exp_total.map(|v| -v),
+            average_expenses: c.exp_average.map(|v| -v),
+            value_realized: c.realized_inc_total.map(|v| -v),
             value_networth_delta: u.0,
             average_networth_delta: u.1,
         });
     }
-    result
+    Ok(result)
 }
@@ -4,12 +4,14 @@ use super::cte_query_balance::{
     cte_balances, cte_balances_currency, CTE_BALANCES_CURRENCY};
 use super::cte_query_networth::{cte_query_networth, CTE_QUERY_NETWORTH};
 use super::dates::{DateRange, DateSet, DateValues, GroupBy, CTE_DATES};
+use super::decimals::SqlDecimal;
+use super::errors::AlereError;
 use super::models::{AccountId, CommodityId};
 use super::occurrences::Occurrences;
-use super::scenarios::Scenario;
+use super::query_builder::{Query, Sql};
+use super::scenarios::{Scenario, NO_SCENARIO};
 use chrono::{DateTime, NaiveDate, Utc};
-use diesel::sql_types::{Bool, Date, Float, Integer};
-use rust_decimal::prelude::*; //  to_f32
+use diesel::sql_types::{Bool, Date, Integer, Text};
 use rust_decimal::Decimal;
 use serde::Serialize;
 use std::collections::HashMap;
@@ -17,9 +19,9 @@ use log::info;
 
 #[derive(Clone, Debug, Serialize)]
 pub struct PerAccount {
-    account_id: AccountId,
-    shares: Vec<Decimal>, // one entry per date index
-    price: Vec<Decimal>,  // one entry per date index
+    pub account_id: AccountId,
+    pub shares: Vec<Decimal>, // one entry per date index
+    pub price: Vec<Decimal>,  // one entry per date index
 }
 
 #[derive(Debug, QueryableByName)]
@@ -30,89 +32,129 @@ struct NetworthRow {
     #[sql_type = "Integer"]
     account: AccountId,
 
-    #[sql_type = "Float"]
-    shares: f32,
+    #[sql_type = "Text"]
+    #[diesel(deserialize_as = "SqlDecimal")]
+    shares: Decimal,
 
-    #[sql_type = "Float"]
-    computed_price: f32,
+    #[sql_type = "Text"]
+    #[diesel(deserialize_as = "SqlDecimal")]
+    computed_price: Decimal,
 }
 
 /// Compute the networth as of certain dates.
 /// The number of "shares" as returned might actually be monetary value, when
 /// the account's commodity is a currency (in which case, the price will
 /// be the exchange rate between that currency and currency_id).
+/// The dates need to be explicit, since the result is indexed by their
+/// position.
 
 pub fn networth(
-    dates: &dyn DateSet,
+    dates: &DateValues,
     currency: CommodityId,
     scenario: Scenario,
     max_scheduled_occurrences: &Occurrences,
-) -> Vec<PerAccount> {
+) -> Result<Vec<PerAccount>, AlereError> {
     let list_splits = cte_list_splits(
         &dates.unbounded_start(),
         scenario,
         max_scheduled_occurrences,
     );
-    let balances = cte_balances();
-    let balances_cur = cte_balances_currency();
+    let balances = cte_balances(&list_splits);
+    let balances_cur = cte_balances_currency(&balances);
     let dates_cte = dates.cte();
-    let query = format!(
+    let query = Query::new(Sql::new(format!(
         "
-       WITH RECURSIVE
-          {list_splits},
-          {balances},
-          {balances_cur},
-          {dates_cte}
        SELECT
           {CTE_DATES}.idx AS idx,
           b.account_id    AS account,
-          b.shares,
+          alr_decimal(b.scaled_shares, b.commodity_scu) AS shares,
           b.computed_price
        FROM {CTE_BALANCES_CURRENCY} b
           JOIN alr_accounts a ON (b.account_id = a.id)
           JOIN alr_account_kinds k ON (a.kind_id = k.id),
           {CTE_DATES}
        WHERE
-          b.currency_id = {currency}
+          b.currency_id = :currency
           AND b.mindate <= {CTE_DATES}.date
           AND {CTE_DATES}.date < b.maxdate
           AND k.is_networth
     "
-    );
-
-    let result = super::connections::execute_and_log::<NetworthRow>("networth", &query);
-    match result {
-        Ok(rows) => {
-            let mut per_account: HashMap<AccountId, PerAccount> = HashMap::new();
-            for row in rows.iter() {
-                let e = per_account.entry(row.account).or_insert(PerAccount {
-                    account_id: row.account,
-                    shares: vec![Decimal::ZERO; 3], // ???
-                    price: vec![Decimal::ZERO; 3],
-                });
-                e.shares[row.idx as usize - 1] = Decimal::new((row.shares * 100.0) as i64, 2);
-                e.price[row.idx as usize - 1] =
-                    Decimal::new((row.computed_price * 100.0) as i64, 2);
-            }
-            per_account.values().cloned().collect()
-        }
-        Err(_) => vec![],
+    ))
+    .bind("currency", currency))
+    .with(&balances_cur)
+    .with(&dates_cte);
+
+    let rows = super::connections::execute_and_log::<NetworthRow>("networth", query)?;
+    let count = dates.len();
+    let mut per_account: HashMap<AccountId, PerAccount> = HashMap::new();
+    for row in rows.iter() {
+        let e = per_account.entry(row.account).or_insert(PerAccount {
+            account_id: row.account,
+            shares: vec![Decimal::ZERO; count],
+            price: vec![Decimal::ZERO; count],
+        });
+        e.shares[row.idx as usize - 1] = row.shares;
+        e.price[row.idx as usize - 1] = row.computed_price;
     }
+    Ok(per_account.values().cloned().collect())
 }
 
-#[derive(QueryableByName, Serialize)]
+#[derive(Serialize)]
 pub struct NWPoint {
-    #[sql_type = "Date"]
     pub date: NaiveDate,
+    pub diff: Decimal,
+    pub average: Decimal,
+    pub value: Decimal,
+}
 
-    #[sql_type = "Float"]
-    pub diff: f32,
+#[derive(QueryableByName)]
+struct NWComponent {
+    #[sql_type = "Date"]
+    date: NaiveDate,
 
-    #[sql_type = "Float"]
-    pub average: f32,
+    #[sql_type = "Text"]
+    #[diesel(deserialize_as = "SqlDecimal")]
+    shares: Decimal,
 
-    #[sql_type = "Float"]
-    pub value: f32,
+    #[sql_type = "Text"]
+    #[diesel(deserialize_as = "SqlDecimal")]
+    computed_price: Decimal,
+}
+
+/// Compute the diff between consecutive values, and the rolling average
+/// of those diffs.
+
+fn rolling_diffs(values: Vec<(NaiveDate, Decimal)>, prior: u8, after: u8) -> Vec<NWPoint> {
+    let diffs: Vec<Option<Decimal>> = values
+        .iter()
+        .enumerate()
+        .map(|(idx, v)| match idx {
+            0 => None,
+            _ => Some(v.1 - values[idx - 1].1),
+        })
+        .collect();
+    values
+        .iter()
+        .enumerate()
+        .map(|(idx, (date, value))| {
+            let window: Vec<Decimal> = diffs
+                [idx.saturating_sub(prior as usize)
+                 ..(idx + after as usize + 1).min(diffs.len())]
+                .iter()
+                .flatten()
+                .cloned()
+                .collect();
+            NWPoint {
+                date: *date,
+                diff: diffs[idx].unwrap_or(Decimal::ZERO),
+                average: match window.len() {
+                    0 => Decimal::ZERO,
+                    n => (window.iter().sum::<Decimal>() / Decimal::from(n)).normalize(),
+                },
+                value: *value,
+            }
+        })
+        .collect()
 }
 
 /// Computes the networth at the end of each month.
@@ -128,66 +170,57 @@ pub fn query_networth_history(
     max_scheduled_occurrences: &Occurrences,
     prior: u8, // number of rows preceding to compute rolling average
     after: u8, // number of rows following
-) -> Vec<NWPoint> {
-    let q_networth = cte_query_networth(currency);
+) -> Result<Vec<NWPoint>, AlereError> {
     let list_splits = cte_list_splits(
         &dates.unbounded_start(), //  from the start to get balances right
         scenario,
         max_scheduled_occurrences,
     );
-    let balances = cte_balances();
-    let balances_currency = cte_balances_currency();
-    let dates_cte = dates.cte();
-    let query = format!(
+    let balances = cte_balances(&list_splits);
+    let balances_currency = cte_balances_currency(&balances);
+    let q_networth = cte_query_networth(&balances_currency, &dates.cte(), currency);
+    let query = Query::new(format!(
         "
-        WITH RECURSIVE {dates_cte}, \
-           {list_splits}, \
-           {balances}, \
-           {balances_currency}, \
-           {q_networth} \
-        SELECT \
-           tmp2.date, \
-           COALESCE(tmp2.diff, 0.0) AS diff, \
-           COALESCE(AVG(tmp2.diff) OVER \
-               (ORDER BY tmp2.date \
-                ROWS BETWEEN {prior} PRECEDING \
-                AND {after} FOLLOWING), 0.0) AS average, \
-           tmp2.value \
-        FROM \
-           (SELECT \
-              tmp.date, \
-              tmp.value - LAG(tmp.value) OVER (ORDER BY tmp.date) as diff, \
-              tmp.value \
-            FROM ({CTE_QUERY_NETWORTH}) tmp \
-           ) tmp2 \
+        SELECT date, shares, computed_price \
+        FROM {CTE_QUERY_NETWORTH} \
+        ORDER BY date \
         "
-    );
-
-    let result = super::connections::execute_and_log::<NWPoint>("query_networth_history", &query);
-    result.unwrap_or_default()
+    ))
+    .with(&q_networth);
+
+    let rows = super::connections::execute_and_log::<NWComponent>(
+        "query_networth_history", query)?;
+    let mut values: Vec<(NaiveDate, Decimal)> = vec![];
+    for row in rows {
+        let v = row.shares * row.computed_price;
+        match values.last_mut() {
+            Some(last) if last.0 == row.date => last.1 += v,
+            _ => values.push((row.date, v)),
+        }
+    }
+    Ok(rolling_diffs(values, prior, after))
 }
 
-#[tauri::command]
+#[cfg_attr(feature = "gui", tauri::command)]
 pub async fn networth_history(
     mindate: DateTime<Utc>,
     maxdate: DateTime<Utc>,
     currency: CommodityId,
-) -> Vec<NWPoint> {
-    info!("networth_history {:?} {:?}", &mindate, &maxdate);
+    scenario: Option<Scenario>,
+) -> Result<Vec<NWPoint>, AlereError> {
+    info!("networth_history {:?} {:?} {:?}", &mindate, &maxdate, &scenario);
 
     let group_by: GroupBy = GroupBy::MONTHS;
     let include_scheduled: bool = false;
     let prior: u8 = 0;
     let after: u8 = 0;
+    let scenario = scenario.unwrap_or(NO_SCENARIO);
     let dates = DateRange::new(
             Some(mindate.date()),
             Some(maxdate.date()),
             group_by)
         .extend(prior, after)
-        .restrict_to_splits(
-           super::scenarios::NO_SCENARIO,
-           &Occurrences::no_recurrence());
-    let scenario = super::scenarios::NO_SCENARIO;
+        .restrict_to_splits(scenario, &Occurrences::no_recurrence())?;
     let occurrences = match include_scheduled {
         true => Occurrences::unlimited(),
         false => Occurrences::no_recurrence(),
@@ -200,17 +233,17 @@ pub async fn networth_history(
 /// For each date, compute the current price and number of shares for each
 /// account.
 
-#[tauri::command]
+#[cfg_attr(feature = "gui", tauri::command)]
 pub async fn balance(
     dates: Vec<DateTime<Utc>>,
     currency: CommodityId,
-) -> Vec<PerAccount> {
+) -> Result<Vec<PerAccount>, AlereError> {
     info!("balance {:?}", &dates);
     networth(
         // ??? Can we pass directly an iterator instead
         &DateValues::new(Some(dates.iter().map(|d| d.date()).collect())),
         currency,
-        super::scenarios::NO_SCENARIO,
+        NO_SCENARIO,
         &Occurrences::no_recurrence(),
     )
 }
@@ -220,8 +253,9 @@ pub struct SplitsPerAccount {
     #[sql_type = "Integer"]
     pub account_id: AccountId,
 
-    #[sql_type = "Float"]
-    pub value: f32,
+    #[sql_type = "Text"]
+    #[diesel(deserialize_as = "SqlDecimal")]
+    pub value: Decimal,
 }
 
 /// For each account, computes the total of splits that apply to it in the
@@ -232,28 +266,23 @@ pub fn sum_splits_per_account(
     currency: CommodityId,
     scenario: Scenario,
     max_scheduled_occurrences: &Occurrences,
-) -> HashMap<AccountId, f32> {
+) -> Result<HashMap<AccountId, Decimal>, AlereError> {
     let list_splits = cte_list_splits(dates, scenario, max_scheduled_occurrences);
-    let with_values = cte_splits_with_values();
-    let query = format!(
+    let with_values = cte_splits_with_values(&list_splits);
+    let query = Query::new(Sql::new(format!(
         "
-        WITH RECURSIVE {list_splits}, \
-          {with_values} \
-        SELECT s.account_id, SUM(s.value) AS value \
+        SELECT s.account_id, \
+           alr_decimal(SUM(s.scaled_value), MAX(s.value_scale)) AS value \
         FROM {CTE_SPLITS_WITH_VALUE} s \
-        WHERE s.value_commodity_id = {currency} \
+        WHERE s.value_commodity_id = :currency \
         GROUP BY s.account_id
         "
-    );
+    ))
+    .bind("currency", currency))
+    .with(&with_values);
     let rows =
-        super::connections::execute_and_log::<SplitsPerAccount>("sum_splits_per_account", &query);
-    let mut res: HashMap<AccountId, f32> = HashMap::new();
-    if let Ok(r) = rows {
-        for row in r.iter() {
-            res.insert(row.account_id, row.value);
-        }
-    }
-    res
+        super::connections::execute_and_log::<SplitsPerAccount>("sum_splits_per_account", query)?;
+    Ok(rows.into_iter().map(|row| (row.account_id, row.value)).collect())
 }
 
 /// Compute the total networth
@@ -278,13 +307,13 @@ where
 /// Sum splits
 
 fn sum_splits<F>(
-    all_splits: &HashMap<AccountId, f32>,
+    all_splits: &HashMap<AccountId, Decimal>,
     filter: F, // receives an account id
-) -> f32
+) -> Decimal
 where
     F: Fn(&AccountId) -> bool,
 {
-    let mut result: f32 = 0.0;
+    let mut result = Decimal::ZERO;
     for (account_id, value) in all_splits {
         if filter(account_id) {
             result += value;
@@ -325,32 +354,34 @@ struct AccountIsNWRow {
 
 #[derive(serde::Serialize)]
 pub struct Networth {
-    income: f32,
-    passive_income: f32,
-    work_income: f32,
-    expenses: f32,
-    income_taxes: f32,
-    other_taxes: f32,
-    networth: f32,
-    networth_start: f32,
-    liquid_assets: f32,
-    liquid_assets_at_start: f32,
+    income: Decimal,
+    passive_income: Decimal,
+    work_income: Decimal,
+    expenses: Decimal,
+    income_taxes: Decimal,
+    other_taxes: Decimal,
+    networth: Decimal,
+    networth_start: Decimal,
+    liquid_assets: Decimal,
+    liquid_assets_at_start: Decimal,
 }
 
-#[tauri::command]
+#[cfg_attr(feature = "gui", tauri::command)]
 pub async fn metrics(
     mindate: DateTime<Utc>,
     maxdate: DateTime<Utc>,
     currency: CommodityId,
-) -> Networth {
-    info!("metrics {:?} {:?}", &mindate, &maxdate);
+    scenario: Option<Scenario>,
+) -> Result<Networth, AlereError> {
+    info!("metrics {:?} {:?} {:?}", &mindate, &maxdate, &scenario);
+    let scenario = scenario.unwrap_or(NO_SCENARIO);
     let dates = DateValues::new(Some(vec![mindate.date(), maxdate.date()]));
     let all_networth = networth(
         &dates,
         currency,
-        super::scenarios::NO_SCENARIO,
+        scenario,
         &Occurrences::no_recurrence(),
-    );
+    )?;
 
     let mut accounts: HashMap<AccountId, AccountIsNWRow> = HashMap::new();
     let equity = super::accounts::AccountKindCategory::EQUITY as u32;
@@ -359,24 +390,25 @@ pub async fn metrics(
 
     let account_rows = super::connections::execute_and_log::<AccountIsNWRow>(
         "metrics",
-        &format!(
+        Sql::new(
             "SELECT a.id AS account_id, \
             k.is_networth, \
-            k.category = {equity} AND k.is_networth AS is_liquid, \
-            k.category = {income} AND not k.is_unrealized AS realized_income, \
+            k.category = :equity AND k.is_networth AS is_liquid, \
+            k.category = :income AND not k.is_unrealized AS realized_income, \
             k.is_passive_income, \
             k.is_work_income, \
-            k.category = {expense} AS is_expense, \
+            k.category = :expense AS is_expense, \
             k.is_misc_tax, \
             k.is_income_tax \
          FROM alr_accounts a JOIN alr_account_kinds k \
          ON (a.kind_id=k.id)"
-        ),
-    );
-    if let Ok(acc) = account_rows {
-        for a in acc {
-            accounts.insert(a.account_id, a);
-        }
+        )
+        .bind("equity", equity)
+        .bind("income", income)
+        .bind("expense", expense),
+    )?;
+    for a in account_rows {
+        accounts.insert(a.account_id, a);
     }
 
     let networth_at_start = sum_networth(
@@ -403,9 +435,9 @@ pub async fn metrics(
     let over_period = sum_splits_per_account(
         &dates,
         currency,
-        super::scenarios::NO_SCENARIO,
+        scenario,
         &Occurrences::no_recurrence(),
-    );
+    )?;
 
     let income = -sum_splits(&over_period, |a| {
         accounts
@@ -432,16 +464,16 @@ pub async fn metrics(
         accounts.get(a).map(|ac| ac.is_income_tax).unwrap_or(false)
     });
 
-    Networth {
+    Ok(Networth {
         income,
         passive_income,
         work_income,
         expenses: expense,
         income_taxes,
         other_taxes,
-        networth: networth_at_end.to_f32().unwrap(),
-        networth_start: networth_at_start.to_f32().unwrap(),
-        liquid_assets: liquid_assets_at_end.to_f32().unwrap(),
-        liquid_assets_at_start: liquid_assets_at_start.to_f32().unwrap(),
-    }
+        networth: networth_at_end.normalize(),
+        networth_start: networth_at_start.normalize(),
+        liquid_assets: liquid_assets_at_end.normalize(),
+        liquid_assets_at_start: liquid_assets_at_start.normalize(),
+    })
 }
@@ -0,0 +1,143 @@
+//! Forward-looking projection of the networth, including the future
+//! occurrences of scheduled transactions.
+
+use super::dates::DateValues;
+use super::errors::AlereError;
+use super::metrics::{networth, PerAccount};
+use super::models::{AccountId, CommodityId};
+use super::occurrences::Occurrences;
+use super::scenarios::{Scenario, NO_SCENARIO};
+use chrono::{Date, NaiveDate, Utc};
+use diesel::sql_types::Integer;
+use log::info;
+use rust_decimal::prelude::*; //  to_f32, from_f64
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashSet;
+
+#[derive(QueryableByName)]
+struct InvestmentAccount {
+    #[sql_type = "Integer"]
+    account_id: AccountId,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ProjectedPoint {
+    pub date: NaiveDate,
+    pub value: f32,       // total networth
+    pub investments: f32, // part of the networth in investment accounts
+}
+
+/// The list of accounts to which an assumed return should be applied
+
+pub fn investment_accounts() -> Result<HashSet<AccountId>, AlereError> {
+    let rows = super::connections::execute_and_log::<InvestmentAccount>(
+        "investment_accounts",
+        "SELECT a.id AS account_id \
+         FROM alr_accounts a JOIN alr_account_kinds k ON (a.kind_id=k.id) \
+         WHERE k.is_trading AND k.is_networth",
+    )?;
+    Ok(rows.iter().map(|r| r.account_id).collect())
+}
+
+/// Compute the value of one account at each date.
+/// When a growth rate is given, the value of the account at the previous
+/// date grows by that rate, and any change in the number of shares is
+/// added (valued at the last known price, which never changes in the
+/// future).
+
+fn project_account(
+    account: &PerAccount,
+    dates: &[Date<Utc>],
+    annual_return: Option<f64>,
+) -> Vec<Decimal> {
+    let raw: Vec<Decimal> = account.shares.iter()
+        .zip(account.price.iter())
+        .map(|(s, p)| s * p)
+        .collect();
+    match annual_return {
+        None => raw,
+        Some(rate) => {
+            let mut result = Vec::with_capacity(raw.len());
+            for (idx, value) in raw.iter().enumerate() {
+                if idx == 0 {
+                    result.push(*value);
+                } else {
+                    let days = (dates[idx] - dates[idx - 1]).num_days() as f64;
+                    let growth = Decimal::from_f64(
+                        (1.0 + rate).powf(days / 365.25)).unwrap_or(Decimal::ONE);
+                    let previous = result[idx - 1];
+                    result.push(previous * growth + value - raw[idx - 1]);
+                }
+            }
+            result
+        }
+    }
+}
+
+/// Project the networth from today, at the end of each month for the next
+/// `years` years. All occurrences of scheduled transactions are taken into
+/// account.
+/// :param annual_return:
+///    if specified, the return (0.05 for 5%) applied every year to
+///    investment accounts. Otherwise their price remains constant.
+
+pub fn project_networth(
+    currency: CommodityId,
+    scenario: Scenario,
+    years: u8,
+    annual_return: Option<f64>,
+) -> Result<Vec<ProjectedPoint>, AlereError> {
+    let dates = DateValues::end_of_months(Utc::today(), years as u16 * 12);
+    let all_dates = dates.dates();
+    // Enough occurrences for a daily schedule over the whole period. They
+    // are still bounded by the end of the date range in the queries.
+    let max_occurrences = u16::try_from(years as u32 * 366).unwrap_or(u16::MAX);
+    let per_account = networth(
+        &dates, currency, scenario, &Occurrences::new(max_occurrences))?;
+    let investments = investment_accounts()?;
+
+    let mut total = vec![Decimal::ZERO; all_dates.len()];
+    let mut invested = vec![Decimal::ZERO; all_dates.len()];
+    for account in &per_account {
+        let is_investment = investments.contains(&account.account_id);
+        let values = project_account(
+            account,
+            all_dates,
+            if is_investment { annual_return } else { None },
+        );
+        for (idx, v) in values.iter().enumerate() {
+            total[idx] += v;
+            if is_investment {
+                invested[idx] += v;
+            }
+        }
+    }
+
+    Ok(all_dates
+        .iter()
+        .enumerate()
+        .map(|(idx, d)| ProjectedPoint {
+            date: d.naive_utc(),
+            value: total[idx].to_f32().unwrap_or(f32::NAN),
+            investments: invested[idx].to_f32().unwrap_or(f32::NAN),
+        })
+        .collect())
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn networth_projection(
+    currency: CommodityId,
+    years: u8,
+    annualreturn: Option<f64>,
+    scenario: Option<Scenario>,
+) -> Result<Vec<ProjectedPoint>, AlereError> {
+    info!("networth_projection {} years={} return={:?} {:?}",
+          currency, years, annualreturn, scenario);
+    project_networth(
+        currency,
+        scenario.unwrap_or(NO_SCENARIO),
+        years,
+        annualreturn,
+    )
+}
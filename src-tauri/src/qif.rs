@@ -0,0 +1,1160 @@
+//! Quicken Interchange Format (QIF), as exported by Quicken, Microsoft
+//! Money and many banks.
+//!
+//! The reader supports bank, cash, credit card and other asset or
+//! liability accounts, investment accounts (!Type:Invst), as well as the
+//! lists of accounts, categories, classes, securities, prices and
+//! memorized transactions.
+//! Categories become income or expense accounts (with their hierarchy, as
+//! in "Auto:Fuel"), and classes become tags. We have no templates, so
+//! memorized transactions only create their payees and categories.
+//! Quicken writes transfers in both accounts: the second occurrence is
+//! skipped.
+//! Securities are held in child accounts of the investment account, which
+//! itself holds the cash.
+//! QIF does not say whether dates are written day first or month first:
+//! this is guessed from the whole file (month first when ambiguous).
+//!
+//! The writer exports the ledger of one account.
+
+use super::accounts::{commodity_kinds, AccountKindCategory};
+use super::cte_accounts::{cte_account_names, CTE_ACCOUNT_NAMES};
+use super::decimals::{format_scaled, from_scaled};
+use super::errors::AlereError;
+use super::importer::{
+    kind_names, Importer, NewSplit, NewTransaction, PayeeId, UNCATEGORIZED,
+};
+use super::models::{AccountId, CommodityId};
+use super::query_builder::{Query, Sql};
+use super::scenarios::NO_SCENARIO;
+use chrono::NaiveDate;
+use diesel::sql_types::{BigInt, Date, Integer, Text};
+use log::info;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Scale for the prices of securities
+const STOCK_PRICE_SCALE: i32 = 10000;
+
+const OPENING_BALANCES: &str = "Opening Balances";
+const COMMISSIONS: &str = "Commissions";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Accounts,
+    Bank,        // also cash, credit cards, other assets and liabilities
+    Investment,
+    Categories,
+    Classes,
+    Memorized,
+    Securities,
+    Prices,
+    Other,
+}
+
+struct Record {
+    section: Section,
+    qif_type: String,  // as found in the !Type header
+    line: usize,
+    fields: Vec<(char, String)>,
+}
+
+impl Record {
+    /// The first non-empty field with that code
+    fn get(&self, code: char) -> Option<&str> {
+        self.fields
+            .iter()
+            .filter(|(c, _)| *c == code)
+            .map(|(_, v)| v.trim())
+            .find(|v| !v.is_empty())
+    }
+
+    fn has(&self, code: char) -> bool {
+        self.fields.iter().any(|(c, _)| *c == code)
+    }
+}
+
+fn error(line: usize, message: impl std::fmt::Display) -> AlereError {
+    AlereError::parse(format!("line {}: {}", line, message))
+}
+
+fn section(qif_type: &str) -> Section {
+    match qif_type.to_lowercase().as_str() {
+        "bank" | "cash" | "ccard" | "oth a" | "oth l" => Section::Bank,
+        "invst" => Section::Investment,
+        "cat" => Section::Categories,
+        "class" => Section::Classes,
+        "memorized" => Section::Memorized,
+        "security" => Section::Securities,
+        "prices" => Section::Prices,
+        _ => Section::Other,
+    }
+}
+
+/// Split the file into records, each terminated by a "^" line
+fn parse(text: &str) -> Vec<Record> {
+    let mut records = vec![];
+    let mut current = Section::Other;
+    let mut qif_type = String::new();
+    let mut fields = vec![];
+    let mut start = 0;
+    let mut flush = |fields: &mut Vec<(char, String)>, section, qif_type: &str, start| {
+        if !fields.is_empty() {
+            records.push(Record {
+                section,
+                qif_type: qif_type.to_string(),
+                line: start,
+                fields: std::mem::take(fields),
+            });
+        }
+    };
+
+    for (idx, raw) in text.lines().enumerate() {
+        let line = raw.trim_start_matches('\u{feff}').trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('!') {
+            flush(&mut fields, current, &qif_type, start);
+            let header = header.trim();
+            let lower = header.to_lowercase();
+            if lower == "account" {
+                current = Section::Accounts;
+            } else if lower.starts_with("type:") {
+                qif_type = header[5..].trim().to_string();
+                current = section(&qif_type);
+            }
+            // "!Option:AutoSwitch" and "!Clear:AutoSwitch" do not change
+            // the kind of records
+            continue;
+        }
+        if line == "^" {
+            flush(&mut fields, current, &qif_type, start);
+            continue;
+        }
+        if fields.is_empty() {
+            start = idx + 1;
+        }
+        if current == Section::Prices {
+            // the whole line: "SYMBOL",price,"date"
+            fields.push(('"', line.to_string()));
+        } else {
+            let mut chars = line.chars();
+            let code = chars.next().unwrap_or_default();
+            fields.push((code, chars.as_str().to_string()));
+        }
+    }
+    flush(&mut fields, current, &qif_type, start);
+    records
+}
+
+/// The three numbers of a date, and whether the year comes first
+fn date_parts(s: &str) -> Option<([u32; 3], bool)> {
+    let parts: Vec<&str> = s
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|p| !p.is_empty())
+        .collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    Some((
+        [parts[0].parse().ok()?, parts[1].parse().ok()?, parts[2].parse().ok()?],
+        parts[0].len() == 4,
+    ))
+}
+
+/// Parse a date like "12/31/2020", "31.12.20", "1/ 5'04" or "2020-12-31".
+/// Quicken writes an apostrophe before two-digit years after 2000.
+
+fn parse_date(s: &str, day_first: bool) -> Option<NaiveDate> {
+    let (n, year_first) = date_parts(s)?;
+    if year_first {
+        return NaiveDate::from_ymd_opt(n[0] as i32, n[1], n[2]);
+    }
+    let year = match n[2] {
+        y if y >= 100 => y,
+        y if s.contains('\'') || y < 70 => 2000 + y,
+        y => 1900 + y,
+    };
+    let (month, day) = if day_first { (n[1], n[0]) } else { (n[0], n[1]) };
+    NaiveDate::from_ymd_opt(year as i32, month, day)
+}
+
+/// Parse an amount, with either "." or "," as the decimal separator
+fn parse_amount(s: &str) -> Option<Decimal> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace() && *c != '$').collect();
+    let s = match (s.rfind('.'), s.rfind(',')) {
+        (Some(dot), Some(comma)) if comma > dot => s.replace('.', "").replace(',', "."),
+        (None, Some(comma)) if s.len() - comma != 4 => s.replace(',', "."),
+        _ => s.replace(',', ""),
+    };
+    Decimal::from_str(&s).ok()
+}
+
+/// Split a line of the prices section: "SYMBOL",price,"date"
+fn price_fields(line: &str) -> Option<(&str, String, &str)> {
+    let parts: Vec<&str> = line.split(',').map(|p| p.trim().trim_matches('"').trim()).collect();
+    match parts.as_slice() {
+        [symbol, price @ .., date] if !price.is_empty() => Some((symbol, price.join(","), date)),
+        _ => None,
+    }
+}
+
+enum Target {
+    None,
+    Category(String),
+    Transfer(String),  // "[Account]"
+}
+
+/// Parse the category of a transaction or split: "Auto:Fuel/Class" or
+/// "[Account]/Class".
+
+fn parse_target(s: &str) -> (Target, Option<String>) {
+    let s = s.trim();
+    let (target, class) = match s.strip_prefix('[').and_then(|r| r.split_once(']')) {
+        Some((account, rest)) => (Target::Transfer(account.trim().to_string()), rest),
+        None => match s.split_once('/') {
+            Some((category, class)) => (Target::Category(category.trim().to_string()), class),
+            None => (Target::Category(s.to_string()), ""),
+        },
+    };
+    let target = match target {
+        Target::Category(c) if c.is_empty() => Target::None,
+        t => t,
+    };
+    let class = class.trim().trim_start_matches('/').trim();
+    (target, (!class.is_empty()).then(|| class.to_string()))
+}
+
+fn reconcile(flag: Option<&str>) -> &'static str {
+    match flag {
+        Some("*") | Some("c") => "C",
+        Some("X") | Some("x") | Some("R") => "R",
+        _ => "n",
+    }
+}
+
+fn account_kind(qif_type: &str) -> (&'static str, AccountKindCategory) {
+    match qif_type.to_lowercase().as_str() {
+        "ccard" | "oth l" => (kind_names::LIABILITY, AccountKindCategory::LIABILITY),
+        "oth a" => (kind_names::ASSET, AccountKindCategory::ASSET),
+        "invst" | "port" | "mutual" | "401(k)/403(b)" => {
+            (kind_names::INVESTMENT, AccountKindCategory::EQUITY)
+        }
+        _ => (kind_names::BANK, AccountKindCategory::EQUITY),
+    }
+}
+
+/// One category of a transaction, or one of its splits
+struct Part {
+    target: Target,
+    class: Option<String>,
+    amount: Decimal,
+    memo: Option<String>,
+}
+
+struct QifImport<'a, 'b> {
+    imp: &'b mut Importer<'a>,
+    day_first: bool,
+    currency: CommodityId,
+    file_name: String,
+    current: Option<AccountId>,              // account of the transactions
+    income: HashMap<String, bool>,           // categories of the list
+    securities: HashMap<String, String>,     // name to symbol
+    security_digits: HashMap<String, u32>,   // largest number of decimals of quantities
+
+    // Transfers already imported, whose other side is expected later:
+    // (date, account of the other side, account, amount for the other side)
+    transfers: Vec<(NaiveDate, AccountId, AccountId, Decimal)>,
+}
+
+impl<'a, 'b> QifImport<'a, 'b> {
+    /// Collect what is needed before the transactions are imported, since
+    /// lists can appear anywhere in the file.
+
+    fn scan(&mut self, records: &[Record]) {
+        let mut dates = vec![];
+        for r in records {
+            match r.section {
+                Section::Bank | Section::Investment | Section::Memorized => {
+                    dates.extend(r.get('D'));
+                }
+                Section::Prices => {
+                    dates.extend(r.get('"').and_then(price_fields).map(|(_, _, d)| d));
+                }
+                Section::Categories => {
+                    if let Some(name) = r.get('N') {
+                        self.income.insert(name.to_string(), r.has('I') && !r.has('E'));
+                    }
+                }
+                Section::Securities => {
+                    if let Some(name) = r.get('N') {
+                        let symbol = r.get('S').unwrap_or(name);
+                        self.securities.insert(name.to_string(), symbol.to_string());
+                    }
+                }
+                _ => {}
+            }
+            if r.section == Section::Investment {
+                if let (Some(name), Some(qty)) = (r.get('Y'), r.get('Q').and_then(parse_amount)) {
+                    let digits = self.security_digits.entry(name.to_string()).or_default();
+                    *digits = (*digits).max(qty.normalize().scale()).min(8);
+                }
+            }
+        }
+
+        // Day first as soon as a day larger than 12 is found first
+        self.day_first = dates
+            .iter()
+            .filter_map(|d| date_parts(d))
+            .any(|(n, year_first)| !year_first && n[0] > 12 && n[1] <= 12);
+    }
+
+    fn date(&self, r: &Record) -> Result<NaiveDate, AlereError> {
+        let d = r.get('D').ok_or_else(|| error(r.line, "missing date"))?;
+        parse_date(d, self.day_first).ok_or_else(|| error(r.line, format!("invalid date {}", d)))
+    }
+
+    fn amount(&self, r: &Record, code: char) -> Result<Option<Decimal>, AlereError> {
+        r.get(code)
+            .map(|a| parse_amount(a).ok_or_else(|| error(r.line, format!("invalid amount {}", a))))
+            .transpose()
+    }
+
+    /// The account for the transactions, when no !Account header was found
+    fn current_account(&mut self, r: &Record) -> Result<AccountId, AlereError> {
+        if let Some(a) = self.current {
+            return Ok(a);
+        }
+        let account = match self.imp.default_account {
+            Some(a) => a,
+            None => self.imp.account(
+                None, &self.file_name.clone(), account_kind(&r.qif_type), self.currency, 100)?,
+        };
+        self.current = Some(account);
+        Ok(account)
+    }
+
+    fn account_header(&mut self, r: &Record) -> Result<(), AlereError> {
+        let name = r.get('N').ok_or_else(|| error(r.line, "account without a name"))?;
+        let account = match self.imp.find_account_named(name) {
+            Some(a) => a,
+            None => self.imp.account(
+                None, name, account_kind(r.get('T').unwrap_or("Bank")), self.currency, 100)?,
+        };
+        self.imp.update_account(account, r.get('D'), None, None, None)?;
+        self.current = Some(account);
+        Ok(())
+    }
+
+    /// Find or create a category, and its parents
+    fn category(&mut self, path: &str, income: bool) -> Result<AccountId, AlereError> {
+        let kind = match income {
+            true => (kind_names::INCOME, AccountKindCategory::INCOME),
+            false => (kind_names::EXPENSE, AccountKindCategory::EXPENSE),
+        };
+        let mut parent = None;
+        for name in path.split(':').map(str::trim).filter(|n| !n.is_empty()) {
+            parent = Some(self.imp.account(parent, name, kind, self.currency, 100)?);
+        }
+        parent.ok_or_else(|| AlereError::parse(format!("invalid category {:?}", path)))
+    }
+
+    /// Whether a category is for income, from the list of categories or
+    /// else from the sign of the amount (as seen from the account).
+
+    fn is_income(&self, path: &str, amount: Decimal) -> bool {
+        self.income
+            .get(path)
+            .or_else(|| self.income.get(path.split(':').next().unwrap_or_default()))
+            .copied()
+            .unwrap_or(amount > Decimal::ZERO)
+    }
+
+    fn opening_balances(&mut self) -> Result<AccountId, AlereError> {
+        self.imp.account(
+            None,
+            OPENING_BALANCES,
+            (kind_names::EQUITY, AccountKindCategory::EQUITY),
+            self.currency,
+            100,
+        )
+    }
+
+    fn transfer_account(&mut self, name: &str) -> Result<AccountId, AlereError> {
+        match self.imp.find_account_named(name) {
+            Some(a) => Ok(a),
+            None => self.imp.account(
+                None,
+                name,
+                (kind_names::BANK, AccountKindCategory::EQUITY),
+                self.currency,
+                100,
+            ),
+        }
+    }
+
+    /// The other account of a part, and whether it is a transfer.
+    /// Quicken writes opening balances as transfers to the account itself.
+
+    fn counterpart(
+        &mut self,
+        target: &Target,
+        account: AccountId,
+        amount: Decimal,
+    ) -> Result<(AccountId, bool), AlereError> {
+        match target {
+            Target::Transfer(name) => match self.transfer_account(name)? {
+                a if a == account => Ok((self.opening_balances()?, false)),
+                a => Ok((a, true)),
+            },
+            Target::Category(path) => {
+                let income = self.is_income(path, amount);
+                Ok((self.category(path, income)?, false))
+            }
+            Target::None => Ok((self.category(UNCATEGORIZED, amount > Decimal::ZERO)?, false)),
+        }
+    }
+
+    /// Whether the other side of this transfer was already imported
+    fn take_transfer(
+        &mut self,
+        date: NaiveDate,
+        account: AccountId,
+        other: AccountId,
+        amount: Decimal,
+    ) -> bool {
+        match self.transfers.iter().position(|t| *t == (date, account, other, amount)) {
+            Some(idx) => {
+                self.transfers.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn split(
+        &self,
+        account: AccountId,
+        amount: Decimal,
+        date: NaiveDate,
+        reconcile: &'static str,
+        payee: Option<PayeeId>,
+    ) -> NewSplit {
+        NewSplit {
+            account,
+            qty: amount,
+            value: amount,
+            value_commodity: self.currency,
+            reconcile,
+            reconcile_date: None,
+            post_date: date,
+            payee,
+        }
+    }
+
+    fn insert(
+        &mut self,
+        r: &Record,
+        date: NaiveDate,
+        memos: Vec<String>,
+        check_number: Option<&str>,
+        tags: Vec<String>,
+        splits: Vec<NewSplit>,
+    ) -> Result<(), AlereError> {
+        let tags = tags.iter().map(|t| self.imp.tag(t)).collect::<Result<_, _>>()?;
+        let memos: Vec<String> = r.get('M').map(str::to_string).into_iter().chain(memos).collect();
+        self.imp.transaction(&NewTransaction {
+            timestamp: date,
+            memo: (!memos.is_empty()).then(|| memos.join("; ")),
+            check_number: check_number.map(str::to_string),
+            tags,
+            splits,
+            scheduled: None,
+            last_occurrence: None,
+        })?;
+        Ok(())
+    }
+
+    /// Insert a transaction of account, made of the given parts.
+    /// Transfers already imported from the other account are left out.
+
+    fn record(
+        &mut self,
+        r: &Record,
+        account: AccountId,
+        date: NaiveDate,
+        total: Decimal,
+        parts: Vec<Part>,
+        check_number: Option<&str>,
+    ) -> Result<(), AlereError> {
+        let payee = r.get('P').map(|p| self.imp.payee(p)).transpose()?;
+        let mut splits = vec![];
+        let mut own = total;
+        let mut memos: Vec<String> = vec![];
+        let mut tags = vec![];
+        for part in parts {
+            let (other, transfer) = self.counterpart(&part.target, account, part.amount)?;
+            if transfer {
+                if self.take_transfer(date, account, other, part.amount) {
+                    own -= part.amount;
+                    continue;
+                }
+                self.transfers.push((date, other, account, -part.amount));
+            }
+            splits.push(self.split(other, -part.amount, date, "n", payee));
+            tags.extend(part.class);
+            memos.extend(part.memo.filter(|m| !memos.contains(m)));
+        }
+        if splits.is_empty() && own.is_zero() {
+            return Ok(());
+        }
+        splits.insert(0, self.split(account, own, date, reconcile(r.get('C')), payee));
+        self.insert(r, date, memos, check_number, tags, splits)
+    }
+
+    fn bank(&mut self, r: &Record) -> Result<(), AlereError> {
+        let account = self.current_account(r)?;
+        let date = self.date(r)?;
+        let total = match self.amount(r, 'T')? {
+            Some(t) => t,
+            None => self.amount(r, 'U')?.unwrap_or_default(),
+        };
+        let mut parts: Vec<Part> = vec![];
+        for (code, value) in &r.fields {
+            match code {
+                'S' => {
+                    let (target, class) = parse_target(value);
+                    parts.push(Part { target, class, amount: Decimal::ZERO, memo: None });
+                }
+                'E' => if let Some(p) = parts.last_mut() {
+                    p.memo = Some(value.trim().to_string()).filter(|m| !m.is_empty());
+                },
+                '$' => if let Some(p) = parts.last_mut() {
+                    p.amount = parse_amount(value)
+                        .ok_or_else(|| error(r.line, format!("invalid amount {}", value)))?;
+                },
+                _ => {}
+            }
+        }
+        if parts.is_empty() {
+            let (target, class) = parse_target(r.get('L').unwrap_or_default());
+            parts.push(Part { target, class, amount: total, memo: None });
+        }
+        self.record(r, account, date, total, parts, r.get('N'))
+    }
+
+    /// The account holding a security, below the investment account
+    fn security(&mut self, investment: AccountId, name: &str) -> Result<AccountId, AlereError> {
+        let symbol = self.securities.get(name).cloned().unwrap_or_else(|| name.to_string());
+        let commodity = self.imp.commodity(
+            &symbol, Some(name), commodity_kinds::STOCK, STOCK_PRICE_SCALE)?;
+        let digits = self.security_digits.get(name).copied().unwrap_or_default();
+        self.imp.account(
+            Some(investment),
+            name,
+            (kind_names::STOCK, AccountKindCategory::EQUITY),
+            commodity,
+            10_i32.pow(digits),
+        )
+    }
+
+    fn investment(&mut self, r: &Record) -> Result<(), AlereError> {
+        let account = self.current_account(r)?;
+        let date = self.date(r)?;
+        let action = r.get('N').unwrap_or_default().to_lowercase();
+        let (target, class) = parse_target(r.get('L').unwrap_or_default());
+
+        // Cash moving in or out of the account, as in bank accounts
+        if let "xin" | "xout" | "cash" = action.as_str() {
+            let mut total = self.amount(r, 'T')?.or(self.amount(r, 'U')?).unwrap_or_default();
+            if action != "cash" {
+                total = if action == "xin" { total.abs() } else { -total.abs() };
+            }
+            let parts = vec![Part { target, class, amount: total, memo: None }];
+            return self.record(r, account, date, total, parts, None);
+        }
+
+        let qty = self.amount(r, 'Q')?.unwrap_or_default().abs();
+        let commission = self.amount(r, 'O')?.unwrap_or_default();
+        let total = match self.amount(r, 'T')?.or(self.amount(r, 'U')?) {
+            Some(t) => t,
+            None => self.amount(r, 'I')?.unwrap_or_default() * qty,
+        }
+        .abs();
+
+        // The "X" actions move the cash to or from another account
+        let (base, elsewhere) = match action.strip_suffix('x') {
+            Some(base) => (base, true),
+            None => (action.as_str(), false),
+        };
+        let category = match (&target, elsewhere) {
+            (Target::Category(c), false) => Some(c.clone()),
+            _ => None,
+        };
+        let security = |qif: &mut Self| match r.get('Y') {
+            Some(name) => qif.security(account, name),
+            None => Err(error(r.line, format!("{} without a security", action))),
+        };
+
+        // (account, qty, value) in addition to the cash
+        let mut legs = vec![];
+        let cash = match base {
+            "buy" => {
+                legs.push((security(self)?, qty, total - commission));
+                -total
+            }
+            "sell" => {
+                legs.push((security(self)?, -qty, -(total + commission)));
+                total
+            }
+            "div" | "intinc" | "cglong" | "cgmid" | "cgshort" | "miscinc" | "rtrncap" => {
+                let name = category.unwrap_or_else(|| income_category(base).to_string());
+                let income = self.category(&name, true)?;
+                legs.push((income, -total, -total));
+                total
+            }
+            "reinvdiv" | "reinvint" | "reinvlg" | "reinvmd" | "reinvsh" => {
+                legs.push((security(self)?, qty, total));
+                let name = category.unwrap_or_else(|| income_category(base).to_string());
+                let income = self.category(&name, true)?;
+                legs.push((income, -total, -total));
+                Decimal::ZERO
+            }
+            "shrsin" | "shrsout" => {
+                let sign = if base == "shrsin" { Decimal::ONE } else { -Decimal::ONE };
+                legs.push((security(self)?, sign * qty, sign * total));
+                let equity = self.opening_balances()?;
+                legs.push((equity, -sign * total, -sign * total));
+                Decimal::ZERO
+            }
+            "miscexp" | "margint" => {
+                let name = category.unwrap_or_else(|| match base {
+                    "margint" => "Margin interest".to_string(),
+                    _ => "Investment expenses".to_string(),
+                });
+                let expense = self.category(&name, false)?;
+                legs.push((expense, total, total));
+                -total
+            }
+            _ => {
+                self.imp.summary.ignored += 1;
+                self.imp.warn(format!("line {}: unsupported action {:?}", r.line, action));
+                return Ok(());
+            }
+        };
+        if !commission.is_zero() && (base == "buy" || base == "sell") {
+            let expense = self.category(COMMISSIONS, false)?;
+            legs.push((expense, commission, commission));
+        }
+
+        if !cash.is_zero() {
+            let mut cash_account = account;
+            if elsewhere {
+                if let Target::Transfer(name) = &target {
+                    let other = self.transfer_account(name)?;
+                    if other != account && !self.take_transfer(date, account, other, -cash) {
+                        self.transfers.push((date, other, account, cash));
+                        cash_account = other;
+                    }
+                }
+            }
+            legs.insert(0, (cash_account, cash, cash));
+        }
+
+        let payee = r.get('P').map(|p| self.imp.payee(p)).transpose()?;
+        let cleared = reconcile(r.get('C'));
+        let splits = legs
+            .into_iter()
+            .map(|(a, qty, value)| NewSplit {
+                qty,
+                reconcile: if a == account { cleared } else { "n" },
+                ..self.split(a, value, date, "n", payee)
+            })
+            .collect();
+        self.insert(r, date, vec![], None, class.into_iter().collect(), splits)
+    }
+
+    fn category_entry(&mut self, r: &Record) -> Result<(), AlereError> {
+        if let Some(name) = r.get('N') {
+            let income = self.income.get(name).copied().unwrap_or_default();
+            let account = self.category(name, income)?;
+            self.imp.update_account(account, r.get('D'), None, None, None)?;
+        }
+        Ok(())
+    }
+
+    fn memorized(&mut self, r: &Record) -> Result<(), AlereError> {
+        if let Some(p) = r.get('P') {
+            self.imp.payee(p)?;
+        }
+        let amount = self.amount(r, 'T')?.unwrap_or_default();
+        if let (Target::Category(path), _) = parse_target(r.get('L').unwrap_or_default()) {
+            let income = self.is_income(&path, amount);
+            self.category(&path, income)?;
+        }
+        Ok(())
+    }
+
+    fn price(&mut self, r: &Record) -> Result<(), AlereError> {
+        let line = r.get('"').unwrap_or_default();
+        let (symbol, price, date) = price_fields(line)
+            .ok_or_else(|| error(r.line, format!("invalid price {}", line)))?;
+        let (price, date) = match (parse_amount(&price), parse_date(date, self.day_first)) {
+            (Some(p), Some(d)) => (p, d),
+            _ => {
+                self.imp.summary.ignored += 1;
+                self.imp.warn(format!("line {}: invalid price {}", r.line, line));
+                return Ok(());
+            }
+        };
+        let name = self.securities.iter().find(|(_, s)| *s == symbol).map(|(n, _)| n.clone());
+        let commodity = self.imp.commodity(
+            symbol, name.as_deref(), commodity_kinds::STOCK, STOCK_PRICE_SCALE)?;
+        self.imp.price(date, commodity, self.currency, price)
+    }
+
+    fn import(&mut self, records: &[Record]) -> Result<(), AlereError> {
+        self.scan(records);
+
+        // Accounts can be the target of transfers before their own header,
+        // create them first with the right kind
+        for r in records.iter().filter(|r| r.section == Section::Accounts) {
+            self.account_header(r)?;
+        }
+        self.current = None;
+
+        for r in records {
+            match r.section {
+                Section::Accounts => self.account_header(r)?,
+                Section::Bank => self.bank(r)?,
+                Section::Investment => self.investment(r)?,
+                Section::Categories => self.category_entry(r)?,
+                Section::Classes => {
+                    if let Some(name) = r.get('N') {
+                        self.imp.tag(name)?;
+                    }
+                }
+                Section::Memorized => self.memorized(r)?,
+                Section::Securities => {
+                    if let Some(name) = r.get('N') {
+                        let symbol = r.get('S').unwrap_or(name);
+                        self.imp.commodity(
+                            symbol, Some(name), commodity_kinds::STOCK, STOCK_PRICE_SCALE)?;
+                    }
+                }
+                Section::Prices => self.price(r)?,
+                Section::Other => self.imp.summary.ignored += 1,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Default income category for investment actions
+fn income_category(action: &str) -> &'static str {
+    match action {
+        "div" | "reinvdiv" => "Dividends",
+        "intinc" | "reinvint" => "Interest",
+        "cglong" | "cgmid" | "cgshort" | "reinvlg" | "reinvmd" | "reinvsh" => "Capital gains",
+        "rtrncap" => "Return of capital",
+        _ => "Investment income",
+    }
+}
+
+/// Import a QIF file. Transactions go to the account named in the file,
+/// else to the default account of the importer, else to a new account
+/// named after the file.
+
+pub fn import(imp: &mut Importer, path: &Path) -> Result<(), AlereError> {
+    let bytes = std::fs::read(path)?;
+    // Older files are not in UTF-8, but in some Windows code page, which
+    // is close enough to Latin-1
+    let text = String::from_utf8(bytes)
+        .unwrap_or_else(|e| e.into_bytes().iter().map(|b| *b as char).collect());
+    let currency = imp.default_currency()?;
+    let file_name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let records = parse(&text);
+    QifImport {
+        imp,
+        day_first: false,
+        currency,
+        file_name,
+        current: None,
+        income: HashMap::new(),
+        securities: HashMap::new(),
+        security_digits: HashMap::new(),
+        transfers: vec![],
+    }
+    .import(&records)
+}
+
+#[derive(QueryableByName)]
+struct AccountRow {
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Integer"]
+    category: i32,
+    #[sql_type = "Text"]
+    commodity_kind: String,
+    #[sql_type = "Text"]
+    commodity_name: String,
+    #[sql_type = "Text"]
+    symbol: String,
+}
+
+#[derive(QueryableByName)]
+struct SplitRow {
+    #[sql_type = "Integer"]
+    transaction_id: i32,
+    #[sql_type = "Integer"]
+    account_id: AccountId,
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Text"]
+    full_name: String,
+    #[sql_type = "Integer"]
+    category: i32,
+    #[sql_type = "Date"]
+    post_date: NaiveDate,
+    #[sql_type = "BigInt"]
+    scaled_qty: i64,
+    #[sql_type = "Integer"]
+    commodity_scu: i32,
+    #[sql_type = "BigInt"]
+    scaled_value: i64,
+    #[sql_type = "Integer"]
+    value_scale: i32,
+    #[sql_type = "Text"]
+    reconcile: String,
+    #[sql_type = "diesel::sql_types::Nullable<Text>"]
+    memo: Option<String>,
+    #[sql_type = "diesel::sql_types::Nullable<Text>"]
+    check_number: Option<String>,
+    #[sql_type = "diesel::sql_types::Nullable<Text>"]
+    payee: Option<String>,
+}
+
+impl SplitRow {
+    fn is_category(&self) -> bool {
+        self.category == AccountKindCategory::EXPENSE as i32
+            || self.category == AccountKindCategory::INCOME as i32
+    }
+
+    /// How the account is referenced in the other accounts' transactions
+    fn label(&self) -> String {
+        match self.is_category() {
+            true => self.full_name.clone(),
+            false => format!("[{}]", self.name),
+        }
+    }
+
+    fn value(&self) -> Decimal {
+        from_scaled(self.scaled_value, self.value_scale.into()).unwrap_or_default()
+    }
+}
+
+fn one_line(s: &str) -> String {
+    s.replace(['\n', '\r'], " ")
+}
+
+/// Format an amount with the precision of the scale
+fn format_amount(amount: Decimal, scale: i32) -> String {
+    let scaled = (amount * Decimal::from(scale)).round().to_i64().unwrap_or_default();
+    format_scaled(scaled, scale)
+}
+
+/// Write the ledger of one account. Stock accounts are written as
+/// investment accounts. Returns the number of transactions written.
+
+pub fn write_qif(out: &mut impl Write, account: AccountId) -> Result<usize, AlereError> {
+    use super::connections::execute_and_log;
+
+    let header: AccountRow = execute_and_log::<AccountRow>(
+        "qif_account",
+        Sql::new(
+            "SELECT a.name, k.category, c.kind AS commodity_kind,
+                c.name AS commodity_name,
+                COALESCE(c.quote_symbol, c.symbol_after) AS symbol
+             FROM alr_accounts a
+                JOIN alr_account_kinds k ON (a.kind_id = k.id)
+                JOIN alr_commodities c ON (a.commodity_id = c.id)
+             WHERE a.id = :account",
+        )
+        .bind("account", account),
+    )?
+    .pop()
+    .ok_or_else(|| AlereError::not_found(format!("No account {}", account)))?;
+
+    let names = cte_account_names();
+    let rows: Vec<SplitRow> = execute_and_log(
+        "qif_splits",
+        Query::new(
+            Sql::new(format!(
+                "SELECT s.transaction_id, s.account_id, a.name, n.full_name,
+                    k.category, date(s.post_date) AS post_date, s.scaled_qty,
+                    a.commodity_scu, s.scaled_value, c.price_scale AS value_scale,
+                    s.reconcile, t.memo, t.check_number, p.name AS payee
+                 FROM alr_splits s
+                    JOIN alr_transactions t ON (s.transaction_id = t.id)
+                    JOIN alr_accounts a ON (s.account_id = a.id)
+                    JOIN alr_account_kinds k ON (a.kind_id = k.id)
+                    JOIN {CTE_ACCOUNT_NAMES} n ON (n.account_id = a.id)
+                    JOIN alr_commodities c ON (s.value_commodity_id = c.id)
+                    LEFT JOIN alr_payees p ON (s.payee_id = p.id)
+                 WHERE s.transaction_id IN
+                       (SELECT transaction_id FROM alr_splits WHERE account_id = :account)
+                    AND t.scheduled IS NULL
+                    AND t.scenario_id = :no_scenario
+                 ORDER BY t.timestamp, t.id, s.id"
+            ))
+            .bind("account", account)
+            .bind("no_scenario", NO_SCENARIO),
+        )
+        .with(&names),
+    )?;
+
+    let investment = header.commodity_kind == commodity_kinds::STOCK;
+    let qif_type = match header.category {
+        _ if investment => "Invst",
+        c if c == AccountKindCategory::LIABILITY as i32 => "Oth L",
+        c if c == AccountKindCategory::ASSET as i32 => "Oth A",
+        _ => "Bank",
+    };
+    writeln!(out, "!Account\nN{}\nT{}\n^", one_line(&header.name), qif_type)?;
+    if investment {
+        writeln!(
+            out,
+            "!Type:Security\nN{}\nS{}\nTStock\n^",
+            one_line(&header.commodity_name),
+            one_line(&header.symbol),
+        )?;
+    }
+    writeln!(out, "!Type:{}", qif_type)?;
+
+    let mut count = 0;
+    let mut start = 0;
+    while start < rows.len() {
+        let id = rows[start].transaction_id;
+        let end = rows[start..]
+            .iter()
+            .position(|s| s.transaction_id != id)
+            .map_or(rows.len(), |p| start + p);
+        let (own, others): (Vec<&SplitRow>, Vec<&SplitRow>) =
+            rows[start..end].iter().partition(|s| s.account_id == account);
+        start = end;
+
+        let first = own[0];
+        let scu = first.commodity_scu;
+        let qty = from_scaled(own.iter().map(|s| s.scaled_qty).sum(), scu.into())
+            .unwrap_or_default();
+        let value: Decimal = own.iter().map(|s| s.value()).sum();
+        let payee = own.iter().chain(&others).find_map(|s| s.payee.as_ref());
+        let cleared = match first.reconcile.as_str() {
+            "R" => Some("X"),
+            "C" => Some("*"),
+            _ => None,
+        };
+
+        if investment {
+            let transfer = others.iter().find(|s| !s.is_category());
+            let income = others
+                .iter()
+                .find(|s| s.category == AccountKindCategory::INCOME as i32);
+            let commission: Decimal = others
+                .iter()
+                .filter(|s| s.category == AccountKindCategory::EXPENSE as i32)
+                .map(|s| s.value())
+                .sum();
+            let (action, total) = match (qty.cmp(&Decimal::ZERO), transfer, income) {
+                (std::cmp::Ordering::Greater, Some(_), _) => ("BuyX", value + commission),
+                (std::cmp::Ordering::Greater, None, Some(_)) => ("ReinvDiv", value),
+                (std::cmp::Ordering::Greater, None, None) => ("ShrsIn", value),
+                (std::cmp::Ordering::Less, Some(_), _) => ("SellX", -value - commission),
+                (std::cmp::Ordering::Less, None, _) => ("ShrsOut", -value),
+                (_, Some(t), Some(_)) => ("DivX", t.value()),
+                _ => continue,
+            };
+            writeln!(out, "D{}", first.post_date.format("%m/%d/%Y"))?;
+            writeln!(out, "N{}", action)?;
+            writeln!(out, "Y{}", one_line(&header.commodity_name))?;
+            if !qty.is_zero() {
+                writeln!(out, "I{}", (value / qty).abs().round_dp(6).normalize())?;
+                writeln!(out, "Q{}", format_amount(qty.abs(), scu))?;
+            }
+            writeln!(out, "T{}", format_amount(total, first.value_scale))?;
+            if !commission.is_zero() {
+                writeln!(out, "O{}", format_amount(commission, first.value_scale))?;
+            }
+            if let Some(t) = transfer {
+                writeln!(out, "L{}\n${}", t.label(), format_amount(t.value().abs(), t.value_scale))?;
+            }
+        } else {
+            writeln!(out, "D{}", first.post_date.format("%m/%d/%Y"))?;
+            writeln!(out, "T{}", format_amount(qty, scu))?;
+            if let Some(n) = &first.check_number {
+                writeln!(out, "N{}", one_line(n))?;
+            }
+            // Other splits are converted to the commodity of the account
+            let ratio = if value.is_zero() { Decimal::ONE } else { qty / value };
+            match others.as_slice() {
+                [] => {}
+                [other] => writeln!(out, "L{}", other.label())?,
+                _ => for other in &others {
+                    writeln!(out, "S{}\n${}", other.label(), format_amount(-other.value() * ratio, scu))?;
+                },
+            }
+        }
+        if let Some(c) = cleared {
+            writeln!(out, "C{}", c)?;
+        }
+        if let Some(p) = payee {
+            writeln!(out, "P{}", one_line(p))?;
+        }
+        if let Some(m) = &first.memo {
+            writeln!(out, "M{}", one_line(m))?;
+        }
+        writeln!(out, "^")?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Export the ledger of one account as a QIF file, and return the number
+/// of transactions written.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn export_qif(account: AccountId, path: String) -> Result<usize, AlereError> {
+    info!("export_qif {} {}", account, path);
+    let file = std::fs::File::create(&path)?;
+    write_qif(&mut std::io::BufWriter::new(file), account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::{get_connection, test_database};
+    use crate::importer::import_test_file;
+    use commodity_kinds::{CURRENCY, STOCK};
+    use diesel::prelude::*;
+
+    const CHECKING: &str = "\
+!Account
+NChecking
+TBank
+^
+!Type:Bank
+D1/ 1'21
+T1,000.00
+CX
+POpening Balance
+L[Checking]
+^
+D1/15'21
+T2,500.00
+C*
+PEmployer
+LSalary
+^
+D1/20'21
+T-150.25
+N1001
+PSupermarket
+MWeekly
+SFood:Groceries
+$-100.25
+SFood
+$-50.00
+^
+D2/01'21
+T-1000.00
+L[Brokerage]
+^
+!Account
+NBrokerage
+TInvst
+^
+!Type:Security
+NApple Inc
+SAAPL
+TStock
+^
+!Type:Invst
+D2/01'21
+NXIn
+T1000.00
+L[Checking]
+^
+D2/02'21
+NBuy
+YApple Inc
+I130.5
+Q5
+O2.50
+T655.00
+^
+D3/15'21
+NSell
+YApple Inc
+I140
+Q2
+O1
+T279
+^
+";
+
+    /// A new database, with only a currency
+    fn new_book(name: &str) -> std::sync::MutexGuard<'static, ()> {
+        let db = test_database(name);
+        import_test_file("currency.beancount", "1970-01-01 commodity EUR\n", None);
+        db
+    }
+
+    /// Export the ledger of an account, found by name and commodity kind.
+    /// Importing the QIF of a stock account creates both an investment
+    /// account and a stock account with the same name.
+    fn export(name: &str, kind: &str) -> String {
+        use crate::schema::alr_accounts::dsl as a;
+        use crate::schema::alr_commodities::dsl as cm;
+        let account: AccountId = a::alr_accounts
+            .filter(a::name.eq(name))
+            .filter(a::commodity_id.eq_any(
+                cm::alr_commodities.select(cm::id).filter(cm::kind.eq(kind))))
+            .select(a::id)
+            .first(&get_connection().unwrap())
+            .unwrap();
+        let mut out = vec![];
+        write_qif(&mut out, account).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (checking, stock) = {
+            let _db = new_book("qif1");
+            import_test_file("book.qif", CHECKING, None);
+            (export("Checking", CURRENCY), export("Apple Inc", STOCK))
+        };
+        assert!(checking.contains("T2500.00"), "{}", checking);
+        assert!(stock.contains("NBuyX"), "{}", stock);
+
+        {
+            let _db = new_book("qif2");
+            let summary = import_test_file("checking.qif", &checking, None);
+            assert_eq!(summary.transactions, 4);
+            assert_eq!(export("Checking", CURRENCY), checking);
+        }
+
+        let _db = new_book("qif3");
+        let summary = import_test_file("stock.qif", &stock, None);
+        assert_eq!(summary.transactions, 2);
+        assert_eq!(export("Apple Inc", STOCK), stock);
+    }
+}
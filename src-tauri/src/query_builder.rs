@@ -0,0 +1,523 @@
+//! Composition of SQL queries from common table expressions.
+//!
+//! SQL is written as text with named parameters (":name"), and the values
+//! are bound separately, so are never formatted into the query:
+//!     Sql::new("SELECT * FROM alr_accounts WHERE id IN (:ids)")
+//!         .bind("ids", &account_ids)
+//!
+//! A Cte knows the other common table expressions it reads from, so a Query
+//! always emits all of them, in an order where each is defined before being
+//! used:
+//!     let splits = cte_list_splits(&dates, scenario, &occurrences);
+//!     let values = cte_splits_with_values(&splits);
+//!     Query::new(Sql::new("SELECT * FROM ...")).with(&values)
+//!
+//! Optional parts of a query (filters for instance) are themselves Sql,
+//! inserted with `fragment()`.
+//! The names of tables and CTEs cannot be parameters, so are still part of
+//! the text (they are constants).
+
+use super::errors::AlereError;
+use chrono::{Date, NaiveDate, Utc};
+use diesel::query_builder::{AstPass, QueryFragment, QueryId};
+use diesel::query_dsl::{LoadQuery, RunQueryDsl};
+use diesel::deserialize::QueryableByName;
+use diesel::sql_types::{BigInt, Bool, Double, Integer, Nullable, Text};
+use diesel::sqlite::{Sqlite, SqliteConnection};
+use diesel::{Connection, QueryResult};
+
+/// A value bound to a parameter
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    Bool(bool),
+    List(Vec<SqlValue>), // expanded for "IN (:param)"
+}
+
+macro_rules! integer_value {
+    ($($t:ty),*) => {
+        $(impl From<$t> for SqlValue {
+            fn from(v: $t) -> Self {
+                SqlValue::Integer(v as i64)
+            }
+        })*
+    };
+}
+integer_value!(i32, i64, u8, u16, u32);
+
+impl From<f64> for SqlValue {
+    fn from(v: f64) -> Self {
+        SqlValue::Float(v)
+    }
+}
+
+impl From<bool> for SqlValue {
+    fn from(v: bool) -> Self {
+        SqlValue::Bool(v)
+    }
+}
+
+impl From<String> for SqlValue {
+    fn from(v: String) -> Self {
+        SqlValue::Text(v)
+    }
+}
+
+impl From<&str> for SqlValue {
+    fn from(v: &str) -> Self {
+        SqlValue::Text(v.to_string())
+    }
+}
+
+/// Dates are compared as strings in sqlite
+impl From<NaiveDate> for SqlValue {
+    fn from(v: NaiveDate) -> Self {
+        SqlValue::Text(v.format("%Y-%m-%d").to_string())
+    }
+}
+
+impl From<Date<Utc>> for SqlValue {
+    fn from(v: Date<Utc>) -> Self {
+        v.naive_utc().into()
+    }
+}
+
+impl<T: Into<SqlValue>> From<Option<T>> for SqlValue {
+    fn from(v: Option<T>) -> Self {
+        v.map(|v| v.into()).unwrap_or(SqlValue::Null)
+    }
+}
+
+impl<T: Into<SqlValue> + Clone> From<&[T]> for SqlValue {
+    fn from(v: &[T]) -> Self {
+        SqlValue::List(v.iter().cloned().map(|v| v.into()).collect())
+    }
+}
+
+impl<T: Into<SqlValue> + Clone> From<&Vec<T>> for SqlValue {
+    fn from(v: &Vec<T>) -> Self {
+        v.as_slice().into()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Param {
+    Value(SqlValue),
+    Fragment(Sql),
+}
+
+/// Some SQL text, with the values for its named parameters
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sql {
+    text: String,
+    binds: Vec<(String, Param)>,
+}
+
+impl Sql {
+    pub fn new(text: impl Into<String>) -> Self {
+        Sql { text: text.into(), binds: vec![] }
+    }
+
+    /// An empty fragment
+    pub fn empty() -> Self {
+        Sql::new("")
+    }
+
+    fn set(mut self, name: &str, param: Param) -> Self {
+        match self.binds.iter_mut().find(|(n, _)| n == name) {
+            Some(b) => b.1 = param,
+            None => self.binds.push((name.to_string(), param)),
+        }
+        self
+    }
+
+    /// Set the value for the parameter ":name"
+    pub fn bind(self, name: &str, value: impl Into<SqlValue>) -> Self {
+        self.set(name, Param::Value(value.into()))
+    }
+
+    /// Replace ":name" with some other SQL, which has its own parameters
+    pub fn fragment(self, name: &str, sql: Sql) -> Self {
+        self.set(name, Param::Fragment(sql))
+    }
+
+    /// Split the text on parameters, and replace them with their values.
+    /// String literals and comments are left untouched.
+
+    fn pieces(&self, into: &mut Vec<Piece>) -> Result<(), AlereError> {
+        let bytes = self.text.as_bytes();
+        let mut start = 0;
+        let mut idx = 0;
+        while idx < bytes.len() {
+            match bytes[idx] {
+                b'\'' => {
+                    idx += 1;
+                    while idx < bytes.len() && bytes[idx] != b'\'' {
+                        idx += 1;
+                    }
+                    idx += 1;
+                }
+                b'-' if bytes.get(idx + 1) == Some(&b'-') => {
+                    while idx < bytes.len() && bytes[idx] != b'\n' {
+                        idx += 1;
+                    }
+                }
+                b':' if bytes.get(idx + 1)
+                    .map(|c| c.is_ascii_alphabetic() || *c == b'_')
+                    .unwrap_or(false) =>
+                {
+                    let name_start = idx + 1;
+                    let mut name_end = name_start;
+                    while name_end < bytes.len()
+                        && (bytes[name_end].is_ascii_alphanumeric()
+                            || bytes[name_end] == b'_')
+                    {
+                        name_end += 1;
+                    }
+                    let name = &self.text[name_start..name_end];
+                    let param = self.binds.iter()
+                        .find(|(n, _)| n == name)
+                        .map(|(_, v)| v)
+                        .ok_or_else(|| AlereError::validation(
+                            format!("No value for SQL parameter :{}", name)))?;
+                    into.push(Piece::Text(self.text[start..idx].to_string()));
+                    match param {
+                        Param::Fragment(sql) => sql.pieces(into)?,
+                        Param::Value(SqlValue::List(values)) if values.is_empty() => {
+                            // "x IN (NULL)" is never true
+                            into.push(Piece::Text("NULL".to_string()));
+                        }
+                        Param::Value(SqlValue::List(values)) => {
+                            for (i, v) in values.iter().enumerate() {
+                                if i > 0 {
+                                    into.push(Piece::Text(",".to_string()));
+                                }
+                                into.push(Piece::Bind(v.clone()));
+                            }
+                        }
+                        Param::Value(v) => into.push(Piece::Bind(v.clone())),
+                    }
+                    idx = name_end;
+                    start = idx;
+                }
+                _ => idx += 1,
+            }
+        }
+        into.push(Piece::Text(self.text[start.min(bytes.len())..].to_string()));
+        Ok(())
+    }
+}
+
+impl From<&str> for Sql {
+    fn from(text: &str) -> Self {
+        Sql::new(text)
+    }
+}
+
+impl From<String> for Sql {
+    fn from(text: String) -> Self {
+        Sql::new(text)
+    }
+}
+
+/// A common table expression, and those it depends on
+
+#[derive(Clone, Debug)]
+pub struct Cte {
+    name: &'static str,
+    columns: &'static str, // optional list of column names, e.g. "(idx, date)"
+    body: Sql,
+    deps: Vec<Cte>,
+}
+
+impl Cte {
+    pub fn new(name: &'static str, body: Sql) -> Self {
+        Cte { name, columns: "", body, deps: vec![] }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Name the columns of the result
+    pub fn columns(mut self, columns: &'static str) -> Self {
+        self.columns = columns;
+        self
+    }
+
+    /// Declare that self reads from another common table expression
+    pub fn requires(mut self, dep: &Cte) -> Self {
+        self.deps.push(dep.clone());
+        self
+    }
+
+    /// Add self and its dependencies to the list, dependencies first.
+    fn flatten<'a>(&'a self, into: &mut Vec<&'a Cte>) -> Result<(), AlereError> {
+        for d in &self.deps {
+            d.flatten(into)?;
+        }
+        match into.iter().find(|c| c.name == self.name) {
+            None => into.push(self),
+            Some(c) if c.columns == self.columns && c.body == self.body => {}
+            Some(_) => {
+                return Err(AlereError::validation(format!(
+                    "Conflicting definitions for {}", self.name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A full query: the common table expressions and the final statement
+
+#[derive(Clone, Debug)]
+pub struct Query {
+    ctes: Vec<Cte>,
+    body: Sql,
+}
+
+impl Query {
+    pub fn new(body: impl Into<Sql>) -> Self {
+        Query { ctes: vec![], body: body.into() }
+    }
+
+    /// Make a common table expression (and its dependencies) available to
+    /// the query
+    pub fn with(mut self, cte: &Cte) -> Self {
+        self.ctes.push(cte.clone());
+        self
+    }
+
+    /// Resolve dependencies and parameters
+    pub fn build(&self) -> Result<BoundQuery, AlereError> {
+        let mut ordered = vec![];
+        for c in &self.ctes {
+            c.flatten(&mut ordered)?;
+        }
+
+        let mut pieces = vec![];
+        for (idx, c) in ordered.iter().enumerate() {
+            pieces.push(Piece::Text(format!(
+                "{} {}{} AS (",
+                if idx == 0 { "WITH RECURSIVE" } else { "," },
+                c.name,
+                c.columns,
+            )));
+            c.body.pieces(&mut pieces)?;
+            pieces.push(Piece::Text(")\n".to_string()));
+        }
+        self.body.pieces(&mut pieces)?;
+        Ok(BoundQuery { pieces })
+    }
+}
+
+impl From<&str> for Query {
+    fn from(text: &str) -> Self {
+        Query::new(text)
+    }
+}
+
+impl From<Sql> for Query {
+    fn from(body: Sql) -> Self {
+        Query::new(body)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Piece {
+    Text(String),
+    Bind(SqlValue),
+}
+
+/// A query ready to be executed, where parameters are replaced with "?"
+
+#[derive(Clone, Debug)]
+pub struct BoundQuery {
+    pieces: Vec<Piece>,
+}
+
+impl BoundQuery {
+    /// The text of the query, for logging
+    pub fn text(&self) -> String {
+        self.pieces
+            .iter()
+            .map(|p| match p {
+                Piece::Text(t) => t.as_str(),
+                Piece::Bind(_) => "?",
+            })
+            .collect()
+    }
+}
+
+impl QueryFragment<Sqlite> for BoundQuery {
+    fn walk_ast(&self, mut out: AstPass<Sqlite>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        for p in &self.pieces {
+            match p {
+                Piece::Text(t) => out.push_sql(t),
+                Piece::Bind(SqlValue::Null) => {
+                    out.push_bind_param::<Nullable<Integer>, Option<i32>>(&None)?
+                }
+                Piece::Bind(SqlValue::Integer(v)) => out.push_bind_param::<BigInt, _>(v)?,
+                Piece::Bind(SqlValue::Float(v)) => out.push_bind_param::<Double, _>(v)?,
+                Piece::Bind(SqlValue::Text(v)) => out.push_bind_param::<Text, _>(v)?,
+                Piece::Bind(SqlValue::Bool(v)) => out.push_bind_param::<Bool, _>(v)?,
+                Piece::Bind(SqlValue::List(_)) => unreachable!(), // expanded in pieces()
+            }
+        }
+        Ok(())
+    }
+}
+
+impl QueryId for BoundQuery {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T: QueryableByName<Sqlite>> LoadQuery<SqliteConnection, T> for BoundQuery {
+    fn internal_load(self, conn: &SqliteConnection) -> QueryResult<Vec<T>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl RunQueryDsl<SqliteConnection> for BoundQuery {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::sql_types::Integer;
+
+    fn binds(query: &BoundQuery) -> Vec<SqlValue> {
+        query.pieces
+            .iter()
+            .filter_map(|p| match p {
+                Piece::Bind(v) => Some(v.clone()),
+                Piece::Text(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bind() {
+        let q = Query::new(
+            Sql::new("SELECT * FROM t WHERE a = :a AND b > :b OR a = :a")
+                .bind("a", 1)
+                .bind("b", "x")
+                .bind("a", 2),  // replaces the previous value
+        )
+        .build()
+        .unwrap();
+        assert_eq!(q.text(), "SELECT * FROM t WHERE a = ? AND b > ? OR a = ?");
+        assert_eq!(binds(&q), vec![
+            SqlValue::Integer(2),
+            SqlValue::Text("x".to_string()),
+            SqlValue::Integer(2),
+        ]);
+    }
+
+    #[test]
+    fn literals_and_comments() {
+        let q = Query::new(
+            Sql::new("SELECT ':a', 'it''s :a' -- :a\nFROM t WHERE b = :b").bind("b", 1),
+        )
+        .build()
+        .unwrap();
+        assert_eq!(q.text(), "SELECT ':a', 'it''s :a' -- :a\nFROM t WHERE b = ?");
+        assert_eq!(binds(&q), vec![SqlValue::Integer(1)]);
+    }
+
+    #[test]
+    fn missing_value() {
+        assert!(Query::new("SELECT :a").build().is_err());
+    }
+
+    #[test]
+    fn list() {
+        let ids = vec![1, 2, 3];
+        let empty: Vec<i32> = vec![];
+        let q = Query::new(
+            Sql::new("a IN (:ids) AND b IN (:none)")
+                .bind("ids", &ids)
+                .bind("none", &empty),
+        )
+        .build()
+        .unwrap();
+        assert_eq!(q.text(), "a IN (?,?,?) AND b IN (NULL)");
+        assert_eq!(binds(&q), vec![
+            SqlValue::Integer(1),
+            SqlValue::Integer(2),
+            SqlValue::Integer(3),
+        ]);
+    }
+
+    #[test]
+    fn fragment() {
+        let q = Query::new(
+            Sql::new("SELECT * FROM t WHERE a = :a :filter")
+                .bind("a", 1)
+                .fragment("filter", Sql::new("AND b = :a").bind("a", 2)),
+        )
+        .build()
+        .unwrap();
+        assert_eq!(q.text(), "SELECT * FROM t WHERE a = ? AND b = ?");
+        assert_eq!(binds(&q), vec![SqlValue::Integer(1), SqlValue::Integer(2)]);
+    }
+
+    #[test]
+    fn merge_ctes() {
+        let base = Cte::new("base", Sql::new("SELECT :x AS v").bind("x", 1));
+        let left = Cte::new("left_cte", "SELECT v FROM base".into()).requires(&base);
+        let right = Cte::new("right_cte", "SELECT v FROM base".into())
+            .columns("(v)")
+            .requires(&base);
+        let q = Query::new("SELECT * FROM left_cte, right_cte")
+            .with(&left)
+            .with(&right)
+            .build()
+            .unwrap();
+
+        // base is only emitted once, before the others
+        assert_eq!(
+            q.text(),
+            "WITH RECURSIVE base AS (SELECT ? AS v)\n\
+             , left_cte AS (SELECT v FROM base)\n\
+             , right_cte(v) AS (SELECT v FROM base)\n\
+             SELECT * FROM left_cte, right_cte",
+        );
+        assert_eq!(binds(&q), vec![SqlValue::Integer(1)]);
+
+        // Two different definitions with the same name are an error
+        let other = Cte::new("base", Sql::new("SELECT :x AS v").bind("x", 2));
+        assert!(Query::new("SELECT 1").with(&left).with(&other).build().is_err());
+    }
+
+    #[derive(QueryableByName)]
+    struct Row {
+        #[sql_type = "Integer"]
+        v: i32,
+    }
+
+    #[test]
+    fn execute() {
+        let conn = SqliteConnection::establish(":memory:").unwrap();
+        let values = Cte::new(
+            "cte_values",
+            Sql::new("SELECT 1 UNION ALL SELECT 2 UNION ALL SELECT :three").bind("three", 3),
+        )
+        .columns("(v)");
+        let rows: Vec<Row> = Query::new(
+            Sql::new("SELECT v FROM cte_values WHERE v IN (:ids) ORDER BY v")
+                .bind("ids", &vec![2, 3, 4]),
+        )
+        .with(&values)
+        .build()
+        .unwrap()
+        .load(&conn)
+        .unwrap();
+        assert_eq!(rows.iter().map(|r| r.v).collect::<Vec<_>>(), vec![2, 3]);
+    }
+}
@@ -1,35 +1,249 @@
 use diesel::prelude::*;
-use chrono::{DateTime, Utc, TimeZone};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc, TimeZone};
 use log::info;
 use serde::Serialize;
 use std::collections::HashMap;
-use diesel::sql_types::Integer;
+use diesel::sql_types::{BigInt, Bool, Integer, Text, Timestamp};
+use rust_decimal::prelude::*; //  to_f32
 use super::accounts::{commodity_kinds, price_sources};
-use super::models::{AccountId, CommodityId, Commodity, Roi};
+use super::decimals::{from_scaled, SqlDecimal};
+use super::errors::AlereError;
+use super::models::{AccountId, CommodityId, Commodity};
+use super::query_builder::Sql;
+
+/// The return on investment of an account, during a range of time where
+/// neither its number of shares nor the price changes. All values are
+/// in the currency requested for the report.
+
+pub struct Roi {
+    pub mindate: NaiveDateTime,
+    pub maxdate: NaiveDateTime,
+    pub account_id: AccountId,
+    pub realized_gain: Decimal,
+    pub invested: Decimal,
+    pub shares: Decimal,
+    pub balance: Decimal,
+    pub computed_price: Decimal,
+    pub roi: Option<f32>,
+    pub pl: Decimal,
+    pub average_cost: Option<Decimal>,
+    pub weighted_average: Option<Decimal>,
+}
+
+/// A split of an investment account, combined with one of the splits of
+/// the same transaction in a networth account (possibly the split itself).
+/// Money moved from or to other accounts is invested or realized.
+
+#[derive(QueryableByName)]
+struct Movement {
+    #[sql_type = "Integer"]
+    account_id: AccountId,
+    #[sql_type = "Timestamp"]
+    post_date: NaiveDateTime,
+    #[sql_type = "BigInt"]
+    scaled_qty: i64,
+    #[sql_type = "Bool"]
+    same_account: bool,
+    #[sql_type = "BigInt"]
+    other_value: i64,
+    #[sql_type = "Integer"]
+    other_scale: i32,
+    #[sql_type = "Integer"]
+    other_commodity_id: CommodityId,
+    #[sql_type = "Timestamp"]
+    other_date: NaiveDateTime,
+}
+
+/// The price of a commodity in the currency, during [mindate, maxdate).
+/// Prices are only floats when converted from the reverse direction, or
+/// through another currency. Others are loaded exactly.
+
+#[derive(QueryableByName)]
+struct Rate {
+    #[sql_type = "Integer"]
+    origin_id: CommodityId,
+    #[sql_type = "Text"]
+    #[diesel(deserialize_as = "SqlDecimal")]
+    scaled_price: Decimal,
+    #[sql_type = "Integer"]
+    price_scale: i32,
+    #[sql_type = "Timestamp"]
+    mindate: NaiveDateTime,
+    #[sql_type = "Timestamp"]
+    maxdate: NaiveDateTime,
+}
+
+impl Rate {
+    fn price(&self) -> Decimal {
+        self.scaled_price
+            .checked_div(Decimal::from(self.price_scale))
+            .unwrap_or_default()
+    }
+}
+
+/// Totals for an account since its first transaction
+
+#[derive(Clone, Default)]
+struct Invested {
+    scaled_shares: i64,
+    invested: Decimal,
+    realized_gain: Decimal,
+    invested_for_shares: Decimal,
+    scaled_shares_transacted: i64,
+}
+
+/// Compute the return on investment of the accounts over time, from the
+/// scaled amounts of their splits and the price of their commodity.
+/// :param accounts: the accounts, and the scale of their commodity
+
+fn compute_rois(
+    accounts: &HashMap<AccountId, (CommodityId, i32)>,
+    currency: CommodityId,
+) -> Result<Vec<Roi>, AlereError> {
+    let ids: Vec<AccountId> = accounts.keys().cloned().collect();
+    let movements = super::connections::execute_and_log::<Movement>(
+        "quotes,movements",
+        Sql::new(
+            "
+            SELECT s.account_id,
+               s.post_date,
+               s.scaled_qty,
+               s.account_id = s2.account_id AS same_account,
+               s2.scaled_value AS other_value,
+               c2.price_scale AS other_scale,
+               s2.value_commodity_id AS other_commodity_id,
+               s2.post_date AS other_date
+            FROM alr_splits s
+               JOIN alr_splits s2 USING (transaction_id)
+               JOIN alr_accounts s2a ON (s2.account_id = s2a.id)
+               JOIN alr_account_kinds s2ak
+                  ON (s2a.kind_id = s2ak.id AND s2ak.is_networth)
+               JOIN alr_commodities c2 ON (s2.value_commodity_id = c2.id)
+            WHERE s.account_id IN (:accs)
+            ORDER BY s.account_id, s.post_date
+            "
+        )
+        .bind("accs", &ids),
+    )?;
+
+    let mut rates: HashMap<CommodityId, Vec<Rate>> = HashMap::new();
+    for r in super::connections::execute_and_log::<Rate>(
+        "quotes,rates",
+        Sql::new(
+            "
+            SELECT origin_id, scaled_price, price_scale, mindate, maxdate
+            FROM alr_price_history_with_turnkey
+            WHERE target_id = :currency
+            ORDER BY origin_id, mindate
+            "
+        )
+        .bind("currency", currency),
+    )? {
+        rates.entry(r.origin_id).or_default().push(r);
+    }
+    let rate = |commodity: CommodityId, date: NaiveDateTime| {
+        rates.get(&commodity)?
+            .iter()
+            .find(|r| r.mindate <= date && date < r.maxdate)
+            .map(|r| r.price())
+    };
+
+    // The totals for each account, after all the splits of each day
+    let end_of_time = NaiveDate::from_ymd(2999, 12, 31).and_hms(0, 0, 0);
+    let mut ranges: Vec<(AccountId, NaiveDateTime, NaiveDateTime, Invested)> = vec![];
+    let mut current = Invested::default();
+    for (idx, m) in movements.iter().enumerate() {
+        // Splits that cannot be converted to the currency are ignored
+        if let Some(r) = rate(m.other_commodity_id, m.other_date) {
+            let value = from_scaled(m.other_value, m.other_scale.into())
+                .unwrap_or_default() * r;
+            if m.same_account {
+                current.scaled_shares += m.scaled_qty;
+            } else {
+                if value.is_sign_negative() {
+                    current.invested -= value;
+                } else {
+                    current.realized_gain += value;
+                }
+                if !value.is_zero() && m.scaled_qty != 0 {
+                    current.invested_for_shares += value.abs();
+                    current.scaled_shares_transacted += m.scaled_qty.abs();
+                }
+            }
+        }
+
+        let next = movements.get(idx + 1);
+        if next.map(|n| n.account_id != m.account_id).unwrap_or(true) {
+            ranges.push((m.account_id, m.post_date, end_of_time, current));
+            current = Invested::default();
+        } else if next.map(|n| n.post_date != m.post_date).unwrap_or(false) {
+            let next_date = next.unwrap().post_date;
+            ranges.push((m.account_id, m.post_date, next_date, current.clone()));
+        }
+    }
+
+    // Combine with the price of the commodity
+    let mut rois = vec![];
+    for (account_id, mindate, maxdate, inv) in ranges {
+        let (commodity, scu) = accounts[&account_id];
+        let shares = from_scaled(inv.scaled_shares, scu.into()).unwrap_or_default();
+        let transacted = from_scaled(inv.scaled_shares_transacted, scu.into())
+            .unwrap_or_default();
+        for p in rates.get(&commodity).into_iter().flatten() {
+            if mindate >= p.maxdate || p.mindate >= maxdate {
+                continue;
+            }
+            let price = p.price();
+            let balance = shares * price;
+            rois.push(Roi {
+                mindate: mindate.max(p.mindate),
+                maxdate: maxdate.min(p.maxdate),
+                account_id,
+                realized_gain: inv.realized_gain.normalize(),
+                invested: inv.invested.normalize(),
+                shares,
+                balance: balance.normalize(),
+                computed_price: price.normalize(),
+                roi: (balance + inv.realized_gain)
+                    .checked_div(inv.invested)
+                    .and_then(|r| r.to_f32()),
+                pl: (balance + inv.realized_gain - inv.invested).normalize(),
+                average_cost: (inv.invested - inv.realized_gain)
+                    .checked_div(shares)
+                    .map(|d| d.normalize()),
+                weighted_average: inv.invested_for_shares
+                    .checked_div(transacted)
+                    .map(|d| d.normalize()),
+            });
+        }
+    }
+    rois.sort_by_key(|r| r.mindate);
+    Ok(rois)
+}
 
 #[derive(Serialize)]
 pub struct Position {
-    avg_cost: f32,
-    equity: f32,
-    gains: f32,
-    invested: f32,
-    pl: f32,
-    roi: f32,
-    shares: f32,
-    weighted_avg: f32,
+    pub avg_cost: Option<Decimal>,
+    pub equity: Decimal,
+    pub gains: Decimal,
+    pub invested: Decimal,
+    pub pl: Decimal,
+    pub roi: f32,
+    pub shares: Decimal,
+    pub weighted_avg: Option<Decimal>,
 }
 
 impl Position {
     pub fn new(roi: &Roi) -> Self {
         Position {
-            avg_cost: roi.average_cost.unwrap_or(f32::NAN),
+            avg_cost: roi.average_cost,
             equity: roi.balance,
             gains: roi.realized_gain,
             invested: roi.invested,
             pl: roi.pl,
             roi: roi.roi.unwrap_or(f32::NAN),
             shares: roi.shares,
-            weighted_avg: roi.weighted_average.unwrap_or(f32::NAN),
+            weighted_avg: roi.weighted_average,
         }
     }
 }
@@ -37,14 +251,14 @@ impl Position {
 impl Default for Position {
     fn default() -> Self {
         Position {
-            avg_cost: 0.0,
-            equity: 0.0,
-            gains: 0.0,
-            invested: 0.0,
-            pl: 0.0,
+            avg_cost: Some(Decimal::ZERO),
+            equity: Decimal::ZERO,
+            gains: Decimal::ZERO,
+            invested: Decimal::ZERO,
+            pl: Decimal::ZERO,
             roi: 0.0,
-            shares: 0.0,
-            weighted_avg: 0.0,
+            shares: Decimal::ZERO,
+            weighted_avg: Some(Decimal::ZERO),
         }
     }
 }
@@ -52,24 +266,24 @@ impl Default for Position {
 #[derive(Serialize)]
 pub struct Price {
     t: i64,  //  DateTime<Utc>,
-    price: f32,
+    price: Decimal,
     roi: f32,
-    shares: f32,
+    shares: Decimal,
 }
 
 /// Details on an investment account
 
 #[derive(Serialize)]
 pub struct ForAccount {
-    account: AccountId,
-    start: Position,                    // as of mindate
-    end: Position,                      // as of maxdate
-    oldest: Option<DateTime<Utc>>,      // oldest transaction (for annualized)
-    most_recent: Option<DateTime<Utc>>, // most recent transaction
-    now_for_annualized: DateTime<Utc>,
-    prices: Vec<Price>,
-    annualized_roi: f32,
-    period_roi: f32,
+    pub account: AccountId,
+    pub start: Position,                    // as of mindate
+    pub end: Position,                      // as of maxdate
+    pub oldest: Option<DateTime<Utc>>,      // oldest transaction (for annualized)
+    pub most_recent: Option<DateTime<Utc>>, // most recent transaction
+    pub now_for_annualized: DateTime<Utc>,
+    pub prices: Vec<Price>,
+    pub annualized_roi: f32,
+    pub period_roi: f32,
 }
 
 impl ForAccount {
@@ -92,12 +306,12 @@ impl ForAccount {
 
 #[derive(Serialize)]
 pub struct Symbol {  //  <'a> {
-    id: CommodityId,
-    ticker: String,
-    source: i32,
-    is_currency: bool,
-    accounts: Vec<AccountId>,
-    price_scale: i32,
+    pub id: CommodityId,
+    pub ticker: String,
+    pub source: i32,
+    pub is_currency: bool,
+    pub accounts: Vec<AccountId>,
+    pub price_scale: i32,
 }
 
 #[derive(QueryableByName)]
@@ -108,24 +322,27 @@ struct AccountIdAndCommodity {
 
     #[sql_type = "Integer"]
     commodity_id: CommodityId,
+
+    #[sql_type = "Integer"]
+    commodity_scu: i32,
 }
 
-#[tauri::command]
+#[cfg_attr(feature = "gui", tauri::command)]
 pub async fn quotes(
     mindate: DateTime<Utc>,
     maxdate: DateTime<Utc>,
     currency: CommodityId,
     commodities: Option<Vec<CommodityId>>,
     accounts: Option<Vec<AccountId>>
-) -> (Vec<Symbol>, HashMap<AccountId, ForAccount>) {
+) -> Result<(Vec<Symbol>, HashMap<AccountId, ForAccount>), AlereError> {
     info!("quotes {:?} {:?} {}", &mindate, &maxdate, currency);
 
     // Find all commodities
 
     let mut all_commodities: Vec<Commodity> = {
        use super::schema::alr_commodities::dsl::*;
-       let c = &super::connections::get_connection();
-       alr_commodities.load::<Commodity>(c).expect("Error reading commodities")
+       let c = &super::connections::get_connection()?;
+       alr_commodities.load::<Commodity>(c)?
     };
 
     if let Some(commodities) = commodities {
@@ -151,36 +368,30 @@ pub async fn quotes(
     // Find the corresponding accounts
 
     let filter_account = match accounts {
-        Some(accs) => format!(
-            "AND a.id IN ({})",
-            accs
-            .iter()
-            .map(|id| id.to_string())
-            .collect::<Vec<_>>()
-            .join(",")
-        ),
-        None       => "".to_string(),
+        Some(accs) => Sql::new("AND a.id IN (:ids)").bind("ids", &accs),
+        None       => Sql::empty(),
     };
-    let query = format!(
+    let query = Sql::new(
         "
-        SELECT a.id, a.commodity_id
-        FROM alr_accounts a 
+        SELECT a.id, a.commodity_id, a.commodity_scu
+        FROM alr_accounts a
         JOIN alr_account_kinds k ON (a.kind_id = k.id)
-        WHERE k.is_trading {filter_account}
+        WHERE k.is_trading :filter_account
         "
-    );
-    let result = super::connections::execute_and_log::<AccountIdAndCommodity>(
-        "quotes,acc", &query);
+    )
+    .fragment("filter_account", filter_account);
+    let accounts = super::connections::execute_and_log::<AccountIdAndCommodity>(
+        "quotes,acc", query)?;
     let mut accs = HashMap::new();
-    if let Ok(accounts) = result {
-        accounts
-        .iter()
-        .for_each(|a| {
-            let acc = ForAccount::new(a.id);
-            accs.insert(a.id, acc);
-            symbols.get_mut(&a.commodity_id).unwrap().accounts.push(a.id);
-        });
-    }
+    accounts
+    .iter()
+    .for_each(|a| {
+        // Skip accounts whose commodity was filtered out
+        if let Some(s) = symbols.get_mut(&a.commodity_id) {
+            accs.insert(a.id, ForAccount::new(a.id));
+            s.accounts.push(a.id);
+        }
+    });
 
     // Remove all symbols for which we have zero account, to limit the scope
     // of the following query.
@@ -189,83 +400,66 @@ pub async fn quotes(
 
     // Compute metrics
 
-    let accs_ids = accs.iter()
-        .map(|(id, _)| id.to_string())
-        .collect::<Vec<_>>()
-        .join(",");
-    let query = format!(
-        "
-        SELECT *
-        FROM alr_roi r
-        WHERE r.account_id IN ({accs_ids})
-        AND r.currency_id = {currency}
-        ORDER BY r.mindate
-        "
-    );
-
-    let result = super::connections::execute_and_log::<Roi>(
-        "quotes,roi", &query);
-    match result {
-        Ok(rois) => {
-            rois
-            .iter()
-            .for_each(|r| {
-                let a_in_map = accs.get_mut(&r.account_id);
-                let mut a = a_in_map.unwrap();
-                let mi = Utc.from_utc_datetime(&r.mindate);
-                let ma = Utc.from_utc_datetime(&r.maxdate);
-
-                if a.oldest.is_none() {
-                    a.oldest = Some(mi);
-                }
-                a.most_recent = Some(mi);
+    let scales: HashMap<AccountId, (CommodityId, i32)> = accounts
+        .iter()
+        .filter(|a| accs.contains_key(&a.id))
+        .map(|a| (a.id, (a.commodity_id, a.commodity_scu)))
+        .collect();
+    let rois = compute_rois(&scales, currency)?;
+    rois
+    .iter()
+    .for_each(|r| {
+        let a = match accs.get_mut(&r.account_id) {
+            Some(a) => a,
+            None => return,
+        };
+        let mi = Utc.from_utc_datetime(&r.mindate);
+        let ma = Utc.from_utc_datetime(&r.maxdate);
 
-                if mi <= mindate && mindate < ma {
-                    a.start = Position::new(r);
-                }
-                if mi <= maxdate && maxdate < ma {
-                    a.end = Position::new(r);
-                }
+        if a.oldest.is_none() {
+            a.oldest = Some(mi);
+        }
+        a.most_recent = Some(mi);
 
-                a.prices.push(Price {
-                    t: mi.timestamp_millis(),
-                    price: r.computed_price,
-                    roi: match r.roi {
-                        Some(val) => (val - 1.0) * 100.0,
-                        None      => f32::NAN,
-                    },
-                    shares: r.shares,
-                });
-            });
+        if mi <= mindate && mindate < ma {
+            a.start = Position::new(r);
+        }
+        if mi <= maxdate && maxdate < ma {
+            a.end = Position::new(r);
+        }
 
-            for (_, a) in accs.iter_mut() {
-                let now = Utc::now();
+        a.prices.push(Price {
+            t: mi.timestamp_millis(),
+            price: r.computed_price,
+            roi: match r.roi {
+                Some(val) => (val - 1.0) * 100.0,
+                None      => f32::NAN,
+            },
+            shares: r.shares,
+        });
+    });
 
-                // Annualized total return on investment
-                if let Some(old) = a.oldest {
-                    let d = (now - old).num_days() as f32;
-                    a.annualized_roi = f32::powf(a.end.roi, 365.0 / d);
-                }
+    for (_, a) in accs.iter_mut() {
+        let now = Utc::now();
 
-                // Return over the period [mindata, maxdate]
-                let d2 = a.start.equity + a.end.invested - a.start.invested;
-                if f32::abs(d2) >= 1E-6 {
-                    a.period_roi =
-                        (a.end.equity + a.end.gains - a.start.gains)
-                        / d2;
-                }
-            }
+        // Annualized total return on investment
+        if let Some(old) = a.oldest {
+            let d = (now - old).num_days() as f32;
+            a.annualized_roi = f32::powf(a.end.roi, 365.0 / d);
+        }
 
-            (
-                symbols.into_values().collect::<Vec<_>>(),
-                accs,
-            )
-        },
-        Err(_) => {
-            (
-                vec![],
-                HashMap::new(),
-            )
+        // Return over the period [mindata, maxdate]
+        let d2 = a.start.equity + a.end.invested - a.start.invested;
+        if d2.abs() >= Decimal::new(1, 6) {
+            a.period_roi =
+                ((a.end.equity + a.end.gains - a.start.gains) / d2)
+                .to_f32()
+                .unwrap_or(f32::NAN);
         }
     }
+
+    Ok((
+        symbols.into_values().collect::<Vec<_>>(),
+        accs,
+    ))
 }
@@ -1,3 +1,193 @@
+use super::audit::with_operation;
+use super::cashflow::{monthly_cashflow, CashFlow};
+use super::connections::last_insert_rowid;
+use super::dates::{DateRange, GroupBy};
+use super::errors::AlereError;
+use super::metrics::{query_networth_history, NWPoint};
+use super::models::CommodityId;
+use super::occurrences::Occurrences;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::Integer;
+use log::info;
+use serde::Serialize;
+
 pub type Scenario = u16;
 
 pub const NO_SCENARIO: Scenario = 0;
+
+#[derive(Queryable, Debug, Serialize)]
+pub struct ScenarioDescr {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// The list of all scenarios, including the one for actual transactions
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn list_scenarios() -> Result<Vec<ScenarioDescr>, AlereError> {
+    use super::schema::alr_scenarios::dsl::*;
+    let c = &super::connections::get_connection()?;
+    Ok(alr_scenarios.order(id).load(c)?)
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn create_scenario(
+    name: String,
+    description: Option<String>,
+) -> Result<ScenarioDescr, AlereError> {
+    use super::schema::alr_scenarios::dsl as s;
+    info!("create_scenario {:?}", &name);
+    let c = &super::connections::get_connection()?;
+    c.transaction::<_, AlereError, _>(|| {
+        diesel::insert_into(s::alr_scenarios)
+            .values((s::name.eq(&name), s::description.eq(&description)))
+            .execute(c)?;
+        let id = diesel::select(last_insert_rowid).get_result::<i32>(c)?;
+        Ok(ScenarioDescr { id, name, description })
+    })
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn update_scenario(
+    id: Scenario,
+    name: String,
+    description: Option<String>,
+) -> Result<(), AlereError> {
+    use super::schema::alr_scenarios::dsl as s;
+    info!("update_scenario {} {:?}", id, &name);
+    let c = &super::connections::get_connection()?;
+    let count = diesel::update(s::alr_scenarios.find(id as i32))
+        .set((s::name.eq(&name), s::description.eq(&description)))
+        .execute(c)?;
+    match count {
+        0 => Err(AlereError::not_found(format!("No such scenario {}", id))),
+        _ => Ok(()),
+    }
+}
+
+/// Delete a scenario and all transactions that were specific to it.
+/// The scenario for actual transactions can never be deleted.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn delete_scenario(id: Scenario) -> Result<(), AlereError> {
+    info!("delete_scenario {}", id);
+    if id == NO_SCENARIO {
+        return Err(AlereError::validation(
+            "Cannot delete the actual transactions"));
+    }
+    let c = &super::connections::get_connection()?;
+    with_operation(c, &format!("Delete scenario {}", id), || {
+        diesel::sql_query(
+            "DELETE FROM alr_splits WHERE transaction_id IN \
+             (SELECT t.id FROM alr_transactions t WHERE t.scenario_id = ?)",
+        )
+        .bind::<Integer, _>(id as i32)
+        .execute(c)?;
+        diesel::sql_query("DELETE FROM alr_transactions WHERE scenario_id = ?")
+            .bind::<Integer, _>(id as i32)
+            .execute(c)?;
+        diesel::sql_query("DELETE FROM alr_scenarios WHERE id = ?")
+            .bind::<Integer, _>(id as i32)
+            .execute(c)?;
+        Ok(())
+    })
+}
+
+/// Copy a set of transactions (and all their splits) into a scenario.
+/// A scenario always includes the actual transactions, so this is mostly
+/// useful to clone scheduled transactions and then modify the copy (for
+/// instance to simulate a change in salary).
+/// The copies are never reconciled.
+/// Returns the ids of the new transactions, in the same order.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn clone_to_scenario(
+    scenario: Scenario,
+    transactionids: Vec<i32>,
+) -> Result<Vec<i32>, AlereError> {
+    info!("clone_to_scenario {} {:?}", scenario, &transactionids);
+    if scenario == NO_SCENARIO {
+        return Err(AlereError::validation(
+            "Cannot clone into the actual transactions"));
+    }
+    let c = &super::connections::get_connection()?;
+    with_operation(c, "Clone to scenario", || {
+        let mut result = Vec::new();
+        for tr in &transactionids {
+            let count = diesel::sql_query(
+                "INSERT INTO alr_transactions
+                    (timestamp, memo, check_number, scheduled,
+                     last_occurrence, scenario_id)
+                 SELECT timestamp, memo, check_number, scheduled,
+                     last_occurrence, ?
+                 FROM alr_transactions WHERE id = ?",
+            )
+            .bind::<Integer, _>(scenario as i32)
+            .bind::<Integer, _>(*tr)
+            .execute(c)?;
+            if count == 0 {
+                return Err(AlereError::not_found(
+                    format!("No such transaction {}", tr)));
+            }
+            let new_id = diesel::select(last_insert_rowid).get_result::<i32>(c)?;
+            diesel::sql_query(
+                "INSERT INTO alr_splits
+                    (scaled_qty, scaled_value, reconcile, reconcile_date,
+                     post_date, account_id, payee_id, transaction_id,
+                     value_commodity_id)
+                 SELECT scaled_qty, scaled_value, 'n', NULL,
+                     post_date, account_id, payee_id, ?,
+                     value_commodity_id
+                 FROM alr_splits WHERE transaction_id = ?",
+            )
+            .bind::<Integer, _>(new_id)
+            .bind::<Integer, _>(*tr)
+            .execute(c)?;
+            result.push(new_id);
+        }
+        Ok(result)
+    })
+}
+
+#[derive(Serialize)]
+pub struct ScenarioCurves {
+    scenario: Scenario,
+    networth: Vec<NWPoint>,
+    cashflow: Vec<CashFlow>,
+}
+
+/// Compute the networth history and the monthly cashflow for several
+/// scenarios, on the same set of dates, so that they can be displayed
+/// side by side.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn compare_scenarios(
+    mindate: DateTime<Utc>,
+    maxdate: DateTime<Utc>,
+    currency: CommodityId,
+    scenarios: Vec<Scenario>,
+    scheduled: bool,
+) -> Result<Vec<ScenarioCurves>, AlereError> {
+    info!("compare_scenarios {:?} {:?} {:?}", &mindate, &maxdate, &scenarios);
+    let occurrences = match scheduled {
+        true => Occurrences::unlimited(),
+        false => Occurrences::no_recurrence(),
+    };
+    let dates = DateRange::new(
+        Some(mindate.date()),
+        Some(maxdate.date()),
+        GroupBy::MONTHS,
+    );
+    scenarios
+        .iter()
+        .map(|&scenario| Ok(ScenarioCurves {
+            scenario,
+            networth: query_networth_history(
+                &dates, currency, scenario, &occurrences, 0, 0)?,
+            cashflow: monthly_cashflow(
+                &dates, currency, scenario, &occurrences, 0, 0, &None)?,
+        }))
+        .collect()
+}
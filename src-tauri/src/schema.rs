@@ -34,6 +34,47 @@ table! {
     }
 }
 
+table! {
+    alr_attachments (id) {
+        id -> Integer,
+        transaction_id -> Integer,
+        hash -> Text,
+        name -> Text,
+        mime_type -> Nullable<Text>,
+        size -> BigInt,
+        added -> Timestamp,
+    }
+}
+
+table! {
+    alr_audit_log (id) {
+        id -> Integer,
+        operation_id -> Nullable<Integer>,
+        timestamp -> Timestamp,
+        table_name -> Text,
+        row_id -> Integer,
+        before -> Nullable<Text>,
+        after -> Nullable<Text>,
+    }
+}
+
+table! {
+    alr_audit_operations (id) {
+        id -> Integer,
+        timestamp -> Timestamp,
+        description -> Text,
+        undone -> Bool,
+    }
+}
+
+table! {
+    alr_audit_state (id) {
+        id -> Integer,
+        operation_id -> Nullable<Integer>,
+        replaying -> Bool,
+    }
+}
+
 table! {
     alr_commodities (id) {
         id -> Integer,
@@ -49,6 +90,25 @@ table! {
     }
 }
 
+table! {
+    alr_goal_accounts (goal_id, account_id) {
+        goal_id -> Integer,
+        account_id -> Integer,
+        share -> Double,
+    }
+}
+
+table! {
+    alr_goals (id) {
+        id -> Integer,
+        name -> Text,
+        description -> Nullable<Text>,
+        scaled_target -> BigInt,
+        target_date -> Date,
+        currency_id -> Integer,
+    }
+}
+
 table! {
     alr_institutions (id) {
         id -> Integer,
@@ -65,6 +125,7 @@ table! {
     alr_payees (id) {
         id -> Integer,
         name -> Text,
+        iban -> Nullable<Text>,
     }
 }
 
@@ -79,7 +140,7 @@ table! {
     alr_prices (id) {
         id -> Integer,
         date -> Timestamp,
-        scaled_price -> Integer,
+        scaled_price -> BigInt,
         origin_id -> Integer,
         source_id -> Integer,
         target_id -> Integer,
@@ -94,11 +155,18 @@ table! {
     }
 }
 
+table! {
+    alr_split_tags (split_id, tag_id) {
+        split_id -> Integer,
+        tag_id -> Integer,
+    }
+}
+
 table! {
     alr_splits (id) {
         id -> Integer,
-        scaled_qty -> Integer,
-        scaled_value -> Integer,
+        scaled_qty -> BigInt,
+        scaled_value -> BigInt,
         reconcile -> Text,
         reconcile_date -> Nullable<Timestamp>,
         post_date -> Timestamp,
@@ -109,6 +177,21 @@ table! {
     }
 }
 
+table! {
+    alr_tags (id) {
+        id -> Integer,
+        name -> Text,
+        description -> Nullable<Text>,
+    }
+}
+
+table! {
+    alr_transaction_tags (transaction_id, tag_id) {
+        transaction_id -> Integer,
+        tag_id -> Integer,
+    }
+}
+
 table! {
     alr_transactions (id) {
         id -> Integer,
@@ -124,23 +207,41 @@ table! {
 joinable!(alr_accounts -> alr_account_kinds (kind_id));
 joinable!(alr_accounts -> alr_commodities (commodity_id));
 joinable!(alr_accounts -> alr_institutions (institution_id));
+joinable!(alr_attachments -> alr_transactions (transaction_id));
+joinable!(alr_audit_log -> alr_audit_operations (operation_id));
 joinable!(alr_commodities -> alr_price_sources (quote_source_id));
+joinable!(alr_goal_accounts -> alr_accounts (account_id));
+joinable!(alr_goal_accounts -> alr_goals (goal_id));
+joinable!(alr_goals -> alr_commodities (currency_id));
 joinable!(alr_prices -> alr_price_sources (source_id));
+joinable!(alr_split_tags -> alr_splits (split_id));
+joinable!(alr_split_tags -> alr_tags (tag_id));
 joinable!(alr_splits -> alr_accounts (account_id));
 joinable!(alr_splits -> alr_commodities (value_commodity_id));
 joinable!(alr_splits -> alr_payees (payee_id));
 joinable!(alr_splits -> alr_transactions (transaction_id));
+joinable!(alr_transaction_tags -> alr_tags (tag_id));
+joinable!(alr_transaction_tags -> alr_transactions (transaction_id));
 joinable!(alr_transactions -> alr_scenarios (scenario_id));
 
 allow_tables_to_appear_in_same_query!(
     alr_account_kinds,
     alr_accounts,
+    alr_attachments,
+    alr_audit_log,
+    alr_audit_operations,
+    alr_audit_state,
     alr_commodities,
+    alr_goal_accounts,
+    alr_goals,
     alr_institutions,
     alr_payees,
     alr_price_sources,
     alr_prices,
     alr_scenarios,
+    alr_split_tags,
     alr_splits,
+    alr_tags,
+    alr_transaction_tags,
     alr_transactions,
 );
@@ -0,0 +1,133 @@
+//! Full-text search of transactions, combined with structured filters.
+//! The text index is maintained by triggers in the database (see the
+//! alr_transactions_search table).
+
+use super::cte_list_splits::{cte_list_splits, cte_splits_with_values, CTE_SPLITS_WITH_VALUE};
+use super::dates::{DateRange, GroupBy};
+use super::errors::AlereError;
+use super::ledger::{splits_to_transactions, SplitRow, TransactionDescr};
+use super::models::AccountId;
+use super::occurrences::Occurrences;
+use super::query_builder::{Cte, Query, Sql};
+use super::scenarios::{Scenario, NO_SCENARIO};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::Deserialize;
+
+const DEFAULT_LIMIT: u32 = 200;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchQuery {
+    pub text: Option<String>,
+    pub minamount: Option<f64>,  // compared to the absolute value of splits
+    pub maxamount: Option<f64>,
+    pub mindate: Option<DateTime<Utc>>,
+    pub maxdate: Option<DateTime<Utc>>,
+    pub accountids: Option<Vec<AccountId>>,
+    pub reconcile: Option<String>,  // any of the reconcile flags, e.g. "nC"
+    pub scenario: Option<Scenario>,
+    pub limit: Option<u32>,
+}
+
+/// Convert the user's input to a FTS5 query. Each word is searched as a
+/// prefix, and all words must match. Special characters of the FTS5
+/// syntax lose their meaning.
+
+fn to_fts_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split_whitespace()
+        .map(|w| format!("\"{}\"*", w.replace('"', "\"\"")))
+        .collect();
+    match terms.len() {
+        0 => None,
+        _ => Some(terms.join(" ")),
+    }
+}
+
+/// Search transactions. Only the splits matching the filters are used to
+/// select transactions, but all splits of those transactions are returned.
+/// The most recent transactions are returned first.
+
+pub fn search_transactions(
+    query: &SearchQuery,
+) -> Result<Vec<TransactionDescr>, AlereError> {
+    let dates = DateRange::new(
+        query.mindate.map(|d| d.date()),
+        query.maxdate.map(|d| d.date()),
+        GroupBy::DAYS,
+    );
+    let list_splits = cte_list_splits(
+        &dates,
+        query.scenario.unwrap_or(NO_SCENARIO),
+        &Occurrences::no_recurrence(),
+    );
+    let with_values = cte_splits_with_values(&list_splits);
+    let filter_accounts = match &query.accountids {
+        Some(ids) if !ids.is_empty() => {
+            Sql::new("AND s.account_id IN (:accountids)").bind("accountids", ids)
+        }
+        _ => Sql::empty(),
+    };
+    let matches = Cte::new(
+        "matches",
+        Sql::new(format!(
+            "
+              SELECT s.transaction_id, max(s.timestamp) AS timestamp
+              FROM {CTE_SPLITS_WITH_VALUE} s
+              WHERE (:text IS NULL OR s.transaction_id IN
+                       (SELECT rowid FROM alr_transactions_search
+                        WHERE alr_transactions_search MATCH :text))
+                 AND (:minamount IS NULL OR ABS(s.value) >= :minamount)
+                 AND (:maxamount IS NULL OR ABS(s.value) <= :maxamount)
+                 AND (:reconcile IS NULL OR instr(:reconcile, s.reconcile) > 0)
+                 :filter_accounts
+              GROUP BY s.transaction_id
+              ORDER BY timestamp DESC, s.transaction_id DESC
+              LIMIT :limit
+            "
+        ))
+        .bind("text", query.text.as_deref().and_then(to_fts_query))
+        .bind("minamount", query.minamount)
+        .bind("maxamount", query.maxamount)
+        .bind("reconcile", query.reconcile.clone())
+        .fragment("filter_accounts", filter_accounts)
+        .bind("limit", query.limit.unwrap_or(DEFAULT_LIMIT)),
+    )
+    .requires(&with_values);
+    let sql = Query::new(format!(
+        "
+        SELECT
+           s.transaction_id,
+           s.occurrence,
+           strftime('%Y-%m-%d', s.timestamp) AS timestamp,
+           s.memo,
+           s.check_number,
+           s.scaled_qty,
+           a.commodity_scu,
+           s.computed_price,
+           s.account_id,
+           strftime('%Y-%m-%d', s.post_date) AS post_date,
+           alr_decimal(s.scaled_value, s.value_scale) AS value,
+           s.value_commodity_id,
+           s.reconcile,
+           s.scheduled,
+           p.name AS payee,
+           0 AS scaled_qty_balance
+        FROM {CTE_SPLITS_WITH_VALUE} s
+           JOIN matches m USING (transaction_id)
+           JOIN alr_accounts a ON (s.account_id = a.id)
+           LEFT JOIN alr_payees p ON (s.payee_id = p.id)
+        ORDER BY m.timestamp DESC, s.transaction_id DESC
+        "
+    ))
+    .with(&matches);
+
+    let rows = super::connections::execute_and_log::<SplitRow>("search", sql)?;
+    Ok(splits_to_transactions(rows, -1))
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn search(query: SearchQuery) -> Result<Vec<TransactionDescr>, AlereError> {
+    info!("search {:?}", &query);
+    search_transactions(&query)
+}
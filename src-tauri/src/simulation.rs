@@ -0,0 +1,350 @@
+//! Monte Carlo simulation of the networth until and during retirement.
+//!
+//! Each path starts from the current holdings, grouped into asset classes
+//! that each have their own expected return and volatility. Before
+//! retirement, the projected savings (from scheduled transactions) are
+//! added every month; after retirement, the expenses are removed. Planned
+//! withdrawals are removed in the months they cover, before or after
+//! retirement. Expenses and withdrawals are adjusted for inflation.
+
+use super::cashflow::monthly_cashflow;
+use super::dates::{DateRange, DateValues, GroupBy};
+use super::errors::AlereError;
+use super::metrics::networth;
+use super::models::{AccountId, CommodityId};
+use super::occurrences::Occurrences;
+use super::projection::project_networth;
+use super::scenarios::{Scenario, NO_SCENARIO};
+use chrono::{Duration, NaiveDate, Utc};
+use log::info;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Normal};
+use rust_decimal::prelude::*; //  to_f64
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AssetClass {
+    pub name: String,
+    pub annual_return: f64,  // 0.05 for 5%, above the inflation of the class
+    pub volatility: f64,     // standard deviation of the annual return
+
+    // Inflation that applies to this class (real estate for instance), if
+    // different from the general inflation
+    pub inflation: Option<f64>,
+
+    // Accounts in this class. At most one class should leave this unset,
+    // and will then receive all other accounts.
+    pub accounts: Option<Vec<AccountId>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PlannedWithdrawal {
+    pub start: NaiveDate,
+    pub end: Option<NaiveDate>,
+    pub monthly: f64,        // in today's money
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SimulationParams {
+    pub currency: CommodityId,
+    pub scenario: Option<Scenario>,
+    pub years: u8,
+    pub paths: u32,
+    pub seed: u64,
+    pub inflation: f64,      // 0.02 for 2%, for expenses and by default
+    pub retirement: NaiveDate,
+
+    // Monthly expenses after retirement, in today's money. Defaults to the
+    // average expenses over the last twelve months.
+    pub expenses: Option<f64>,
+
+    pub withdrawals: Vec<PlannedWithdrawal>,
+    pub classes: Vec<AssetClass>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Band {
+    pub date: NaiveDate,
+    pub p5: f64,
+    pub p25: f64,
+    pub p50: f64,
+    pub p75: f64,
+    pub p95: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SimulationResult {
+    pub bands: Vec<Band>,
+    pub success_probability: f64, // paths that never ran out of money
+    pub monthly_expenses: f64,    // as used for the simulation
+}
+
+/// The money flows and market assumptions, independent of the database.
+/// All vectors have one entry per month, the first entry being the start
+/// date.
+
+pub struct Model {
+    pub dates: Vec<NaiveDate>,
+    pub holdings: Vec<f64>,   // initial value, per asset class
+    pub returns: Vec<(f64, f64)>, // nominal annual return and volatility, per class
+    pub flows: Vec<f64>,      // contributions (> 0) or withdrawals (< 0)
+}
+
+/// Return the value at the given percentile, in a sorted vector
+
+fn percentile(sorted: &[f64], pct: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let idx = ((sorted.len() - 1) as f64 * pct).round() as usize;
+    sorted[idx]
+}
+
+/// Run the simulation. The result only depends on the model and the seed.
+
+pub fn simulate(model: &Model, paths: u32, seed: u64) -> Result<SimulationResult, AlereError> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let months = model.dates.len();
+    let monthly: Vec<Normal<f64>> = model.returns
+        .iter()
+        .map(|(ret, vol)| match *vol >= 0.0 {
+            true => Normal::new(ret / 12.0, vol / 12f64.sqrt()).ok(),
+            false => None,  // also for NaN
+        }.ok_or_else(|| AlereError::validation(format!("Invalid volatility {}", vol))))
+        .collect::<Result<_, _>>()?;
+
+    // values[month][path]
+    let mut values = vec![Vec::<f64>::with_capacity(paths as usize); months];
+    let mut successes: u32 = 0;
+
+    for _ in 0..paths {
+        let mut holdings = model.holdings.clone();
+        let mut ruined = false;
+        for (month, v) in values.iter_mut().enumerate() {
+            if month > 0 && !ruined {
+                for (h, dist) in holdings.iter_mut().zip(monthly.iter()) {
+                    *h *= 1.0 + dist.sample(&mut rng);
+                }
+
+                // Contributions and withdrawals are spread proportionally
+                // to the current holdings.
+                let total: f64 = holdings.iter().sum();
+                let flow = model.flows[month];
+                if flow < 0.0 && total + flow <= 0.0 {
+                    ruined = true;
+                    holdings.iter_mut().for_each(|h| *h = 0.0);
+                } else if total > 0.0 {
+                    holdings.iter_mut().for_each(|h| *h += flow * *h / total);
+                } else if let Some(h) = holdings.first_mut() {
+                    *h += flow;
+                }
+            }
+            v.push(holdings.iter().sum());
+        }
+        if !ruined {
+            successes += 1;
+        }
+    }
+
+    Ok(SimulationResult {
+        bands: values
+            .iter_mut()
+            .zip(model.dates.iter())
+            .map(|(v, date)| {
+                v.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+                Band {
+                    date: *date,
+                    p5: percentile(v, 0.05),
+                    p25: percentile(v, 0.25),
+                    p50: percentile(v, 0.50),
+                    p75: percentile(v, 0.75),
+                    p95: percentile(v, 0.95),
+                }
+            })
+            .collect(),
+        success_probability: match paths {
+            0 => f64::NAN,
+            _ => successes as f64 / paths as f64,
+        },
+        monthly_expenses: 0.0,
+    })
+}
+
+/// Average monthly expenses over the last twelve months
+
+fn average_expenses(currency: CommodityId, scenario: Scenario) -> Result<f64, AlereError> {
+    let today = Utc::today();
+    let dates = DateRange::new(
+        Some(today - Duration::days(365)),
+        Some(today),
+        GroupBy::MONTHS,
+    );
+    let cashflow = monthly_cashflow(
+        &dates, currency, scenario, &Occurrences::no_recurrence(), 0, 0, &None)?;
+    if cashflow.is_empty() {
+        return Ok(0.0);
+    }
+    Ok(cashflow.iter()
+        .map(|c| c.exp_total.unwrap_or(Decimal::ZERO).abs().to_f64().unwrap_or(0.0))
+        .sum::<f64>() / cashflow.len() as f64)
+}
+
+/// Build the model from the current data in the database
+
+fn build_model(params: &SimulationParams, expenses: f64) -> Result<Model, AlereError> {
+    let scenario = params.scenario.unwrap_or(NO_SCENARIO);
+    let months = params.years as u16 * 12;
+    let all_dates = DateValues::end_of_months(Utc::today(), months);
+    let dates: Vec<NaiveDate> =
+        all_dates.dates().iter().map(|d| d.naive_utc()).collect();
+
+    // Current holdings, per asset class
+    let today = DateValues::new(Some(vec![Utc::today()]));
+    let mut holdings = vec![0.0; params.classes.len()];
+    for acc in networth(&today, params.currency, scenario, &Occurrences::no_recurrence())? {
+        let value = (acc.shares[0] * acc.price[0]).to_f64().unwrap_or(0.0);
+        let class = params.classes.iter()
+            .position(|c| c.accounts.as_ref()
+                      .map(|a| a.contains(&acc.account_id))
+                      .unwrap_or(false))
+            .or_else(|| params.classes.iter().position(|c| c.accounts.is_none()));
+        if let Some(idx) = class {
+            holdings[idx] += value;
+        }
+    }
+
+    // Savings before retirement, from scheduled transactions
+    let projected = project_networth(
+        params.currency, scenario, params.years, None)?;
+    let savings: Vec<f64> = projected
+        .windows(2)
+        .map(|w| (w[1].value - w[0].value) as f64)
+        .collect();
+    let flows = monthly_flows(params, &dates, &savings, expenses);
+
+    Ok(Model {
+        dates,
+        holdings,
+        returns: params.classes.iter()
+            .map(|c| {
+                let inflation = c.inflation.unwrap_or(params.inflation);
+                ((1.0 + c.annual_return) * (1.0 + inflation) - 1.0, c.volatility)
+            })
+            .collect(),
+        flows,
+    })
+}
+
+/// The money added (or removed) every month.
+/// :param savings: the savings during each month before retirement, so
+///    `savings[0]` is for the month ending at `dates[1]`.
+
+fn monthly_flows(
+    params: &SimulationParams,
+    dates: &[NaiveDate],
+    savings: &[f64],
+    expenses: f64,
+) -> Vec<f64> {
+    let monthly_inflation = (1.0 + params.inflation).powf(1.0 / 12.0);
+    dates.iter()
+        .enumerate()
+        .map(|(idx, date)| {
+            if idx == 0 {
+                return 0.0;
+            }
+            let inflation = monthly_inflation.powi(idx as i32);
+            let planned: f64 = params.withdrawals.iter()
+                .filter(|w| w.start <= *date
+                        && w.end.map(|e| *date <= e).unwrap_or(true))
+                .map(|w| w.monthly)
+                .sum();
+            let income = match *date <= params.retirement {
+                true => savings.get(idx - 1).copied().unwrap_or(0.0),
+                false => -expenses * inflation,
+            };
+            income - planned * inflation
+        })
+        .collect()
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn retirement_simulation(
+    params: SimulationParams,
+) -> Result<SimulationResult, AlereError> {
+    info!("retirement_simulation {:?}", &params);
+    let expenses = match params.expenses {
+        Some(e) => e,
+        None => average_expenses(
+            params.currency, params.scenario.unwrap_or(NO_SCENARIO))?,
+    };
+    let model = build_model(&params, expenses)?;
+    let mut result = simulate(&model, params.paths, params.seed)?;
+    result.monthly_expenses = expenses;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(withdrawals: Vec<PlannedWithdrawal>) -> SimulationParams {
+        SimulationParams {
+            currency: 1,
+            scenario: None,
+            years: 1,
+            paths: 200,
+            seed: 42,
+            inflation: 0.0,
+            retirement: NaiveDate::from_ymd(2030, 3, 31),
+            expenses: None,
+            withdrawals,
+            classes: vec![],
+        }
+    }
+
+    fn dates() -> Vec<NaiveDate> {
+        (1..=6).map(|m| NaiveDate::from_ymd(2030, m, 28)).collect()
+    }
+
+    fn model(volatility: f64) -> Model {
+        Model {
+            dates: dates(),
+            holdings: vec![10_000.0, 5_000.0],
+            returns: vec![(0.07, volatility), (0.02, 0.01)],
+            flows: vec![0.0, 100.0, 100.0, -800.0, -800.0, -800.0],
+        }
+    }
+
+    #[test]
+    fn same_seed_same_percentiles() {
+        let band = |b: &Band| (b.p5, b.p25, b.p50, b.p75, b.p95);
+        let r1 = simulate(&model(0.15), 500, 7).unwrap();
+        let r2 = simulate(&model(0.15), 500, 7).unwrap();
+        assert_eq!(
+            r1.bands.iter().map(band).collect::<Vec<_>>(),
+            r2.bands.iter().map(band).collect::<Vec<_>>(),
+        );
+        assert_eq!(r1.success_probability, r2.success_probability);
+
+        let r3 = simulate(&model(0.15), 500, 8).unwrap();
+        assert_ne!(r1.bands[5].p50, r3.bands[5].p50);
+    }
+
+    #[test]
+    fn invalid_volatility() {
+        assert!(simulate(&model(-0.1), 10, 7).is_err());
+        assert!(simulate(&model(f64::NAN), 10, 7).is_err());
+    }
+
+    #[test]
+    fn withdrawals_before_retirement() {
+        let p = params(vec![PlannedWithdrawal {
+            start: NaiveDate::from_ymd(2030, 2, 1),
+            end: Some(NaiveDate::from_ymd(2030, 4, 30)),
+            monthly: 50.0,
+        }]);
+        let flows = monthly_flows(&p, &dates(), &[100.0; 5], 1000.0);
+        assert_eq!(flows, vec![0.0, 50.0, 50.0, -1050.0, -1000.0, -1000.0]);
+    }
+}
@@ -0,0 +1,259 @@
+//! Tags are cross-cutting labels attached to transactions or to
+//! individual splits. A split is considered tagged when either itself or
+//! its transaction has the tag (see the alr_tagged_splits view).
+
+use super::accounts::AccountKindCategory;
+use super::connections::last_insert_rowid;
+use super::cte_list_splits::{cte_list_splits, cte_splits_with_values, CTE_SPLITS_WITH_VALUE};
+use super::dates::DateValues;
+use super::decimals::SqlDecimal;
+use super::errors::AlereError;
+use super::models::{AccountId, CommodityId};
+use super::occurrences::Occurrences;
+use super::query_builder::{Query, Sql};
+use super::scenarios::{Scenario, NO_SCENARIO};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Integer, Text};
+use log::info;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+pub type TagId = i32;
+
+#[derive(Queryable, Debug, Serialize)]
+pub struct Tag {
+    pub id: TagId,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// An SQL condition (starting with AND) that restricts splits to those
+/// with any of the tags. Empty if there are no tags to filter on.
+/// :param split_id: the SQL expression for the split's id
+
+pub fn filter_splits_with_tags(tags: &Option<Vec<TagId>>, split_id: &str) -> Sql {
+    match tags {
+        Some(t) if !t.is_empty() => Sql::new(format!(
+            "AND {split_id} IN (SELECT split_id FROM alr_tagged_splits \
+             WHERE tag_id IN (:tags))"
+        ))
+        .bind("tags", t),
+        _ => Sql::empty(),
+    }
+}
+
+/// An SQL condition (starting with AND) that restricts transactions to
+/// those with any of the tags, on themselves or on one of their splits.
+/// :param transaction_id: the SQL expression for the transaction's id
+
+pub fn filter_transactions_with_tags(
+    tags: &Option<Vec<TagId>>,
+    transaction_id: &str,
+) -> Sql {
+    match tags {
+        Some(t) if !t.is_empty() => Sql::new(format!(
+            "AND {transaction_id} IN (SELECT transaction_id \
+             FROM alr_tagged_splits WHERE tag_id IN (:tags) \
+             UNION SELECT transaction_id FROM alr_transaction_tags \
+             WHERE tag_id IN (:tags))"
+        ))
+        .bind("tags", t),
+        _ => Sql::empty(),
+    }
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn list_tags() -> Result<Vec<Tag>, AlereError> {
+    use super::schema::alr_tags::dsl::*;
+    let c = &super::connections::get_connection()?;
+    Ok(alr_tags.order(name).load(c)?)
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn create_tag(
+    name: String,
+    description: Option<String>,
+) -> Result<Tag, AlereError> {
+    use super::schema::alr_tags::dsl as t;
+    info!("create_tag {:?}", &name);
+    let c = &super::connections::get_connection()?;
+    c.transaction::<_, AlereError, _>(|| {
+        diesel::insert_into(t::alr_tags)
+            .values((t::name.eq(&name), t::description.eq(&description)))
+            .execute(c)?;
+        let id = diesel::select(last_insert_rowid).get_result::<i32>(c)?;
+        Ok(Tag { id, name, description })
+    })
+}
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn update_tag(
+    id: TagId,
+    name: String,
+    description: Option<String>,
+) -> Result<(), AlereError> {
+    use super::schema::alr_tags::dsl as t;
+    info!("update_tag {} {:?}", id, &name);
+    let c = &super::connections::get_connection()?;
+    let count = diesel::update(t::alr_tags.find(id))
+        .set((t::name.eq(&name), t::description.eq(&description)))
+        .execute(c)?;
+    match count {
+        0 => Err(AlereError::not_found(format!("No such tag {}", id))),
+        _ => Ok(()),
+    }
+}
+
+/// Delete a tag. Links to transactions and splits are removed by a trigger
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn delete_tag(id: TagId) -> Result<(), AlereError> {
+    use super::schema::alr_tags::dsl as t;
+    info!("delete_tag {}", id);
+    let c = &super::connections::get_connection()?;
+    diesel::delete(t::alr_tags.find(id)).execute(c)?;
+    Ok(())
+}
+
+/// Add or remove a tag on transactions and splits.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn set_tag(
+    tag: TagId,
+    transactionids: Vec<i32>,
+    splitids: Vec<i32>,
+    tagged: bool,
+) -> Result<(), AlereError> {
+    use super::schema::alr_split_tags::dsl as st;
+    use super::schema::alr_transaction_tags::dsl as tt;
+    info!("set_tag {} {:?} {:?} {}", tag, &transactionids, &splitids, tagged);
+    let c = &super::connections::get_connection()?;
+    c.transaction::<_, AlereError, _>(|| {
+        if tagged {
+            for id in &transactionids {
+                diesel::replace_into(tt::alr_transaction_tags)
+                    .values((tt::transaction_id.eq(id), tt::tag_id.eq(tag)))
+                    .execute(c)?;
+            }
+            for id in &splitids {
+                diesel::replace_into(st::alr_split_tags)
+                    .values((st::split_id.eq(id), st::tag_id.eq(tag)))
+                    .execute(c)?;
+            }
+        } else {
+            diesel::delete(
+                tt::alr_transaction_tags
+                    .filter(tt::tag_id.eq(tag))
+                    .filter(tt::transaction_id.eq_any(&transactionids)),
+            )
+            .execute(c)?;
+            diesel::delete(
+                st::alr_split_tags
+                    .filter(st::tag_id.eq(tag))
+                    .filter(st::split_id.eq_any(&splitids)),
+            )
+            .execute(c)?;
+        }
+        Ok(())
+    })
+}
+
+#[derive(QueryableByName)]
+struct TagRow {
+    #[sql_type = "Integer"]
+    tag_id: TagId,
+
+    #[sql_type = "Integer"]
+    account_id: AccountId,
+
+    #[sql_type = "Bool"]
+    is_expense: bool,
+
+    #[sql_type = "Text"]
+    #[diesel(deserialize_as = "SqlDecimal")]
+    value: Decimal,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TagAccount {
+    accountid: AccountId,
+    value: Decimal,  // as for income_expense: expenses are negative
+}
+
+#[derive(Serialize, Debug)]
+pub struct TagReport {
+    tag: TagId,
+    expenses: Decimal, // total spent, across all accounts
+    income: Decimal,
+    items: Vec<TagAccount>,
+}
+
+/// For each tag, the total income and expenses in the time range.
+
+#[cfg_attr(feature = "gui", tauri::command)]
+pub async fn tag_report(
+    mindate: DateTime<Utc>,
+    maxdate: DateTime<Utc>,
+    currency: CommodityId,
+    tags: Option<Vec<TagId>>,
+    scenario: Option<Scenario>,
+) -> Result<Vec<TagReport>, AlereError> {
+    info!("tag_report {:?} {:?} {:?}", &mindate, &maxdate, &tags);
+    let list_splits = cte_list_splits(
+        &DateValues::new(Some(vec![mindate.date(), maxdate.date()])),
+        scenario.unwrap_or(NO_SCENARIO),
+        &Occurrences::no_recurrence());
+    let with_values = cte_splits_with_values(&list_splits);
+    let income = AccountKindCategory::INCOME as u32;
+    let expense = AccountKindCategory::EXPENSE as u32;
+    let filter_tags = match &tags {
+        Some(t) if !t.is_empty() => Sql::new("AND t.tag_id IN (:tags)").bind("tags", t),
+        _ => Sql::empty(),
+    };
+    let query = Query::new(Sql::new(format!(
+        "
+        SELECT t.tag_id, s.account_id, \
+           k.category = :expense AS is_expense, \
+           alr_decimal(SUM(s.scaled_value), MAX(s.value_scale)) AS value \
+        FROM {CTE_SPLITS_WITH_VALUE} s \
+        JOIN alr_tagged_splits t ON (t.split_id = s.split_id) \
+        JOIN alr_accounts a ON (a.id = s.account_id) \
+        JOIN alr_account_kinds k ON (k.id = a.kind_id) \
+        WHERE s.value_commodity_id = :currency \
+        AND NOT k.is_unrealized \
+        AND k.category IN (:income, :expense) \
+        :filter_tags \
+        GROUP BY t.tag_id, s.account_id \
+        ORDER BY t.tag_id
+        "
+    ))
+    .bind("currency", currency)
+    .bind("income", income)
+    .bind("expense", expense)
+    .fragment("filter_tags", filter_tags))
+    .with(&with_values);
+    let rows = super::connections::execute_and_log::<TagRow>("tag_report", query)?;
+    let mut result: Vec<TagReport> = vec![];
+    for row in rows {
+        if result.last().map(|r| r.tag != row.tag_id).unwrap_or(true) {
+            result.push(TagReport {
+                tag: row.tag_id,
+                expenses: Decimal::ZERO,
+                income: Decimal::ZERO,
+                items: vec![],
+            });
+        }
+        let r = result.last_mut().unwrap();
+        if row.is_expense {
+            r.expenses += row.value;
+        } else {
+            r.income -= row.value;
+        }
+        r.items.push(TagAccount {
+            accountid: row.account_id,
+            value: -row.value,
+        });
+    }
+    Ok(result)
+}
@@ -0,0 +1,101 @@
+//! XML documents, for the files we import (GnuCash books, bank
+//! statements). The whole document is parsed with roxmltree, and loaded as
+//! a tree of elements. Elements keep the namespace prefix used in the
+//! document, but can also be looked up by their local name.
+
+use super::errors::AlereError;
+
+#[derive(Debug, Default)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    /// Whether the element has that name. Without a prefix, the name is
+    /// compared to the local name of the element.
+    pub fn is(&self, name: &str) -> bool {
+        self.name == name
+            || (!name.contains(':') && self.name.rsplit(':').next() == Some(name))
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.is(name))
+    }
+
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |c| c.is(name))
+    }
+
+    /// Follow a path of child names, for instance "Bal/Amt"
+    pub fn find(&self, path: &str) -> Option<&Element> {
+        path.split('/').try_fold(self, |e, name| e.child(name))
+    }
+
+    /// The text of the element found at path, without surrounding spaces
+    pub fn text_of(&self, path: &str) -> Option<&str> {
+        self.find(path).map(|e| e.text.trim())
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Escape text for use in element content or quoted attributes, when
+/// writing documents
+
+pub fn escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// The name of an element or attribute, with the prefix used in the
+/// document if any
+fn qualified_name(node: roxmltree::Node, namespace: Option<&str>, name: &str) -> String {
+    match namespace.and_then(|ns| node.lookup_prefix(ns)) {
+        Some(prefix) if !prefix.is_empty() => format!("{}:{}", prefix, name),
+        _ => name.to_string(),
+    }
+}
+
+fn to_element(node: roxmltree::Node) -> Element {
+    let tag = node.tag_name();
+    let mut element = Element {
+        name: qualified_name(node, tag.namespace(), tag.name()),
+        ..Default::default()
+    };
+    for a in node.attributes() {
+        let name = qualified_name(node, a.namespace(), a.name());
+        element.attributes.push((name, a.value().to_string()));
+    }
+    for child in node.children() {
+        if child.is_element() {
+            element.children.push(to_element(child));
+        } else if child.is_text() {
+            element.text.push_str(child.text().unwrap_or_default());
+        }
+    }
+    element
+}
+
+/// Parse a document, and return its root element
+
+pub fn parse(text: &str) -> Result<Element, AlereError> {
+    let options = roxmltree::ParsingOptions { allow_dtd: true, ..Default::default() };
+    let document = roxmltree::Document::parse_with_options(text, options)
+        .map_err(|e| AlereError::parse(format!("Invalid XML: {}", e)))?;
+    Ok(to_element(document.root_element()))
+}
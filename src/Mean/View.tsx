@@ -8,20 +8,26 @@ import Numeric from '@/Numeric';
 import { AccountIdSet } from '@/services/useAccountIds';
 import usePrefs from '@/services/usePrefs';
 import AutoSizer from 'react-virtualized-auto-sizer';
-import useFetch from '@/services/useFetch';
+import useFetch, { toNumber } from '@/services/useFetch';
 import useColors from '@/services/useColors';
 import './Mean.scss';
 
 interface PointJSON {
+   date: string;
+   value_expenses: string | null;
+   average_expenses: string | null;
+   value_realized: string | null;
+   value_networth_delta: string; // how much the networth changed that month
+   average_networth_delta: string;
+}
+
+interface Point {
    date: string;
    value_expenses: number;
    average_expenses: number;
    value_realized: number;
-   value_networth_delta: number; // how much the networth changed that month
+   value_networth_delta: number;
    average_networth_delta: number;
-}
-
-interface Point extends PointJSON {
    average_realized: number;
    value_unrealized?: number;
    average_unrealized?: number;
@@ -61,7 +67,15 @@ const useMeanHistory = (
    const { data } = useFetch({
       cmd: 'mean',
       args,
-      parse: (data: PointJSON[]): Point[] => data.map(p => {
+      parse: (data: PointJSON[]): Point[] => data.map(json => {
+         const p = {
+            date: json.date,
+            value_expenses: toNumber(json.value_expenses),
+            average_expenses: toNumber(json.average_expenses),
+            value_realized: toNumber(json.value_realized),
+            value_networth_delta: toNumber(json.value_networth_delta),
+            average_networth_delta: toNumber(json.average_networth_delta),
+         };
          const average_realized = 0;
          return {
             ...p,
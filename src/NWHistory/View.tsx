@@ -7,7 +7,7 @@ import { ComposedChart, XAxis, YAxis, CartesianGrid, Bar,
 import { CommodityId } from '@/services/useAccounts';
 import Numeric from '@/Numeric';
 import usePrefs from '@/services/usePrefs';
-import useFetch from '@/services/useFetch';
+import useFetch, { toNumber } from '@/services/useFetch';
 import AutoSizer from 'react-virtualized-auto-sizer';
 import { Option } from '@/Form';
 import './NetworthHistory.scss';
@@ -21,7 +21,7 @@ export const groupByOptions: Option<GroupBy>[] = [
 
 interface PointJSON {
    date: string;
-   value: number;
+   value: string;
 }
 
 interface Point {
@@ -36,7 +36,7 @@ const bisectDate = d3Array.bisector((p: Point) => p.parsedDate);
 const parse = (data: PointJSON[]): Point[] =>
    data.map(d => ({
       date: d.date,
-      networth: d.value,
+      networth: toNumber(d.value),
       parsedDate: new Date(d.date),
    }));
 
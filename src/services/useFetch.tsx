@@ -2,14 +2,31 @@ import { useQuery, UseQueryOptions, useQueries,
    UseQueryResult } from 'react-query';
 import { invoke } from '@tauri-apps/api'
 
+/**
+ * Errors reported by the server
+ */
+export interface AlereError {
+   kind: 'Database' | 'Parse' | 'Validation' | 'NotFound' | 'Io' | 'Locked';
+   message: string;
+   query?: string;   // the failing query, in debug builds only
+}
+
 export interface FetchProps<T, RAW_T, TArgs extends Record<string, any>> {
    cmd: string,
    args?: TArgs,
    parse?: (json: RAW_T) => T;  // parse the server's response
    enabled?: boolean;
-   options?: UseQueryOptions<T, string /* error */, T /* TData */>;
+   options?: UseQueryOptions<T, AlereError, T /* TData */>;
 }
 
+/**
+ * Amounts are sent by the server as strings, so that they are exact. They
+ * are only converted to numbers for display. null is used when the value
+ * is unknown.
+ */
+export const toNumber = (s: string | null | undefined): number =>
+   s === null || s === undefined ? NaN : parseFloat(s);
+
 const toQueryProps = <T, RAW_T, TArgs extends Record<string, any>>
 (p: FetchProps<T, RAW_T, TArgs>) => ({
    queryKey: [p.cmd, p.args],
@@ -21,8 +38,9 @@ const toQueryProps = <T, RAW_T, TArgs extends Record<string, any>>
             ? json as T
             : p.parse(json as RAW_T);
       } catch (err) {
-         window.console.error(err);
-         return undefined;
+         // Let react-query report the error, instead of showing empty data
+         window.console.error(p.cmd, err);
+         throw err as AlereError;
       };
    },
    ...p.options,
@@ -35,7 +53,7 @@ const toQueryProps = <T, RAW_T, TArgs extends Record<string, any>>
  */
 const useFetch = <T, RAW_T, TArgs extends Record<string, any>> (
    p: FetchProps<T | undefined, RAW_T, TArgs>
-): UseQueryResult<T | undefined, string> => {
+): UseQueryResult<T | undefined, AlereError> => {
    return useQuery(toQueryProps(p));
 };
 
@@ -44,7 +62,7 @@ const useFetch = <T, RAW_T, TArgs extends Record<string, any>> (
  */
 export const useFetchMultiple = <T, RAW_T, TArgs extends Record<string, any>> (
    p: FetchProps<T | undefined, RAW_T, TArgs>[],
-): UseQueryResult<T | undefined, string>[] => {
+): UseQueryResult<T | undefined, AlereError>[] => {
    return useQueries(p.map(toQueryProps));
 }
 
@@ -4,7 +4,7 @@
 
 import * as React from 'react';
 import { DateRange, toDates } from '@/Dates';
-import useFetch, { useFetchMultiple } from '@/services/useFetch';
+import useFetch, { useFetchMultiple, toNumber } from '@/services/useFetch';
 import usePrefs from '@/services/usePrefs';
 import useAccounts, {
    AccountId, CommodityId, Account, AccountList
@@ -12,7 +12,7 @@ import useAccounts, {
 
 interface OneIEJSON {
    accountid: AccountId;
-   value: number;         // total for this account in the time range
+   value: string;         // total for this account in the time range
 }
 interface IncomeExpenseInPeriodJSON {
    items:   OneIEJSON[];
@@ -72,11 +72,11 @@ const toFetchProps = (
          items: json.items.map(it => ({
             accountId: it.accountid,
             account: accounts.getAccount(it.accountid),
-            value: it.value,
+            value: toNumber(it.value),
          })),
          mindate: json.mindate,
          maxdate: json.maxdate,
-         total: json.items.reduce((tot, v) => tot + v.value, 0),
+         total: json.items.reduce((tot, v) => tot + toNumber(v.value), 0),
          currency,
       }),
    };
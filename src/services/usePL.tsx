@@ -1,7 +1,7 @@
 import * as React from 'react';
 import { DateRange, toDates } from '@/Dates';
 import { CommodityId } from '@/services/useAccounts';
-import useFetch, { useFetchMultiple } from '@/services/useFetch';
+import useFetch, { useFetchMultiple, toNumber } from '@/services/useFetch';
 
 export interface Metric {
    income: number;
@@ -16,6 +16,21 @@ export interface Metric {
    liquid_assets_at_start: number;
 }
 
+type MetricJSON = Record<keyof Metric, string>;
+
+const parse = (json: MetricJSON): Metric => ({
+   income: toNumber(json.income),
+   passive_income: toNumber(json.passive_income),
+   work_income: toNumber(json.work_income),
+   expenses: toNumber(json.expenses),
+   income_taxes: toNumber(json.income_taxes),
+   other_taxes: toNumber(json.other_taxes),
+   networth: toNumber(json.networth),
+   networth_start: toNumber(json.networth_start),
+   liquid_assets: toNumber(json.liquid_assets),
+   liquid_assets_at_start: toNumber(json.liquid_assets_at_start),
+});
+
 const NULL_METRIC: Metric = {
    income: NaN,
    passive_income: NaN,
@@ -42,7 +57,8 @@ const usePL = (range: DateRange, currencyId: CommodityId): Metric => {
       [range, currencyId]
    );
 
-   const { data } = useFetch<Metric, unknown, {}>({cmd: 'metrics', args});
+   const { data } = useFetch<Metric, MetricJSON, {}>(
+      {cmd: 'metrics', args, parse});
    return data ?? NULL_METRIC;
 }
 
@@ -60,12 +76,13 @@ export const usePLMultiple = (
                maxdate: rg[1],
                currency: currencyId,
             },
+            parse,
          };
       }),
       [ranges, currencyId]
    );
 
-   const result = useFetchMultiple<Metric, unknown, {}>(queries);
+   const result = useFetchMultiple<Metric, MetricJSON, {}>(queries);
    return result.map(r => r.data ?? NULL_METRIC);
 }
 
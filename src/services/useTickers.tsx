@@ -4,17 +4,24 @@ import { ClosePrice } from '@/PriceGraph';
 import { DateRange, toDates } from '@/Dates';
 import useAccounts, { AccountId, CommodityId } from '@/services/useAccounts';
 import useAccountIds, { AccountIdSet } from '@/services/useAccountIds';
-import useFetch from '@/services/useFetch';
+import useFetch, { toNumber } from '@/services/useFetch';
 
 interface PositionJSON {
-   avg_cost: number | null;  // null for NaN
-   equity: number | null;
-   gains: number;
-   invested: number;
-   pl: number | null;
+   avg_cost: string | null;  // null when unknown
+   equity: string;
+   gains: string;
+   invested: string;
+   pl: string;
+   roi: number | null;       // null for NaN
+   shares: string;
+   weighted_avg: string | null;
+}
+
+interface ClosePriceJSON {
+   t: number;
+   price: string;
    roi: number | null;
-   shares: number;
-   weighted_avg: number | null;
+   shares: string;
 }
 
 interface TickerJSON {
@@ -36,7 +43,7 @@ interface ForAccountJSON {
    period_roi: number | null;  // null for NaN
 
    // sorted chronologically, given in the currency used in the query
-   prices: ClosePrice[];
+   prices: ClosePriceJSON[];
 }
 
 type FullJSON = [
@@ -55,6 +62,16 @@ const useTickers = (
    const { accounts } = useAccounts();
    const accs = useAccountIds(accountIds);
    const nan_dec = (n: number|null) => n === null ? NaN : n;
+   const position = (p: PositionJSON) => ({
+      avg_cost: toNumber(p.avg_cost),
+      equity: toNumber(p.equity),
+      gains: toNumber(p.gains),
+      invested: toNumber(p.invested),
+      pl: toNumber(p.pl),
+      roi: nan_dec(p.roi),
+      shares: toNumber(p.shares),
+      weighted_avg: toNumber(p.weighted_avg),
+   });
 
    const args = React.useMemo(
       () => {
@@ -92,23 +109,14 @@ const useTickers = (
                   oldest_transaction: new Date(a.oldest ?? 0),
                   most_recent_transaction: new Date(a.most_recent ?? 0),
                   now_for_annualized: new Date(a.now_for_annualized),
-                  prices: a.prices,
-                  start: {
-                     ...a.start,
-                     avg_cost: nan_dec(a.start.avg_cost),
-                     equity: nan_dec(a.start.equity),
-                     pl: nan_dec(a.start.pl),
-                     roi: nan_dec(a.start.roi),
-                     weighted_avg: nan_dec(a.start.weighted_avg),
-                  },
-                  end: {
-                     ...a.end,
-                     avg_cost: nan_dec(a.end.avg_cost),
-                     equity: nan_dec(a.end.equity),
-                     pl: nan_dec(a.end.pl),
-                     roi: nan_dec(a.end.roi),
-                     weighted_avg: nan_dec(a.end.weighted_avg),
-                  },
+                  prices: a.prices.map((p): ClosePrice => ({
+                     t: p.t,
+                     price: toNumber(p.price),
+                     roi: nan_dec(p.roi),
+                     shares: toNumber(p.shares),
+                  })),
+                  start: position(a.start),
+                  end: position(a.end),
                };
             }),
          }));
@@ -2,7 +2,7 @@ import * as React from 'react';
 import useAccounts, { Account } from '@/services/useAccounts';
 import { DateRange, toDates } from '@/Dates';
 import { Transaction, incomeExpenseSplits } from '@/Transaction';
-import useFetch from '@/services/useFetch';
+import useFetch, { toNumber } from '@/services/useFetch';
 
 const NO_TRANSACTIONS: Transaction[] = [];
 
@@ -19,12 +19,19 @@ const useTransactions = (
    const { accounts } = useAccounts();
    const discardIE = accountList.length > 1;
    const parse = React.useCallback(
-      (resp: Transaction[]): Transaction[] => {
-         resp.forEach(t =>
-            t.splits.forEach(s =>
-               s.account = accounts.getAccount(s.account_id)
-            )
-         );
+      (json: Transaction[]): Transaction[] => {
+         // Amounts are received as strings
+         let resp = json.map(t => ({
+            ...t,
+            balance: toNumber(t.balance as unknown as string|null),
+            splits: t.splits.map(s => ({
+               ...s,
+               amount: toNumber(s.amount as unknown as string),
+               shares: toNumber(s.shares as unknown as string),
+               price: toNumber(s.price as unknown as string|null),
+               account: accounts.getAccount(s.account_id),
+            })),
+         }));
          if (discardIE) {
             // remove internal transfers
             resp = resp.filter(t => incomeExpenseSplits(t).length > 0);